@@ -1,9 +1,49 @@
 use crate::rand_gen::get_rand_int_range;
-use crate::ray::HitRecord;
-use crate::types::{create_shared_mut, Shared, SharedHittable};
+use crate::memory::MemoryCounter;
+use crate::ray::{HitRecord, HittableList};
+use crate::types::{create_shared_mut, shared_id, Shared, SharedHittable};
 use crate::{Hittable, Ray};
 use itertools::izip;
 use na::Point3;
+use std::cell::Cell;
+
+thread_local! {
+    /// Bounding boxes tested by BVH traversals on this thread, for the heat map debug view.
+    static BOX_TESTS: Cell<usize> = Cell::new(0);
+}
+
+/// Return and reset the number of bounding box tests done on this thread.
+pub fn take_box_tests() -> usize {
+    BOX_TESTS.with(|tests| tests.replace(0))
+}
+
+/// Most objects in a BVH leaf when `BVH_LEAF_SIZE` does not say otherwise.
+const DEFAULT_LEAF_SIZE: usize = 4;
+
+/// Objects per BVH leaf, from the `BVH_LEAF_SIZE` environment variable.
+pub fn leaf_size() -> usize {
+    std::env::var("BVH_LEAF_SIZE")
+        .ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_LEAF_SIZE)
+        .max(1)
+}
+
+/// How much a refitted BVH may outgrow the node area it was built with before it is built anew,
+/// when `BVH_REBUILD_RATIO` does not say otherwise.
+const DEFAULT_REBUILD_RATIO: f32 = 1.5;
+
+/// Growth of the summed node area past which a refit BVH is rebuilt, from `BVH_REBUILD_RATIO`.
+fn rebuild_ratio() -> f32 {
+    std::env::var("BVH_REBUILD_RATIO")
+        .ok()
+        .and_then(|ratio| ratio.parse::<f32>().ok())
+        .filter(|ratio| *ratio >= 1.)
+        .unwrap_or(DEFAULT_REBUILD_RATIO)
+}
+
+/// Bound on the relative rounding error of the distance to a slab, 3 operations of half an ULP.
+const SLAB_ERROR: f32 = 3. * f32::EPSILON / 2. / (1. - 3. * f32::EPSILON / 2.);
 
 #[derive(Clone, Copy)]
 pub struct AxisAlignedBoundingBox {
@@ -40,6 +80,15 @@ impl AxisAlignedBoundingBox {
         }
     }
 
+    /// Surface area, the chance of a random ray hitting the box being proportional to it.
+    pub fn area(&self) -> f32 {
+        let d = self.maximum - self.minimum;
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Whether the ray passes through the box between `t_min` and `t_max`, narrowing the range
+    /// slab by slab. Rays parallel to a slab are inside or outside it for good, instead of
+    /// dividing by zero into infinities and NaNs.
     pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
         izip!(
             ray.origin.iter(),
@@ -47,72 +96,131 @@ impl AxisAlignedBoundingBox {
             self.minimum.iter(),
             self.maximum.iter()
         )
-        .all(|(&a, &b, &min, &max)| {
+        .try_fold((t_min, t_max), |(t_min, t_max), (&a, &b, &min, &max)| {
+            if b == 0. {
+                return (min..=max).contains(&a).then(|| (t_min, t_max))
+            }
             let inv_b = 1. / b;
             let mut t0 = (min - a) * inv_b;
             let mut t1 = (max - a) * inv_b;
             if inv_b < 0. {
                 std::mem::swap(&mut t0, &mut t1);
             }
+            // Widened by the rounding of the subtraction and product, so grazing rays still enter
+            t1 *= 1. + 2. * SLAB_ERROR;
             let (t_min, t_max) = (t_min.max(t0), t_max.min(t1));
-            t_max > t_min
+            (t_min <= t_max).then(|| (t_min, t_max))
         })
+        .is_some()
     }
 }
 
 pub struct BVHNode {
     left: SharedHittable,
     right: SharedHittable,
+    /// Whether the left and the right side are objects of the tree themselves rather than nodes
+    /// or lists of them, a leaf of a single object being traced without a list around it.
+    objects: [bool; 2],
+    /// Which of the sides that are objects are hidden from the outliner.
+    hidden: [bool; 2],
     bbox: AxisAlignedBoundingBox,
-    label: Option<String>
+    label: Option<String>,
+    time: [f32; 2],
+    leaf_size: usize,
+    /// Summed area of the nodes when the tree was built, which refits are measured against.
+    built_area: f32,
 }
 
 impl BVHNode {
-    pub fn new(objects: &[SharedHittable], time0: f32, time1: f32, label: Option<String>) -> Shared<Self> {
-        let mut objects: Vec<_> = objects.iter().map(|x| x.clone()).collect();
+    /// A tree over `objects` with up to `leaf_size` objects per leaf, the leaves being plain lists
+    /// tested one after the other. Fails if one of the objects has no bounding box.
+    pub fn new(objects: &[SharedHittable], time0: f32, time1: f32, leaf_size: usize, label: Option<String>) -> crate::Result<SharedHittable> {
+        Ok(Self::build(objects, [time0, time1], leaf_size, label)?.0)
+    }
+
+    /// The tree over `objects` and the summed area of its nodes.
+    fn build(objects: &[SharedHittable], time: [f32; 2], leaf_size: usize, label: Option<String>) -> crate::Result<(SharedHittable, f32)> {
+        let objects = objects
+            .iter()
+            .map(|x| Ok((Self::box_compare(x)?, x.clone())))
+            .collect::<crate::Result<Vec<_>>>()?;
+        if objects.len() <= leaf_size.max(1) {
+            // A tree of a single object is still a list, which can hide it for the outliner
+            let objects: Vec<_> = objects.into_iter().map(|(_, obj)| obj).collect();
+            return Ok((HittableList::leaf(objects, label), 0.))
+        }
+        let node = Self::split(objects, time, leaf_size, label)?;
+        let area = node.built_area;
+        Ok((create_shared_mut(node), area))
+    }
+
+    /// A node splitting `objects`, keyed by the corner they are sorted on, in two halves.
+    fn split(mut objects: Vec<(Point3<f32>, SharedHittable)>, time: [f32; 2], leaf_size: usize, label: Option<String>) -> crate::Result<Self> {
         let axis = get_rand_int_range(0, 3) as usize;
-        let obj_span = objects.len();
-        let (left, right) = if obj_span == 1 {
-            (objects[0].clone(), objects[0].clone())
-        } else if obj_span == 2 {
-            if Self::box_compare(&objects[0], axis) < Self::box_compare(&objects[1], axis) {
-                (objects[0].clone(), objects[1].clone())
-            } else {
-                (objects[1].clone(), objects[0].clone())
-            }
-        } else {
-            objects.sort_by(|x, y| {
-                Self::box_compare(x, axis)
-                    .partial_cmp(&Self::box_compare(y, axis))
-                    .expect("NaN")
-            });
-            let mid = obj_span / 2;
-            (
-                Self::new(&objects[0..mid], time0, time1, None) as SharedHittable,
-                Self::new(&objects[mid..obj_span], time0, time1, None) as SharedHittable,
-            )
+        objects.sort_by(|(x, _), (y, _)| x[axis].total_cmp(&y[axis]));
+        let objects: Vec<_> = objects.into_iter().map(|(_, obj)| obj).collect();
+        let mid = objects.len() / 2;
+        let side = |objects: &[SharedHittable]| match objects {
+            [object] => Ok((object.clone(), 0.)),
+            objects => Self::build(objects, time, leaf_size, None),
         };
-        let left_box = left.read().unwrap().bounding_box(time0, time1);
-        let right_box = right.read().unwrap().bounding_box(time0, time1);
-        let bbox =
-            AxisAlignedBoundingBox::surrounding_box(left_box, right_box).expect("No bounding_box");
-        create_shared_mut(Self { left, right, bbox, label })
+        let (left, left_area) = side(&objects[..mid])?;
+        let (right, right_area) = side(&objects[mid..])?;
+        let objects = [mid == 1, objects.len() - mid == 1];
+        let left_box = left.read().unwrap().bounding_box(time[0], time[1]);
+        let right_box = right.read().unwrap().bounding_box(time[0], time[1]);
+        let bbox = AxisAlignedBoundingBox::surrounding_box(left_box, right_box)
+            .ok_or(crate::Error::NoBoundingBox)?;
+        let built_area = left_area + right_area + bbox.area();
+        Ok(Self { left, right, objects, hidden: [false; 2], bbox, label, time, leaf_size, built_area })
+    }
+
+    /// The sides traced: the nodes and lists, and the objects not hidden.
+    fn shown(&self) -> impl Iterator<Item = &SharedHittable> {
+        [&self.left, &self.right].into_iter().zip(self.hidden).filter(|(_, hidden)| !hidden).map(|(side, _)| side)
+    }
+
+    /// Which side `child` is, if it is one of the sides that are objects.
+    fn object_side(&self, child: &SharedHittable) -> Option<usize> {
+        let id = shared_id(child);
+        [&self.left, &self.right].into_iter().zip(self.objects).position(|(side, object)| object && shared_id(side) == id)
     }
 
-    fn box_compare(a: &SharedHittable, axis: usize) -> f32 {
+    /// Build the tree anew over the objects in its leaves, keeping the hidden ones hidden.
+    fn rebuild(&mut self) -> crate::Result<()> {
+        let objects = self.children();
+        let hidden: Vec<_> = objects.iter().filter(|obj| self.is_child_hidden(obj)).cloned().collect();
+        let objects = objects
+            .into_iter()
+            .map(|x| Ok((Self::box_compare(&x)?, x)))
+            .collect::<crate::Result<Vec<_>>>()?;
+        *self = Self::split(objects, self.time, self.leaf_size, self.label.clone())?;
+        hidden.iter().for_each(|obj| {
+            self.set_child_hidden(obj, true);
+        });
+        Ok(())
+    }
+
+    fn box_compare(a: &SharedHittable) -> crate::Result<Point3<f32>> {
         a.read()
             .unwrap()
             .bounding_box(0., 0.)
-            .expect("No bounding_box!")
-            .minimum[axis]
+            .map(|bbox| bbox.minimum)
+            .ok_or(crate::Error::NoBoundingBox)
     }
 }
 
 impl Hittable for BVHNode {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        BOX_TESTS.with(|tests| tests.set(tests.get() + 1));
         if !self.bbox.hit(ray, t_min, t_max) {
             return None;
         }
+        if self.hidden != [false; 2] {
+            return self.shown().fold(None, |hit, side| {
+                side.read().unwrap().hit(ray, t_min, hit.as_ref().map_or(t_max, |hit: &HitRecord| hit.t)).or(hit)
+            })
+        }
         let hit_left = self.left.read().unwrap().hit(ray, t_min, t_max);
         if let Some(hit_rec) = &hit_left {
             self.right
@@ -125,6 +233,11 @@ impl Hittable for BVHNode {
         }
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        BOX_TESTS.with(|tests| tests.set(tests.get() + 1));
+        self.bbox.hit(ray, t_min, t_max) && self.shown().any(|side| side.read().unwrap().hit_any(ray, t_min, t_max))
+    }
+
     fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
         Some(self.bbox)
     }
@@ -132,4 +245,102 @@ impl Hittable for BVHNode {
     fn get_label(&self) -> Option<&String> {
         self.label.as_ref()
     }
+    // The tree only speeds up tracing, the outliner lists the objects in its leaves
+    fn children(&self) -> Vec<SharedHittable> {
+        [&self.left, &self.right].into_iter().zip(self.objects).flat_map(|(side, object)| {
+            if object { vec![side.clone()] } else { side.read().unwrap().children() }
+        }).collect()
+    }
+    fn set_child_hidden(&mut self, child: &SharedHittable, hidden: bool) -> bool {
+        if let Some(side) = self.object_side(child) {
+            self.hidden[side] = hidden;
+            return true
+        }
+        [&self.left, &self.right].into_iter().zip(self.objects)
+            .any(|(side, object)| !object && side.write().unwrap().set_child_hidden(child, hidden))
+    }
+    fn is_child_hidden(&self, child: &SharedHittable) -> bool {
+        match self.object_side(child) {
+            Some(side) => self.hidden[side],
+            None => [&self.left, &self.right].into_iter().zip(self.objects)
+                .any(|(side, object)| !object && side.read().unwrap().is_child_hidden(child)),
+        }
+    }
+    /// Goes down the side whose box grows the least, widening the boxes on the way. Leaves grow
+    /// past the leaf size and the boxes overlap more, until [`Hittable::refit`] rebuilds the tree.
+    fn add_child(&mut self, child: SharedHittable) -> bool {
+        let bbox = match child.read().unwrap().bounding_box(self.time[0], self.time[1]) {
+            Some(bbox) => bbox,
+            None => return false,
+        };
+        let growth = |side: &SharedHittable| {
+            let side = side.read().unwrap().bounding_box(self.time[0], self.time[1]);
+            let area = side.map_or(0., |side| side.area());
+            AxisAlignedBoundingBox::surrounding_box(side, Some(bbox)).map_or(0., |grown| grown.area()) - area
+        };
+        let index = if growth(&self.left) <= growth(&self.right) { 0 } else { 1 };
+        let side = if index == 0 { &mut self.left } else { &mut self.right };
+        if self.objects[index] {
+            // The object becomes a leaf of two, which keeps it hidden if it was
+            let leaf = HittableList::leaf(vec![side.clone(), child], None);
+            leaf.write().unwrap().set_child_hidden(side, self.hidden[index]);
+            *side = leaf as SharedHittable;
+            self.objects[index] = false;
+            self.hidden[index] = false;
+        } else if !side.write().unwrap().add_child(child) {
+            return false
+        }
+        self.bbox = AxisAlignedBoundingBox::surrounding_box(Some(self.bbox), Some(bbox)).unwrap_or(self.bbox);
+        true
+    }
+    // The boxes shrink back around what is left
+    fn remove_child(&mut self, child: &SharedHittable) -> bool {
+        if let Some(index) = self.object_side(child) {
+            // An empty leaf, without a box, in its place
+            let side = if index == 0 { &mut self.left } else { &mut self.right };
+            *side = HittableList::leaf(vec![], None) as SharedHittable;
+            self.objects[index] = false;
+            self.hidden[index] = false;
+        } else if !([&self.left, &self.right].into_iter().zip(self.objects)
+            .any(|(side, object)| !object && side.write().unwrap().remove_child(child))) {
+            return false
+        }
+        let left_box = self.left.read().unwrap().bounding_box(self.time[0], self.time[1]);
+        let right_box = self.right.read().unwrap().bounding_box(self.time[0], self.time[1]);
+        if let Some(bbox) = AxisAlignedBoundingBox::surrounding_box(left_box, right_box) {
+            self.bbox = bbox;
+        }
+        true
+    }
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.bvh += std::mem::size_of_val(self);
+        counter.hittable(&self.left);
+        counter.hittable(&self.right);
+    }
+    /// Grow the boxes around moved objects, which is cheap but makes them overlap more and
+    /// more. Once the nodes add up to [`rebuild_ratio`] times the area they were built with,
+    /// rays test so many more boxes that the tree is split anew instead.
+    fn refit(&mut self) -> f32 {
+        let children_area = self.left.write().unwrap().refit() + self.right.write().unwrap().refit();
+        let left_box = self.left.read().unwrap().bounding_box(self.time[0], self.time[1]);
+        let right_box = self.right.read().unwrap().bounding_box(self.time[0], self.time[1]);
+        if let Some(bbox) = AxisAlignedBoundingBox::surrounding_box(left_box, right_box) {
+            self.bbox = bbox;
+        }
+        let area = children_area + self.bbox.area();
+        let growth = area / self.built_area;
+        if growth.is_nan() || growth <= rebuild_ratio() {
+            return area
+        }
+        match self.rebuild() {
+            Ok(()) => {
+                log::info!("Rebuilt a BVH whose nodes grew to {:.1} times their area", growth);
+                self.built_area
+            }
+            Err(e) => {
+                log::warn!("{}, keeping the refitted BVH", e);
+                area
+            }
+        }
+    }
 }
@@ -0,0 +1,135 @@
+//! The samples of the image kept between passes, in `f32` or, where memory is short as in the
+//! browser and on phones, in half the space with `f16`.
+
+use half::f16;
+use serde::{Deserialize, Serialize};
+use crate::renderer::Sample;
+
+/// Precision the image is accumulated in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Precision {
+    /// Sums of the samples in `f32`, a pass traced in full before it is added.
+    Full,
+    /// Averages of the samples in `f16`, each with the part rounding it to `f16` lost also in
+    /// `f16`, updated in `f32` as every part of a pass comes in. The lost part carries the
+    /// updates too small for the average alone, which would otherwise stop changing after a
+    /// few thousand samples, and no pass is kept in `f32` beside the image.
+    Half,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
+/// The accumulated samples, one per image and light group of every pixel.
+pub(crate) enum Accumulation {
+    Full(Vec<Sample>),
+    Half {
+        averages: Vec<[f16; 4]>,
+        /// What rounding every average to `f16` lost.
+        residuals: Vec<[f16; 4]>,
+    },
+}
+
+impl Accumulation {
+    /// `len` samples yet to be traced.
+    pub fn new(precision: Precision, len: usize) -> Self {
+        match precision {
+            Precision::Full => Self::Full(vec![Sample::zeros(); len]),
+            Precision::Half => Self::Half { averages: vec![[f16::ZERO; 4]; len], residuals: vec![[f16::ZERO; 4]; len] },
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self {
+            Self::Full(_) => Precision::Full,
+            Self::Half { .. } => Precision::Half,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Full(sums) => sums.len(),
+            Self::Half { averages, .. } => averages.len(),
+        }
+    }
+
+    /// Average of the `i`th sample over the `samples` taken so far.
+    pub fn average(&self, i: usize, samples: usize) -> Option<Sample> {
+        match self {
+            Self::Full(sums) => sums.get(i).map(|sum| sum / samples.max(1) as f32),
+            Self::Half { averages, residuals } => Some(widen(averages.get(i)?) + widen(&residuals[i])),
+        }
+    }
+
+    /// Averages of all the samples over the `samples` taken so far.
+    pub fn averages(&self, samples: usize) -> Box<dyn Iterator<Item = Sample> + '_> {
+        match self {
+            Self::Full(sums) => Box::new(sums.iter().map(move |sum| sum / samples.max(1) as f32)),
+            Self::Half { averages, residuals } => {
+                Box::new(averages.iter().zip(residuals).map(|(average, residual)| widen(average) + widen(residual)))
+            }
+        }
+    }
+
+    /// Add a pass of `added` samples per pixel to the `done` ones so far.
+    pub fn add(&mut self, pass: Vec<Sample>, done: usize, added: usize) {
+        match self {
+            Self::Full(sums) => sums.iter_mut().zip(pass).for_each(|(sum, sample)| *sum += sample),
+            Self::Half { .. } => self.fold(0, &pass, done, added),
+        }
+    }
+
+    /// Add the sums of `added` samples of the pixels from the `start`th sample on to the `done`
+    /// ones so far, in place. Only for [`Precision::Half`], whose averages stay right for every
+    /// pixel as they come in; sums would have to wait for the whole pass.
+    pub fn fold(&mut self, start: usize, sums: &[Sample], done: usize, added: usize) {
+        let (averages, residuals) = match self {
+            Self::Half { averages, residuals } => (averages, residuals),
+            Self::Full(_) => unreachable!("sums are added a whole pass at a time"),
+        };
+        let total = (done + added) as f32;
+        let range = start..start + sums.len();
+        averages[range.clone()].iter_mut().zip(&mut residuals[range]).zip(sums).for_each(|((average, residual), sum)| {
+            let updated = ((widen(average) + widen(residual)) * done as f32 + sum) / total;
+            *average = narrow(&updated);
+            *residual = narrow(&(updated - widen(average)));
+        });
+    }
+
+    /// Show `pass` as the image until it is added, e.g. the rows of the first pass traced so far.
+    pub fn show(&mut self, pass: &[Sample]) {
+        match self {
+            Self::Full(sums) => sums.copy_from_slice(pass),
+            Self::Half { .. } => self.fold(0, pass, 0, 1),
+        }
+    }
+
+    /// Drop every sample, keeping the size.
+    pub fn clear(&mut self) {
+        *self = Self::new(self.precision(), self.len());
+    }
+
+    /// The accumulation of `samples` per pixel summing up to `sums`.
+    pub fn from_sums(precision: Precision, sums: impl Iterator<Item = Sample>, samples: usize) -> Self {
+        match precision {
+            Precision::Full => Self::Full(sums.collect()),
+            Precision::Half => {
+                let sums: Vec<_> = sums.collect();
+                let mut accumulation = Self::new(precision, sums.len());
+                accumulation.fold(0, &sums, 0, samples.max(1));
+                accumulation
+            }
+        }
+    }
+}
+
+fn widen(sample: &[f16; 4]) -> Sample {
+    Sample::from_fn(|i, _| sample[i].to_f32())
+}
+
+fn narrow(sample: &Sample) -> [f16; 4] {
+    [0, 1, 2, 3].map(|i| f16::from_f32(sample[i]))
+}
@@ -0,0 +1,276 @@
+use std::f32::consts::PI;
+use image::Rgb32FImage;
+use na::{Point3, UnitVector3, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::resource;
+use crate::types::Color;
+use std::sync::Arc;
+use crate::pdf::{Distribution2D, EnvironmentPDF, PDF, SpherePDF};
+
+/// Angular radius of the sun disk in degrees, twice the real one for softer shadows.
+const SUN_RADIUS: f32 = 0.5;
+/// Radiance of the sun high in the sky, lighting the ground about as brightly as the sky above.
+const SUN_RADIANCE: f32 = 12000.;
+
+/// Radiance arriving from directions that miss every object.
+#[derive(Clone)]
+pub enum Background {
+    Solid(Color),
+    /// The sky of the book, blending from `bottom` straight down to `top` straight up.
+    Sky { bottom: Color, top: Color },
+    /// Blend from `horizon` to `zenith` with the height of the direction.
+    Gradient { horizon: Color, zenith: Color },
+    /// Equirectangular environment map, turned around the y axis by `rotation` degrees.
+    /// `distribution` follows the brightness of the map, to aim diffuse bounces at the sun and windows.
+    Hdri { image: Option<Rgb32FImage>, rotation: f32, distribution: Option<Arc<Distribution2D>> },
+    /// A procedural daylight sky with the sun in direction `sun`, reddening and dimming as it sets.
+    SunSky { sun: UnitVector3<f32> },
+    /// Skybox of six square images, in the order of [`CUBE_FACES`], turned around the y axis by
+    /// `rotation` degrees.
+    Cubemap { faces: Option<Arc<[Rgb32FImage; 6]>>, rotation: f32 },
+}
+
+/// First bytes of every OpenEXR file.
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+/// Linear RGB of the environment map or skybox face in `bytes`, read with the `exr` crate for
+/// OpenEXR files, which the `image` crate is built without.
+fn decode(bytes: &[u8]) -> crate::Result<Rgb32FImage> {
+    if !bytes.starts_with(&EXR_MAGIC) {
+        return Ok(image::load_from_memory(bytes)?.to_rgb32f())
+    }
+    use exr::prelude::*;
+    let image = read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .rgba_channels(
+            |resolution, _| Rgb32FImage::new(resolution.width() as u32, resolution.height() as u32),
+            |image, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                image.put_pixel(position.x() as u32, position.y() as u32, image::Rgb([r, g, b]))
+            },
+        )
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(std::io::Cursor::new(bytes))?;
+    Ok(image.layer_data.channel_data.pixels)
+}
+
+/// The faces of a [`Background::Cubemap`] in the order they are given, seen from the inside with
+/// the y axis up: the OpenGL layout most skybox downloads come in.
+pub const CUBE_FACES: [&str; 6] = ["right (+x)", "left (-x)", "top (+y)", "bottom (-y)", "front (+z)", "back (-z)"];
+
+impl Background {
+    /// White to sky blue, as in *Ray Tracing in One Weekend*.
+    pub fn sky() -> Self {
+        Self::Sky { bottom: Color::repeat(1.), top: Color::from([0.5, 0.7, 1.]) }
+    }
+
+    /// The sun and sky seen at `time`.
+    pub fn time_of_day(time: TimeOfDay) -> Self {
+        Self::SunSky { sun: time.sun() }
+    }
+
+    /// Bytes held by the environment map and its distribution.
+    pub fn memory(&self) -> usize {
+        match self {
+            Self::Hdri { image, distribution, .. } =>
+                image.as_ref().map_or(0, |image| image.as_raw().len() * std::mem::size_of::<f32>())
+                    + distribution.as_ref().map_or(0, |distribution| distribution.memory()),
+            Self::Cubemap { faces: Some(faces), .. } =>
+                faces.iter().map(|face| face.as_raw().len() * std::mem::size_of::<f32>()).sum(),
+            _ => 0,
+        }
+    }
+
+    pub fn hdri(filename: &str, rotation: f32) -> Self {
+        let image = resource::load_binary(filename)
+            .and_then(|bytes| decode(&bytes))
+            .map_err(|e| log::error!("Failed to load {}: {}", filename, e))
+            .ok();
+        let distribution = image.as_ref().and_then(|image| {
+            let height = image.height();
+            let weights: Vec<f32> = image
+                .enumerate_pixels()
+                .map(|(_, y, p)| {
+                    // Rows near the poles cover less of the sphere
+                    let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+                    (0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]) * sin_theta
+                })
+                .collect();
+            Distribution2D::new(image.width() as usize, &weights).map(Arc::new)
+        });
+        Self::Hdri { image, rotation, distribution }
+    }
+
+    /// A skybox of the six images `files`, in the order of [`CUBE_FACES`].
+    pub fn cubemap(files: &[String; 6], rotation: f32) -> Self {
+        let faces: Option<Vec<_>> = files
+            .iter()
+            .map(|filename| {
+                resource::load_binary(filename)
+                    .and_then(|bytes| decode(&bytes))
+                    .map_err(|e| log::error!("Failed to load {}: {}", filename, e))
+                    .ok()
+            })
+            .collect();
+        let faces = faces.and_then(|faces| <[Rgb32FImage; 6]>::try_from(faces).ok()).map(Arc::new);
+        Self::Cubemap { faces, rotation }
+    }
+
+    /// Directions drawn in proportion to the brightness of an environment map, or towards the sun.
+    /// `None` for the other backgrounds, which are smooth enough for the directions of the materials.
+    pub fn importance(&self) -> Option<Box<dyn PDF + Send + Sync>> {
+        match self {
+            Self::Hdri { distribution: Some(distribution), rotation, .. } =>
+                Some(EnvironmentPDF::new(distribution.clone(), *rotation)),
+            Self::SunSky { sun } if sun.y > -SUN_RADIUS.to_radians() =>
+                Some(SpherePDF::new(Point3::origin(), Point3::from(sun.into_inner()), SUN_RADIUS.to_radians().sin())),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self, direction: UnitVector3<f32>) -> Color {
+        match self {
+            Self::Solid(color) => *color,
+            Self::Sky { bottom, top } => bottom.lerp(top, 0.5 * (direction.y + 1.)),
+            Self::Gradient { horizon, zenith } => {
+                let t = direction.y.max(0.);
+                horizon.lerp(zenith, t)
+            }
+            Self::Hdri { image: Some(image), rotation, .. } => {
+                let [u, v] = direction_to_map(direction, *rotation);
+                let (w, h) = image.dimensions();
+                let x = ((u * w as f32) as u32).min(w - 1);
+                let y = ((v * h as f32) as u32).min(h - 1);
+                Color::from(image.get_pixel(x, y).0)
+            }
+            Self::Hdri { image: None, .. } | Self::Cubemap { faces: None, .. } => Color::from([0., 1., 1.]),
+            Self::Cubemap { faces: Some(faces), rotation } => {
+                let (face, [u, v]) = direction_to_cube(direction, *rotation);
+                let image = &faces[face];
+                let (w, h) = image.dimensions();
+                let x = ((u * w as f32) as u32).min(w - 1);
+                let y = ((v * h as f32) as u32).min(h - 1);
+                Color::from(image.get_pixel(x, y).0)
+            }
+            Self::SunSky { sun } => sun_sky(*sun, direction),
+        }
+    }
+}
+
+/// Light of a clear sky seen along `direction` with the sun towards `sun`. Not a physical model, a
+/// blend of a blue zenith and a pale horizon turning orange around the sun when it is low.
+fn sun_sky(sun: UnitVector3<f32>, direction: UnitVector3<f32>) -> Color {
+    let smoothstep = |edge0: f32, edge1: f32, x: f32| {
+        let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+        t * t * (3. - 2. * t)
+    };
+    // Daylight fades out over the twilight, down to a faint night sky
+    let day = smoothstep(-0.15, 0.1, sun.y);
+    let low = 1. - smoothstep(0., 0.4, sun.y);
+    let height = direction.y.max(0.);
+    let zenith = Color::new(0.15, 0.35, 0.9);
+    let horizon = Color::new(0.75, 0.85, 1.).lerp(&Color::new(1., 0.55, 0.3), low);
+    let mut sky = horizon.lerp(&zenith, height.powf(0.5));
+    // Glow around the sun, wide and warm at sunset
+    let glow = direction.dot(&sun).max(0.).powi(8);
+    sky += Color::new(1., 0.6, 0.3) * glow * (0.3 + low);
+    // The ground below the horizon only sees a darker sky
+    if direction.y < 0. {
+        sky *= 0.5;
+    }
+    let mut color = sky * day + Color::new(0.002, 0.003, 0.008);
+    if direction.dot(&sun) >= SUN_RADIUS.to_radians().cos() && direction.y > 0. {
+        let white = Color::new(1., 0.95, 0.9);
+        let red = Color::new(1., 0.35, 0.08);
+        color += white.lerp(&red, low) * SUN_RADIANCE * smoothstep(-0.02, 0.1, sun.y);
+    }
+    color
+}
+
+/// Where and when the sun of a [`Background::SunSky`] is seen from.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TimeOfDay {
+    /// Degrees north of the equator, negative to the south.
+    pub latitude: f32,
+    /// Day of the year, 1 on January 1st.
+    pub day: f32,
+    /// Local solar time in hours, 12 at noon.
+    pub hour: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        // A summer afternoon in central Europe
+        Self { latitude: 48., day: 172., hour: 15. }
+    }
+}
+
+impl TimeOfDay {
+    pub fn sun(&self) -> UnitVector3<f32> {
+        sun_direction(self.latitude, self.day, self.hour)
+    }
+}
+
+impl std::str::FromStr for TimeOfDay {
+    type Err = String;
+
+    /// Parse `latitude,day,hour`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(',').map(str::trim).collect::<Vec<_>>()[..] {
+            [latitude, day, hour] => Ok(Self {
+                latitude: latitude.parse().map_err(|e| format!("{}: {}", latitude, e))?,
+                day: day.parse().map_err(|e| format!("{}: {}", day, e))?,
+                hour: hour.parse().map_err(|e| format!("{}: {}", hour, e))?,
+            }),
+            _ => Err(format!("expected latitude,day,hour, got {}", s)),
+        }
+    }
+}
+
+/// The direction of the sun seen from `latitude` degrees north on day `day` of the year (1 on
+/// January 1st) at `hour`s of local solar time, 12 at noon. The y axis points up, -z north and x east.
+pub fn sun_direction(latitude: f32, day: f32, hour: f32) -> UnitVector3<f32> {
+    let latitude = latitude.to_radians();
+    // Approximations of the declination and the hour angle, good to about a degree
+    let declination = (-23.44_f32).to_radians() * (2. * PI / 365. * (day + 10.)).cos();
+    let hour_angle = (15. * (hour - 12.)).to_radians();
+    let east = -declination.cos() * hour_angle.sin();
+    let north = latitude.cos() * declination.sin() - latitude.sin() * declination.cos() * hour_angle.cos();
+    let up = latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    UnitVector3::new_normalize(Vector3::new(east, up, -north))
+}
+
+/// Coordinates in `0..1` of `direction` on an equirectangular map turned by `rotation` degrees,
+/// from the left and top edges.
+pub(crate) fn direction_to_map(direction: UnitVector3<f32>, rotation: f32) -> [f32; 2] {
+    let u = ((-direction.z).atan2(direction.x) + PI) / (2. * PI) + rotation / 360.;
+    let v = (-direction.y).acos() / PI;
+    [u.rem_euclid(1.), (1. - v).clamp(0., 1.)]
+}
+
+/// The direction at `uv` of an equirectangular map turned by `rotation` degrees, see [`direction_to_map`].
+pub(crate) fn map_to_direction(uv: [f32; 2], rotation: f32) -> UnitVector3<f32> {
+    let phi = 2. * PI * (uv[0] - rotation / 360.) - PI;
+    let theta = PI * (1. - uv[1]);
+    let sin_theta = theta.sin();
+    UnitVector3::new_normalize(Vector3::new(sin_theta * phi.cos(), -theta.cos(), -sin_theta * phi.sin()))
+}
+
+/// The face of a cube map `direction` falls on, in the order of [`CUBE_FACES`], and the
+/// coordinates in `0..1` on it from the left and top edges, the map turned by `rotation` degrees.
+pub(crate) fn direction_to_cube(direction: UnitVector3<f32>, rotation: f32) -> (usize, [f32; 2]) {
+    let (sin, cos) = rotation.to_radians().sin_cos();
+    let (x, y, z) = (direction.x * cos - direction.z * sin, direction.y, direction.x * sin + direction.z * cos);
+    let axis = Vector3::new(x, y, z).iamax();
+    // Major axis, then the directions along the right and down edges of the face
+    let (face, major, right, down) = match axis {
+        0 if x > 0. => (0, x, -z, -y),
+        0 => (1, -x, z, -y),
+        1 if y > 0. => (2, y, x, z),
+        1 => (3, -y, x, -z),
+        _ if z > 0. => (4, z, x, -y),
+        _ => (5, -z, -x, -y),
+    };
+    (face, [right / major, down / major].map(|c| ((c + 1.) / 2.).clamp(0., 1.)))
+}
@@ -1,7 +1,10 @@
 use crate::Ray;
 use na::{Point3, UnitVector3, Vector3};
+use serde::{Deserialize, Serialize};
+use strum::EnumIter;
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct Camera {
     pub origin: Point3<f32>,
     pub horizontal: Vector3<f32>,
@@ -17,15 +20,17 @@ pub struct Camera {
     focus_dist: f32,
     time0: f32,
     time1: f32,
+    settings: CameraSettings,
 }
 
-use crate::rand_gen::{get_rand_range, rand_vec3_in_unit_disk};
+use crate::rand_gen::{get_rand, rand_vec3_in_unit_disk};
 use std::f32::consts::PI;
 
 pub fn degree_to_radian(degree: f32) -> f32 {
     degree / 180.0 * PI
 }
 impl Camera {
+    /// A thin lens camera with a vertical field of view of `vfov` degrees, exposed from `time0` to `time1`.
     pub fn new(
         lookfrom: Point3<f32>,
         direction: UnitVector3<f32>,
@@ -43,6 +48,8 @@ impl Camera {
         let viewport_width = viewport_height * aspect_ratio;
 
         let w = -direction;
+        // Straight up or down, any horizontal up will do
+        let vup = if vup.cross(&w).norm_squared() < 1e-12 { Vector3::z() } else { vup };
         let u = vup.cross(&w);
         let v = w.cross(&u);
 
@@ -64,77 +71,466 @@ impl Camera {
             focus_dist,
             time0,
             time1,
-            aspect_ratio
+            aspect_ratio,
+            settings: CameraSettings {
+                lookfrom: lookfrom.into(),
+                lookat: (lookfrom + direction.into_inner() * focus_dist).into(),
+                vfov,
+                aperture,
+                focus_dist,
+                shutter: [time0, time1],
+                motion_blur: time0 != time1,
+                shutter_curve: ShutterCurve::Box,
+                rolling_shutter: 0.,
+                projection: Projection::Perspective,
+            },
         }
     }
 
     #[cfg(feature = "window")]
     pub(crate) fn rebuild(&mut self) {
-        *self = Camera::new(
-            self.origin,
-            -self.w,
-            self.vup,
-            self.vfov,
-            self.aspect_ratio,
-            self.len_radius * 2.,
-            self.focus_dist,
-            self.time0,
-            self.time1,
-        );
+        *self = Camera::from_settings(&self.settings, self.aspect_ratio);
     }
 
     pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.len_radius * rand_vec3_in_unit_disk();
-        let offset = self.u * rd.x + self.v * rd.y;
+        let rd = rand_vec3_in_unit_disk();
+        self.sample_ray(s, t, [rd.x, rd.y], get_rand())
+    }
+
+    /// The ray through `(s, t)` from the point `lens` of the unit disk, at the fraction `time` of the shutter interval.
+    pub fn sample_ray(&self, s: f32, t: f32, lens: [f32; 2], time: f32) -> Ray {
+        let time = self.exposure_time(t, time);
+        if self.settings.projection == Projection::Stereographic {
+            return Ray::new(self.origin, self.stereographic_direction(s, t), self.time0 + time * (self.time1 - self.time0))
+        }
+        let offset = self.len_radius * (self.u * lens[0] + self.v * lens[1]);
         Ray::new(
             self.origin + offset,
             UnitVector3::new_normalize(self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset),
-            get_rand_range(self.time0, self.time1),
+            self.time0 + time * (self.time1 - self.time0),
         )
     }
 
+    /// When the row at height `t` sees the scene, for a uniform `time` in the shutter interval:
+    /// drawn from the shutter curve within the exposure of the row, which starts later the lower
+    /// the row is with a rolling shutter.
+    fn exposure_time(&self, t: f32, time: f32) -> f32 {
+        let rolling = self.settings.rolling_shutter.clamp(0., 1.);
+        rolling * (1. - t.clamp(0., 1.)) + (1. - rolling) * self.settings.shutter_curve.warp(time)
+    }
+
+    /// Half the height of the image in the plane of a stereographic projection, where a direction
+    /// `theta` off the view lies `2 tan(theta / 2)` from the center.
+    fn stereographic_half_height(&self) -> f32 {
+        2. * (degree_to_radian(self.vfov) / 4.).tan()
+    }
+
+    /// The direction seen at `(s, t)` through a stereographic projection. Without a lens, all of
+    /// the image is in focus.
+    fn stereographic_direction(&self, s: f32, t: f32) -> UnitVector3<f32> {
+        let half_height = self.stereographic_half_height();
+        let x = (2. * s - 1.) * half_height * self.aspect_ratio;
+        let y = (2. * t - 1.) * half_height;
+        let theta = 2. * (x.hypot(y) / 2.).atan();
+        let phi = y.atan2(x);
+        let side = phi.cos() * self.u.normalize() + phi.sin() * self.v.normalize();
+        UnitVector3::new_normalize(-theta.cos() * self.w.into_inner() + theta.sin() * side)
+    }
+
+    /// The camera moved sideways by `offset`, positive to the right, still framing the same
+    /// rectangle of the focus plane: the two eyes of a stereo pair converge at the focus distance.
+    pub fn stereo_eye(&self, offset: f32) -> Self {
+        Self {
+            origin: self.origin + offset * self.u.normalize(),
+            ..self.clone()
+        }
+    }
+
+    /// Pinhole cameras looking along the axes from the same point, the faces of a cube map named
+    /// `px`, `nx`, `py`, `ny`, `pz` and `nz`. The side faces are upright and the top and bottom
+    /// ones meet the `nz` face along their bottom and top edge.
+    pub fn cube_faces(&self) -> [(&'static str, Camera); 6] {
+        let face = |direction: [f32; 3], up: [f32; 3]| Camera::new(
+            self.origin,
+            UnitVector3::new_normalize(Vector3::from(direction)),
+            Vector3::from(up),
+            90.,
+            1.,
+            0.,
+            1.,
+            self.time0,
+            self.time1,
+        );
+        [
+            ("px", face([1., 0., 0.], [0., 1., 0.])),
+            ("nx", face([-1., 0., 0.], [0., 1., 0.])),
+            ("py", face([0., 1., 0.], [0., 0., 1.])),
+            ("ny", face([0., -1., 0.], [0., 0., -1.])),
+            ("pz", face([0., 0., 1.], [0., 1., 0.])),
+            ("nz", face([0., 0., -1.], [0., 1., 0.])),
+        ]
+    }
+
+    /// A ray through the center of the lens, for picking rather than rendering.
+    pub fn get_pinhole_ray(&self, s: f32, t: f32) -> Ray {
+        self.sample_ray(s, t, [0.; 2], 0.)
+    }
+
+    /// Normalized image coordinates of a world point, `None` if it is behind the camera.
+    pub fn project(&self, p: Point3<f32>) -> Option<[f32; 2]> {
+        let d = p - self.origin;
+        if self.settings.projection == Projection::Stereographic {
+            // Right behind the camera is infinitely far out
+            let d = d.normalize();
+            let (x, y) = (d.dot(&self.u.normalize()), d.dot(&self.v.normalize()));
+            let theta = x.hypot(y).atan2(-d.dot(&self.w));
+            if theta > PI * 0.99 {
+                return None
+            }
+            let r = 2. * (theta / 2.).tan() / x.hypot(y).max(f32::MIN_POSITIVE);
+            let half_height = self.stereographic_half_height();
+            return Some([
+                (x * r / (half_height * self.aspect_ratio) + 1.) / 2.,
+                (y * r / half_height + 1.) / 2.,
+            ])
+        }
+        let depth = -d.dot(&self.w);
+        if depth <= 0. {
+            return None
+        }
+        let on_plane = self.origin + d * (self.focus_dist / depth) - self.lower_left_corner;
+        Some([
+            on_plane.dot(&self.horizontal) / self.horizontal.norm_squared(),
+            on_plane.dot(&self.vertical) / self.vertical.norm_squared(),
+        ])
+    }
+
+    /// The camera described by `settings` for an image of `aspect_ratio`.
+    pub fn from_settings(settings: &CameraSettings, aspect_ratio: f32) -> Self {
+        let lookfrom = Point3::from(settings.lookfrom);
+        let lookat = Point3::from(settings.lookat);
+        let mut camera = Camera::new(
+            lookfrom,
+            UnitVector3::new_normalize(lookat - lookfrom),
+            Vector3::y(),
+            settings.vfov,
+            aspect_ratio,
+            settings.aperture,
+            settings.focus_dist,
+            settings.shutter[0],
+            if settings.motion_blur { settings.shutter[1] } else { settings.shutter[0] },
+        );
+        camera.settings = *settings;
+        camera
+    }
+
+    pub fn settings(&self) -> CameraSettings {
+        self.settings
+    }
+
+    /// The camera a built-in scene is set up for.
     pub fn select_camera(aspect_ratio: f32, scene: &str) -> Self {
+        Self::from_settings(&CameraSettings::select(scene), aspect_ratio)
+    }
+}
+
+/// The user-facing camera parameters, editable from the GUI.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct CameraSettings {
+    pub lookfrom: [f32; 3],
+    pub lookat: [f32; 3],
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    /// When the shutter opens and closes, within the 0 to 1 the moving objects are built for.
+    #[serde(default = "default_shutter")]
+    pub shutter: [f32; 2],
+    /// Off, every ray is shot at the opening time and moving objects render crisp.
+    #[serde(default = "default_motion_blur")]
+    pub motion_blur: bool,
+    #[serde(default = "default_shutter_curve")]
+    pub shutter_curve: ShutterCurve,
+    /// Fraction of the shutter interval the rows are read out over, from the top one, 0 for a
+    /// global shutter exposing them all at once.
+    #[serde(default)]
+    pub rolling_shutter: f32,
+    #[serde(default = "default_projection")]
+    pub projection: Projection,
+}
+
+/// How open the shutter is while it opens and closes, weighting the times moving objects are
+/// seen at.
+#[derive(Clone, Copy, PartialEq, Debug, EnumIter, Serialize, Deserialize)]
+pub enum ShutterCurve {
+    /// Fully open at once, for evenly blurred streaks.
+    Box,
+    /// Opening over the first quarter of the interval and closing over the last one.
+    Trapezoid,
+    /// Opening over half of the interval and closing right after, for streaks fading out at both ends.
+    Triangle,
+}
+
+impl ShutterCurve {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Box => "box",
+            Self::Trapezoid => "trapezoid",
+            Self::Triangle => "triangle",
+        }
+    }
+
+    /// Fraction of the interval spent opening, and as much closing.
+    fn ramp(&self) -> f32 {
+        match self {
+            Self::Box => 0.,
+            Self::Trapezoid => 0.25,
+            Self::Triangle => 0.5,
+        }
+    }
+
+    /// A time in `0..1` drawn with the density of the curve, from a uniform `u`.
+    pub fn warp(&self, u: f32) -> f32 {
+        let ramp = self.ramp();
+        if ramp == 0. {
+            return u
+        }
+        // Inverse of the CDF of a trapezoid of height `h`, quadratic along the ramps
+        let h = 1. / (1. - ramp);
+        let ramp_mass = h * ramp / 2.;
+        if u < ramp_mass {
+            (2. * ramp * u / h).sqrt()
+        } else if u > 1. - ramp_mass {
+            1. - (2. * ramp * (1. - u) / h).sqrt()
+        } else {
+            u / h + ramp / 2.
+        }
+    }
+}
+
+impl std::str::FromStr for ShutterCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Self::Box),
+            "trapezoid" => Ok(Self::Trapezoid),
+            "triangle" => Ok(Self::Triangle),
+            _ => Err(format!("expected box, trapezoid or triangle, got {}", s)),
+        }
+    }
+}
+
+fn default_shutter_curve() -> ShutterCurve {
+    ShutterCurve::Box
+}
+
+/// How the directions around the camera are laid out on the image.
+#[derive(Clone, Copy, PartialEq, Debug, EnumIter, Serialize, Deserialize)]
+pub enum Projection {
+    /// Straight lines stay straight, for fields of view below 180 degrees.
+    Perspective,
+    /// Up to nearly all directions around the camera, bending lines into circles. Looking
+    /// straight down at the ground gives the "little planet" of a panorama.
+    Stereographic,
+}
+
+impl Projection {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Perspective => "perspective",
+            Self::Stereographic => "stereographic",
+        }
+    }
+
+    /// The widest vertical field of view it can show, in degrees.
+    pub fn max_vfov(&self) -> f32 {
+        match self {
+            Self::Perspective => 120.,
+            Self::Stereographic => 340.,
+        }
+    }
+}
+
+impl std::str::FromStr for Projection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perspective" => Ok(Self::Perspective),
+            "stereographic" => Ok(Self::Stereographic),
+            _ => Err(format!("expected perspective or stereographic, got {}", s)),
+        }
+    }
+}
+
+fn default_projection() -> Projection {
+    Projection::Perspective
+}
+
+fn default_shutter() -> [f32; 2] {
+    [0., 1.]
+}
+
+fn default_motion_blur() -> bool {
+    true
+}
+
+impl CameraSettings {
+    /// The camera settings a built-in scene is set up for.
+    pub fn select(scene: &str) -> Self {
         let mut aperture = 0.1;
         let dist_to_focus = 10.;
         let mut vfov = 20.;
-        let vup = Vector3::y();
         let mut lookfrom = Point3::from([13., 1.5, 3.]) * 2.;
         let mut lookat = Point3::from([0., 2., 0.]);
-        let mut direction = lookat - lookfrom;
-        let time0 = 0.;
-        let time1 = 1.;
         match scene {
             "cornell" | "smoke" => {
                 lookfrom = Point3::from([278., 278., -800.]);
                 lookat = Point3::from([278., 278., 0.]);
-                direction = lookat - lookfrom;
                 vfov = 40.0;
                 aperture = 0.;
             }
             "simplelight" => {
 
+            }
+            "asteroids" => {
+                lookfrom = Point3::from([0., 7., 28.]);
+                lookat = Point3::origin();
+                vfov = 40.;
+                aperture = 0.;
+            }
+            "matpreview" => {
+                lookfrom = Point3::from([0., 2.5, 6.]);
+                lookat = Point3::from([0., 1.2, 0.]);
+                vfov = 30.;
+                aperture = 0.;
+            }
+            "furnace" => {
+                lookfrom = Point3::from([0., 0., 5.]);
+                lookat = Point3::origin();
+                vfov = 30.;
+                aperture = 0.;
+            }
+            "portal" => {
+                lookfrom = Point3::from([0., 2., 8.]);
+                lookat = Point3::from([-1., 1.5, 0.]);
+                vfov = 40.;
+                aperture = 0.;
+            }
+            "city" => {
+                lookfrom = Point3::from([16., 9., 20.]);
+                lookat = Point3::from([0., 1., 0.]);
+                vfov = 40.;
+                aperture = 0.;
             }
             "final" => {
                 lookfrom = Point3::from([478., 278., -600.]);
                 lookat = Point3::from([278., 278., 0.]);
-                direction = lookat - lookfrom;
                 vfov = 40.;
             },
             _ => {
                 lookfrom = Point3::from([13., 2., 3.]);
-                direction = Vector3::from([-13., -2., -3.]);
+                lookat = Point3::origin();
             }
         };
-        Camera::new(
-            lookfrom,
-            UnitVector3::new_normalize(direction),
-            vup,
+        Self {
+            lookfrom: lookfrom.into(),
+            lookat: lookat.into(),
             vfov,
-            aspect_ratio,
             aperture,
-            dist_to_focus,
-            time0,
-            time1,
-        )
+            focus_dist: dist_to_focus,
+            shutter: default_shutter(),
+            motion_blur: default_motion_blur(),
+            shutter_curve: default_shutter_curve(),
+            rolling_shutter: 0.,
+            projection: default_projection(),
+        }
+    }
+}
+
+/// Values of a camera setting at some frames of an animation, eased in and out from one to the
+/// next, e.g. the focus distance of a focus pull.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Keyframes(Vec<(usize, f32)>);
+
+impl Keyframes {
+    /// The value at `frame`, held before the first key and after the last.
+    pub fn at(&self, frame: usize) -> f32 {
+        let next = self.0.iter().position(|&(key, _)| key > frame).unwrap_or(self.0.len());
+        match (next.checked_sub(1).map(|i| self.0[i]), self.0.get(next)) {
+            (Some((frame0, value0)), Some(&(frame1, value1))) => {
+                let t = (frame - frame0) as f32 / (frame1 - frame0) as f32;
+                value0 + (value1 - value0) * t * t * (3. - 2. * t)
+            }
+            (Some((_, value)), None) | (None, Some(&(_, value))) => value,
+            (None, None) => unreachable!("keyframes are never empty"),
+        }
+    }
+}
+
+impl std::str::FromStr for Keyframes {
+    type Err = String;
+
+    /// Parse `frame:value,frame:value,...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = s
+            .split(',')
+            .map(|key| {
+                let (frame, value) = key.split_once(':').ok_or_else(|| format!("expected frame:value, got {}", key))?;
+                let frame = frame.trim().parse::<usize>().map_err(|e| format!("{}: {}", frame, e))?;
+                let value = value.trim().parse::<f32>().map_err(|e| format!("{}: {}", value, e))?;
+                Ok((frame, value))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        keys.sort_by_key(|&(frame, _)| frame);
+        keys.dedup_by_key(|&mut (frame, _)| frame);
+        Ok(Self(keys))
+    }
+}
+
+/// Camera moves of an animation needing no keyframes, to make a showcase of any scene.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CameraPath {
+    /// Once around the center of the scene, at the distance and height the camera starts at.
+    Turntable,
+    /// A dolly in towards the center of the scene, easing in and out, ending at a third of the
+    /// distance the camera starts at.
+    Flythrough,
+}
+
+impl CameraPath {
+    /// `settings` at `frame` of `frames` along the path around `center`, looking at and focused on it.
+    pub fn at(&self, settings: &CameraSettings, center: Point3<f32>, frame: usize, frames: usize) -> CameraSettings {
+        let offset = Point3::from(settings.lookfrom) - center;
+        let offset = match self {
+            Self::Turntable => {
+                // The last frame leads back into the first one for looping
+                let angle = 2. * PI * frame as f32 / frames.max(1) as f32;
+                let (sin, cos) = angle.sin_cos();
+                Vector3::new(offset.x * cos + offset.z * sin, offset.y, offset.z * cos - offset.x * sin)
+            }
+            Self::Flythrough => {
+                let t = frame as f32 / frames.saturating_sub(1).max(1) as f32;
+                offset * (1. - 2. / 3. * t * t * (3. - 2. * t))
+            }
+        };
+        CameraSettings {
+            lookfrom: (center + offset).into(),
+            lookat: center.into(),
+            focus_dist: offset.norm(),
+            ..*settings
+        }
+    }
+}
+
+impl std::str::FromStr for CameraPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "turntable" => Ok(Self::Turntable),
+            "flythrough" => Ok(Self::Flythrough),
+            _ => Err(format!("expected turntable or flythrough, got {}", s)),
+        }
     }
 }
@@ -0,0 +1,248 @@
+//! C ABI over the renderer, for batch jobs driven from C, C++ or Python through ctypes or cffi,
+//! built with feature `capi`. `just header` writes the declarations to `target/ray_tracing.h`.
+//!
+//! ```c
+//! RtScene *scene = rt_create_scene("cornell");
+//! RtRenderer *renderer = rt_create_renderer(scene, 800, 800);
+//! rt_set_samples(renderer, 256);
+//! if (rt_render(renderer) != 0) fprintf(stderr, "%s\n", rt_last_error());
+//! const uint8_t *rgba = rt_get_pixels(renderer);
+//! rt_destroy_renderer(renderer);
+//! ```
+//!
+//! Every function taking a handle expects one returned by this API and not yet destroyed.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use crate::camera::{Camera, CameraSettings};
+use crate::renderer::Renderer;
+use crate::scene::{select_scene, Scene};
+use crate::scene_file::SceneFile;
+use crate::memory::memory_budget;
+
+/// A scene and the camera it comes with, to be rendered.
+pub struct RtScene {
+    scene: Scene,
+    camera: CameraSettings,
+}
+
+/// A renderer and the RGBA8 pixels it last rendered.
+pub struct RtRenderer {
+    renderer: Renderer,
+    frame: Vec<u8>,
+}
+
+thread_local! {
+    /// What the last failing call of the thread went wrong with.
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_error(error: impl ToString) {
+    let message = CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// 0 if `f` succeeded, else -1 with what went wrong, or that `what` panicked, kept for
+/// [`rt_last_error`].
+fn status(what: &str, f: impl FnOnce() -> crate::Result<()>) -> i32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_error(e);
+            -1
+        }
+        Err(_) => {
+            set_error(format!("{} panicked", what));
+            -1
+        }
+    }
+}
+
+/// The message of the last error on this thread, NULL if there was none. Valid until the next call
+/// that fails.
+#[no_mangle]
+pub extern "C" fn rt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |message| message.as_ptr()))
+}
+
+/// Build a scene from `scene`: the name of a built-in scene, the JSON of a scene file or the path
+/// of one ending in `.json`. NULL on failure, see [`rt_last_error`].
+///
+/// # Safety
+/// `scene` is a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_create_scene(scene: *const c_char) -> *mut RtScene {
+    if scene.is_null() {
+        set_error("no scene given");
+        return std::ptr::null_mut()
+    }
+    let scene = CStr::from_ptr(scene).to_string_lossy().into_owned();
+    let built = catch_unwind(|| {
+        if scene.trim_start().starts_with('{') {
+            SceneFile::parse(scene.as_bytes(), "capi", memory_budget(), None)
+        } else if scene.ends_with(".json") {
+            SceneFile::load(Path::new(&scene), memory_budget(), None)
+        } else {
+            Ok((select_scene(&scene, None), Some(CameraSettings::select(&scene))))
+        }
+    });
+    match built {
+        Ok(Ok((scene, camera))) => Box::into_raw(Box::new(RtScene {
+            scene,
+            camera: camera.unwrap_or_else(|| CameraSettings::select("")),
+        })),
+        Ok(Err(e)) => {
+            set_error(e);
+            std::ptr::null_mut()
+        }
+        Err(_) => {
+            set_error("building the scene panicked");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a scene that was not handed to [`rt_create_renderer`].
+///
+/// # Safety
+/// `scene` is NULL or a scene of [`rt_create_scene`], not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rt_destroy_scene(scene: *mut RtScene) {
+    if !scene.is_null() {
+        drop(Box::from_raw(scene));
+    }
+}
+
+/// A renderer of `width` by `height` pixels taking over `scene`, which must not be used or
+/// destroyed afterwards. NULL if `scene` is NULL or on failure, see [`rt_last_error`].
+///
+/// # Safety
+/// `scene` is NULL or a scene of [`rt_create_scene`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_create_renderer(scene: *mut RtScene, width: u32, height: u32) -> *mut RtRenderer {
+    if scene.is_null() {
+        set_error("no scene given");
+        return std::ptr::null_mut()
+    }
+    let RtScene { scene, camera } = *Box::from_raw(scene);
+    let (width, height) = (width.max(1), height.max(1));
+    let len = match (width as usize).checked_mul(height as usize).and_then(|pixels| pixels.checked_mul(4)) {
+        Some(len) => len,
+        None => {
+            set_error(format!("a frame of {}x{} pixels does not fit in memory", width, height));
+            return std::ptr::null_mut()
+        }
+    };
+    let created = catch_unwind(AssertUnwindSafe(|| {
+        let camera = Camera::from_settings(&camera, width as f32 / height as f32);
+        RtRenderer {
+            renderer: Renderer::new(width, height, scene, camera),
+            frame: vec![0; len],
+        }
+    }));
+    match created {
+        Ok(renderer) => Box::into_raw(Box::new(renderer)),
+        Err(_) => {
+            set_error("creating the renderer panicked");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free a renderer and its pixels.
+///
+/// # Safety
+/// `renderer` is NULL or a renderer of [`rt_create_renderer`], not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rt_destroy_renderer(renderer: *mut RtRenderer) {
+    if !renderer.is_null() {
+        drop(Box::from_raw(renderer));
+    }
+}
+
+/// Samples per pixel of the next [`rt_render`]. 0 on success, -1 on failure, see [`rt_last_error`].
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_set_samples(renderer: *mut RtRenderer, samples: u32) -> i32 {
+    let renderer = &mut (*renderer).renderer;
+    status("setting the samples", || {
+        renderer.set_samples(samples as usize);
+        Ok(())
+    })
+}
+
+/// Bounces of a path before it is cut off. 0 on success, -1 on failure, see [`rt_last_error`].
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_set_max_depth(renderer: *mut RtRenderer, max_depth: u32) -> i32 {
+    let renderer = &mut (*renderer).renderer;
+    status("setting the depth", || {
+        renderer.set_max_depth(max_depth as usize);
+        Ok(())
+    })
+}
+
+/// Render the image anew, blocking until all samples are done. 0 on success, -1 on failure,
+/// see [`rt_last_error`].
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(renderer: *mut RtRenderer) -> i32 {
+    let RtRenderer { renderer, frame } = &mut *renderer;
+    renderer.dirty = true;
+    status("rendering", || {
+        renderer.draw(frame);
+        Ok(())
+    })
+}
+
+/// The pixels of the last render, `4 * width * height` bytes of RGBA8 row by row from the top.
+/// Valid until the next [`rt_render`] or [`rt_destroy_renderer`].
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_get_pixels(renderer: *const RtRenderer) -> *const u8 {
+    (*renderer).frame.as_ptr()
+}
+
+/// Save the last render to `path`, in the format of its extension as the command line does.
+/// 0 on success, -1 on failure, see [`rt_last_error`].
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`] and `path` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_save(renderer: *const RtRenderer, path: *const c_char) -> i32 {
+    if path.is_null() {
+        set_error("no path given");
+        return -1
+    }
+    let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+    let RtRenderer { renderer, frame } = &*renderer;
+    status("saving", || renderer.save_image(frame, Path::new(&path)))
+}
+
+/// Width of the image in pixels.
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_width(renderer: *const RtRenderer) -> u32 {
+    (*renderer).renderer.width
+}
+
+/// Height of the image in pixels.
+///
+/// # Safety
+/// `renderer` is a renderer of [`rt_create_renderer`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_height(renderer: *const RtRenderer) -> u32 {
+    (*renderer).renderer.height
+}
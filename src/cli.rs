@@ -1,44 +1,677 @@
-use crate::renderer::Renderer;
-use crate::scene::select_scene;
+use crate::renderer::{format_duration, MaterialOverride, Precision, Region, Renderer, DEFAULT_GLASS_DEPTH};
+use crate::scene::{furnace_materials, select_scene, PREVIEW_BALL};
 use crate::{HEIGHT, WIDTH};
 use clap::Parser;
-use crate::camera::Camera;
+use std::path::{Path, PathBuf};
+use na::Point3;
+use crate::camera::{Camera, CameraPath, CameraSettings, Keyframes, Projection, ShutterCurve};
+use crate::scene_file::SceneFile;
+use crate::rand_gen;
+use crate::post::{PostProcess, Transfer};
+use crate::output::{save_pfm, save_ply, Frame, Image, ImageFormat, StereoLayout};
+use crate::ray::Bias;
+use crate::background::{Background, TimeOfDay};
+use crate::diff::{DiffImage, Difference, DEFAULT_PPD};
+use crate::types::Color;
+use crate::preview::Preview;
+use crate::daemon;
 
 /// Get ray tracing parameters
+///
+/// Every flag falls back to the environment variable named in its `env` attribute.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, default_value_t = 50)]
+    #[clap(short, long, env = "DEPTH", default_value_t = 50)]
     max_depth: usize,
-    #[clap(short, long, default_value_t = 100)]
+    /// Bounces beyond the depth paths refracting through glass with most of their light go on for
+    #[clap(long, env = "GLASS_DEPTH", default_value_t = DEFAULT_GLASS_DEPTH)]
+    glass_depth: usize,
+    #[clap(short, long, env = "SAMPLE", default_value_t = 100)]
     sample_count: usize,
-    #[clap(short, long, default_value_t = 10)]
+    #[clap(short, long, env = "SCALE", default_value_t = 10)]
     down_scale: u32,
-    #[clap(long, default_value = "random")]
+    /// Width of the image in pixels instead of the scaled down default, the height following at
+    /// the default aspect ratio unless given too
+    #[clap(long, env = "IMAGE_WIDTH")]
+    width: Option<u32>,
+    /// Height of the image in pixels instead of the scaled down default
+    #[clap(long, env = "IMAGE_HEIGHT")]
+    height: Option<u32>,
+    /// Render at the sample count, depth and size the scene recommends instead
+    #[clap(long, env = "AUTO_QUALITY")]
+    auto_quality: bool,
+    #[clap(long, env = "SCENE", default_value = "random")]
     scene: String,
+    /// Scene description file, replaces --scene
+    #[clap(long, env = "SCENE_FILE")]
+    scene_file: Option<PathBuf>,
+    /// Write the intermediate image every N samples per pixel
+    #[clap(long, env = "SAVE_EVERY")]
+    save_every: Option<usize>,
+    /// Write the intermediate image at least every N minutes
+    #[clap(long, env = "SAVE_EVERY_MINS")]
+    save_every_mins: Option<u64>,
+    /// Only render the pixels in x0,y0,x1,y1, counted from the top left corner
+    #[clap(long, env = "CROP")]
+    crop: Option<Region>,
+    /// Time the shutter opens, between 0 and 1
+    #[clap(long, env = "SHUTTER_OPEN")]
+    shutter_open: Option<f32>,
+    /// Time the shutter closes, between 0 and 1
+    #[clap(long, env = "SHUTTER_CLOSE")]
+    shutter_close: Option<f32>,
+    /// Lay the view out as perspective or stereographic, the latter up to 340 degrees wide for
+    /// "little planets" when looking down
+    #[clap(long, env = "PROJECTION")]
+    projection: Option<Projection>,
+    /// Vertical field of view in degrees, instead of the one the scene comes with
+    #[clap(long, env = "VFOV")]
+    vfov: Option<f32>,
+    /// Render an animation of this many frames, numbered after the output name
+    #[clap(long, env = "FRAMES")]
+    frames: Option<usize>,
+    /// Move the camera through the animation without keyframes: turntable around the center of
+    /// the scene or flythrough towards it, over 120 frames unless --frames is given
+    #[clap(long, env = "ANIMATE")]
+    animate: Option<CameraPath>,
+    /// Focus distance at some frames of the animation as frame:distance,..., eased in between for focus pulls
+    #[clap(long, env = "FOCUS_KEYS")]
+    focus_keys: Option<Keyframes>,
+    /// Aperture at some frames of the animation as frame:aperture,...
+    #[clap(long, env = "APERTURE_KEYS")]
+    aperture_keys: Option<Keyframes>,
+    /// How the shutter opens and closes, weighting the motion blur: box, trapezoid or triangle
+    #[clap(long, env = "SHUTTER_CURVE")]
+    shutter_curve: Option<ShutterCurve>,
+    /// Fraction of the shutter interval the rows are read out over from the top, skewing moving objects
+    #[clap(long, env = "ROLLING_SHUTTER")]
+    rolling_shutter: Option<f32>,
+    /// Render moving objects crisp at the time the shutter opens
+    #[clap(long, env = "NO_MOTION_BLUR")]
+    no_motion_blur: bool,
+    /// Write alpha 0 where the background shows, for compositing (png and exr)
+    #[clap(long, env = "TRANSPARENT")]
+    transparent: bool,
+    /// Light the scene with a sun and sky seen from latitude,day,hour, e.g. 48,172,15 for a summer
+    /// afternoon, the day counted from January 1st and the hour in local solar time
+    #[clap(long, env = "TIME_OF_DAY")]
+    time_of_day: Option<TimeOfDay>,
+    /// Learn where the light comes from while rendering and aim diffuse bounces there, for caustics
+    #[clap(long, env = "GUIDING")]
+    guiding: bool,
+    /// Trace the paths by the materials alone, 1024 bounces deep, without light sampling, guiding or
+    /// Russian roulette: a slow reference to check the image of the default integrator against
+    #[clap(long, env = "REFERENCE")]
+    reference: bool,
+    /// Show this many spheres along the width of the image hovering over the scene, colored by the
+    /// light falling on them from every direction, to see how the scene is lit
+    #[clap(long, env = "PROBES", default_value_t = 0)]
+    probes: usize,
+    /// Show the scene in clay, or only its lights: off, clay or emitters
+    #[clap(long, env = "MATERIALS", default_value = "off")]
+    materials: MaterialOverride,
+    /// Ray offsets off surfaces as origin_ulps,medium_ulps,shadow, against acne or leaks at seams
+    #[clap(long, env = "BIAS", default_value = "16,1024,0.001")]
+    bias: Bias,
+    /// Encoding of the 8 bit images: gamma2, gamma2.2, srgb or linear, recorded in PNG files
+    #[clap(long, env = "TRANSFER", default_value = "gamma2")]
+    transfer: Transfer,
+    /// Strength of a glow around lights and highlights brighter than the display, 0 for none
+    #[clap(long, env = "BLOOM", default_value_t = 0.)]
+    bloom: f32,
+    /// Darkening of the corners as by a simple lens, 0 for none and 1 for a strong falloff
+    #[clap(long, env = "VIGNETTE", default_value_t = 0.)]
+    vignette: f32,
+    /// Fraction the red and blue images are scaled apart by in the corners, e.g. 0.005, 0 for none
+    #[clap(long, env = "CHROMATIC_ABERRATION", default_value_t = 0.)]
+    chromatic_aberration: f32,
+    /// ISO speed of film grain laid over the image, e.g. 400 or a grainier 3200, 0 for none
+    #[clap(long, env = "GRAIN", default_value_t = 0.)]
+    grain: f32,
+    /// Dither the 8 bit images with blue noise against banding in smooth gradients
+    #[clap(long, env = "DITHER")]
+    dither: bool,
+    /// Seed of the random numbers, to repeat a render recorded in the JSON written next to it
+    #[clap(long, env = "SEED")]
+    seed: Option<u64>,
+    /// Seed of the layout of the random built-in scenes, the render seed if not given
+    #[clap(long, env = "SCENE_SEED")]
+    scene_seed: Option<u64>,
+    /// Lay the random built-in scenes out anew instead of with the render seed, unless a scene seed is given
+    #[clap(long, env = "REROLL")]
+    reroll: bool,
+    /// Write PNG files with 16 bits per channel, as TIFF files always are
+    #[clap(long, env = "PNG16")]
+    png16: bool,
+    /// Add an ID matte of every object, named after its label, to EXR files in the Cryptomatte layout
+    #[clap(long, env = "CRYPTOMATTE")]
+    cryptomatte: bool,
+    /// Add normal, depth, albedo and object ID layers and the object ID mattes to EXR files, beside the beauty
+    #[clap(long, env = "AOVS")]
+    aovs: bool,
+    /// Render both eyes of a stereo pair and save them side-by-side or as an anaglyph
+    #[clap(long, env = "STEREO")]
+    stereo: Option<StereoLayout>,
+    /// Distance between the eyes of a stereo pair, in scene units
+    #[clap(long, env = "INTEROCULAR", default_value_t = 0.065)]
+    interocular: f32,
+    /// Render the six square faces of a cube map around the camera, saved next to the output
+    /// with _px, _nx, _py, _ny, _pz and _nz appended to its name
+    #[clap(long, env = "CUBE_MAP")]
+    cube_map: bool,
+    /// Bake the light falling on the object of this label into a lightmap of its texture
+    /// coordinates instead of rendering the camera
+    #[clap(long, env = "BAKE")]
+    bake: Option<String>,
+    /// Width and height of the baked lightmap, in texels
+    #[clap(long, env = "BAKE_SIZE", default_value_t = 1024)]
+    bake_size: u32,
+    /// Also write the distance from the camera to the first surface of every pixel as a PFM file,
+    /// infinite where the background shows. One per face of a cube map or eye of a stereo pair,
+    /// named with `_<face>` or `_<eye>` added
+    #[clap(long, env = "DEPTH_MAP")]
+    depth_map: Option<PathBuf>,
+    /// Also write the first surface of every pixel, with the color rendered there, as a PLY point cloud,
+    /// one per face or eye like the depth map
+    #[clap(long, env = "POINT_CLOUD")]
+    point_cloud: Option<PathBuf>,
+    /// Render the furnace scene with each material that does not glow and fail if one sends back
+    /// more light than falls on it, instead of rendering an image
+    #[clap(long, env = "FURNACE")]
+    furnace: bool,
+    /// Serve the image as it accumulates on this port, to watch a remote render in a browser
+    #[clap(long, env = "HTTP_PREVIEW")]
+    http_preview: Option<u16>,
+    /// Serve the progress, rays per second and memory as Prometheus metrics on /metrics of this port
+    #[clap(long, env = "METRICS")]
+    metrics: Option<u16>,
+    /// Address the preview and metrics listen on, e.g. 0.0.0.0 to serve them to other machines
+    #[clap(long, env = "PREVIEW_HOST", default_value = "127.0.0.1")]
+    preview_host: String,
+    /// Record where the time goes, per scene and BVH build, row and image encode, as a Chrome
+    /// trace to open in chrome://tracing or Perfetto
+    #[clap(long, env = "TRACE")]
+    trace: Option<PathBuf>,
+    /// Count the rays every object of the scene stops and list the objects by them after the render,
+    /// to find the geometry the time goes to
+    #[clap(long, env = "OBJECT_STATS")]
+    object_stats: bool,
+    /// Refuse scenes needing more than this many MiB for their shapes, BVH and textures
+    #[clap(long, env = "MEMORY_BUDGET")]
+    memory_budget: Option<usize>,
+    /// Shrink image textures to at most this many texels a side as they are loaded, e.g. 2048
+    /// for quick previews of scenes with 8K textures
+    #[clap(long, env = "MAX_TEXTURE_SIZE")]
+    max_texture_size: Option<u32>,
+    /// Accumulate the image in half precision, for images too large for the memory at hand
+    #[clap(long, env = "HALF_PRECISION")]
+    half_precision: bool,
+    /// Write the image to the output, which must be an EXR, a strip of tiles at a time as they are
+    /// traced rather than keeping it all in memory, for poster sized renders. Only the linear image
+    /// and its alpha are written, without post-processing
+    #[clap(long, env = "STREAM")]
+    stream: bool,
+    /// Give up on pixels taking more than this many seconds per sample, e.g. rays trapped between
+    /// coincident surfaces, and show them in magenta; 0 never gives up
+    #[clap(long, env = "PIXEL_BUDGET", default_value_t = 1.)]
+    pixel_budget: f32,
+    /// Render the jobs dropped into this directory one after the other instead of an image,
+    /// each a JSON file of command line flags such as {"args": ["--scene", "cornell"]}
+    #[clap(long, env = "DAEMON")]
+    daemon: Option<PathBuf>,
+    /// Output file, its extension picks the format (png, jpg, exr or tif)
+    #[clap(short, long, env = "OUTPUT", default_value = "screenshot.png")]
+    output: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Compare a render to a reference: print the RMSE and FLIP error and write where they differ
+    /// as a false-color image, the FLIP error itself in EXR files
+    Diff {
+        reference: PathBuf,
+        test: PathBuf,
+        /// Difference image, its extension picks the format (png, jpg, exr or tif)
+        #[clap(short, long, default_value = "diff.png")]
+        output: PathBuf,
+        /// Pixels per degree of visual angle the images are looked at with
+        #[clap(long, default_value_t = DEFAULT_PPD)]
+        ppd: f32,
+        /// Exit with 1 if the RMSE is above this, e.g. to check that a render converges to a
+        /// --reference one
+        #[clap(long)]
+        max_rmse: Option<f32>,
+    },
 }
 
 pub fn image_mode() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // Flushes the trace when dropped at the end of the render
+    let _trace = args.trace.as_ref().map(|path| {
+        use tracing_subscriber::prelude::*;
+        let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path).include_args(true).build();
+        tracing_subscriber::registry().with(layer).init();
+        guard
+    });
+    if let Some(Command::Diff { reference, test, output, ppd, max_rmse }) = &args.command {
+        match diff(reference, test, output, *ppd) {
+            Ok(rmse) if max_rmse.map_or(false, |max_rmse| rmse > max_rmse) => {
+                log::error!("RMSE {:.6} is above {}", rmse, max_rmse.unwrap_or_default());
+                std::process::exit(1)
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return
+    }
+    if let Some(dir) = &args.daemon {
+        if let Err(e) = daemon::run(dir) {
+            log::error!("{}", e);
+        }
+        std::process::exit(1)
+    }
     let scale = args.down_scale;
-    let (width, height) = (WIDTH / scale, HEIGHT / scale);
-    let camera = Camera::select_camera(width as f32 / height as f32, &args.scene);
-    let mut renderer = Renderer::new(width, height, select_scene(&args.scene), camera);
+    let (width, height) = match (args.width, args.height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, width * HEIGHT / WIDTH),
+        (None, Some(height)) => (height * WIDTH / HEIGHT, height),
+        (None, None) => (WIDTH / scale, HEIGHT / scale),
+    };
+    let (width, height) = if args.cube_map { (height, height) } else { (width, height) };
+    let seed = args.seed.unwrap_or_else(rand_gen::random_seed);
+    let budget = args.memory_budget.map(|mib| mib << 20);
+    let (scene, mut camera) = match &args.scene_file {
+        Some(path) => match SceneFile::load(path, budget, args.max_texture_size) {
+            Ok((scene, camera)) => (scene, camera.unwrap_or_else(|| CameraSettings::select(""))),
+            Err(e) => {
+                log::error!("Failed to load scene file: {}", e);
+                std::process::exit(1)
+            }
+        },
+        None => {
+            let scene_seed = match args.scene_seed {
+                Some(scene_seed) => scene_seed,
+                None if args.reroll => rand_gen::random_seed(),
+                None => seed,
+            };
+            log::info!("Scene seed: {}", scene_seed);
+            rand_gen::seed(scene_seed);
+            let name = if args.furnace { "furnace" } else { &args.scene };
+            let mut scene = select_scene(name, args.max_texture_size);
+            rand_gen::unseed();
+            scene.seed = Some(scene_seed);
+            (scene, CameraSettings::select(name))
+        }
+    };
+    let scene = match args.time_of_day {
+        Some(time) => scene.with_background(Background::time_of_day(time)),
+        None => scene,
+    };
+    let (width, height) = if args.auto_quality {
+        let recommended = scene.recommended;
+        log::info!("Recommended settings: {:?}", recommended);
+        args.sample_count = recommended.samples;
+        args.max_depth = recommended.max_depth;
+        let [width, height] = recommended.size;
+        if args.cube_map { (height, height) } else { (width, height) }
+    } else {
+        (width, height)
+    };
+    let memory = scene.memory();
+    log::info!("Scene memory: {} MiB", memory.total() >> 20);
+    if let Err(e) = memory.check(budget) {
+        log::error!("{}", e);
+        std::process::exit(1)
+    }
+    if let Some(open) = args.shutter_open {
+        camera.shutter[0] = open;
+    }
+    if let Some(close) = args.shutter_close {
+        camera.shutter[1] = close;
+    }
+    camera.motion_blur &= !args.no_motion_blur;
+    if let Some(curve) = args.shutter_curve {
+        camera.shutter_curve = curve;
+    }
+    if let Some(rolling) = args.rolling_shutter {
+        camera.rolling_shutter = rolling;
+    }
+    if let Some(projection) = args.projection {
+        camera.projection = projection;
+    }
+    if let Some(vfov) = args.vfov {
+        camera.vfov = vfov;
+    }
+    let settings = camera;
+    let camera = Camera::from_settings(&camera, width as f32 / height as f32);
+    let precision = if args.half_precision { Precision::Half } else { Precision::Full };
+    let mut renderer = Renderer::new(width, height, scene, camera).with_precision(precision);
     renderer.multisample = args.sample_count;
     renderer.max_depth = args.max_depth;
+    renderer.glass_depth = args.glass_depth;
+    renderer.region = args.crop;
+    renderer.pixel_budget = match instant::Duration::try_from_secs_f32(args.pixel_budget) {
+        Ok(budget) => Some(budget).filter(|budget| !budget.is_zero()),
+        Err(_) => {
+            log::warn!("Ignoring the pixel budget of {} seconds, never giving up on a pixel", args.pixel_budget);
+            None
+        }
+    };
+    renderer.transparent = args.transparent;
+    renderer.guiding = args.guiding;
+    renderer.set_reference(args.reference);
+    renderer.probe_count = args.probes;
+    renderer.set_object_stats(args.object_stats);
+    renderer.material_override = args.materials;
+    renderer.png16 = args.png16;
+    renderer.cryptomatte = args.cryptomatte;
+    renderer.aovs = args.aovs;
+    renderer.set_bias(args.bias);
+    renderer.set_post_process(PostProcess {
+        transfer: args.transfer,
+        tag_color_space: true,
+        dither: args.dither,
+        bloom: args.bloom,
+        vignette: args.vignette,
+        chromatic_aberration: args.chromatic_aberration,
+        grain: args.grain,
+        ..Default::default()
+    });
+    renderer.seed = seed;
+    if args.stream {
+        if ImageFormat::from_path(&args.output) != Some(ImageFormat::Exr) {
+            log::error!("Only EXR files can be streamed, got {:?}", args.output);
+            std::process::exit(1)
+        }
+        if let Err(e) = renderer.stream_exr(&args.output) {
+            log::error!("Failed to save {:?}: {}", args.output, e);
+            std::process::exit(1)
+        }
+        return
+    }
     let mut pixels = vec![0; (width * height * 4) as usize];
-    renderer.draw(&mut pixels);
-    image::save_buffer(
-        "screenshot.png",
-        &pixels,
-        renderer.width,
-        renderer.height,
-        image::ColorType::Rgba8,
-    )
-    .ok();
-    std::process::Command::new("sh")
+    if args.furnace {
+        if !furnace(&mut renderer, &mut pixels) {
+            std::process::exit(1)
+        }
+    } else if let Some(frames) = args.frames.or_else(|| args.animate.map(|_| ANIMATION_FRAMES)) {
+        animation(&mut renderer, &mut pixels, settings, frames, &args);
+    } else if let Some(label) = &args.bake {
+        bake(&renderer, label, args.bake_size, &args.output);
+    } else if args.cube_map {
+        cube_map(&mut renderer, &mut pixels, &args);
+    } else if let Some(layout) = args.stereo {
+        stereo(&mut renderer, &mut pixels, layout, &args);
+    } else {
+        if args.save_every.is_none() && args.save_every_mins.is_none() && args.http_preview.is_none() && args.metrics.is_none() {
+            renderer.draw(&mut pixels);
+            save(&renderer, &pixels, &args.output);
+        } else {
+            progressive_save(&mut renderer, &mut pixels, &args);
+        }
+        export_geometry(&renderer, &pixels, &args, None);
+    }
+    if args.object_stats {
+        log_object_stats(&renderer);
+    }
+    let played = std::process::Command::new("sh")
         .arg("-c")
         .arg("play /usr/share/sounds/Oxygen-Im-New-Mail.ogg")
-        .output()
-        .expect("Failed to play");
+        .output();
+    if let Err(e) = played {
+        log::warn!("Failed to play the notification sound: {}", e);
+    }
+}
+
+/// Accumulate the image in passes, overwriting the output after each checkpoint
+/// so a killed render still leaves a usable picture behind.
+/// The preview and metrics, if served, are updated after every pass.
+fn progressive_save(renderer: &mut Renderer, pixels: &mut [u8], args: &Args) {
+    // One server for both on the same port, else each on its own
+    let routes = match (args.http_preview, args.metrics) {
+        (Some(preview), Some(metrics)) if preview == metrics => vec![(preview, true, true)],
+        (preview, metrics) => preview.map(|port| (port, true, false)).into_iter()
+            .chain(metrics.map(|port| (port, false, true)))
+            .collect(),
+    };
+    let servers: Vec<_> = routes.into_iter().map(|(port, frames, metrics)| {
+        Preview::start(&args.preview_host, port, frames, metrics).unwrap_or_else(|e| {
+            let served = if frames { "the preview" } else { "metrics" };
+            log::error!("Failed to serve {} on {}:{}: {}", served, args.preview_host, port, e);
+            std::process::exit(1)
+        })
+    }).collect();
+    let now = instant::Instant::now();
+    let interval = args.save_every_mins.map(|mins| instant::Duration::from_secs(mins * 60));
+    let pass = args.save_every.unwrap_or(1).max(1);
+    let mut last_save = now;
+    renderer.reset_accumulation();
+    while renderer.samples_done < args.sample_count {
+        let samples = pass.min(args.sample_count - renderer.samples_done);
+        renderer.accumulate(samples);
+        let done = renderer.samples_done == args.sample_count;
+        let by_samples = args.save_every.is_some() && renderer.samples_done % pass == 0;
+        let by_time = interval.filter(|&i| last_save.elapsed() >= i).is_some();
+        let stats = renderer.stats();
+        servers.iter().for_each(|server| server.publish_stats(&stats));
+        if args.http_preview.is_some() {
+            renderer.write_frame(pixels);
+            match renderer.encode_png(pixels) {
+                Ok(png) => servers.iter().for_each(|server| server.publish(png.clone())),
+                Err(e) => log::warn!("Failed to encode the preview: {}", e),
+            }
+        }
+        if done || by_samples || by_time {
+            renderer.write_frame(pixels);
+            save(renderer, pixels, &args.output);
+            last_save = instant::Instant::now();
+            tracing::info!("Saved {:?} at {} spp", args.output, renderer.samples_done);
+        }
+    }
+    tracing::info!("Time: {}", format_duration(now.elapsed()));
+}
+
+/// Log the objects by the rays they stopped, most first.
+fn log_object_stats(renderer: &Renderer) {
+    let stats = renderer.object_stats();
+    let total = stats.iter().map(|(_, count)| count.total()).sum::<usize>().max(1);
+    log::info!("{:<24} {:>12} {:>12} {:>7}", "Object", "Hits", "Occlusions", "Share");
+    for (name, count) in stats {
+        let share = count.total() as f32 * 100. / total as f32;
+        log::info!("{:<24} {:>12} {:>12} {:>6.1}%", name, count.hits, count.occlusions, share);
+    }
+}
+
+/// Write the depth map and point cloud of the camera rays, if asked for, with the colors of `pixels`,
+/// to files named as asked with `_<view>` added for one of several views, e.g. the faces of a cube map.
+fn export_geometry(renderer: &Renderer, pixels: &[u8], args: &Args, view: Option<&str>) {
+    if args.depth_map.is_none() && args.point_cloud.is_none() {
+        return
+    }
+    let hits = renderer.primary_hits();
+    if let Some(path) = &args.depth_map {
+        let path = &view.map_or_else(|| path.clone(), |view| of_view(path, view));
+        let depth: Vec<_> = hits.iter().map(|hit| hit.map_or(f32::INFINITY, |(t, _)| t)).collect();
+        if let Err(e) = save_pfm(path, renderer.width, renderer.height, &depth) {
+            log::error!("Failed to save {:?}: {}", path, e);
+        }
+    }
+    if let Some(path) = &args.point_cloud {
+        let path = &view.map_or_else(|| path.clone(), |view| of_view(path, view));
+        let points: Vec<_> = hits.iter().zip(pixels.chunks_exact(4)).filter_map(|(hit, rgba)| {
+            hit.map(|(_, point)| (point, [rgba[0], rgba[1], rgba[2]]))
+        }).collect();
+        if let Err(e) = save_ply(path, &points) {
+            log::error!("Failed to save {:?}: {}", path, e);
+        }
+    }
+}
+
+/// `path` with `_<view>` added to the name before the extension, `png` if it has none.
+fn of_view(path: &Path, view: &str) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|ext| ext.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+    path.with_file_name(format!("{}_{}.{}", stem, view, extension))
+}
+
+/// Frames of an animation along a camera path when their number is not given.
+const ANIMATION_FRAMES: usize = 120;
+/// Times the distance of the camera to what it looks at an object may be across to count
+/// towards the center camera paths turn around.
+const TURNTABLE_REACH: f32 = 10.;
+
+/// Render `frames` frames with the camera `settings` moved along the path and keyframed by the
+/// arguments, each saved to a file of its own numbered from 0.
+fn animation(renderer: &mut Renderer, pixels: &mut [u8], settings: CameraSettings, frames: usize, args: &Args) {
+    let path = &args.output;
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|ext| ext.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+    let aspect_ratio = renderer.camera().aspect_ratio;
+    // Objects many times farther across than the camera is from what it looks at are taken for
+    // the ground or the sky, not for what the camera turns around
+    let lookat = Point3::from(settings.lookat);
+    let reach = (Point3::from(settings.lookfrom) - lookat).norm() * TURNTABLE_REACH;
+    let center = renderer.scene().center(reach).unwrap_or(lookat);
+    for frame in 0..frames {
+        let mut camera = match args.animate {
+            Some(path) => path.at(&settings, center, frame, frames),
+            None => settings,
+        };
+        if let Some(keys) = &args.focus_keys {
+            camera.focus_dist = keys.at(frame);
+        }
+        if let Some(keys) = &args.aperture_keys {
+            camera.aperture = keys.at(frame);
+        }
+        renderer.set_camera(Camera::from_settings(&camera, aspect_ratio));
+        renderer.draw(pixels);
+        save(renderer, pixels, &path.with_file_name(format!("{}_{:04}.{}", stem, frame, extension)));
+        tracing::info!("Frame {} of {}", frame + 1, frames);
+    }
+}
+
+/// How far above 1 the average of a material in the furnace may be, for the noise of the render.
+const FURNACE_TOLERANCE: f32 = 0.01;
+
+/// Put each of the furnace materials on the ball and check it does not outshine the environment,
+/// returns whether all of them passed.
+fn furnace(renderer: &mut Renderer, pixels: &mut [u8]) -> bool {
+    furnace_materials().into_iter().fold(true, |passed, (name, material)| {
+        renderer.scene().set_material(PREVIEW_BALL, material);
+        renderer.dirty = true;
+        renderer.draw(pixels);
+        let energy = renderer.surface_average();
+        if energy.max() > 1. + FURNACE_TOLERANCE {
+            log::error!("{} reflects {:.4} {:.4} {:.4}, more than the light falling on it", name, energy.x, energy.y, energy.z);
+            false
+        } else {
+            log::info!("{} reflects {:.4} {:.4} {:.4}", name, energy.x, energy.y, energy.z);
+            passed
+        }
+    })
+}
+
+/// Render a face of a cube map at a time, each saved to a file of its own along with its geometry.
+fn cube_map(renderer: &mut Renderer, pixels: &mut [u8], args: &Args) {
+    for (name, camera) in renderer.camera().cube_faces() {
+        renderer.set_camera(camera);
+        renderer.draw(pixels);
+        save(renderer, pixels, &of_view(&args.output, name));
+        export_geometry(renderer, pixels, args, Some(name));
+    }
+}
+
+/// Bake the lighting of the object labelled `label` into a `size` by `size` lightmap.
+fn bake(renderer: &Renderer, label: &str, size: u32, path: &Path) {
+    let scene = renderer.scene();
+    let object = match scene.objects.iter().find(|obj| obj.read().unwrap().get_label().map(String::as_str) == Some(label)) {
+        Some(object) => object,
+        None => {
+            log::error!("No object labelled {:?} to bake", label);
+            std::process::exit(1)
+        }
+    };
+    let now = instant::Instant::now();
+    let baked = renderer.bake(object, size, size);
+    tracing::info!("Baked {:?} in {}", label, format_duration(now.elapsed()));
+    if let Err(e) = renderer.save_bake(label, &baked, size, size, path) {
+        log::error!("Failed to save {:?}: {}", path, e);
+    }
+}
+
+/// Render the scene from both eyes, the interocular distance apart, and save them together as
+/// `layout`, the geometry of each eye to files of their own.
+fn stereo(renderer: &mut Renderer, pixels: &mut [u8], layout: StereoLayout, args: &Args) {
+    let (interocular, path) = (args.interocular, &args.output);
+    let center = renderer.camera().clone();
+    let [left, right] = [(-0.5, "left"), (0.5, "right")].map(|(side, eye)| {
+        renderer.set_camera(center.stereo_eye(side * interocular));
+        renderer.draw(pixels);
+        export_geometry(renderer, pixels, args, Some(eye));
+        Frame {
+            width: renderer.width,
+            height: renderer.height,
+            rgba: pixels.to_vec(),
+            linear: renderer.linear_frame(),
+        }
+    });
+    let frame = layout.combine(&left, &right);
+    let mut metadata = renderer.metadata();
+    metadata.push(("Stereo".into(), format!("{:?}, interocular {}", layout, interocular)));
+    let image = Image {
+        width: frame.width,
+        height: frame.height,
+        rgba: &frame.rgba,
+        rgba16: &[],
+        linear: &frame.linear,
+        alpha: &[],
+        light_groups: &[],
+        aovs: &[],
+        mattes: None,
+        transfer: None,
+        metadata,
+    };
+    if let Err(e) = image.save(path) {
+        log::error!("Failed to save {:?}: {}", path, e);
+    }
+}
+
+/// Compare `test` to `reference` and write the difference image to `output`, returns the RMSE.
+fn diff(reference: &Path, test: &Path, output: &Path, ppd: f32) -> crate::Result<f32> {
+    let load = |path: &Path| DiffImage::load(path).map_err(|e| e.in_file(path));
+    let difference = Difference::new(&load(reference)?, &load(test)?, ppd)?;
+    log::info!("RMSE: {:.6}", difference.rmse);
+    log::info!("FLIP: {:.6}", difference.mean_flip());
+    let rgba = difference.false_color();
+    let linear: Vec<_> = difference.flip.iter().map(|&e| Color::repeat(e)).collect();
+    let image = Image {
+        width: difference.width,
+        height: difference.height,
+        rgba: &rgba,
+        rgba16: &[],
+        linear: &linear,
+        alpha: &[],
+        light_groups: &[],
+        aovs: &[],
+        mattes: None,
+        transfer: None,
+        metadata: vec![
+            ("Reference".into(), reference.display().to_string()),
+            ("Test".into(), test.display().to_string()),
+        ],
+    };
+    image.save(output)?;
+    Ok(difference.rmse)
+}
+
+fn save(renderer: &Renderer, pixels: &[u8], path: &Path) {
+    if let Err(e) = renderer.save_image(pixels, path) {
+        log::error!("Failed to save {:?}: {}", path, e);
+    }
 }
@@ -0,0 +1,129 @@
+//! Render jobs dropped into a directory one after the other, for a workstation shared by several people.
+//!
+//! A job is a JSON file holding the command line flags of the render, e.g.
+//! `{"args": ["--scene-file", "room.json", "--sample-count", "500", "-o", "room.exr"]}`,
+//! written under another name and renamed to `*.json` once complete. Jobs run oldest first,
+//! each in a process of its own so a failing one cannot take the daemon down, from the drop-box
+//! directory, so relative paths in them are relative to it. The directory is laid out as
+//!
+//! * `running/`: the job being rendered, put back in the queue if the daemon stopped during it,
+//! * `done/` and `failed/`: the finished jobs, by exit status,
+//! * `logs/`: what each job printed, as `<job>.log`,
+//! * `output/`: the images of jobs not naming an output, as `<job>.png`.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+use serde::Deserialize;
+use crate::renderer::format_duration;
+
+/// How often the drop-box is looked at while there is nothing to do.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SUBDIRECTORIES: [&str; 5] = ["running", "done", "failed", "logs", "output"];
+
+#[derive(Deserialize)]
+struct Job {
+    /// Flags as given on the command line, the output defaulting to `output/<job>.png`.
+    args: Vec<String>,
+}
+
+/// Render the jobs dropped into `dir` as they come, forever.
+pub fn run(dir: &Path) -> crate::Result<()> {
+    for sub in SUBDIRECTORIES {
+        std::fs::create_dir_all(dir.join(sub)).map_err(|e| crate::Error::from(e).in_file(dir.join(sub)))?;
+    }
+    requeue(dir)?;
+    let exe = std::env::current_exe()?;
+    log::info!("Waiting for jobs in {:?}", dir);
+    loop {
+        match next_job(dir) {
+            Ok(Some(path)) => process(&exe, dir, &path),
+            Ok(None) => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                // E.g. the drop-box on a network share going away for a moment
+                log::error!("Failed to look for jobs in {:?}: {}", dir, e);
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Put the jobs left in `running/` by a daemon that stopped during them back in the queue of `dir`.
+fn requeue(dir: &Path) -> crate::Result<()> {
+    for entry in std::fs::read_dir(dir.join("running"))? {
+        let path = entry?.path();
+        if let Some(file_name) = path.file_name().filter(|_| path.is_file()) {
+            log::warn!("Job {:?} was left running, queueing it again", file_name);
+            std::fs::rename(&path, dir.join(file_name)).map_err(|e| crate::Error::from(e).in_file(&path))?;
+        }
+    }
+    Ok(())
+}
+
+/// The oldest job waiting in `dir`.
+fn next_job(dir: &Path) -> crate::Result<Option<PathBuf>> {
+    let mut jobs = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == "json") {
+            let modified = path.metadata().and_then(|meta| meta.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+            jobs.push((modified, path));
+        }
+    }
+    Ok(jobs.into_iter().min().map(|(_, path)| path))
+}
+
+/// Run the job at `path`, filed under `done/` or `failed/` afterwards.
+fn process(exe: &Path, dir: &Path, path: &Path) {
+    let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let file_name = path.file_name().expect("jobs are files");
+    let running = dir.join("running").join(file_name);
+    if let Err(e) = std::fs::rename(path, &running) {
+        // Taken by another daemon or removed by its owner
+        log::warn!("Failed to take job {:?}: {}", path, e);
+        return
+    }
+    let now = instant::Instant::now();
+    log::info!("Job {} started", name);
+    let succeeded = match render(exe, dir, &name, &running) {
+        Ok(true) => true,
+        Ok(false) => {
+            log::error!("Job {} failed, see logs/{}.log", name, name);
+            false
+        }
+        Err(e) => {
+            log::error!("Job {} failed: {}", name, e);
+            false
+        }
+    };
+    if succeeded {
+        log::info!("Job {} done in {}", name, format_duration(now.elapsed()));
+    }
+    let finished = dir.join(if succeeded { "done" } else { "failed" }).join(file_name);
+    if let Err(e) = std::fs::rename(&running, &finished) {
+        log::warn!("Failed to move job {:?} to {:?}: {}", running, finished, e);
+    }
+}
+
+/// Render the job `name` read from `path`, returns whether it succeeded.
+fn render(exe: &Path, dir: &Path, name: &str, path: &Path) -> crate::Result<bool> {
+    let job: Job = serde_json::from_slice(&std::fs::read(path)?).map_err(|e| crate::Error::from(e).in_file(path))?;
+    // A job turning into a daemon would never finish, holding up the queue
+    if job.args.iter().any(|arg| arg == "--daemon" || arg.starts_with("--daemon=")) {
+        return Err(crate::Error::InvalidJob("--daemon is not a flag of a render".into()))
+    }
+    let log_path = dir.join("logs").join(format!("{}.log", name));
+    let log = File::create(&log_path).map_err(|e| crate::Error::from(e).in_file(&log_path))?;
+    let status = Command::new(exe)
+        .args(&job.args)
+        .current_dir(dir)
+        // Flags fall back to the environment, the job must not turn into a daemon itself
+        .env_remove("DAEMON")
+        .env("OUTPUT", Path::new("output").join(format!("{}.png", name)))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .status()?;
+    Ok(status.success())
+}
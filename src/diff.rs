@@ -0,0 +1,295 @@
+//! Comparing a render to a reference: the RMSE of the pixel values and the LDR variant of FLIP,
+//! the perceptual error of Andersson et al. 2020, "FLIP: A Difference Evaluator for Alternating Images".
+
+use std::path::Path;
+use crate::error::{Error, Result};
+use crate::output::ImageFormat;
+use crate::post::Transfer;
+use crate::types::Color;
+use na::{Matrix3, Vector3};
+
+/// Pixels per degree of visual angle, for a 0.7 m distance from a 24" monitor 3840 pixels wide.
+pub const DEFAULT_PPD: f32 = 67.;
+
+/// Exponent of the color difference, compressing it as the eye does.
+const COLOR_EXPONENT: f32 = 0.7;
+/// Share of the largest color difference mapped to `COLOR_TARGET` of the error range.
+const COLOR_CUTOFF: f32 = 0.4;
+const COLOR_TARGET: f32 = 0.95;
+const FEATURE_EXPONENT: f32 = 0.5;
+/// Width of the edges and points compared, in degrees.
+const FEATURE_WIDTH: f32 = 0.082;
+
+/// Contrast sensitivity of the achromatic, red-green and blue-yellow channels, each a sum of two
+/// Gaussians given as `(a, b)` pairs in degrees.
+const CSF: [[(f32, f32); 2]; 3] = [
+    [(1., 0.0047), (0., 1e-5)],
+    [(1., 0.0053), (0., 1e-5)],
+    [(34.1, 0.04), (13.5, 0.025)],
+];
+
+/// An image loaded for comparison.
+pub struct DiffImage {
+    pub width: u32,
+    pub height: u32,
+    /// Values as stored, row by row from the top.
+    pub pixels: Vec<Color>,
+    /// Whether the values are linear light, as in EXR and HDR files, rather than display encoded.
+    pub linear: bool,
+}
+
+impl DiffImage {
+    /// Any image the renderer writes, and Radiance HDR files.
+    pub fn load(path: &Path) -> Result<Self> {
+        if ImageFormat::from_path(path) == Some(ImageFormat::Exr) {
+            use exr::prelude::*;
+            let image = read_first_rgba_layer_from_file(
+                path,
+                |resolution, _| vec![vec![Color::zeros(); resolution.width()]; resolution.height()],
+                |rows: &mut Vec<Vec<Color>>, position: Vec2<usize>, (r, g, b, _a): (f32, f32, f32, f32)| {
+                    rows[position.y()][position.x()] = Color::new(r, g, b)
+                },
+            )?;
+            let size = image.layer_data.size;
+            return Ok(Self {
+                width: size.width() as u32,
+                height: size.height() as u32,
+                pixels: image.layer_data.channel_data.pixels.into_iter().flatten().collect(),
+                linear: true,
+            })
+        }
+        let image = image::open(path)?;
+        let linear = matches!(image, image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_));
+        let image = image.into_rgb32f();
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().map(|p| Color::from(p.0)).collect(),
+            linear,
+        })
+    }
+
+    /// The values as shown on an sRGB display, linear light clipped to `0..=1` and encoded.
+    fn display(&self) -> Vec<Color> {
+        if self.linear {
+            self.pixels.iter().map(|c| c.map(|c| Transfer::Srgb.encode(c.clamp(0., 1.)))).collect()
+        } else {
+            self.pixels.clone()
+        }
+    }
+}
+
+/// The per-pixel differences of a test image to a reference.
+pub struct Difference {
+    pub width: u32,
+    pub height: u32,
+    /// Root of the mean squared difference of the stored values over all channels.
+    pub rmse: f32,
+    /// FLIP error of every pixel in `0..=1`, row by row from the top.
+    pub flip: Vec<f32>,
+}
+
+impl Difference {
+    /// Compare `test` to `reference`, seen at `ppd` pixels per degree. Fails if they differ in size.
+    pub fn new(reference: &DiffImage, test: &DiffImage, ppd: f32) -> Result<Self> {
+        if (reference.width, reference.height) != (test.width, test.height) {
+            return Err(Error::ImageSize { expected: (reference.width, reference.height), found: (test.width, test.height) })
+        }
+        let squared: f32 = reference.pixels.iter().zip(&test.pixels).map(|(r, t)| (r - t).norm_squared()).sum();
+        let rmse = (squared / (3 * reference.pixels.len()).max(1) as f32).sqrt();
+        let (width, height) = (reference.width as usize, reference.height as usize);
+        let flip = flip(&reference.display(), &test.display(), width, height, ppd);
+        Ok(Self { width: reference.width, height: reference.height, rmse, flip })
+    }
+
+    pub fn mean_flip(&self) -> f32 {
+        self.flip.iter().sum::<f32>() / self.flip.len().max(1) as f32
+    }
+
+    /// The FLIP error in the magma color map, black where the images agree, as RGBA8.
+    pub fn false_color(&self) -> Vec<u8> {
+        self.flip.iter().flat_map(|&e| {
+            let [r, g, b] = magma(e).map(|c| (c * 255.).round() as u8);
+            [r, g, b, 255]
+        }).collect()
+    }
+}
+
+/// Stops of the magma color map, evenly spaced from 0 to 1, in sRGB.
+const MAGMA: [[f32; 3]; 9] = [
+    [0.001, 0.000, 0.014],
+    [0.079, 0.054, 0.212],
+    [0.232, 0.060, 0.438],
+    [0.390, 0.100, 0.502],
+    [0.550, 0.161, 0.506],
+    [0.716, 0.215, 0.475],
+    [0.869, 0.288, 0.409],
+    [0.968, 0.440, 0.360],
+    [0.987, 0.991, 0.750],
+];
+
+fn magma(e: f32) -> [f32; 3] {
+    let x = e.clamp(0., 1.) * (MAGMA.len() - 1) as f32;
+    let i = (x as usize).min(MAGMA.len() - 2);
+    let f = x - i as f32;
+    [0, 1, 2].map(|c| MAGMA[i][c] + f * (MAGMA[i + 1][c] - MAGMA[i][c]))
+}
+
+fn srgb_to_xyz() -> Matrix3<f32> {
+    Matrix3::new(
+        0.4124564, 0.3575761, 0.1804375,
+        0.2126729, 0.7151522, 0.0721750,
+        0.0193339, 0.1191920, 0.9503041,
+    )
+}
+
+fn srgb_decode(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Opponent space whose channels are filtered with the contrast sensitivity functions.
+fn xyz_to_ycxcz(xyz: Vector3<f32>, white: Vector3<f32>) -> Vector3<f32> {
+    let [x, y, z]: [f32; 3] = xyz.component_div(&white).into();
+    Vector3::new(116. * y - 16., 500. * (x - y), 200. * (y - z))
+}
+
+fn ycxcz_to_xyz(ycxcz: Vector3<f32>, white: Vector3<f32>) -> Vector3<f32> {
+    let y = (ycxcz.x + 16.) / 116.;
+    Vector3::new(y + ycxcz.y / 500., y, y - ycxcz.z / 200.).component_mul(&white)
+}
+
+/// CIELAB with the Hunt effect, chroma fading with lightness.
+fn xyz_to_hunt_lab(xyz: Vector3<f32>, white: Vector3<f32>) -> Vector3<f32> {
+    const DELTA: f32 = 6. / 29.;
+    let f = |t: f32| if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3. * DELTA * DELTA) + 4. / 29. };
+    let [x, y, z]: [f32; 3] = xyz.component_div(&white).map(f).into();
+    let l = 116. * y - 16.;
+    Vector3::new(l, 0.01 * l * 500. * (x - y), 0.01 * l * 200. * (y - z))
+}
+
+/// Distance between colors in CIELAB, the lightness and chroma differences added.
+fn hyab(a: Vector3<f32>, b: Vector3<f32>) -> f32 {
+    (a.x - b.x).abs() + (a.y - b.y).hypot(a.z - b.z)
+}
+
+/// Convolve the `width` by `height` channel with `kernel_x` along rows then `kernel_y` along
+/// columns, both centered, repeating the border pixels.
+fn convolve(channel: &[f32], width: usize, height: usize, kernel_x: &[f32], kernel_y: &[f32]) -> Vec<f32> {
+    let pass = |input: &[f32], kernel: &[f32], horizontal: bool| -> Vec<f32> {
+        let radius = (kernel.len() / 2) as isize;
+        (0..width * height).map(|i| {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            kernel.iter().enumerate().map(|(k, w)| {
+                let offset = k as isize - radius;
+                let (sx, sy) = if horizontal {
+                    ((x + offset).clamp(0, width as isize - 1), y)
+                } else {
+                    (x, (y + offset).clamp(0, height as isize - 1))
+                };
+                w * input[sy as usize * width + sx as usize]
+            }).sum()
+        }).collect()
+    };
+    pass(&pass(channel, kernel_x, true), kernel_y, false)
+}
+
+/// A Gaussian `exp(-pi^2 x^2 / b)` of `x` in degrees, over `-radius..=radius` pixels.
+fn csf_gaussian(b: f32, radius: isize, ppd: f32) -> Vec<f32> {
+    (-radius..=radius).map(|x| {
+        let x = x as f32 / ppd;
+        (-std::f32::consts::PI.powi(2) * x * x / b).exp()
+    }).collect()
+}
+
+/// Scale the positive weights to add up to 1 and the negative ones to -1.
+fn balance(kernel: &mut [f32]) {
+    let positive: f32 = kernel.iter().filter(|w| **w > 0.).sum();
+    let negative: f32 = -kernel.iter().filter(|w| **w < 0.).sum::<f32>();
+    kernel.iter_mut().for_each(|w| {
+        if *w > 0. {
+            *w /= positive;
+        } else if *w < 0. {
+            *w /= negative;
+        }
+    });
+}
+
+/// Magnitudes of the edges and points of a luminance channel, as seen at `ppd`.
+fn features(luminance: &[f32], width: usize, height: usize, ppd: f32) -> (Vec<f32>, Vec<f32>) {
+    let sd = 0.5 * FEATURE_WIDTH * ppd;
+    let radius = (3. * sd).ceil() as isize;
+    let gaussian: Vec<f32> = (-radius..=radius).map(|x| (-(x * x) as f32 / (2. * sd * sd)).exp()).collect();
+    let sum: f32 = gaussian.iter().sum();
+    let smooth: Vec<f32> = gaussian.iter().map(|g| g / sum).collect();
+    let mut edge: Vec<f32> = (-radius..=radius).zip(&gaussian).map(|(x, g)| -x as f32 * g).collect();
+    let mut point: Vec<f32> = (-radius..=radius).zip(&gaussian).map(|(x, g)| ((x * x) as f32 / (sd * sd) - 1.) * g).collect();
+    balance(&mut edge);
+    balance(&mut point);
+    let magnitude = |kernel: &[f32]| -> Vec<f32> {
+        let dx = convolve(luminance, width, height, kernel, &smooth);
+        let dy = convolve(luminance, width, height, &smooth, kernel);
+        dx.iter().zip(&dy).map(|(x, y)| x.hypot(*y)).collect()
+    };
+    (magnitude(&edge), magnitude(&point))
+}
+
+/// Per-pixel FLIP error of the display encoded `test` image against `reference`, both `width`
+/// by `height` and seen at `ppd` pixels per degree.
+pub fn flip(reference: &[Color], test: &[Color], width: usize, height: usize, ppd: f32) -> Vec<f32> {
+    let to_xyz = srgb_to_xyz();
+    let from_xyz = to_xyz.try_inverse().expect("the sRGB primaries are independent");
+    let white = to_xyz * Vector3::repeat(1.);
+    let max_difference = hyab(
+        xyz_to_hunt_lab(to_xyz * Vector3::y(), white),
+        xyz_to_hunt_lab(to_xyz * Vector3::z(), white),
+    ).powf(COLOR_EXPONENT);
+
+    let radius = CSF.iter().flatten().map(|(_, b)| *b).fold(0., f32::max);
+    let radius = (3. * (radius / (2. * std::f32::consts::PI.powi(2))).sqrt() * ppd).ceil() as isize;
+    // Each channel filtered with the sum of its two Gaussians, weighted as the full 2D kernel is
+    let filters: Vec<Vec<(f32, Vec<f32>)>> = CSF.iter().map(|terms| {
+        let terms: Vec<_> = terms.iter().map(|&(a, b)| {
+            let kernel = csf_gaussian(b, radius, ppd);
+            let sum: f32 = kernel.iter().sum();
+            (a * (std::f32::consts::PI / b).sqrt() * sum * sum, kernel.iter().map(|k| k / sum).collect::<Vec<_>>())
+        }).collect();
+        let total: f32 = terms.iter().map(|(weight, _)| weight).sum();
+        terms.into_iter().map(|(weight, kernel)| (weight / total, kernel)).collect()
+    }).collect();
+
+    let perceive = |image: &[Color]| {
+        let ycxcz: Vec<_> = image.iter().map(|c| xyz_to_ycxcz(to_xyz * c.map(srgb_decode), white)).collect();
+        let filtered: Vec<Vec<f32>> = filters.iter().enumerate().map(|(channel, terms)| {
+            let values: Vec<f32> = ycxcz.iter().map(|c| c[channel]).collect();
+            terms.iter()
+                .filter(|(weight, _)| *weight > 0.)
+                .fold(vec![0.; values.len()], |sum, (weight, kernel)| {
+                    let blurred = convolve(&values, width, height, kernel, kernel);
+                    sum.iter().zip(blurred).map(|(s, b)| s + weight * b).collect()
+                })
+        }).collect();
+        let lab: Vec<_> = (0..image.len()).map(|i| {
+            let ycxcz = Vector3::new(filtered[0][i], filtered[1][i], filtered[2][i]);
+            let rgb = (from_xyz * ycxcz_to_xyz(ycxcz, white)).map(|c| c.clamp(0., 1.));
+            xyz_to_hunt_lab(to_xyz * rgb, white)
+        }).collect();
+        let luminance: Vec<f32> = ycxcz.iter().map(|c| (c.x + 16.) / 116.).collect();
+        let (edges, points) = features(&luminance, width, height, ppd);
+        (lab, edges, points)
+    };
+    let (reference_lab, reference_edges, reference_points) = perceive(reference);
+    let (test_lab, test_edges, test_points) = perceive(test);
+
+    (0..reference.len()).map(|i| {
+        let color = hyab(reference_lab[i], test_lab[i]).powf(COLOR_EXPONENT);
+        let cutoff = COLOR_CUTOFF * max_difference;
+        let color = if color < cutoff {
+            COLOR_TARGET / cutoff * color
+        } else {
+            COLOR_TARGET + (color - cutoff) / (max_difference - cutoff) * (1. - COLOR_TARGET)
+        };
+        let feature = (reference_edges[i] - test_edges[i]).abs().max((reference_points[i] - test_points[i]).abs());
+        let feature = (feature / 2_f32.sqrt()).powf(FEATURE_EXPONENT);
+        color.min(1.).powf(1. - feature)
+    }).collect()
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Everything that can go wrong outside of tracing rays.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+    #[error(transparent)]
+    Exr(#[from] exr::error::Error),
+    #[error(transparent)]
+    Tiff(#[from] tiff::TiffError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("{name}: {reason}")]
+    Resource { name: String, reason: String },
+    #[error("unknown image format: {0:?}")]
+    UnknownImageFormat(PathBuf),
+    #[error("frame does not match {width}x{height}")]
+    FrameSize { width: u32, height: u32 },
+    #[error("images differ in size: {}x{} and {}x{}", expected.0, expected.1, found.0, found.1)]
+    ImageSize { expected: (u32, u32), found: (u32, u32) },
+    #[error("invalid IES profile: {0}")]
+    Ies(String),
+    #[error("unknown material: {0}")]
+    UnknownMaterial(String),
+    #[error("invalid scene: {0}")]
+    InvalidScene(String),
+    /// A kind registered by a plugin failed to build, or was not registered after all.
+    #[error("{kind}: {reason}")]
+    Plugin { kind: String, reason: String },
+    /// Objects without bounding box, e.g. infinite planes, cannot go into a BVH.
+    #[error("object without bounding box in a BVH")]
+    NoBoundingBox,
+    #[error("the scene needs about {} MiB, over the budget of {} MiB", used >> 20, budget >> 20)]
+    MemoryBudget { used: usize, budget: usize },
+    /// A job of the daemon it cannot run, see [`crate::daemon`].
+    #[error("invalid job: {0}")]
+    InvalidJob(String),
+    #[error("{path:?}: {source}")]
+    File { path: PathBuf, #[source] source: Box<Error> },
+    /// A browser API call failed.
+    #[error("{0}")]
+    Web(String),
+}
+
+impl Error {
+    /// Tell which file the error is about.
+    pub fn in_file(self, path: impl Into<PathBuf>) -> Self {
+        Self::File { path: path.into(), source: Box::new(self) }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
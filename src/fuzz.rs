@@ -0,0 +1,114 @@
+//! Random rays fired at every primitive, checking what the renderer takes for granted of a hit:
+//! the point is on the surface and in the bounding box, the normal is of unit length and faces
+//! the ray, and `t` is within the range asked for.
+
+use crate::geo::{AlignedAxis, AxisAlignedRect, Cuboid, Disk, FlipFace, RotationY, Sphere, Translation};
+use crate::material::Metal;
+use crate::mesh::Triangle;
+use crate::types::SharedHittable;
+use crate::{Hittable, Ray};
+use na::{Point3, Rotation3, UnitVector3, Vector3};
+use proptest::prelude::*;
+
+/// Distance a hit may be off the surface or outside the box, relative to its magnitude.
+const EPSILON: f32 = 1e-3;
+
+/// A primitive and the distance of a point to its surface.
+struct Fixture {
+    name: &'static str,
+    object: SharedHittable,
+    distance: Box<dyn Fn(Point3<f32>) -> f32>,
+}
+
+/// Distance of `p` to the surface of the box from `min` to `max`, inside or out.
+fn box_distance(p: Point3<f32>, min: Point3<f32>, max: Point3<f32>) -> f32 {
+    let d = (min - p).sup(&(p - max));
+    (d.map(|x| x.max(0.)).norm() + d.max().min(0.)).abs()
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let material = Metal::new([0.5; 3], 0.);
+    let (min, max) = (Point3::new(-1., -2., -1.5), Point3::new(1.5, 1., 2.));
+    let vertices = [Point3::new(-2., -1., 0.), Point3::new(2., -1., 1.), Point3::new(0., 2., -1.)];
+    let normal = (vertices[1] - vertices[0]).cross(&(vertices[2] - vertices[0])).normalize();
+    let disk_normal = Vector3::new(1., 2., -1.).normalize();
+    let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), 30_f32.to_radians());
+    vec![
+        Fixture {
+            name: "sphere",
+            object: Sphere::new([1., -1., 2.], 3., material.clone()),
+            distance: Box::new(|p| ((p - Point3::new(1., -1., 2.)).norm() - 3.).abs()),
+        },
+        Fixture {
+            name: "rectangle",
+            object: AxisAlignedRect::new(material.clone(), 1., [-3., -2.], [2., 4.], AlignedAxis::XZ),
+            distance: Box::new(|p| (p.y - 1.).abs()),
+        },
+        Fixture {
+            name: "disk",
+            object: Disk::new([0., 1., 0.], disk_normal.into(), 2.5, material.clone()),
+            distance: Box::new(move |p| (p - Point3::new(0., 1., 0.)).dot(&disk_normal).abs()),
+        },
+        Fixture {
+            name: "triangle",
+            object: Triangle::new(vertices, material.clone()),
+            distance: Box::new(move |p| (p - vertices[0]).dot(&normal).abs()),
+        },
+        Fixture {
+            name: "cuboid",
+            object: Cuboid::new(min.into(), max.into(), material.clone()),
+            distance: Box::new(move |p| box_distance(p, min, max)),
+        },
+        Fixture {
+            name: "translated cuboid",
+            object: Translation::new(Cuboid::new(min.into(), max.into(), material.clone()), [2., 0., -1.]),
+            distance: Box::new(move |p| box_distance(p - Vector3::new(2., 0., -1.), min, max)),
+        },
+        Fixture {
+            name: "rotated cuboid",
+            object: RotationY::new(Cuboid::new(min.into(), max.into(), material.clone()), 30.),
+            distance: Box::new(move |p| box_distance(rotation.inverse() * p, min, max)),
+        },
+        Fixture {
+            name: "flipped sphere",
+            object: FlipFace::new(Sphere::new([0.; 3], 2., material)),
+            distance: Box::new(|p| (p.coords.norm() - 2.).abs()),
+        },
+    ]
+}
+
+fn ray_strategy() -> impl Strategy<Value = Ray> {
+    (prop::array::uniform3(-10f32..10.), prop::array::uniform3(-1f32..1.))
+        .prop_filter_map("direction too short to normalize", |(origin, direction)| {
+            UnitVector3::try_new(Vector3::from(direction), 1e-3)
+                .map(|direction| Ray::new(Point3::from(origin), direction, 0.))
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2048))]
+
+    #[test]
+    fn hits_hold_invariants(ray in ray_strategy(), t_min in 0f32..1e-2, t_max in prop_oneof![1f32..30., Just(f32::INFINITY)]) {
+        for fixture in fixtures() {
+            let object = fixture.object.read().unwrap();
+            let hit = object.hit(&ray, t_min, t_max);
+            prop_assert_eq!(hit.is_some(), object.hit_any(&ray, t_min, t_max), "{}: hit and hit_any disagree", fixture.name);
+            let hit = match hit {
+                Some(hit) => hit,
+                None => continue,
+            };
+            let scale = hit.point.coords.amax().max(1.);
+            prop_assert!((t_min..=t_max).contains(&hit.t), "{}: t = {} outside [{}, {}]", fixture.name, hit.t, t_min, t_max);
+            prop_assert!((hit.point - ray.at(hit.t)).norm() <= EPSILON * scale, "{}: {} is not at t along the ray", fixture.name, hit.point);
+            prop_assert!((fixture.distance)(hit.point) <= EPSILON * scale, "{}: {} is off the surface", fixture.name, hit.point);
+            prop_assert!((hit.normal.norm() - 1.).abs() <= EPSILON, "{}: normal of length {}", fixture.name, hit.normal.norm());
+            prop_assert!(hit.normal.dot(&ray.direction) <= EPSILON, "{}: normal {} does not face the ray", fixture.name, hit.normal.into_inner());
+            let bbox = object.bounding_box(0., 1.).expect("primitives are bounded");
+            let pad = Vector3::repeat(EPSILON * scale);
+            let inside = (bbox.minimum - pad).coords.iter().zip(hit.point.iter()).all(|(min, p)| min <= p)
+                && (bbox.maximum + pad).coords.iter().zip(hit.point.iter()).all(|(max, p)| p <= max);
+            prop_assert!(inside, "{}: {} outside its bounding box", fixture.name, hit.point);
+        }
+    }
+}
@@ -1,20 +1,28 @@
 use crate::aabb::AxisAlignedBoundingBox;
-use crate::ray::{HitRecord, Hittable, HittableList};
-use crate::types::{create_shared_mut, RGB, Shared, SharedHittable, SharedMaterial, SharedSphere, SharedTexture};
+use crate::memory::MemoryCounter;
+use crate::ray::{HitRecord, Hittable, HittableList, ShapeParams, SurfacePoint, VisibilityFlags};
+use crate::types::{create_shared_mut, Color, RGB, Shared, SharedHittable, SharedMaterial, SharedSphere, SharedTexture};
 use crate::{camera, Ray};
 use na::{Point3, Rotation3, UnitVector3, Vector3};
 use strum::{EnumIter, IntoEnumIterator};
-use crate::material::Isotropic;
-use crate::onb::ONB;
-use crate::rand_gen::{get_rand, get_rand_range};
+use serde::Deserialize;
+use crate::material::{Isotropic, Material, MaterialParams, ScatterRecord, ScatterType};
+use crate::pdf::{PDF, RectPDF, SpherePDF};
+use crate::rand_gen::{get_rand, rand_vec3_in_unit_disk, rand_vec3_on_unit_sphere};
 use crate::texture::SolidColor;
+use crate::onb::ONB;
+#[cfg(feature = "enum-dispatch")]
+use crate::primitive::Primitive;
 
 const PI: f32 = std::f32::consts::PI;
 
+#[derive(Clone)]
 pub struct Sphere {
     pub center0: Point3<f32>,
     pub center1: Point3<f32>,
     pub radius: f32,
+    /// Normals point towards the center, for the inner wall of a hollow shell.
+    inverted: bool,
     material: SharedMaterial,
     time0: f32,
     time1: f32,
@@ -23,9 +31,11 @@ pub struct Sphere {
 }
 
 impl Sphere {
+    /// A sphere around `center`; a negative radius makes it [`inverted`](Self::inverted).
     pub fn new(center: [f32; 3], radius: f32, material: SharedMaterial) -> SharedSphere {
         Self::new_with_label(center, radius, material, None)
     }
+    /// A sphere with a name shown when it is picked in the GUI.
     pub fn new_with_label(center: [f32; 3], radius: f32, material: SharedMaterial, label: Option<String>) -> SharedSphere {
 
         create_shared_mut(Sphere {
@@ -33,12 +43,31 @@ impl Sphere {
             center1: Point3::from(center),
             time0: 0.,
             time1: 0.,
-            radius,
+            radius: radius.abs(),
+            inverted: radius < 0.,
             material,
             moving: false,
             label
         })
     }
+    /// A sphere whose normals point towards its center.
+    pub fn inverted(center: [f32; 3], radius: f32, material: SharedMaterial) -> SharedSphere {
+        Self::new(center, -radius.abs(), material)
+    }
+    /// A shell of `thickness` inside the radius `outer_radius`, like a glass bubble: the outer
+    /// sphere and an inverted inner one, so a ray crossing it enters and leaves the material twice.
+    pub fn hollow(center: [f32; 3], outer_radius: f32, thickness: f32, material: SharedMaterial) -> Shared<HittableList> {
+        let inner_radius = outer_radius - thickness;
+        assert!(inner_radius > 0., "a shell of thickness {} does not fit in a radius of {}", thickness, outer_radius);
+        HittableList::new(
+            vec![
+                Sphere::new(center, outer_radius, material.clone()),
+                Sphere::inverted(center, inner_radius, material),
+            ],
+            None,
+        )
+    }
+    /// A sphere moving from `center0` at `time0` to `center1` at `time1`, blurred by the shutter.
     pub fn new_moving(
         center0: [f32; 3],
         center1: [f32; 3],
@@ -53,7 +82,8 @@ impl Sphere {
             center1: Point3::from(center1),
             time0,
             time1,
-            radius,
+            radius: radius.abs(),
+            inverted: radius < 0.,
             material,
             moving: true,
             label: None
@@ -73,20 +103,11 @@ impl Sphere {
         let phi = (-p.z).atan2(p.x) + PI;
         [phi / (2. * PI), theta / PI]
     }
-
-    fn random_to_sphere(&self, d: f32) -> UnitVector3<f32>{
-        let r1 = get_rand();
-        let r2 = get_rand();
-        let z = 1. + r2 * ((1. - self.radius * self.radius / d).sqrt() - 1.);
-        let phi = 2. * PI * r1;
-        let x = phi.cos() * (1. - z * z).sqrt();
-        let y = phi.sin() * (1. - z * z).sqrt();
-        UnitVector3::new_unchecked(Vector3::from([x, y, z]))
-    }
 }
 
-impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+impl Sphere {
+    /// The closest ray parameter in `t_min..=t_max` where the ray meets the sphere.
+    fn root(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
         let oc = ray.origin - self.get_center(ray.time);
         let a = ray.direction.norm_squared();
         let half_b = oc.dot(&ray.direction);
@@ -96,23 +117,34 @@ impl Hittable for Sphere {
             return None;
         }
         let sqrtd = discriminant.sqrt();
-        let mut root = (-half_b - sqrtd) / a;
-        if root < t_min || root > t_max {
-            root = (-half_b + sqrtd) / a;
-            if root < t_min || root > t_max {
-                return None;
-            }
-        }
-        let mut hit_record = HitRecord::default();
-        hit_record.t = root;
-        hit_record.point = ray.at(root);
-        let outward_normal = (hit_record.point - self.get_center(ray.time)) / self.radius;
-        hit_record.uv = Self::get_sphere_uv(outward_normal.into());
-        hit_record.set_face_normal(ray, UnitVector3::new_normalize(outward_normal));
-        hit_record.material = self.material.clone();
+        [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a]
+            .into_iter()
+            .find(|root| (t_min..=t_max).contains(root))
+    }
+}
+
+impl Hittable for Sphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let root = self.root(ray, t_min, t_max)?;
+        let mut hit_record = HitRecord::new(ray, root, self.material.clone());
+        let center = self.get_center(ray.time);
+        let radial = UnitVector3::new_normalize(hit_record.point - center);
+        // Put the point back onto the sphere, the root of a large sphere is far less precise
+        hit_record.point = center + radial.into_inner() * self.radius;
+        hit_record.error = (center.coords.amax() + self.radius) * 4. * f32::EPSILON;
+        // Texture coordinates and tangents follow the surface whichever way it faces
+        hit_record.uv = Self::get_sphere_uv(radial.into_inner().into());
+        // Around the y axis, along the longitude `u` grows with
+        hit_record.dpdu = Some(2. * PI * self.radius * Vector3::new(radial.z, 0., -radial.x));
+        let outward_normal = if self.inverted { -radial } else { radial };
+        hit_record.set_face_normal(ray, outward_normal);
         Some(hit_record)
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.root(ray, t_min, t_max).is_some()
+    }
+
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
         let offset = Vector3::repeat(self.radius);
         if self.moving {
@@ -133,23 +165,69 @@ impl Hittable for Sphere {
         self.label.as_ref()
     }
 
-    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
-        if let Some(_hit_record) = self.hit(&Ray::new(origin, v, 0.), 0.001, f32::INFINITY) {
-          let cos_theta_max = (1. - self.radius * self.radius / (self.center0 - origin).norm_squared()).sqrt();
-          let solid_angle = 2. * PI * (1. - cos_theta_max);
-        return 1. / solid_angle
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.material.clone())
+    }
+
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        Some(Primitive::Sphere(self.clone()))
+    }
+
+    fn set_material(&mut self, material: SharedMaterial) -> bool {
+        self.material = material;
+        true
+    }
+
+    /// The inverse of [`Sphere::get_sphere_uv`], at the position the shutter opens with.
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        let (theta, phi) = (uv[1] * PI, uv[0] * 2. * PI);
+        let radial = UnitVector3::new_normalize(Vector3::new(-theta.sin() * phi.cos(), -theta.cos(), theta.sin() * phi.sin()));
+        Some(SurfacePoint {
+            point: self.center0 + radial.into_inner() * self.radius,
+            normal: if self.inverted { -radial } else { radial },
+        })
+    }
+
+    fn shape_params(&self) -> ShapeParams {
+        ShapeParams {
+            radius: Some(self.radius),
+            center: Some(self.center0.into()),
+            ..Default::default()
         }
-        0.
+    }
+
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        if let Some(radius) = params.radius {
+            self.radius = radius.abs();
+        }
+        // Moving spheres keep their motion
+        if let Some(center) = params.center {
+            let shift = Point3::from(center) - self.center0;
+            self.center0 += shift;
+            self.center1 += shift;
+        }
+    }
+
+    fn area(&self) -> f32 {
+        4. * PI * self.radius * self.radius
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        let radial = rand_vec3_on_unit_sphere();
+        let normal = if self.inverted { -radial } else { radial };
+        Some(SurfacePoint { point: self.center0 + self.radius * radial.into_inner(), normal })
+    }
+
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        SpherePDF::new(origin, self.center0, self.radius).value(v)
     }
     fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
-        let direction = self.center0 - origin;
-        let dis_sq = direction.norm_squared();
-        let uvw = ONB::build_from_w(UnitVector3::new_normalize(direction));
-        uvw.local_dir(self.random_to_sphere(dis_sq))
+        SpherePDF::new(origin, self.center0, self.radius).generate()
     }
 }
 
-#[derive(EnumIter, Clone, Copy, Debug)]
+#[derive(EnumIter, Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AlignedAxis {
     XY,
     XZ,
@@ -165,31 +243,50 @@ impl AlignedAxis {
         }
     }
 }
+#[derive(Clone)]
 pub struct AxisAlignedRect {
     material: SharedMaterial,
     k: f32,
-    p0: Point3<f32>,
-    p1: Point3<f32>,
+    /// Corners in the plane, in the coordinates of the first two axes of `axis`.
+    c0: [f32; 2],
+    c1: [f32; 2],
     axis: AlignedAxis
 }
 
 
 impl AxisAlignedRect {
+    /// A rectangle in the plane `axis` at offset `k` along the third axis, spanning corners `c0` to `c1`.
     pub fn new(material: SharedMaterial, k: f32, c0: [f32; 2], c1: [f32; 2], axis: AlignedAxis) -> Shared<Self> {
-        let axis_map = axis.get_indexes();
-        let mut p0 = Point3::origin();
-        let mut p1 = Point3::origin();
         assert!(c0[0] < c1[0] && c0[1] < c1[1]);
-        for i in 0..2 {
-            p0[axis_map[i]] = c0[i];
-            p1[axis_map[i]] = c1[i];
-        }
-        p0[axis_map[2]] = k - 0.0001;
-        p1[axis_map[2]] = k + 0.0001;
         create_shared_mut(Self {
-            material, k, p0, p1, axis
+            material, k, c0, c1, axis
         })
     }
+
+    /// The point of the plane at `c`.
+    fn point(&self, c: [f32; 2]) -> Point3<f32> {
+        let [xi, yi, zi] = self.axis.get_indexes();
+        let mut p = Point3::origin();
+        p[xi] = c[0];
+        p[yi] = c[1];
+        p[zi] = self.k;
+        p
+    }
+
+    /// The sides of the rectangle.
+    fn edges(&self) -> [Vector3<f32>; 2] {
+        let [xi, yi, _] = self.axis.get_indexes();
+        let mut edges = [Vector3::zeros(); 2];
+        edges[0][xi] = self.c1[0] - self.c0[0];
+        edges[1][yi] = self.c1[1] - self.c0[1];
+        edges
+    }
+
+    /// Directions from `origin` towards the rectangle, aimed at the bright parts of an image it shows.
+    fn pdf(&self, origin: Point3<f32>) -> Box<RectPDF> {
+        RectPDF::new(origin, self.point(self.c0), self.edges())
+            .with_distribution(self.material.read().unwrap().emission_distribution())
+    }
 }
 
 impl Hittable for AxisAlignedRect {
@@ -199,92 +296,307 @@ impl Hittable for AxisAlignedRect {
         if t < t_min || t > t_max {return None}
         let xyz = ray.at(t);
         let [x, y] = [xyz[xi], xyz[yi]];
-        if x < self.p0[xi] || x > self.p1[xi] || y < self.p0[yi] || y > self.p1[yi] {
+        if x < self.c0[0] || x > self.c1[0] || y < self.c0[1] || y > self.c1[1] {
             return None
         }
-        let mut hit_record = HitRecord::default();
-        let upper = xyz - self.p0;
-        let lower = self.p1 - self.p0;
-        hit_record.uv = [upper[xi] / lower[xi], upper[yi] / lower[yi]];
+        let mut hit_record = HitRecord::new(ray, t, self.material.clone());
+        // Exactly in the plane, only the other coordinates carry the error of `t`
+        hit_record.point[zi] = self.k;
+        hit_record.error = self.k.abs() * f32::EPSILON;
+        let [size_x, size_y] = [self.c1[0] - self.c0[0], self.c1[1] - self.c0[1]];
+        hit_record.uv = [(x - self.c0[0]) / size_x, (y - self.c0[1]) / size_y];
+        hit_record.dpdu = Some(self.edges()[0]);
         let mut outward_normal = Vector3::zeros();
         outward_normal[zi] = 1.;
         hit_record.set_face_normal(ray, UnitVector3::new_unchecked(outward_normal));
-        hit_record.material = self.material.clone();
-        hit_record.point = xyz;
-        hit_record.t = t;
         Some(hit_record)
     }
 
     fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
-        let output_box = AxisAlignedBoundingBox::new(self.p0, self.p1);
-        Some(output_box)
+        // Boxes without volume are never entered, only the box gets a thickness, not the rectangle
+        let zi = self.axis.get_indexes()[2];
+        let mut pad = Vector3::zeros();
+        pad[zi] = self.k.abs().max(1.) * 1e-4;
+        Some(AxisAlignedBoundingBox::new(self.point(self.c0) - pad, self.point(self.c1) + pad))
     }
 
-    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
-        if let Some(hit_record) = self.hit(&Ray::new(origin, v, 0.2), 0.001, f32::INFINITY) {
-            let [xi, yi, _] = self.axis.get_indexes();
-            let diag = self.p1 - self.p0;
-            let area = (diag[xi] * diag[yi]).abs();
-            let dis_squared = hit_record.t * hit_record.t * v.norm_squared();
-            let cosine = v.dot(&hit_record.normal).abs();
-            return dis_squared / (cosine * area)
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.material.clone())
+    }
+
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        Some(Primitive::Rect(self.clone()))
+    }
+
+    fn shape_params(&self) -> ShapeParams {
+        ShapeParams {
+            corners: Some([self.c0, self.c1]),
+            ..Default::default()
         }
-        0.
+    }
+
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        if let Some([c0, c1]) = params.corners.filter(|[c0, c1]| c0[0] < c1[0] && c0[1] < c1[1]) {
+            self.c0 = c0;
+            self.c1 = c1;
+        }
+    }
+
+    fn area(&self) -> f32 {
+        (self.c1[0] - self.c0[0]) * (self.c1[1] - self.c0[1])
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        let [e0, e1] = self.edges();
+        let mut normal = Vector3::zeros();
+        normal[self.axis.get_indexes()[2]] = 1.;
+        Some(SurfacePoint {
+            point: self.point(self.c0) + get_rand() * e0 + get_rand() * e1,
+            normal: UnitVector3::new_unchecked(normal),
+        })
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        let [e0, e1] = self.edges();
+        let mut normal = Vector3::zeros();
+        normal[self.axis.get_indexes()[2]] = 1.;
+        Some(SurfacePoint {
+            point: self.point(self.c0) + uv[0] * e0 + uv[1] * e1,
+            normal: UnitVector3::new_unchecked(normal),
+        })
+    }
+
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        self.pdf(origin).value(v)
     }
     fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
-        let [xi, yi, zi] = self.axis.get_indexes();
-        let mut xyz = [0.; 3];
-        xyz[xi] = get_rand_range(self.p0[xi], self.p1[xi]);
-        xyz[yi] = get_rand_range(self.p0[yi], self.p1[yi]);
-        xyz[zi] = (self.p0[zi] + self.p1[zi]) / 2.;
-        let random_point = Point3::from(xyz);
-        UnitVector3::new_normalize(random_point - origin)
+        self.pdf(origin).generate()
+    }
+}
+
+/// A flat round disk facing `normal`, e.g. a spot light or a table top.
+#[derive(Clone)]
+pub struct Disk {
+    center: Point3<f32>,
+    frame: ONB,
+    radius: f32,
+    material: SharedMaterial,
+}
+
+impl Disk {
+    pub fn new(center: [f32; 3], normal: [f32; 3], radius: f32, material: SharedMaterial) -> Shared<Self> {
+        create_shared_mut(Self {
+            center: Point3::from(center),
+            frame: ONB::build_from_w(UnitVector3::new_normalize(Vector3::from(normal))),
+            radius,
+            material,
+        })
     }
 }
 
+impl Hittable for Disk {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let normal = self.frame.w();
+        let t = (self.center - ray.origin).dot(&normal) / ray.direction.dot(&normal);
+        if !(t_min..=t_max).contains(&t) {
+            return None
+        }
+        let offset = ray.at(t) - self.center;
+        let (x, y) = (offset.dot(&self.frame.u()), offset.dot(&self.frame.v()));
+        let r = x.hypot(y);
+        if r > self.radius {
+            return None
+        }
+        let mut hit_record = HitRecord::new(ray, t, self.material.clone());
+        // Exactly in the plane, as for rectangles
+        hit_record.point = self.center + self.frame.local([x, y, 0.]);
+        hit_record.uv = [(y.atan2(x) + PI) / (2. * PI), r / self.radius];
+        hit_record.dpdu = Some(2. * PI * self.frame.local([-y, x, 0.]));
+        hit_record.set_face_normal(ray, normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
+        // The extent of a circle along each axis, padded as it is flat along its normal
+        let normal = self.frame.w();
+        let extent = normal.map(|n| self.radius * (1. - n * n).max(0.).sqrt() + self.radius * 1e-4);
+        Some(AxisAlignedBoundingBox::new(self.center - extent, self.center + extent))
+    }
+
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.material.clone())
+    }
+
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        Some(Primitive::Disk(self.clone()))
+    }
+
+    fn shape_params(&self) -> ShapeParams {
+        ShapeParams {
+            radius: Some(self.radius),
+            center: Some(self.center.into()),
+            ..Default::default()
+        }
+    }
+
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        if let Some(radius) = params.radius.filter(|&radius| radius > 0.) {
+            self.radius = radius;
+        }
+        if let Some(center) = params.center {
+            self.center = Point3::from(center);
+        }
+    }
+
+    fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        let p = self.radius * rand_vec3_in_unit_disk();
+        Some(SurfacePoint { point: self.center + self.frame.local([p.x, p.y, 0.]), normal: self.frame.w() })
+    }
+
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        let (angle, r) = (uv[0] * 2. * PI - PI, uv[1] * self.radius);
+        Some(SurfacePoint { point: self.center + self.frame.local([r * angle.cos(), r * angle.sin(), 0.]), normal: self.frame.w() })
+    }
+}
+
+#[derive(Clone)]
 pub struct Cuboid {
     cuboid_min: Point3<f32>,
     cuboid_max: Point3<f32>,
-    sides: SharedHittable,
+    material: SharedMaterial,
 }
 
 impl Cuboid {
+    /// An axis-aligned box, intersected with a single slab test rather than as six rectangles.
     pub fn new(cuboid_min: [f32; 3], cuboid_max: [f32; 3], material: SharedMaterial) -> Shared<Self> {
-        let sides = AlignedAxis::iter()
-            .map(|axis| {
-                let axis_map = axis.get_indexes();
-                let c0 = [cuboid_min[axis_map[0]], cuboid_min[axis_map[1]]];
-                let c1 = [cuboid_max[axis_map[0]], cuboid_max[axis_map[1]]];
-                let ks = [cuboid_min[axis_map[2]], cuboid_max[axis_map[2]]];
-                ks.into_iter().map(|k|
-                        AxisAlignedRect::new(material.clone(),
-                                             k,
-                                             c0,
-                                             c1,
-                                             axis
-                        ) as SharedHittable
-                ).collect::<Vec<_>>()
+        create_shared_mut(Self {
+            cuboid_min: Point3::from(cuboid_min),
+            cuboid_max: Point3::from(cuboid_max),
+            material
+        })
+    }
+
+    /// Distances along the ray to where it enters and leaves the box, with the axis of the
+    /// face crossed each time.
+    fn slabs(&self, ray: &Ray) -> Option<((f32, usize), (f32, usize))> {
+        let mut near = (f32::NEG_INFINITY, 0);
+        let mut far = (f32::INFINITY, 0);
+        for i in 0..3 {
+            let (a, b) = (ray.origin[i], ray.direction[i]);
+            if b == 0. {
+                // Parallel to the slab, inside or outside it for good
+                if a < self.cuboid_min[i] || a > self.cuboid_max[i] {
+                    return None
+                }
+                continue
+            }
+            let inv_b = 1. / b;
+            let mut t0 = (self.cuboid_min[i] - a) * inv_b;
+            let mut t1 = (self.cuboid_max[i] - a) * inv_b;
+            if inv_b < 0. {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            if t0 > near.0 {
+                near = (t0, i);
             }
-        ).flatten().collect::<Vec<_>>();
-        let sides = HittableList::new(sides, None);
-    create_shared_mut(Self {
-        cuboid_min: Point3::from(cuboid_min),
-        cuboid_max: Point3::from(cuboid_max),
-        sides
-    })
+            if t1 < far.0 {
+                far = (t1, i);
+            }
+        }
+        (near.0 <= far.0).then(|| (near, far))
+    }
+
+    /// Sizes of the box along the axes.
+    fn size(&self) -> Vector3<f32> {
+        self.cuboid_max - self.cuboid_min
+    }
+
+    /// The face axes of the faces facing along `axis`, as for the rectangle of the same orientation.
+    fn face_axes(axis: usize) -> [usize; 3] {
+        AlignedAxis::iter()
+            .map(|aligned| aligned.get_indexes())
+            .find(|indexes| indexes[2] == axis)
+            .unwrap()
     }
 }
 
 impl Hittable for Cuboid {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        self.sides.read().unwrap().hit(ray, t_min, t_max)
+        let ((t_near, near_axis), (t_far, far_axis)) = self.slabs(ray)?;
+        // Rays starting inside the box leave it through the far face
+        let (t, zi) = if (t_min..=t_max).contains(&t_near) {
+            (t_near, near_axis)
+        } else if (t_min..=t_max).contains(&t_far) {
+            (t_far, far_axis)
+        } else {
+            return None
+        };
+        let [xi, yi, _] = Self::face_axes(zi);
+        let mut hit_record = HitRecord::new(ray, t, self.material.clone());
+        // Snap onto the face crossed, which of the two is told by the ray direction
+        let outward = if (t == t_near) == (ray.direction[zi] < 0.) { 1. } else { -1. };
+        let k = if outward > 0. { self.cuboid_max[zi] } else { self.cuboid_min[zi] };
+        hit_record.point[zi] = k;
+        hit_record.error = hit_record.error.max(k.abs() * f32::EPSILON);
+        let size = self.size();
+        let local = hit_record.point - self.cuboid_min;
+        hit_record.uv = [
+            (local[xi] / size[xi]).clamp(0., 1.),
+            (local[yi] / size[yi]).clamp(0., 1.),
+        ];
+        let mut dpdu = Vector3::zeros();
+        dpdu[xi] = size[xi];
+        hit_record.dpdu = Some(dpdu);
+        let mut outward_normal = Vector3::zeros();
+        outward_normal[zi] = outward;
+        hit_record.set_face_normal(ray, UnitVector3::new_unchecked(outward_normal));
+        Some(hit_record)
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.slabs(ray).map_or(false, |((t_near, _), (t_far, _))| {
+            (t_min..=t_max).contains(&t_near) || (t_min..=t_max).contains(&t_far)
+        })
     }
 
     fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
         Some(AxisAlignedBoundingBox::new(self.cuboid_min, self.cuboid_max))
     }
-    fn get_one(&self) -> Option<SharedHittable> {
-        self.sides.read().unwrap().get_one()
+    fn area(&self) -> f32 {
+        let size = self.size();
+        2. * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        let size = self.size();
+        // A face pair picked by its area, then one of the two faces and a point on it
+        let areas = [size.y * size.z, size.z * size.x, size.x * size.y];
+        let mut pick = get_rand() * areas.iter().sum::<f32>();
+        let zi = areas.iter().position(|&area| {
+            pick -= area;
+            pick < 0.
+        }).unwrap_or(2);
+        let mut point = self.cuboid_min + size.component_mul(&Vector3::new(get_rand(), get_rand(), get_rand()));
+        let mut normal = Vector3::zeros();
+        if get_rand() < 0.5 {
+            point[zi] = self.cuboid_min[zi];
+            normal[zi] = -1.;
+        } else {
+            point[zi] = self.cuboid_max[zi];
+            normal[zi] = 1.;
+        }
+        Some(SurfacePoint { point, normal: UnitVector3::new_unchecked(normal) })
+    }
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.material.clone())
+    }
+
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        Some(Primitive::Cuboid(self.clone()))
     }
 }
 
@@ -294,6 +606,7 @@ pub struct Translation {
 }
 
 impl Translation {
+    /// `obj` moved by `offset`.
     pub fn new(obj: SharedHittable, offset: [f32; 3]) -> Shared<Self> {
         create_shared_mut(Self {
             obj,
@@ -304,7 +617,7 @@ impl Translation {
 
 impl Hittable for Translation {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let moved_ray = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
+        let moved_ray = Ray { origin: ray.origin - self.offset, ..*ray };
         if let Some(mut hit_record) = self.obj.read().unwrap().hit(&moved_ray, t_min, t_max) {
             hit_record.point += self.offset;
             // hit_record.set_face_normal(&moved_ray, hit_record.normal);
@@ -314,6 +627,11 @@ impl Hittable for Translation {
         }
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let moved_ray = Ray { origin: ray.origin - self.offset, ..*ray };
+        self.obj.read().unwrap().hit_any(&moved_ray, t_min, t_max)
+    }
+
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
         if let Some(bbox) = self.obj.read().unwrap().bounding_box(time0, time1) {
             Some(AxisAlignedBoundingBox::new(bbox.minimum + self.offset, bbox.maximum + self.offset))
@@ -321,73 +639,183 @@ impl Hittable for Translation {
             None
         }
     }
+    // The object sits at its own origin, so is the point it is seen from
     fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
-        self.obj.read().unwrap().pdf_val(origin, v)
+        self.obj.read().unwrap().pdf_val(origin - self.offset, v)
     }
     fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
-        self.obj.read().unwrap().random(origin)
+        self.obj.read().unwrap().random(origin - self.offset)
+    }
+    fn material(&self) -> Option<SharedMaterial> {
+        self.obj.read().unwrap().material()
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        counter.hittable(&self.obj);
+    }
+    fn refit(&mut self) -> f32 {
+        self.obj.write().unwrap().refit()
+    }
+    // Moving the object moves the offset, the object stays where it is in its own space
+    fn shape_params(&self) -> ShapeParams {
+        let params = self.obj.read().unwrap().shape_params();
+        let center = Point3::from(params.center.unwrap_or_default()) + self.offset;
+        ShapeParams { center: Some(center.into()), ..params }
+    }
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        let mut obj = self.obj.write().unwrap();
+        if let Some(center) = params.center {
+            self.offset = Vector3::from(center) - Vector3::from(obj.shape_params().center.unwrap_or_default());
+        }
+        obj.set_shape_params(&ShapeParams { center: None, ..*params })
+    }
+    fn area(&self) -> f32 {
+        self.obj.read().unwrap().area()
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().sample_point().map(|sample| SurfacePoint { point: sample.point + self.offset, ..sample })
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().surface_at(uv).map(|sample| SurfacePoint { point: sample.point + self.offset, ..sample })
     }
 }
 
 pub struct RotationY {
     obj: SharedHittable,
+    /// Angles in radians at the start and end of the shutter, equal unless the object spins.
+    angles: [f32; 2],
     rotation: Rotation3<f32>,
     inv_rot: Rotation3<f32>,
     bbox: Option<AxisAlignedBoundingBox>
 }
 
+/// Angle between the rotations a swept bounding box is built from, in radians.
+const SWEEP_STEP: f32 = PI / 16.;
+
 impl RotationY {
+    /// `obj` turned around the y axis by `degree`.
     pub fn new(obj: SharedHittable, degree: f32) -> Shared<Self> {
-        let radians = camera::degree_to_radian(degree);
-
-        let mut min = Point3::from([f32::INFINITY; 3]);
-        let mut max = Point3::from([f32::NEG_INFINITY; 3]);
-        let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), radians);
-        let bbox = if let Some(bbox) = obj.read().unwrap().bounding_box(0., 1.) {
-            let minimum = bbox.minimum - Point3::origin();
-            let maximum = bbox.maximum - Point3::origin();
-            let ones = Vector3::repeat(1.);
-            let range = 2usize;
-            (0..range).for_each(|i| (0..range).for_each(|j| (0..range).for_each(|k| {
-               let vec = Vector3::from([i as f32, j as f32, k as f32]);
-                let xyz = rotation * (vec.component_mul(&maximum) + (ones - vec).component_mul(&minimum));
-                min.iter_mut().zip(xyz.iter()).for_each(|(mi, &c)| *mi = mi.min(c));
-                max.iter_mut().zip(xyz.iter()).for_each(|(mi, &c)| *mi = mi.max(c));
-            })));
-         Some(AxisAlignedBoundingBox::new(min, max))
-        } else {
-            None
-        };
+        Self::spinning(obj, degree, degree)
+    }
 
+    /// `obj` turning around the y axis from `degree0` when the shutter opens to `degree1` when
+    /// it closes, blurred along the way.
+    pub fn spinning(obj: SharedHittable, degree0: f32, degree1: f32) -> Shared<Self> {
+        let angles = [degree0, degree1].map(camera::degree_to_radian);
+        let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), angles[0]);
+        let bbox = obj.read().unwrap().bounding_box(0., 1.).map(|bbox| Self::swept_box(&bbox, angles));
         create_shared_mut(Self {
             obj,
+            angles,
             rotation,
             inv_rot: rotation.inverse(),
             bbox
         })
     }
+
+    /// The box around `bbox` turned through every angle between `angles`. The corners are turned
+    /// in steps of at most [`SWEEP_STEP`], and the box grown by how far the arc between two steps
+    /// bulges past their chord, so an object spinning fast never leaves its box.
+    fn swept_box(bbox: &AxisAlignedBoundingBox, angles: [f32; 2]) -> AxisAlignedBoundingBox {
+        let mut min = Point3::from([f32::INFINITY; 3]);
+        let mut max = Point3::from([f32::NEG_INFINITY; 3]);
+        let sweep = angles[1] - angles[0];
+        let steps = (sweep.abs() / SWEEP_STEP).ceil().max(1.) as usize;
+        let corners: Vec<_> = (0..8)
+            .map(|i| Point3::new(
+                if i & 1 == 0 { bbox.minimum.x } else { bbox.maximum.x },
+                if i & 2 == 0 { bbox.minimum.y } else { bbox.maximum.y },
+                if i & 4 == 0 { bbox.minimum.z } else { bbox.maximum.z },
+            ))
+            .collect();
+        for step in 0..=steps {
+            let angle = angles[0] + sweep * step as f32 / steps as f32;
+            let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), angle);
+            for corner in &corners {
+                let xyz = rotation * corner;
+                min = min.inf(&xyz);
+                max = max.sup(&xyz);
+            }
+        }
+        if sweep != 0. {
+            let radius = corners.iter().map(|c| c.x.hypot(c.z)).fold(0., f32::max);
+            let bulge = radius * (1. - (sweep.abs() / steps as f32 / 2.).cos());
+            let pad = Vector3::new(bulge, 0., bulge);
+            min -= pad;
+            max += pad;
+        }
+        AxisAlignedBoundingBox::new(min, max)
+    }
+
+    /// The rotation and its inverse at `time`, a fraction of the shutter interval.
+    fn rotation_at(&self, time: f32) -> (Rotation3<f32>, Rotation3<f32>) {
+        if self.angles[0] == self.angles[1] {
+            return (self.rotation, self.inv_rot)
+        }
+        let angle = self.angles[0] + time * (self.angles[1] - self.angles[0]);
+        let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), angle);
+        (rotation, rotation.inverse())
+    }
 }
 
 impl Hittable for RotationY {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let origin = self.inv_rot * ray.origin;
-        let direction = self.inv_rot * ray.direction;
-        let rot_ray = Ray::new(origin, direction, ray.time);
+        let (rotation, inv_rot) = self.rotation_at(ray.time);
+        let origin = inv_rot * ray.origin;
+        let direction = inv_rot * ray.direction;
+        let rot_ray = Ray { origin, direction, ..*ray };
         if let Some(mut hit_record) = self.obj.read().unwrap().hit(&rot_ray, t_min, t_max) {
-            let p = self.rotation * hit_record.point;
-            let normal = self.rotation * hit_record.normal;
+            let p = rotation * hit_record.point;
+            let normal = rotation * hit_record.normal;
             hit_record.point = p;
             hit_record.normal = normal;
+            hit_record.dpdu = hit_record.dpdu.map(|dpdu| rotation * dpdu);
             Some(hit_record)
         } else {
             None
         }
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let inv_rot = self.rotation_at(ray.time).1;
+        let rot_ray = Ray { origin: inv_rot * ray.origin, direction: inv_rot * ray.direction, ..*ray };
+        self.obj.read().unwrap().hit_any(&rot_ray, t_min, t_max)
+    }
+
     fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
         self.bbox
     }
+    fn refit(&mut self) -> f32 {
+        let area = self.obj.write().unwrap().refit();
+        self.bbox = self.obj.read().unwrap().bounding_box(0., 1.).map(|bbox| Self::swept_box(&bbox, self.angles));
+        area
+    }
 
+    fn material(&self) -> Option<SharedMaterial> {
+        self.obj.read().unwrap().material()
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        counter.hittable(&self.obj);
+    }
+    fn area(&self) -> f32 {
+        self.obj.read().unwrap().area()
+    }
+    /// A point at the rotation the shutter opens with.
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().sample_point().map(|sample| SurfacePoint {
+            point: self.rotation * sample.point,
+            normal: self.rotation * sample.normal,
+        })
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().surface_at(uv).map(|sample| SurfacePoint {
+            point: self.rotation * sample.point,
+            normal: self.rotation * sample.normal,
+        })
+    }
 }
 
 pub struct ConstantMedium {
@@ -397,6 +825,7 @@ pub struct ConstantMedium {
 }
 
 impl ConstantMedium {
+    /// Fog or smoke filling `boundary`, which has to be a closed shape.
     pub fn new(boundary: SharedHittable, density: f32, albedo: SharedTexture) -> Shared<Self>{
         create_shared_mut(Self {
             boundary,
@@ -404,6 +833,15 @@ impl ConstantMedium {
             phase_function: Isotropic::new(albedo)
         })
     }
+    /// A glowing medium, e.g. fire, whose `emission` texture is evaluated at every collision.
+    pub fn new_emissive(boundary: SharedHittable, density: f32, albedo: SharedTexture, emission: SharedTexture) -> Shared<Self> {
+        create_shared_mut(Self {
+            boundary,
+            neg_inv_density: -1. / density,
+            phase_function: Isotropic::emissive(albedo, emission),
+        })
+    }
+    /// A medium of a single color.
     pub fn new_c(boundary: SharedHittable, density: f32, color: RGB) -> Shared<Self> {
         Self::new(boundary, density, SolidColor::new(color))
     }
@@ -413,7 +851,8 @@ impl Hittable for ConstantMedium {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let boundary = self.boundary.read().unwrap();
         if let Some(mut hit_record1) = boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY) {
-            if let Some(mut hit_record2) = boundary.hit(ray, hit_record1.t + 0.0001, f32::INFINITY) {
+            let gap = ray.bias.medium_gap(ray.origin, hit_record1.t);
+            if let Some(mut hit_record2) = boundary.hit(ray, hit_record1.t + gap, f32::INFINITY) {
                 hit_record1.t = t_min.max(hit_record1.t).max(0.);
                 hit_record2.t = t_max.min(hit_record2.t);
                 if hit_record1.t >= hit_record2.t {
@@ -424,11 +863,7 @@ impl Hittable for ConstantMedium {
                 if hit_distance > distance_inside_boundary {
                     return None
                 }
-                let mut hit_record = HitRecord::default();
-                hit_record.t = hit_record1.t + hit_distance;
-                hit_record.point = ray.at(hit_record.t);
-                hit_record.material = self.phase_function.clone();
-                return Some(hit_record)
+                return Some(HitRecord::new(ray, hit_record1.t + hit_distance, self.phase_function.clone()))
             }
         }
         None
@@ -437,6 +872,19 @@ impl Hittable for ConstantMedium {
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
         self.boundary.read().unwrap().bounding_box(time0, time1)
     }
+
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.phase_function.clone())
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        counter.hittable(&self.boundary);
+        counter.material(&self.phase_function);
+    }
+    fn refit(&mut self) -> f32 {
+        self.boundary.write().unwrap().refit()
+    }
 }
 
 pub struct FlipFace {
@@ -444,6 +892,7 @@ pub struct FlipFace {
 }
 
 impl FlipFace {
+    /// `obj` seen from the back, e.g. lights facing down.
     pub fn new(obj: SharedHittable) -> Shared<Self> {
         create_shared_mut(Self{
             obj
@@ -459,6 +908,10 @@ impl Hittable for FlipFace {
         })
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.obj.read().unwrap().hit_any(ray, t_min, t_max)
+    }
+
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
         self.obj.read().unwrap().bounding_box(time0, time1)
     }
@@ -468,7 +921,360 @@ impl Hittable for FlipFace {
     fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
         self.obj.read().unwrap().random(origin)
     }
+    fn material(&self) -> Option<SharedMaterial> {
+        self.obj.read().unwrap().material()
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        counter.hittable(&self.obj);
+    }
+    fn refit(&mut self) -> f32 {
+        self.obj.write().unwrap().refit()
+    }
+    fn shape_params(&self) -> ShapeParams {
+        self.obj.read().unwrap().shape_params()
+    }
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        self.obj.write().unwrap().set_shape_params(params)
+    }
+    fn area(&self) -> f32 {
+        self.obj.read().unwrap().area()
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().sample_point().map(|sample| SurfacePoint { normal: -sample.normal, ..sample })
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().surface_at(uv).map(|sample| SurfacePoint { normal: -sample.normal, ..sample })
+    }
     // fn get_one(&self) -> Option<SharedHittable> {
     //     Some({let x = self.obj.read().unwrap().get_one(); x}.unwrap_or(self.obj.clone()))
     // }
-}
\ No newline at end of file
+}
+
+/// `obj` hidden from some kinds of rays, e.g. a light blocker the camera does not see.
+pub struct Visibility {
+    obj: SharedHittable,
+    flags: VisibilityFlags,
+}
+
+impl Visibility {
+    pub fn new(obj: SharedHittable, flags: VisibilityFlags) -> Shared<Self> {
+        create_shared_mut(Self { obj, flags })
+    }
+}
+
+impl Hittable for Visibility {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        if !self.flags.sees(ray.kind) {
+            return None
+        }
+        self.obj.read().unwrap().hit(ray, t_min, t_max)
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.flags.sees(ray.kind) && self.obj.read().unwrap().hit_any(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
+        self.obj.read().unwrap().bounding_box(time0, time1)
+    }
+    // Sampling towards the object as a light is not tracing, it never hides
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        self.obj.read().unwrap().pdf_val(origin, v)
+    }
+    fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
+        self.obj.read().unwrap().random(origin)
+    }
+    fn material(&self) -> Option<SharedMaterial> {
+        self.obj.read().unwrap().material()
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        counter.hittable(&self.obj);
+    }
+    fn refit(&mut self) -> f32 {
+        self.obj.write().unwrap().refit()
+    }
+    fn shape_params(&self) -> ShapeParams {
+        self.obj.read().unwrap().shape_params()
+    }
+    fn set_shape_params(&mut self, params: &ShapeParams) {
+        self.obj.write().unwrap().set_shape_params(params)
+    }
+    fn area(&self) -> f32 {
+        self.obj.read().unwrap().area()
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().sample_point()
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        self.obj.read().unwrap().surface_at(uv)
+    }
+}
+
+/// Angular radius in radians, as seen from the ray origin, below which an object of [`Lod`]
+/// is traced with its first proxy, the next proxy at every halving.
+pub const LOD_DEFAULT_SIZE: f32 = 0.05;
+/// Bounces of a path after which [`Lod`] objects are traced one level coarser.
+const LOD_BOUNCES: u32 = 2;
+
+/// An object with simplified proxies traced in its place by the rays that need less of it:
+/// those it looks small to, and those deep in a path, e.g. a heavy mesh seen by the camera
+/// but only its bounding sphere by the third bounce.
+pub struct Lod {
+    /// The full object first, then ever coarser proxies.
+    levels: Vec<SharedHittable>,
+    center: Point3<f32>,
+    radius: f32,
+    /// Distance from `center` within which every level lies.
+    reach: f32,
+    size: f32,
+}
+
+impl Lod {
+    /// `obj` with `proxies`, coarsest last, used below the angular radius `size`.
+    pub fn new(obj: SharedHittable, proxies: Vec<SharedHittable>, size: f32) -> Shared<Self> {
+        let bbox = obj.read().unwrap().bounding_box(0., 1.);
+        let (center, radius) = bbox.map_or((Point3::origin(), 0.), |bbox| {
+            (na::center(&bbox.minimum, &bbox.maximum), (bbox.maximum - bbox.minimum).norm() / 2.)
+        });
+        let levels: Vec<SharedHittable> = std::iter::once(obj).chain(proxies).collect();
+        let reach = levels.iter().filter_map(|level| level.read().unwrap().bounding_box(0., 1.)).fold(radius, |reach, bbox| {
+            let farthest = (bbox.minimum - center).abs().sup(&(bbox.maximum - center).abs());
+            reach.max(farthest.norm())
+        });
+        create_shared_mut(Self { levels, center, radius, reach, size })
+    }
+
+    /// The sphere around the bounding box of `obj`, in its material, as the coarsest proxy.
+    pub fn bounding_sphere(obj: &SharedHittable) -> Option<SharedHittable> {
+        let obj = obj.read().unwrap();
+        let bbox = obj.bounding_box(0., 1.)?;
+        let center = na::center(&bbox.minimum, &bbox.maximum);
+        let radius = (bbox.maximum - bbox.minimum).norm() / 2.;
+        Some(Sphere::new(center.coords.into(), radius, obj.material()?) as SharedHittable)
+    }
+
+    fn level(&self, ray: &Ray) -> &SharedHittable {
+        let distance = (self.center - ray.origin).norm();
+        // Rays leaving the object, or starting where a proxy could be around them, see the full
+        // object: on a coarser one they would hit the surface they left or start inside it
+        if distance <= self.reach {
+            return &self.levels[0]
+        }
+        let by_size = (self.size * distance / self.radius).log2().max(0.).ceil() as usize;
+        let by_bounces = (ray.bounces / LOD_BOUNCES) as usize;
+        &self.levels[by_size.max(by_bounces).min(self.levels.len() - 1)]
+    }
+}
+
+impl Hittable for Lod {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.level(ray).read().unwrap().hit(ray, t_min, t_max)
+    }
+
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.level(ray).read().unwrap().hit_any(ray, t_min, t_max)
+    }
+
+    // Proxies may stick out of the object, the bounding sphere does
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
+        self.levels.iter().fold(None, |bbox, level| {
+            AxisAlignedBoundingBox::surrounding_box(bbox, level.read().unwrap().bounding_box(time0, time1))
+        })
+    }
+    // Lights are sampled on the full object
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        self.levels[0].read().unwrap().pdf_val(origin, v)
+    }
+    fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
+        self.levels[0].read().unwrap().random(origin)
+    }
+    fn material(&self) -> Option<SharedMaterial> {
+        self.levels[0].read().unwrap().material()
+    }
+
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        self.levels.iter().for_each(|level| counter.hittable(level));
+    }
+    fn area(&self) -> f32 {
+        self.levels[0].read().unwrap().area()
+    }
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        self.levels[0].read().unwrap().sample_point()
+    }
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        self.levels[0].read().unwrap().surface_at(uv)
+    }
+}
+
+/// A parallelogram spanned by the edges `u` and `v` from `origin`, facing `u × v`.
+#[derive(Clone, Copy, Debug)]
+pub struct Quad {
+    pub origin: Point3<f32>,
+    pub u: Vector3<f32>,
+    pub v: Vector3<f32>,
+}
+
+impl Quad {
+    pub fn new(origin: [f32; 3], u: [f32; 3], v: [f32; 3]) -> Self {
+        Self { origin: Point3::from(origin), u: Vector3::from(u), v: Vector3::from(v) }
+    }
+
+    fn normal(&self) -> UnitVector3<f32> {
+        UnitVector3::new_normalize(self.u.cross(&self.v))
+    }
+
+    /// Orthonormal axes along `u`, across it in the plane and along the normal.
+    fn frame(&self) -> [Vector3<f32>; 3] {
+        let normal = self.normal().into_inner();
+        let u = self.u.normalize();
+        [u, normal.cross(&u), normal]
+    }
+
+    /// The point at `uv`, fractions of the edges.
+    fn point(&self, [a, b]: [f32; 2]) -> Point3<f32> {
+        self.origin + a * self.u + b * self.v
+    }
+
+    fn area(&self) -> f32 {
+        self.u.cross(&self.v).norm()
+    }
+
+    /// Where the ray crosses the quad from either side, with the point as fractions of the edges.
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, [f32; 2])> {
+        let n = self.u.cross(&self.v);
+        let denom = ray.direction.dot(&n);
+        if denom == 0. {
+            return None
+        }
+        let t = (self.origin - ray.origin).dot(&n) / denom;
+        if !(t_min..=t_max).contains(&t) {
+            return None
+        }
+        // Coordinates along the edges, from the dual basis of the plane
+        let p = ray.at(t) - self.origin;
+        let w = n / n.norm_squared();
+        let uv = [w.dot(&p.cross(&self.v)), w.dot(&self.u.cross(&p))];
+        uv.iter().all(|c| (0. ..=1.).contains(c)).then(|| (t, uv))
+    }
+}
+
+/// A doorway: rays entering its front come out of the front of another portal, so the scene
+/// behind that one shows through it. Without an exit it is a light portal instead, an opening
+/// such as a window that rays pass through unseen and that only guides the sampling of the
+/// light coming in when put among the lights.
+pub struct Portal {
+    entry: Quad,
+    teleport: Option<SharedMaterial>,
+    label: Option<String>,
+}
+
+impl Portal {
+    /// Two portals leading into each other, walking into the front of one out of the front of
+    /// the other. The quads may differ in size, the view through them is stretched to fit.
+    pub fn pair(a: Quad, b: Quad, tint: RGB, label: Option<String>) -> [Shared<Self>; 2] {
+        [(a, b), (b, a)].map(|(entry, exit)| {
+            let teleport: SharedMaterial = create_shared_mut(Teleport { entry, exit, tint: Color::from(tint) });
+            create_shared_mut(Self { entry, teleport: Some(teleport), label: label.clone() })
+        })
+    }
+
+    /// An opening for the light falling through `window` from outside, to put among the lights.
+    pub fn window(window: Quad) -> Shared<Self> {
+        create_shared_mut(Self { entry: window, teleport: None, label: None })
+    }
+}
+
+impl Hittable for Portal {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let teleport = self.teleport.as_ref()?;
+        // Only entered from the front, rays leaving the other side of a pair go through its back
+        let normal = self.entry.normal();
+        if ray.direction.dot(&normal) >= 0. {
+            return None
+        }
+        let (t, uv) = self.entry.intersect(ray, t_min, t_max)?;
+        let mut hit_record = HitRecord::new(ray, t, teleport.clone());
+        hit_record.point = self.entry.point(uv);
+        hit_record.uv = uv;
+        hit_record.dpdu = Some(self.entry.u);
+        hit_record.set_face_normal(ray, normal);
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
+        let corners = [[0., 0.], [1., 0.], [0., 1.], [1., 1.]].map(|uv| self.entry.point(uv));
+        let min = corners.iter().fold(corners[0], |min, c| min.inf(c));
+        let max = corners.iter().fold(corners[0], |max, c| max.sup(c));
+        let pad = Vector3::repeat(self.entry.u.norm().max(self.entry.v.norm()) * 1e-4);
+        Some(AxisAlignedBoundingBox::new(min - pad, max + pad))
+    }
+
+    fn get_label(&self) -> Option<&String> {
+        self.label.as_ref()
+    }
+
+    fn material(&self) -> Option<SharedMaterial> {
+        self.teleport.clone()
+    }
+
+    // Windows are never hit, the density comes from crossing the quad instead
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        match self.entry.intersect(&Ray::new(origin, v, 0.), 0., f32::INFINITY) {
+            Some((t, _)) => {
+                let cosine = v.dot(&self.entry.normal()).abs();
+                if cosine > 0. { t * t / (cosine * self.area()) } else { 0. }
+            }
+            None => 0.,
+        }
+    }
+
+    fn area(&self) -> f32 {
+        self.entry.area()
+    }
+
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        Some(SurfacePoint { point: self.entry.point([get_rand(), get_rand()]), normal: self.entry.normal() })
+    }
+
+    fn surface_at(&self, uv: [f32; 2]) -> Option<SurfacePoint> {
+        Some(SurfacePoint { point: self.entry.point(uv), normal: self.entry.normal() })
+    }
+}
+
+/// Sends rays hitting `entry` on out of `exit`, turned as `exit` is turned from `entry`.
+struct Teleport {
+    entry: Quad,
+    exit: Quad,
+    tint: Color,
+}
+
+impl Material for Teleport {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+        let [a, b] = hit_record.uv;
+        // Mirrored across the edge `v` as well as the normal, which makes the mapping a rotation
+        let point = self.exit.point([1. - a, b]);
+        let [entry_u, entry_v, entry_n] = self.entry.frame();
+        let [exit_u, exit_v, exit_n] = self.exit.frame();
+        let d = ray_in.direction.into_inner();
+        let direction = UnitVector3::new_normalize(
+            -d.dot(&entry_u) * exit_u + d.dot(&entry_v) * exit_v - d.dot(&entry_n) * exit_n
+        );
+        let origin = point + ray_in.bias.origin_error(point.coords.amax()) * exit_n;
+        ScatterRecord::new(ScatterType::Through(Ray::new(origin, direction, ray_in.time).with_bias(ray_in.bias)), self.tint)
+    }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams { albedo: Some(self.tint.into()), ..Default::default() }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(tint) = params.albedo {
+            self.tint = Color::from(tint);
+        }
+    }
+}
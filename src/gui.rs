@@ -1,10 +1,26 @@
 use crate::winit_egui::Painter;
 use crate::Renderer;
+use crate::camera::{CameraSettings, Projection, ShutterCurve};
+use crate::scene::{Fog, Recommended, Scene as World, PREVIEW_BALL};
+use na::{Point3, Rotation3, Unit, Vector3};
+use crate::post::{luminance_histogram, PostProcess, Tonemap, Transfer};
+use egui::plot::{Bar, BarChart, Plot};
+use crate::background::{Background, TimeOfDay, CUBE_FACES};
+use crate::types::{blackbody, shared_id, Color, SharedHittable, SharedMaterial, RGB};
+use crate::rand_gen::random_seed;
+use crate::renderer::{DebugView, LightPaths, MaterialOverride, format_duration, PixelProbe, Precision, RenderStats, DEFAULT_GLASS_DEPTH};
+use crate::ray::{Bias, ObjectRays};
 use egui::{ClippedPrimitive, Context, TexturesDelta};
-use image::ColorType;
 use pixels::{Pixels, PixelsContext};
+use winit::event::{TouchPhase, VirtualKeyCode};
 use winit::window::Window;
+use winit_input_helper::WinitInputHelper;
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
 use strum::{EnumIter, IntoEnumIterator};
+use cfg_if::cfg_if;
 
 /// Manages all state required for rendering egui over `Pixels`.
 pub(crate) struct Framework {
@@ -16,17 +32,104 @@ pub(crate) struct Framework {
     textures: TexturesDelta,
     // State for the GUI
     pub(crate) gui: Gui,
+    pub(crate) actions: Actions,
+    pub(crate) stats: RenderStats,
     pub scale_factor: f32,
+    screen_size: [u32; 2],
+    /// Window positions where the render region drag started and currently is.
+    marquee: Option<[(f32, f32); 2]>,
+    /// Luminance histogram of the displayed frame.
+    histogram: Vec<u32>,
+    /// Pinned frame shown left of the wipe for A/B comparison.
+    snapshot: Option<Snapshot>,
+    applied_wipe: f32,
+    /// Pixel under the cursor while the probe modifier is held.
+    pub(crate) probe: Option<PixelProbe>,
+    pub(crate) shortcuts: Shortcuts,
+    /// The image was finished the last frame, so it is auto-saved once.
+    finished: bool,
+    /// Crop around the cursor while the magnifier is open.
+    magnifier: Option<Magnifier>,
+    /// Camera moved from the keyboard, applied once it has not moved for [`CAMERA_DEBOUNCE`].
+    pending_camera: Option<(CameraSettings, instant::Instant)>,
+    /// Fingers on the screen by touch id, in physical pixels.
+    touches: BTreeMap<u64, [f32; 2]>,
 }
 
-#[derive(PartialEq, EnumIter, Clone, Copy)]
+const CAMERA_DEBOUNCE: instant::Duration = instant::Duration::from_millis(300);
+/// Camera nudge per frame a key is held, relative to the distance to the look-at point.
+const NUDGE_STEP: f32 = 0.01;
+/// Below this logical width the GUI is laid out for phones.
+const NARROW_WIDTH: f32 = 600.;
+/// Most children listed under an outliner node, so large meshes do not flood it.
+const OUTLINER_MAX_CHILDREN: usize = 200;
+/// Bytes per MiB, for the memory stats.
+const MIB: f64 = (1 << 20) as f64;
+/// Largest width or height of a custom resolution.
+const MAX_CUSTOM_SIZE: u32 = 8192;
+
+/// Key bindings of the viewport.
+pub struct Shortcuts {
+    pub left: VirtualKeyCode,
+    pub right: VirtualKeyCode,
+    pub forward: VirtualKeyCode,
+    pub backward: VirtualKeyCode,
+    pub more_samples: VirtualKeyCode,
+    pub fewer_samples: VirtualKeyCode,
+    pub save: VirtualKeyCode,
+    pub pause: VirtualKeyCode,
+}
+
+impl Default for Shortcuts {
+    fn default() -> Self {
+        Self {
+            left: VirtualKeyCode::Left,
+            right: VirtualKeyCode::Right,
+            forward: VirtualKeyCode::Up,
+            backward: VirtualKeyCode::Down,
+            more_samples: VirtualKeyCode::Equals,
+            fewer_samples: VirtualKeyCode::Minus,
+            save: VirtualKeyCode::S,
+            pause: VirtualKeyCode::Space,
+        }
+    }
+}
+
+struct Snapshot {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+/// The displayed pixels around the cursor, for a close look at focus and noise.
+pub(crate) struct Magnifier {
+    /// Frame pixel in the middle, counted from the top left corner.
+    center: (usize, usize),
+    /// Displayed color and radiance of the pixels, row by row, `None` outside the frame.
+    pixels: Vec<Option<([u8; 4], Color)>>,
+}
+
+impl Magnifier {
+    /// Pixels on every side of the center.
+    const RADIUS: usize = 5;
+    /// Side of a pixel on screen.
+    const ZOOM: f32 = 16.;
+    const SIDE: usize = 2 * Self::RADIUS + 1;
+}
+
+#[derive(PartialEq, EnumIter, Clone, Copy, Serialize, Deserialize)]
 pub enum Scene {
     RANDOM,
     TwoPsp,
     EARTH,
     TwoSp,
     Simplelight,
-    Cornell
+    Cornell,
+    Asteroids,
+    MatPreview,
+    Furnace,
+    Portal,
+    City,
 }
 
 impl Scene {
@@ -39,13 +142,18 @@ impl Scene {
             Self::TwoSp => "2sp",
             Self::Simplelight => "simplelight",
             Self::Cornell => "cornell",
+            Self::Asteroids => "asteroids",
+            Self::MatPreview => "matpreview",
+            Self::Furnace => "furnace",
+            Self::Portal => "portal",
+            Self::City => "city",
         }
     }
 }
 /// Example application state. A real application will need a lot more state than this.
 impl Framework {
     /// Create egui.
-    pub(crate) fn new(window: &Window, pixels: &Pixels) -> Self {
+    pub(crate) fn new(window: &Window, pixels: &Pixels, gui: Option<Gui>) -> Self {
         let painter = Painter::new(window, pixels, 1);
         let scale_factor = window.scale_factor() as f32;
         let egui_ctx = Context::default();
@@ -55,17 +163,32 @@ impl Framework {
         );
 
         let textures = TexturesDelta::default();
-        let gui = Gui::new();
+        let gui = gui.map_or_else(Gui::new, Gui::restored);
 
-        Self {
+        let mut framework = Self {
             egui_ctx,
             egui_state,
             painter,
             paint_jobs: Vec::new(),
             textures,
             gui,
+            actions: Actions::default(),
+            stats: RenderStats::default(),
             scale_factor,
-        }
+            screen_size: window.inner_size().into(),
+            marquee: None,
+            histogram: vec![],
+            snapshot: None,
+            applied_wipe: 0.5,
+            probe: None,
+            magnifier: None,
+            finished: false,
+            shortcuts: Shortcuts::default(),
+            pending_camera: None,
+            touches: BTreeMap::new(),
+        };
+        framework.fit_style();
+        framework
     }
 
     /// Handle input events from the window manager.
@@ -78,16 +201,275 @@ impl Framework {
         if width > 0 && height > 0 {
             self.gui.my_boolean = !self.gui.my_boolean;
             self.painter.on_window_resized(width, height);
+            self.screen_size = [width, height];
+            self.fit_style();
+        }
+    }
+
+    /// Bigger widgets that are easier to hit with a finger on narrow screens.
+    fn fit_style(&self) {
+        let narrow = self.screen_size[0] as f32 / self.scale_factor < NARROW_WIDTH;
+        let mut style = egui::Style::default();
+        if narrow {
+            style.spacing.interact_size.y = 32.;
+            style.spacing.button_padding = egui::vec2(10., 8.);
+            style.spacing.item_spacing = egui::vec2(8., 8.);
+        }
+        self.egui_ctx.set_style(style);
+    }
+
+    /// One finger orbits the camera around the look-at point,
+    /// two fingers pinch to zoom and drag to pan.
+    pub(crate) fn handle_touch(&mut self, id: u64, phase: TouchPhase, position: [f32; 2]) {
+        let before = self.touches.clone();
+        match phase {
+            TouchPhase::Started | TouchPhase::Moved => self.touches.insert(id, position),
+            TouchPhase::Ended | TouchPhase::Cancelled => self.touches.remove(&id),
+        };
+        if phase != TouchPhase::Moved || before.len() != self.touches.len() || self.wants_pointer() {
+            return
+        }
+        let mut camera = self.pending_camera.map_or(self.gui.camera, |(camera, _)| camera);
+        let lookfrom = Point3::from(camera.lookfrom);
+        let lookat = Point3::from(camera.lookat);
+        let forward = lookat - lookfrom;
+        let right = forward.cross(&Vector3::y()).normalize();
+        let up = right.cross(&forward).normalize();
+        // Moving a finger across the height of the screen turns the camera half around
+        let scale = 1. / self.screen_size[1].max(1) as f32;
+        let centroid = |touches: &BTreeMap<u64, [f32; 2]>| {
+            touches.values().fold(Vector3::zeros(), |sum, [x, y]| sum + Vector3::new(*x, *y, 0.)) / touches.len() as f32
+        };
+        let spread = |touches: &BTreeMap<u64, [f32; 2]>| {
+            let center = centroid(touches);
+            touches.values().map(|[x, y]| (Vector3::new(*x, *y, 0.) - center).norm()).sum::<f32>()
+        };
+        match self.touches.len() {
+            1 => {
+                let [x, y] = before[&id];
+                let (yaw, pitch) = ((position[0] - x) * scale * PI, (position[1] - y) * scale * PI);
+                let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), -yaw)
+                    * Rotation3::from_axis_angle(&Unit::new_normalize(right), -pitch);
+                let offset = rotation * (lookfrom - lookat);
+                // Stop short of the poles where the view would flip
+                if offset.normalize().y.abs() < 0.99 {
+                    camera.lookfrom = (lookat + offset).into();
+                }
+            }
+            2 => {
+                let zoom = spread(&before) / spread(&self.touches).max(1.);
+                camera.lookfrom = (lookat - forward * zoom).into();
+                let shift = (centroid(&self.touches) - centroid(&before)) * scale * forward.norm();
+                let pan = -right * shift.x + up * shift.y;
+                camera.lookfrom = (Point3::from(camera.lookfrom) + pan).into();
+                camera.lookat = (lookat + pan).into();
+            }
+            _ => return,
+        }
+        self.pending_camera = Some((camera, instant::Instant::now()));
+    }
+
+    pub(crate) fn update_histogram(&mut self, frame: &[u8]) {
+        self.histogram = luminance_histogram(frame, HISTOGRAM_BINS);
+    }
+
+    pub(crate) fn handle_shortcuts(&mut self, input: &WinitInputHelper) {
+        if !self.egui_ctx.wants_keyboard_input() {
+            let keys = &self.shortcuts;
+            if input.key_pressed(keys.save) {
+                self.actions.save_img = true;
+            }
+            if input.key_pressed(keys.pause) {
+                self.actions.paused = !self.actions.paused;
+            }
+            if input.key_pressed(keys.more_samples) {
+                self.gui.sample_count += 1;
+            }
+            if input.key_pressed(keys.fewer_samples) {
+                self.gui.sample_count = self.gui.sample_count.saturating_sub(1).max(1);
+            }
+            let strafe = input.key_held(keys.right) as i32 - input.key_held(keys.left) as i32;
+            let advance = input.key_held(keys.forward) as i32 - input.key_held(keys.backward) as i32;
+            if strafe != 0 || advance != 0 {
+                let mut camera = self.pending_camera.map_or(self.gui.camera, |(camera, _)| camera);
+                let lookfrom = Point3::from(camera.lookfrom);
+                let lookat = Point3::from(camera.lookat);
+                let forward = lookat - lookfrom;
+                let right = forward.cross(&Vector3::y()).normalize() * forward.norm();
+                let offset = (right * strafe as f32 + forward * advance as f32) * NUDGE_STEP;
+                camera.lookfrom = (lookfrom + offset).into();
+                camera.lookat = (lookat + offset).into();
+                self.pending_camera = Some((camera, instant::Instant::now()));
+            }
+        }
+        if let Some((camera, moved)) = self.pending_camera {
+            if moved.elapsed() >= CAMERA_DEBOUNCE {
+                self.gui.camera = camera;
+                self.pending_camera = None;
+            }
+        }
+    }
+
+    /// Keep the GUI state and window size for the next session.
+    pub(crate) fn save_settings(&self, window: &Window) {
+        let size = window.inner_size().to_logical::<f64>(window.scale_factor());
+        Settings {
+            window_size: [size.width, size.height],
+            gui: self.gui.clone(),
+        }.save();
+    }
+
+    /// Crop the frame around the frame pixel `cursor` for the magnifier. It stays put while
+    /// the pointer is over the GUI, so the pixels in it can be hovered.
+    pub(crate) fn magnify(&mut self, cursor: Option<(usize, usize)>, renderer: &Renderer, frame: &[u8]) {
+        if !self.actions.magnifier {
+            self.magnifier = None;
+            return
         }
+        let center = match (self.wants_pointer(), cursor) {
+            (false, Some(cursor)) => cursor,
+            _ => match &self.magnifier {
+                Some(magnifier) => magnifier.center,
+                None => return,
+            },
+        };
+        let (width, height) = (renderer.width as usize, renderer.height as usize);
+        let radius = Magnifier::RADIUS as isize;
+        let pixels = (-radius..=radius).flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| {
+                let (x, y) = (center.0 as isize + dx, center.1 as isize + dy);
+                if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+                    return None
+                }
+                let (x, y) = (x as usize, y as usize);
+                let start = 4 * (y * width + x);
+                let rgba: [u8; 4] = frame.get(start..start + 4)?.try_into().ok()?;
+                Some((rgba, renderer.radiance(x, y)?))
+            })
+            .collect();
+        self.magnifier = Some(Magnifier { center, pixels });
+    }
+
+    /// Whether the pointer is over an egui window rather than the image.
+    pub(crate) fn wants_pointer(&self) -> bool {
+        self.egui_ctx.wants_pointer_input()
+    }
+
+    /// Drag with the right mouse button to restrict rendering to a region,
+    /// a right click without dragging goes back to the full frame.
+    pub(crate) fn drag_region(&mut self, input: &WinitInputHelper, pixels: &Pixels, renderer: &Renderer) {
+        let mouse = input.mouse();
+        if input.mouse_pressed(1) && !self.wants_pointer() {
+            self.marquee = mouse.map(|pos| [pos, pos]);
+        }
+        if let (Some(marquee), Some(pos)) = (&mut self.marquee, mouse) {
+            marquee[1] = pos;
+        }
+        if input.mouse_released(1) {
+            if let Some([start, end]) = self.marquee.take() {
+                let to_pixel = |pos| pixels.window_pos_to_pixel(pos)
+                    .unwrap_or_else(|pos| pixels.clamp_pixel_pos(pos));
+                let ((x0, y0), (x1, y1)) = (to_pixel(start), to_pixel(end));
+                self.gui.region = if x0 == x1 || y0 == y1 {
+                    None
+                } else {
+                    let (w, h) = (renderer.width as f32, renderer.height as f32);
+                    Some([x0 as f32 / w, y0 as f32 / h, x1 as f32 / w, y1 as f32 / h])
+                };
+            }
+        }
+    }
+
+    /// The screen rectangle of the render region, or of the one being dragged.
+    fn region_outline(&self, renderer: &Renderer) -> Option<egui::Rect> {
+        if let Some([start, end]) = self.marquee {
+            let to_point = |(x, y): (f32, f32)| egui::pos2(x, y) / self.scale_factor;
+            return Some(egui::Rect::from_two_pos(to_point(start), to_point(end)))
+        }
+        self.gui.region.map(|[x0, y0, x1, y1]| egui::Rect::from_two_pos(
+            self.image_to_screen(renderer, [x0, 1. - y0]),
+            self.image_to_screen(renderer, [x1, 1. - y1]),
+        ))
+    }
+
+    /// Maps normalized image coordinates to egui points, following the integer
+    /// scaling and centering that `pixels` applies to its buffer.
+    fn image_to_screen(&self, renderer: &Renderer, [u, v]: [f32; 2]) -> egui::Pos2 {
+        let [screen_w, screen_h] = self.screen_size.map(|x| x as f32);
+        let (w, h) = (renderer.width as f32, renderer.height as f32);
+        let scale = (screen_w / w).min(screen_h / h).max(1.).floor();
+        let offset_x = (screen_w - w * scale) / 2.;
+        let offset_y = (screen_h - h * scale) / 2.;
+        egui::pos2(
+            offset_x + u * w * scale,
+            offset_y + (1. - v) * h * scale,
+        ) / self.scale_factor
+    }
+
+    /// Show the camera being moved at a lower resolution.
+    pub(crate) fn follow_camera(&self, renderer: &mut Renderer, pixels: &mut Pixels) {
+        let camera = self.pending_camera
+            .filter(|_| self.actions.auto_scale)
+            .map(|(camera, _)| camera);
+        renderer.set_interactive(camera, pixels);
+    }
+
+    /// The screen rectangle around the projected bounding box of the selected object.
+    fn selected_outline(&self, renderer: &Renderer) -> Option<egui::Rect> {
+        let scene = renderer.scene();
+        let bbox = self.actions.selected_object
+            .filter(|&i| i < scene.objects.len())
+            .and_then(|i| scene.objects[i].read().unwrap().bounding_box(0., 1.));
+        if let Some(bbox) = bbox {
+            let corners = (0..8).filter_map(|i| {
+                let corner = Point3::from([0, 1, 2].map(|axis|
+                    if i >> axis & 1 == 0 { bbox.minimum[axis] } else { bbox.maximum[axis] }
+                ));
+                renderer.camera().project(corner)
+            }).map(|uv| self.image_to_screen(renderer, uv)).collect::<Vec<_>>();
+            if corners.len() == 8 {
+                return Some(egui::Rect::from_points(&corners))
+            }
+        }
+        None
     }
 
     /// Prepare egui.
-    pub(crate) fn prepare(&mut self, window: &Window) {
+    pub(crate) fn prepare(&mut self, window: &Window, renderer: &Renderer) {
         // Run the egui frame and create all paint jobs to prepare for rendering.
         let raw_input = self.egui_state.take_egui_input(window);
+        let outline = self.selected_outline(renderer);
+        let region = self.region_outline(renderer);
+        let wipe = self.snapshot.as_ref().map(|_| [
+            self.image_to_screen(renderer, [self.actions.wipe, 0.]),
+            self.image_to_screen(renderer, [self.actions.wipe, 1.]),
+        ]);
         let output = self.egui_ctx.run(raw_input, |egui_ctx| {
             // Draw the demo application.
-            self.gui.ui(egui_ctx);
+            self.gui.ui(egui_ctx, &mut self.actions, &self.stats, &renderer.scene().recommended);
+            material_inspector(egui_ctx, &mut self.actions, renderer.scene());
+            histogram_window(egui_ctx, &self.histogram);
+            if self.actions.object_stats {
+                object_stats_window(egui_ctx, &renderer.object_stats());
+            }
+            light_controls(egui_ctx, &mut self.actions, renderer.scene());
+            outliner_window(egui_ctx, &mut self.actions, renderer.scene());
+            if let Some(probe) = &self.probe {
+                probe_window(egui_ctx, probe);
+            }
+            if let Some(magnifier) = &self.magnifier {
+                magnifier_window(egui_ctx, magnifier);
+            }
+            let painter = egui_ctx.layer_painter(egui::LayerId::background());
+            if let Some(rect) = outline {
+                painter.rect_stroke(rect, 0., egui::Stroke::new(2., egui::Color32::YELLOW));
+            }
+            if let Some(rect) = region {
+                painter.rect_stroke(rect, 0., egui::Stroke::new(1., egui::Color32::WHITE));
+            }
+            if let Some(line) = wipe {
+                painter.line_segment(line, egui::Stroke::new(1., egui::Color32::WHITE));
+            }
         });
 
         self.textures.append(output.textures_delta);
@@ -116,32 +498,687 @@ impl Framework {
         self.textures.clear();
     }
 
+    /// Take over the settings a render saved in the browser was made with.
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn restore_gui(&mut self, gui: Gui) {
+        self.gui = gui.restored();
+    }
+
+    /// Forward the render control buttons to the renderer.
+    pub fn control(&mut self, renderer: &mut Renderer) {
+        renderer.paused = self.actions.paused;
+        renderer.png16 = self.actions.png16;
+        if renderer.object_stats != self.actions.object_stats {
+            renderer.set_object_stats(self.actions.object_stats);
+        }
+        renderer.blend = self.actions.blend;
+        renderer.noise_threshold = self.actions.stop_at_noise.then(|| self.actions.noise_threshold);
+        renderer.set_post_process(self.actions.post);
+        if self.actions.moved {
+            self.actions.moved = false;
+            renderer.scene().refit();
+        }
+        if self.actions.restart {
+            self.actions.restart = false;
+            // Light strength or size may have been edited
+            renderer.scene().lights.write().unwrap().weight_by_power();
+            renderer.dirty = true;
+        }
+        if self.actions.cancel {
+            self.actions.cancel = false;
+            renderer.cancel();
+        }
+        if self.actions.open_scene {
+            self.actions.open_scene = false;
+            if let Some(path) = Self::scene_path() {
+                self.gui.scene_file = Some(path);
+            }
+        }
+        if self.actions.reload_scene {
+            self.actions.reload_scene = false;
+            renderer.load_scene(&mut self.gui);
+        }
+        if let Some(material) = self.actions.preview_material.take() {
+            if self.gui.scene_source() != SceneSource::Builtin(Scene::MatPreview.to_str(), self.gui.scene_seed) {
+                self.gui.scene = Scene::MatPreview;
+                self.gui.scene_file = None;
+                renderer.load_scene(&mut self.gui);
+            }
+            // Shared rather than copied, so edits of the original show on the ball
+            renderer.scene().set_material(PREVIEW_BALL, material);
+            renderer.dirty = true;
+        }
+        if let Some(object) = self.actions.remove_object.take() {
+            renderer.scene_mut().remove_object(&object);
+            self.actions.selected_object = None;
+        }
+        if self.actions.wipe != self.applied_wipe {
+            self.applied_wipe = self.actions.wipe;
+            renderer.request_refresh();
+        }
+    }
+
+    /// Load a file dropped onto the window. Scene files replace the scene, HDR images
+    /// become the background and other images are textures of the same name.
+    pub(crate) fn open_dropped(&mut self, name: &str, bytes: Vec<u8>) {
+        log::info!("Dropped {} ({} bytes)", name, bytes.len());
+        crate::resource::add_file(name, bytes);
+        let extension = std::path::Path::new(name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "json" => self.gui.scene_file = Some(name.into()),
+            "hdr" | "exr" => {
+                self.gui.background.kind = BackgroundKind::Hdri;
+                self.gui.background.hdri = name.into();
+            }
+            _ => {}
+        }
+        // The same file may be dropped again with new content
+        self.actions.reload_scene = true;
+    }
+
+    /// Pin the live render as the A side of the comparison, or drop the pinned one.
+    pub fn snapshot(&mut self, renderer: &mut Renderer, pixels: &mut Pixels) {
+        if self.actions.pin_snapshot {
+            self.actions.pin_snapshot = false;
+            let mut rgba = pixels.get_frame().to_vec();
+            renderer.write_frame(&mut rgba);
+            self.snapshot = Some(Snapshot { width: renderer.width, height: renderer.height, rgba });
+            renderer.request_refresh();
+        }
+        if self.actions.clear_snapshot {
+            self.actions.clear_snapshot = false;
+            self.snapshot = None;
+            renderer.request_refresh();
+        }
+        self.actions.has_snapshot = self.snapshot.is_some();
+    }
+
+    /// Show the pinned snapshot left of the wipe position over the live frame.
+    pub(crate) fn composite_snapshot(&mut self, renderer: &Renderer, frame: &mut [u8]) {
+        if let Some(snapshot) = &self.snapshot {
+            // Kept for when the camera settles at the full resolution again
+            if renderer.is_interactive() {
+                return
+            }
+            if (snapshot.width, snapshot.height) != (renderer.width, renderer.height) {
+                self.snapshot = None;
+                self.actions.has_snapshot = false;
+                return
+            }
+            let row_len = 4 * snapshot.width as usize;
+            let split = 4 * (self.actions.wipe * snapshot.width as f32) as usize;
+            frame.chunks_exact_mut(row_len)
+                .zip(snapshot.rgba.chunks_exact(row_len))
+                .for_each(|(row, pinned)| row[..split].copy_from_slice(&pinned[..split]));
+        }
+    }
     pub fn save_img(&mut self, renderer: &Renderer, pixels: &mut Pixels) {
-        if self.gui.save_img {
-            self.gui.save_img = false;
-            image::save_buffer(
-                "screenshot.png",
-                pixels.get_frame(),
-                renderer.width,
-                renderer.height,
-                ColorType::Rgba8,
-            )
-            .ok();
+        let finished = renderer.finished();
+        let auto_save = self.actions.auto_save && finished && !self.finished;
+        self.finished = finished;
+        if self.actions.save_img || auto_save {
+            let asked = std::mem::take(&mut self.actions.save_img);
+            // The frame may show the pinned snapshot, save the live render only
+            let mut rgba = pixels.get_frame().to_vec();
+            renderer.write_frame(&mut rgba);
+            cfg_if! {
+                if #[cfg(target_arch = "wasm32")] {
+                    // No filesystem in the browser, download the PNG instead
+                    let name = if asked { "screenshot.png" } else { "render.png" };
+                    let result = renderer.encode_png(&rgba)
+                        .and_then(|png| crate::output::download(&png, name, "image/png"));
+                    if let Err(e) = result {
+                        log::error!("Failed to download the image: {}", e);
+                    }
+                } else {
+                    let path = if asked { Self::save_path() } else { Some(Self::auto_save_path()) };
+                    if let Some(path) = path {
+                        if let Err(e) = renderer.save_image(&rgba, &path) {
+                            log::error!("Failed to save {:?}: {}", path, e);
+                        }
+                    }
+                }
+            }
         }
     }
+
+    /// A new file for every finished render, named after the time it was saved.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn auto_save_path() -> PathBuf {
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        format!("render_{}.png", seconds).into()
+    }
+
+    /// Ask for the file to save to, the extension picks the format.
+    #[cfg(feature = "rfd")]
+    fn save_path() -> Option<std::path::PathBuf> {
+        use crate::output::ImageFormat;
+        ImageFormat::iter()
+            .fold(rfd::FileDialog::new(), |dialog, format| {
+                dialog.add_filter(format.to_str(), format.extensions())
+            })
+            .set_file_name("screenshot.png")
+            .save_file()
+    }
+
+    #[cfg(not(feature = "rfd"))]
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn save_path() -> Option<std::path::PathBuf> {
+        Some("screenshot.png".into())
+    }
+
+    #[cfg(feature = "rfd")]
+    fn scene_path() -> Option<PathBuf> {
+        rfd::FileDialog::new()
+            .add_filter("Scene", &["json"])
+            .set_directory("static/scenes")
+            .pick_file()
+    }
+
+    #[cfg(not(feature = "rfd"))]
+    fn scene_path() -> Option<PathBuf> {
+        log::warn!("Opening scene files needs the rfd feature");
+        None
+    }
+}
+
+/// Button presses and panel state that act on the current image instead of
+/// going through [`Gui`], so they never mark it as updated.
+pub struct Actions {
+    pub save_img: bool,
+    /// Save PNG files with 16 bits per channel.
+    pub png16: bool,
+    pub paused: bool,
+    pub cancel: bool,
+    /// Scene edited in place, accumulation has to start over.
+    pub restart: bool,
+    /// Objects moved or resized, the bounding boxes around them have to follow.
+    pub moved: bool,
+    pub selected_object: Option<usize>,
+    /// Blackbody temperature the color of the selected light was last set from, in Kelvin.
+    pub light_temperature: f32,
+    pub post: PostProcess,
+    pub pin_snapshot: bool,
+    pub clear_snapshot: bool,
+    pub has_snapshot: bool,
+    /// Fraction of the width showing the pinned snapshot.
+    pub wipe: f32,
+    pub open_scene: bool,
+    pub reload_scene: bool,
+    /// Fade from the last image into the restarted one.
+    pub blend: bool,
+    /// Trace at a lower resolution while the camera moves, instead of waiting for it to settle.
+    pub auto_scale: bool,
+    /// Show the pixels around the cursor enlarged.
+    pub magnifier: bool,
+    /// Count the rays each object stops and list the objects by them.
+    pub object_stats: bool,
+    pub presets: Vec<Preset>,
+    /// Name the current settings are saved as a preset under.
+    pub preset_name: String,
+    /// Stop once the noise of the image drops below `noise_threshold`, before the sample count if need be.
+    pub stop_at_noise: bool,
+    pub noise_threshold: f32,
+    /// Save the image once it is finished.
+    pub auto_save: bool,
+    /// Material to put on the ball of the material preview scene, switching to it.
+    pub preview_material: Option<SharedMaterial>,
+    /// Object to take out of the scene.
+    pub remove_object: Option<SharedHittable>,
 }
 
-#[derive(Clone, PartialEq)]
+impl Default for Actions {
+    fn default() -> Self {
+        Self {
+            save_img: false,
+            png16: false,
+            paused: false,
+            cancel: false,
+            restart: false,
+            moved: false,
+            selected_object: None,
+            light_temperature: 6500.,
+            post: PostProcess::default(),
+            pin_snapshot: false,
+            clear_snapshot: false,
+            has_snapshot: false,
+            wipe: 0.5,
+            open_scene: false,
+            reload_scene: false,
+            blend: true,
+            auto_scale: true,
+            magnifier: false,
+            object_stats: false,
+            presets: Preset::load(),
+            preset_name: String::new(),
+            stop_at_noise: false,
+            noise_threshold: 0.02,
+            auto_save: false,
+            preview_material: None,
+            remove_object: None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Gui {
     /// Only show the egui window when true.
+    #[serde(skip)]
     window_open: bool,
+    #[serde(skip)]
     my_boolean: bool,
     pub scale: u32,
-    pub save_img: bool,
     pub sample_count: usize,
     pub max_depth: usize,
+    /// Bounces beyond `max_depth` for paths refracting through glass with most of their light.
+    pub glass_depth: usize,
+    #[serde(skip)]
     pre: Option<Box<Gui>>,
-    pub scene: Scene
+    pub scene: Scene,
+    pub camera: CameraSettings,
+    /// Render region as fractions of the frame, `[x0, y0, x1, y1]` from the top left corner.
+    pub region: Option<[f32; 4]>,
+    pub background: BackgroundSettings,
+    pub fog: FogSettings,
+    pub resolution: Resolution,
+    pub custom_size: [u32; 2],
+    /// Keep the aspect ratio of the image when the window is resized and letterbox it,
+    /// instead of following the window and starting over.
+    pub lock_aspect: bool,
+    /// Scene description file shown instead of the built-in `scene`.
+    pub scene_file: Option<PathBuf>,
+    pub debug_view: DebugView,
+    /// Clay or emitters only, for lighting and geometry checks.
+    pub material_override: MaterialOverride,
+    pub bias: Bias,
+    pub light_paths: LightPaths,
+    /// Bounce shown by [`LightPaths::Bounce`].
+    pub bounce: usize,
+    /// Save the background as transparent, for compositing.
+    pub transparent: bool,
+    /// Learn where the light comes from while rendering, for caustics.
+    pub guiding: bool,
+    /// Irradiance probes shown along the width of the image, none when 0.
+    pub probes: usize,
+    /// Seed the built-in scenes are laid out with, re-rolled to browse random layouts.
+    pub scene_seed: u64,
+    /// Largest side of the image textures in texels, the full images when `None`.
+    pub max_texture_size: Option<u32>,
+    /// Precision the image is accumulated in, half for large images in the browser or on phones.
+    pub precision: Precision,
+}
+
+impl Default for Gui {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What is kept from one session to the next.
+#[derive(Serialize, Deserialize)]
+pub struct Settings {
+    /// Logical size of the window.
+    pub window_size: [f64; 2],
+    pub gui: Gui,
+}
+
+impl Settings {
+    fn path() -> PathBuf {
+        std::env::var("RAY_TRACING_SETTINGS")
+            .unwrap_or_else(|_| "ray_tracing_settings.json".into())
+            .into()
+    }
+
+    /// The settings saved by the last session, if any.
+    pub fn load() -> Option<Self> {
+        if cfg!(target_arch = "wasm32") {
+            return None
+        }
+        let bytes = std::fs::read(Self::path()).ok()?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| log::warn!("Ignoring saved settings: {}", e))
+            .ok()
+    }
+
+    pub fn save(&self) {
+        if cfg!(target_arch = "wasm32") {
+            return
+        }
+        let saved = serde_json::to_vec_pretty(self)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(Self::path(), json)?));
+        if let Err(e) = saved {
+            log::error!("Failed to save settings: {}", e);
+        }
+    }
+}
+
+/// Named render settings to switch between, e.g. a quick draft and the final image.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub sample_count: usize,
+    pub max_depth: usize,
+    pub scale: u32,
+    pub resolution: Resolution,
+    pub custom_size: [u32; 2],
+    pub tonemap: Tonemap,
+}
+
+impl Preset {
+    /// The current settings of `gui` and the tonemapping of `post`, named `name`.
+    fn capture(name: String, gui: &Gui, post: &PostProcess) -> Self {
+        Self {
+            name,
+            sample_count: gui.sample_count,
+            max_depth: gui.max_depth,
+            scale: gui.scale,
+            resolution: gui.resolution,
+            custom_size: gui.custom_size,
+            tonemap: post.tonemap,
+        }
+    }
+
+    fn apply(&self, gui: &mut Gui, post: &mut PostProcess) {
+        gui.sample_count = self.sample_count;
+        gui.max_depth = self.max_depth;
+        gui.scale = self.scale;
+        gui.resolution = self.resolution;
+        gui.custom_size = self.custom_size;
+        post.tonemap = self.tonemap;
+    }
+
+    fn defaults() -> Vec<Self> {
+        vec![
+            Self {
+                name: "draft".into(),
+                sample_count: 1,
+                max_depth: 8,
+                scale: 4,
+                resolution: Resolution::Window,
+                custom_size: [1280, 720],
+                tonemap: Tonemap::Reinhard,
+            },
+            Self {
+                name: "final 4K".into(),
+                sample_count: 50,
+                max_depth: 50,
+                scale: 1,
+                resolution: Resolution::UHD,
+                custom_size: [3840, 2160],
+                tonemap: Tonemap::ACES,
+            },
+        ]
+    }
+
+    fn path() -> PathBuf {
+        std::env::var("RAY_TRACING_PRESETS")
+            .unwrap_or_else(|_| "ray_tracing_presets.json".into())
+            .into()
+    }
+
+    /// The presets saved last time, or the built-in ones.
+    pub fn load() -> Vec<Self> {
+        if cfg!(target_arch = "wasm32") {
+            return Self::defaults()
+        }
+        std::fs::read(Self::path())
+            .ok()
+            .and_then(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|e| log::warn!("Ignoring saved presets: {}", e))
+                    .ok()
+            })
+            .unwrap_or_else(Self::defaults)
+    }
+
+    pub fn save(presets: &[Self]) {
+        if cfg!(target_arch = "wasm32") {
+            return
+        }
+        let saved = serde_json::to_vec_pretty(presets)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(std::fs::write(Self::path(), json)?));
+        if let Err(e) = saved {
+            log::error!("Failed to save presets: {}", e);
+        }
+    }
+}
+
+/// Where the scene shown in the GUI comes from.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SceneSource {
+    /// A built-in scene, with the seed laying out the random ones.
+    Builtin(&'static str, u64),
+    File(PathBuf),
+}
+
+/// Render resolution, either following the window or fixed.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Resolution {
+    Window,
+    HD,
+    FullHD,
+    UHD,
+    Custom,
+}
+
+impl Resolution {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Window => "window / scale",
+            Self::HD => "720p",
+            Self::FullHD => "1080p",
+            Self::UHD => "4K",
+            Self::Custom => "custom",
+        }
+    }
+
+    pub fn size(&self, custom: [u32; 2]) -> Option<(u32, u32)> {
+        match self {
+            Self::Window => None,
+            Self::HD => Some((1280, 720)),
+            Self::FullHD => Some((1920, 1080)),
+            Self::UHD => Some((3840, 2160)),
+            Self::Custom => Some((custom[0].max(1), custom[1].max(1))),
+        }
+    }
+}
+
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BackgroundKind {
+    Scene,
+    Solid,
+    Gradient,
+    Sky,
+    Hdri,
+    SunSky,
+    Cubemap,
+}
+
+impl BackgroundKind {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Scene => "scene default",
+            Self::Solid => "solid color",
+            Self::Gradient => "gradient",
+            Self::Sky => "sky",
+            Self::Hdri => "HDRI",
+            Self::SunSky => "sun and sky",
+            Self::Cubemap => "skybox",
+        }
+    }
+}
+
+/// Background edited in the GUI, turned into a [`Background`] by the renderer.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackgroundSettings {
+    pub kind: BackgroundKind,
+    pub color: RGB,
+    pub horizon: RGB,
+    pub zenith: RGB,
+    /// Environment map file in the static directory.
+    pub hdri: String,
+    pub rotation: f32,
+    /// Where and when the sun of the sun and sky is seen from.
+    pub time_of_day: TimeOfDay,
+    /// Skybox faces in the static directory, in the order of [`CUBE_FACES`].
+    pub cubemap: [String; 6],
+}
+
+impl Default for BackgroundSettings {
+    fn default() -> Self {
+        Self {
+            kind: BackgroundKind::Scene,
+            color: [0.7, 0.8, 1.],
+            horizon: [1.; 3],
+            zenith: [0.5, 0.7, 1.],
+            hdri: "earthmap.jpg".into(),
+            rotation: 0.,
+            time_of_day: TimeOfDay::default(),
+            cubemap: ["right.jpg", "left.jpg", "top.jpg", "bottom.jpg", "front.jpg", "back.jpg"].map(String::from),
+        }
+    }
+}
+
+impl BackgroundSettings {
+    pub fn build(&self, scene_default: &Background) -> Background {
+        match self.kind {
+            BackgroundKind::Scene => scene_default.clone(),
+            BackgroundKind::Solid => Background::Solid(Color::from(self.color)),
+            BackgroundKind::Gradient => Background::Gradient {
+                horizon: Color::from(self.horizon),
+                zenith: Color::from(self.zenith),
+            },
+            BackgroundKind::Sky => Background::Sky {
+                bottom: Color::from(self.horizon),
+                top: Color::from(self.zenith),
+            },
+            BackgroundKind::Hdri => Background::hdri(&self.hdri, self.rotation),
+            BackgroundKind::SunSky => Background::time_of_day(self.time_of_day),
+            BackgroundKind::Cubemap => Background::cubemap(&self.cubemap, self.rotation),
+        }
+    }
+
+    /// Turn `background`, built from `previous`, to these settings if they differ from them in the
+    /// rotation alone, without loading and decoding its images again. Returns whether it did.
+    pub fn rotate(&self, previous: &Self, background: &mut Background) -> bool {
+        if *self != (Self { rotation: self.rotation, ..previous.clone() }) {
+            return false
+        }
+        match (self.kind, background) {
+            (BackgroundKind::Hdri, Background::Hdri { rotation, .. })
+            | (BackgroundKind::Cubemap, Background::Cubemap { rotation, .. }) => {
+                *rotation = self.rotation;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("Background")
+            .selected_text(self.kind.to_str())
+            .show_ui(ui, |ui| {
+                BackgroundKind::iter().for_each(|x| {
+                    ui.selectable_value(&mut self.kind, x, x.to_str());
+                })
+            });
+        match self.kind {
+            BackgroundKind::Scene => {}
+            BackgroundKind::Solid => {
+                ui.horizontal(|ui| {
+                    ui.label("Color");
+                    ui.color_edit_button_rgb(&mut self.color);
+                });
+            }
+            BackgroundKind::Gradient => {
+                ui.horizontal(|ui| {
+                    ui.label("Horizon");
+                    ui.color_edit_button_rgb(&mut self.horizon);
+                    ui.label("Zenith");
+                    ui.color_edit_button_rgb(&mut self.zenith);
+                });
+            }
+            BackgroundKind::Sky => {
+                ui.horizontal(|ui| {
+                    ui.label("Bottom");
+                    ui.color_edit_button_rgb(&mut self.horizon);
+                    ui.label("Top");
+                    ui.color_edit_button_rgb(&mut self.zenith);
+                });
+            }
+            BackgroundKind::Hdri => {
+                ui.horizontal(|ui| {
+                    ui.label("File");
+                    ui.text_edit_singleline(&mut self.hdri);
+                });
+                ui.add(egui::Slider::new(&mut self.rotation, 0.0..=360.0).text("Rotation"));
+            }
+            BackgroundKind::SunSky => {
+                let time = &mut self.time_of_day;
+                ui.add(egui::Slider::new(&mut time.hour, 0.0..=24.0).text("Time of day"));
+                ui.add(egui::Slider::new(&mut time.day, 1.0..=365.0).text("Day of the year"));
+                ui.add(egui::Slider::new(&mut time.latitude, -90.0..=90.0).text("Latitude"));
+            }
+            BackgroundKind::Cubemap => {
+                self.cubemap.iter_mut().zip(CUBE_FACES).for_each(|(file, face)| {
+                    ui.horizontal(|ui| {
+                        ui.label(face);
+                        ui.text_edit_singleline(file);
+                    });
+                });
+                ui.add(egui::Slider::new(&mut self.rotation, 0.0..=360.0).text("Rotation"));
+            }
+        }
+    }
+}
+
+/// Fog edited in the GUI, replacing the one of the scene while enabled.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FogSettings {
+    pub enabled: bool,
+    pub density: f32,
+    pub color: RGB,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            density: 0.01,
+            color: [1.; 3],
+        }
+    }
+}
+
+impl FogSettings {
+    pub fn build(&self, scene_default: &Option<Fog>) -> Option<Fog> {
+        if self.enabled {
+            (self.density > 0.).then(|| Fog::new(self.density, self.color))
+        } else {
+            scene_default.clone()
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.enabled, "Override the scene fog");
+        ui.add_enabled_ui(self.enabled, |ui| {
+            ui.add(egui::Slider::new(&mut self.density, 0.0..=1.0).logarithmic(true).text("Density"));
+            ui.horizontal(|ui| {
+                ui.label("Color");
+                ui.color_edit_button_rgb(&mut self.color);
+            });
+        });
+    }
 }
 
 impl Gui {
@@ -151,17 +1188,80 @@ impl Gui {
             window_open: false,
             my_boolean: false,
             scale: 10,
-            save_img: false,
             sample_count: 4,
             max_depth: 10,
+            glass_depth: DEFAULT_GLASS_DEPTH,
             pre: None,
-            scene: Scene::EARTH
-
+            scene: Scene::EARTH,
+            camera: CameraSettings::select(Scene::EARTH.to_str()),
+            region: None,
+            background: BackgroundSettings::default(),
+            fog: FogSettings::default(),
+            resolution: Resolution::Window,
+            custom_size: [crate::WIDTH, crate::HEIGHT],
+            lock_aspect: false,
+            scene_file: None,
+            debug_view: DebugView::Off,
+            material_override: MaterialOverride::Off,
+            bias: Bias::default(),
+            light_paths: LightPaths::All,
+            bounce: 1,
+            transparent: false,
+            guiding: false,
+            probes: 0,
+            scene_seed: random_seed(),
+            max_texture_size: None,
+            precision: Precision::Full,
         };
         cur.pre = Some(Box::new(cur.clone()));
         cur
     }
 
+    /// Override settings with `key=value` pairs joined by `&`, as in the query string of the web
+    /// demo: `scene=cornell&spp=16&depth=20&width=960&height=540`.
+    pub fn apply_query(&mut self, query: &str) {
+        let (mut width, mut height) = (None, None);
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let number = || value.parse::<usize>().ok().filter(|&n| n > 0);
+            let applied = match key {
+                "scene" => Scene::iter().find(|scene| scene.to_str() == value).map(|scene| {
+                    self.scene = scene;
+                    self.scene_file = None;
+                    self.camera = CameraSettings::select(scene.to_str());
+                }),
+                "spp" => number().map(|n| self.sample_count = n),
+                "seed" => value.parse().ok().map(|seed| self.scene_seed = seed),
+                "depth" => number().map(|n| self.max_depth = n),
+                "width" => number().map(|n| width = Some(n)),
+                "height" => number().map(|n| height = Some(n)),
+                _ => None,
+            };
+            if applied.is_none() {
+                log::warn!("Ignoring query parameter {}", pair);
+            }
+        }
+        if width.is_none() && height.is_none() {
+            return
+        }
+        if self.resolution != Resolution::Custom {
+            self.custom_size = [crate::WIDTH, crate::HEIGHT];
+        }
+        self.resolution = Resolution::Custom;
+        let [old_width, old_height] = self.custom_size.map(|n| n.max(1) as usize);
+        let clamp = |n: usize| n.clamp(1, MAX_CUSTOM_SIZE as usize);
+        let width = clamp(width.unwrap_or(old_width));
+        // Keep the aspect ratio unless the height is given as well
+        let height = clamp(height.unwrap_or(width.saturating_mul(old_height) / old_width));
+        self.custom_size = [width as u32, height as u32];
+    }
+
+    /// Start tracking changes of a deserialized `Gui`.
+    fn restored(mut self) -> Self {
+        self.pre = Some(Box::new(self.clone()));
+        self
+    }
+
     pub fn updated(&mut self) -> bool {
         if let Some(pre) = self.pre.take() {
             let dirty = *pre != *self;
@@ -171,35 +1271,575 @@ impl Gui {
         false
     }
 
+    pub fn scene_source(&self) -> SceneSource {
+        match &self.scene_file {
+            Some(path) => SceneSource::File(path.clone()),
+            None => SceneSource::Builtin(self.scene.to_str(), self.scene_seed),
+        }
+    }
+
+    /// Show the camera of a newly selected scene without flagging it as a user edit.
+    pub fn set_camera(&mut self, camera: CameraSettings) {
+        self.camera = camera;
+        if let Some(pre) = &mut self.pre {
+            pre.camera = camera;
+        }
+    }
+
     // pub fn update(&mut self) {
     //     self.pre.take();
     //     self.pre = Some(Box::new(self.clone()));
     // }
     /// Create the UI using egui.
-    fn ui(&mut self, ctx: &Context) {
-        egui::Window::new("df").show(ctx, |ui| {
+    fn ui(&mut self, ctx: &Context, actions: &mut Actions, stats: &RenderStats, recommended: &Recommended) {
+        egui::Window::new("df").vscroll(true).show(ctx, |ui| {
             ui.label("A shorter and more convenient way to add a label.");
-            if ui.button("Take a screenshot").clicked() {
-                self.save_img = true;
+            let save_text = if cfg!(target_arch = "wasm32") { "Download image" } else { "Take a screenshot" };
+            ui.horizontal(|ui| {
+                if ui.button(save_text).clicked() {
+                    actions.save_img = true;
+                }
+                ui.checkbox(&mut actions.png16, "16 bit PNG");
+            });
+            ui.horizontal(|ui| {
+                let pause_text = if actions.paused { "Resume" } else { "Pause" };
+                if ui.button(pause_text).clicked() {
+                    actions.paused = !actions.paused;
+                }
+                if ui.button("Cancel").clicked() {
+                    actions.cancel = true;
+                }
+            });
+            ui.add(egui::Slider::new(&mut actions.post.exposure, -5.0..=5.0).text("Exposure"));
+            egui::ComboBox::from_label("Tonemapping")
+                .selected_text(actions.post.tonemap.to_str())
+                .show_ui(ui, |ui| {
+                    Tonemap::iter().for_each(|x| {
+                        ui.selectable_value(&mut actions.post.tonemap, x, x.to_str());
+                    })
+                });
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Transfer")
+                    .selected_text(actions.post.transfer.to_str())
+                    .show_ui(ui, |ui| {
+                        Transfer::iter().for_each(|x| {
+                            ui.selectable_value(&mut actions.post.transfer, x, x.to_str());
+                        })
+                    });
+                ui.checkbox(&mut actions.post.tag_color_space, "Tag saved images");
+            });
+            ui.checkbox(&mut actions.post.dither, "Dither");
+            ui.checkbox(&mut actions.post.show_clipping, "Highlight clipped pixels");
+            ui.checkbox(&mut actions.post.denoise, "Denoise");
+            ui.add(egui::Slider::new(&mut actions.post.bloom, 0.0..=1.0).text("Bloom"));
+            ui.add(egui::Slider::new(&mut actions.post.vignette, 0.0..=1.0).text("Vignette"));
+            ui.add(egui::Slider::new(&mut actions.post.chromatic_aberration, -0.02..=0.02).text("Chromatic aberration"));
+            ui.add(egui::Slider::new(&mut actions.post.grain, 0.0..=6400.0).logarithmic(true).text("Film grain ISO"));
+            ui.checkbox(&mut actions.blend, "Blend restarts");
+            ui.checkbox(&mut actions.auto_scale, "Lower resolution while moving");
+            ui.checkbox(&mut actions.magnifier, "Magnifier");
+            ui.checkbox(&mut actions.object_stats, "Rays per object");
+            ui.horizontal(|ui| {
+                if ui.button("Pin snapshot").clicked() {
+                    actions.pin_snapshot = true;
+                }
+                if actions.has_snapshot && ui.button("Clear snapshot").clicked() {
+                    actions.clear_snapshot = true;
+                }
+            });
+            if actions.has_snapshot {
+                ui.add(egui::Slider::new(&mut actions.wipe, 0.0..=1.0).text("Wipe"));
+            }
+            ui.label(format!("SPP: {}/{}", stats.samples_done, stats.target));
+            if let Some(noise) = stats.noise {
+                ui.label(format!("Noise: {:.2}%", noise * 100.));
             }
+            ui.label(format!("Elapsed: {}", format_duration(stats.elapsed)));
+            ui.label(format!("Rays/s: {:.2}M", stats.rays_per_sec() / 1e6));
+            ui.label(format!(
+                "Memory: {:.1} MiB (BVH {:.1}, textures {:.1})",
+                stats.memory.total() as f64 / MIB,
+                stats.memory.bvh as f64 / MIB,
+                stats.memory.textures as f64 / MIB,
+            ));
+            ui.label(format!(
+                "ETA: {}",
+                stats.eta().map(format_duration).unwrap_or_else(|| "-".into())
+            ));
 
+            let scene_text = match &self.scene_file {
+                Some(path) => format!("{:?}", path.file_name().unwrap_or_default()),
+                None => format!("{:?}", self.scene.to_str()),
+            };
             egui::ComboBox::from_label("Select one scene")
-                .selected_text(format!("{:?}", self.scene.to_str()))
+                .selected_text(scene_text)
                 .show_ui(ui, |ui| {
                     Scene::iter().for_each(|x| {
-                         ui.selectable_value(
+                         if ui.selectable_value(
                             &mut self.scene,
                             x,
                             x.to_str()
-                        );
+                        ).clicked() {
+                             self.scene_file = None;
+                         }
                     }
                     )
                 }
                 );
-            ui.add(egui::Slider::new(&mut self.scale, 1..=20).text("Scale"));
+            ui.horizontal(|ui| {
+                if ui.button("Open scene…").clicked() {
+                    actions.open_scene = true;
+                }
+                if ui.button("Reload scene").clicked() {
+                    actions.reload_scene = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                let [width, height] = recommended.size;
+                ui.label(format!("Recommended: {} spp, depth {}, {}x{}", recommended.samples, recommended.max_depth, width, height));
+                if ui.button("Apply").clicked() {
+                    self.sample_count = recommended.samples;
+                    self.max_depth = recommended.max_depth;
+                    self.resolution = Resolution::Custom;
+                    self.custom_size = recommended.size;
+                }
+            });
+            if self.scene_file.is_none() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Seed: {}", self.scene_seed));
+                    if ui.button("Re-roll").clicked() {
+                        self.scene_seed = random_seed();
+                    }
+                    if ui.button("Copy seed").clicked() {
+                        ui.output().copied_text = self.scene_seed.to_string();
+                    }
+                });
+            }
+            let texture_size = |size: Option<u32>| size.map_or("Full".into(), |size| format!("{} px", size));
+            egui::ComboBox::from_label("Texture size")
+                .selected_text(texture_size(self.max_texture_size))
+                .show_ui(ui, |ui| {
+                    [None, Some(1024), Some(2048), Some(4096)].into_iter().for_each(|size| {
+                        ui.selectable_value(&mut self.max_texture_size, size, texture_size(size));
+                    })
+                });
+            egui::ComboBox::from_label("Accumulation")
+                .selected_text(format!("{:?}", self.precision))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.precision, Precision::Full, "Full");
+                    ui.selectable_value(&mut self.precision, Precision::Half, "Half");
+                });
+            ui.horizontal(|ui| {
+                let mut chosen = None;
+                egui::ComboBox::from_label("Preset")
+                    .selected_text("apply…")
+                    .show_ui(ui, |ui| {
+                        actions.presets.iter().enumerate().for_each(|(i, preset)| {
+                            if ui.selectable_label(false, preset.name.as_str()).clicked() {
+                                chosen = Some(i);
+                            }
+                        })
+                    });
+                if let Some(i) = chosen {
+                    actions.presets[i].apply(self, &mut actions.post);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut actions.preset_name);
+                let name = actions.preset_name.trim().to_string();
+                if ui.add_enabled(!name.is_empty(), egui::Button::new("Save preset")).clicked() {
+                    let preset = Preset::capture(name, self, &actions.post);
+                    // Saving under an existing name replaces that preset
+                    match actions.presets.iter_mut().find(|old| old.name == preset.name) {
+                        Some(old) => *old = preset,
+                        None => actions.presets.push(preset),
+                    }
+                    Preset::save(&actions.presets);
+                    actions.preset_name.clear();
+                }
+            });
+            egui::ComboBox::from_label("Resolution")
+                .selected_text(self.resolution.to_str())
+                .show_ui(ui, |ui| {
+                    Resolution::iter().for_each(|x| {
+                        ui.selectable_value(&mut self.resolution, x, x.to_str());
+                    })
+                });
+            match self.resolution {
+                Resolution::Window => {
+                    ui.add(egui::Slider::new(&mut self.scale, 1..=20).text("Scale"));
+                    ui.horizontal(|ui| {
+                        ui.label("On resize");
+                        ui.selectable_value(&mut self.lock_aspect, true, "Lock render aspect");
+                        ui.selectable_value(&mut self.lock_aspect, false, "Follow window");
+                    });
+                }
+                Resolution::Custom => {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut self.custom_size[0]).clamp_range(1..=MAX_CUSTOM_SIZE).suffix(" px"));
+                        ui.label("x");
+                        ui.add(egui::DragValue::new(&mut self.custom_size[1]).clamp_range(1..=MAX_CUSTOM_SIZE).suffix(" px"));
+                    });
+                }
+                _ => {}
+            }
             // ui.add(egui::DragValue::new(&mut self.scale));
-            ui.add(egui::Slider::new(&mut self.sample_count, 1..=50).text("SampleCount"));
+            ui.add(egui::Slider::new(&mut self.sample_count, 1..=10000).logarithmic(true).text("SampleCount"));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut actions.stop_at_noise, "Stop at noise");
+                ui.add_enabled(
+                    actions.stop_at_noise,
+                    egui::Slider::new(&mut actions.noise_threshold, 0.001..=0.2).logarithmic(true),
+                );
+            });
+            ui.checkbox(&mut actions.auto_save, "Save when finished");
             ui.add(egui::Slider::new(&mut self.max_depth, 1..=50).text("Max depth"));
+            ui.add(egui::Slider::new(&mut self.glass_depth, 0..=50).text("Extra glass depth"));
+            ui.horizontal(|ui| {
+                ui.label("Right drag on the image to render a region");
+                if self.region.is_some() && ui.button("Clear region").clicked() {
+                    self.region = None;
+                }
+            });
+            egui::ComboBox::from_label("Debug view")
+                .selected_text(self.debug_view.to_str())
+                .show_ui(ui, |ui| {
+                    DebugView::iter().for_each(|x| {
+                        ui.selectable_value(&mut self.debug_view, x, x.to_str());
+                    })
+                });
+            egui::ComboBox::from_label("Materials")
+                .selected_text(self.material_override.to_str())
+                .show_ui(ui, |ui| {
+                    MaterialOverride::iter().for_each(|x| {
+                        ui.selectable_value(&mut self.material_override, x, x.to_str());
+                    })
+                });
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Light paths")
+                    .selected_text(self.light_paths.to_str())
+                    .show_ui(ui, |ui| {
+                        LightPaths::iter().for_each(|x| {
+                            ui.selectable_value(&mut self.light_paths, x, x.to_str());
+                        })
+                    });
+                if self.light_paths == LightPaths::Bounce {
+                    ui.add(egui::Slider::new(&mut self.bounce, 0..=self.max_depth.saturating_sub(1)).text("Bounce"));
+                }
+            });
+            ui.checkbox(&mut self.transparent, "Transparent background");
+            ui.checkbox(&mut self.guiding, "Path guiding");
+            ui.add(egui::Slider::new(&mut self.probes, 0..=32).text("Irradiance probes"));
+            egui::CollapsingHeader::new("Ray bias").show(ui, |ui| {
+                let bias = &mut self.bias;
+                ui.add(egui::Slider::new(&mut bias.origin_ulps, 1..=4096).logarithmic(true).text("Origin ULPs"));
+                ui.add(egui::Slider::new(&mut bias.medium_ulps, 1..=65536).logarithmic(true).text("Medium ULPs"));
+                ui.add(egui::Slider::new(&mut bias.shadow, 0.0..=0.1).logarithmic(true).text("Shadow"));
+                if ui.button("Reset").clicked() {
+                    *bias = Bias::default();
+                }
+            });
+            egui::CollapsingHeader::new("Background").show(ui, |ui| self.background.ui(ui));
+            egui::CollapsingHeader::new("Fog").show(ui, |ui| self.fog.ui(ui));
+            egui::CollapsingHeader::new("Camera").show(ui, |ui| {
+                let camera = &mut self.camera;
+                egui::ComboBox::from_label("Projection")
+                    .selected_text(camera.projection.to_str())
+                    .show_ui(ui, |ui| {
+                        Projection::iter().for_each(|x| {
+                            ui.selectable_value(&mut camera.projection, x, x.to_str());
+                        })
+                    });
+                camera.vfov = camera.vfov.min(camera.projection.max_vfov());
+                ui.add(egui::Slider::new(&mut camera.vfov, 1.0..=camera.projection.max_vfov()).text("Vertical FOV"));
+                ui.add(egui::Slider::new(&mut camera.aperture, 0.0..=2.0).text("Aperture"));
+                ui.add(egui::Slider::new(&mut camera.focus_dist, 0.1..=2000.0)
+                    .logarithmic(true)
+                    .text("Focus distance"));
+                xyz_row(ui, "Look from", &mut camera.lookfrom);
+                xyz_row(ui, "Look at", &mut camera.lookat);
+                ui.checkbox(&mut camera.motion_blur, "Motion blur");
+                ui.add_enabled_ui(camera.motion_blur, |ui| {
+                    let [open, close] = &mut camera.shutter;
+                    ui.add(egui::Slider::new(open, 0.0..=1.0).text("Shutter open"));
+                    ui.add(egui::Slider::new(close, 0.0..=1.0).text("Shutter close"));
+                    *close = close.max(*open);
+                    egui::ComboBox::from_label("Shutter curve")
+                        .selected_text(camera.shutter_curve.to_str())
+                        .show_ui(ui, |ui| {
+                            ShutterCurve::iter().for_each(|x| {
+                                ui.selectable_value(&mut camera.shutter_curve, x, x.to_str());
+                            })
+                        });
+                    ui.add(egui::Slider::new(&mut camera.rolling_shutter, 0.0..=1.0).text("Rolling shutter"));
+                });
+            });
         });
     }
 }
+
+/// Lists the scene objects and edits the material of the selected one in place, or removes it.
+fn material_inspector(ctx: &Context, actions: &mut Actions, scene: &World) {
+    egui::Window::new("Materials").default_open(false).show(ctx, |ui| {
+        let selected = actions.selected_object.filter(|&i| i < scene.objects.len());
+        egui::ComboBox::from_label("Object")
+            .selected_text(selected.map(|i| scene.object_name(i)).unwrap_or_default())
+            .show_ui(ui, |ui| {
+                (0..scene.objects.len()).for_each(|i| {
+                    ui.selectable_value(&mut actions.selected_object, Some(i), scene.object_name(i));
+                })
+            });
+        let material = selected.and_then(|i| scene.objects[i].read().unwrap().material());
+        if let Some(material) = material {
+            let old = material.read().unwrap().params();
+            let mut params = old;
+            if let Some(albedo) = &mut params.albedo {
+                ui.horizontal(|ui| {
+                    ui.label("Albedo");
+                    ui.color_edit_button_rgb(albedo);
+                });
+            }
+            if let Some(fuzz) = &mut params.fuzz {
+                ui.add(egui::Slider::new(fuzz, 0.0..=1.0).text("Fuzz"));
+            }
+            if let Some(ior) = &mut params.ior {
+                ui.add(egui::Slider::new(ior, 1.0..=3.0).text("IOR"));
+            }
+            if let Some(emission) = &mut params.emission {
+                ui.add(egui::Slider::new(emission, 0.0..=50.0).text("Emission strength"));
+                if let Some(albedo) = &mut params.albedo {
+                    let temperature = egui::Slider::new(&mut actions.light_temperature, 1000.0..=12000.0).text("Temperature (K)");
+                    if ui.add(temperature).changed() {
+                        *albedo = blackbody(actions.light_temperature);
+                    }
+                }
+            }
+            if let Some(visible) = &mut params.visible {
+                ui.checkbox(visible, "Visible to camera and in reflections");
+            }
+            if let Some(shadow) = &mut params.shadow {
+                ui.add(egui::Slider::new(shadow, 0.0..=1.0).text("Shadow strength"));
+            }
+            if params != old {
+                material.write().unwrap().set_params(&params);
+                actions.restart = true;
+            }
+            if ui.button("Preview on ball").clicked() {
+                actions.preview_material = Some(material);
+            }
+        } else if selected.is_some() {
+            ui.label("No editable material");
+        }
+        if let Some(i) = selected {
+            if ui.button("Remove from scene").clicked() {
+                actions.remove_object = Some(scene.objects[i].clone());
+            }
+        }
+    });
+}
+
+/// Strength and size of every emissive object in the scene.
+fn light_controls(ctx: &Context, actions: &mut Actions, scene: &World) {
+    egui::Window::new("Lights").default_open(false).show(ctx, |ui| {
+        scene.objects.iter().enumerate().for_each(|(i, obj)| {
+            let material = obj.read().unwrap().material();
+            let material = match material.filter(|m| m.read().unwrap().params().emission.is_some()) {
+                Some(material) => material,
+                None => return,
+            };
+            ui.label(scene.object_name(i));
+            let old = material.read().unwrap().params();
+            let mut params = old;
+            if let Some(emission) = &mut params.emission {
+                ui.add(egui::Slider::new(emission, 0.0..=50.0).text("Strength"));
+            }
+            if let Some(visible) = &mut params.visible {
+                ui.checkbox(visible, "Visible to camera and in reflections");
+            }
+            if params != old {
+                material.write().unwrap().set_params(&params);
+                actions.restart = true;
+            }
+            let old = obj.read().unwrap().shape_params();
+            let mut shape = old;
+            if let Some(radius) = &mut shape.radius {
+                ui.add(egui::DragValue::new(radius).speed(0.1).prefix("radius: "));
+            }
+            if let Some(corners) = &mut shape.corners {
+                corners.iter_mut().zip(["from", "to"]).for_each(|(corner, label)| {
+                    ui.horizontal(|ui| {
+                        ui.label(label);
+                        corner.iter_mut().for_each(|c| {
+                            ui.add(egui::DragValue::new(c).speed(1.));
+                        });
+                    });
+                });
+            }
+            if shape != old {
+                obj.write().unwrap().set_shape_params(&shape);
+                actions.moved = true;
+                actions.restart = true;
+            }
+            ui.separator();
+        });
+    });
+}
+
+const HISTOGRAM_BINS: usize = 64;
+
+fn histogram_window(ctx: &Context, histogram: &[u32]) {
+    egui::Window::new("Histogram").default_open(false).show(ctx, |ui| {
+        let bars = histogram
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| Bar::new(i as f64, count as f64).width(1.))
+            .collect();
+        Plot::new("luminance")
+            .height(120.)
+            .show_axes([false, true])
+            .allow_drag(false)
+            .allow_zoom(false)
+            .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars)));
+    });
+}
+
+/// The objects of the scene by the rays they stopped, to find the ones the render time goes to.
+fn object_stats_window(ctx: &Context, stats: &[(String, ObjectRays)]) {
+    egui::Window::new("Rays per object").vscroll(true).show(ctx, |ui| {
+        let total = stats.iter().map(|(_, count)| count.total()).sum::<usize>().max(1);
+        egui::Grid::new("object_rays").striped(true).show(ui, |ui| {
+            ui.label("Object");
+            ui.label("Hits");
+            ui.label("Occlusions");
+            ui.label("Share");
+            ui.end_row();
+            for (name, count) in stats {
+                ui.label(name);
+                ui.label(count.hits.to_string());
+                ui.label(count.occlusions.to_string());
+                ui.label(format!("{:.1}%", count.total() as f32 * 100. / total as f32));
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn probe_window(ctx: &Context, probe: &PixelProbe) {
+    egui::Window::new("Pixel probe").show(ctx, |ui| {
+        let [r, g, b] = probe.radiance.into();
+        let [r8, g8, b8] = probe.rgb;
+        ui.label(format!("Pixel: ({}, {})", probe.x, probe.y));
+        ui.label(format!("Radiance: {:.4} {:.4} {:.4}", r, g, b));
+        ui.label(format!("RGB: {} {} {}", r8, g8, b8));
+        ui.label(format!("Depth: {}", probe.depth.map(|t| format!("{:.3}", t)).unwrap_or_else(|| "-".into())));
+        ui.label(format!("Object: {}", probe.label.as_deref().unwrap_or("background")));
+    });
+}
+
+/// The scene graph with a checkbox per object to leave it out of the render.
+fn outliner_window(ctx: &Context, actions: &mut Actions, scene: &World) {
+    egui::Window::new("Outliner").default_open(false).vscroll(true).show(ctx, |ui| {
+        scene.objects.iter().enumerate().for_each(|(i, obj)| {
+            let mut visible = !scene.is_hidden(obj);
+            if outliner_node(ui, actions, obj, &scene.object_name(i), &mut visible) {
+                scene.set_hidden(obj, !visible);
+                actions.restart = true;
+            }
+        });
+    });
+}
+
+/// A row with a visibility checkbox for `obj`, and its children below it once expanded.
+/// Returns whether the checkbox of `obj` was toggled.
+fn outliner_node(ui: &mut egui::Ui, actions: &mut Actions, obj: &SharedHittable, name: &str, visible: &mut bool) -> bool {
+    let children = obj.read().unwrap().children();
+    if children.is_empty() {
+        return ui.horizontal(|ui| {
+            let toggled = ui.checkbox(visible, name).changed();
+            position_editor(ui, actions, obj);
+            toggled
+        }).inner
+    }
+    let mut toggled = false;
+    let id = ui.make_persistent_id(shared_id(obj));
+    egui::collapsing_header::CollapsingState::load_with_default_open(ui.ctx(), id, false)
+        .show_header(ui, |ui| toggled = ui.checkbox(visible, name).changed())
+        .body(|ui| {
+            children.iter().take(OUTLINER_MAX_CHILDREN).enumerate().for_each(|(i, child)| {
+                let name = child.read().unwrap().get_label().cloned().unwrap_or_else(|| format!("Part {}", i));
+                let mut child_visible = !obj.read().unwrap().is_child_hidden(child);
+                if outliner_node(ui, actions, child, &name, &mut child_visible) {
+                    obj.write().unwrap().set_child_hidden(child, !child_visible);
+                    actions.restart = true;
+                }
+            });
+            if children.len() > OUTLINER_MAX_CHILDREN {
+                ui.label(format!("and {} more", children.len() - OUTLINER_MAX_CHILDREN));
+            }
+        });
+    toggled
+}
+
+/// Drag values moving `obj`, if it can be moved.
+fn position_editor(ui: &mut egui::Ui, actions: &mut Actions, obj: &SharedHittable) {
+    let old = obj.read().unwrap().shape_params();
+    let mut shape = old;
+    if let Some(center) = &mut shape.center {
+        center.iter_mut().for_each(|c| {
+            ui.add(egui::DragValue::new(c).speed(0.1));
+        });
+    }
+    if shape != old {
+        obj.write().unwrap().set_shape_params(&shape);
+        actions.moved = true;
+        actions.restart = true;
+    }
+}
+
+fn magnifier_window(ctx: &Context, magnifier: &Magnifier) {
+    egui::Window::new("Magnifier").resizable(false).show(ctx, |ui| {
+        let (x, y) = magnifier.center;
+        ui.label(format!("Around ({}, {})", x, y));
+        let side = Magnifier::SIDE as f32 * Magnifier::ZOOM;
+        let (response, painter) = ui.allocate_painter(egui::vec2(side, side), egui::Sense::hover());
+        let origin = response.rect.min;
+        let cell = |i: usize| egui::Rect::from_min_size(
+            origin + egui::vec2((i % Magnifier::SIDE) as f32, (i / Magnifier::SIDE) as f32) * Magnifier::ZOOM,
+            egui::Vec2::splat(Magnifier::ZOOM),
+        );
+        magnifier.pixels.iter().enumerate().for_each(|(i, pixel)| {
+            let color = pixel.map_or(egui::Color32::TRANSPARENT, |([r, g, b, _], _)| egui::Color32::from_rgb(r, g, b));
+            painter.rect_filled(cell(i), 0., color);
+        });
+        let center = Magnifier::SIDE * Magnifier::RADIUS + Magnifier::RADIUS;
+        painter.rect_stroke(cell(center), 0., egui::Stroke::new(1., egui::Color32::WHITE));
+        // The hovered pixel, else the one in the middle
+        let hovered = response.hover_pos()
+            .map(|pos| ((pos - origin) / Magnifier::ZOOM).floor())
+            .filter(|cell| (0. ..Magnifier::SIDE as f32).contains(&cell.x) && (0. ..Magnifier::SIDE as f32).contains(&cell.y))
+            .map(|cell| cell.y as usize * Magnifier::SIDE + cell.x as usize);
+        let i = hovered.unwrap_or(center);
+        let (dx, dy) = ((i % Magnifier::SIDE) as isize, (i / Magnifier::SIDE) as isize);
+        let radius = Magnifier::RADIUS as isize;
+        ui.label(format!("Pixel: ({}, {})", x as isize + dx - radius, y as isize + dy - radius));
+        match magnifier.pixels[i] {
+            Some(([r8, g8, b8, _], radiance)) => {
+                let [r, g, b] = radiance.into();
+                ui.label(format!("Radiance: {:.4} {:.4} {:.4}", r, g, b));
+                ui.label(format!("RGB: {} {} {}", r8, g8, b8));
+            }
+            None => {
+                ui.label("Outside the frame");
+            }
+        }
+    });
+}
+
+fn xyz_row(ui: &mut egui::Ui, label: &str, xyz: &mut [f32; 3]) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        xyz.iter_mut().for_each(|c| {
+            ui.add(egui::DragValue::new(c).speed(0.1));
+        });
+    });
+}
@@ -0,0 +1,133 @@
+//! Path guiding: a grid of directional histograms learning where the light at every part of the
+//! scene comes from as the image is rendered, to draw diffuse scatters towards it. Paths that only
+//! reach a light through glass, such as the caustic under a glass ball, are found far more often
+//! than by the BSDF or light sampling alone.
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use na::{Point3, UnitVector3, Vector3};
+use crate::camera::Camera;
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::pdf::PDF;
+use crate::rand_gen::get_rand;
+use crate::scene::Scene;
+
+/// Cells of the hash grid, the grid itself being unbounded.
+const CELLS: usize = 4096;
+/// Cells along the part of the scene the camera sees.
+const RESOLUTION: f32 = 16.;
+/// Direction bins per cell, equal in solid angle: bands of the same height in `z` split by `φ`.
+const BANDS: usize = 8;
+const SECTORS: usize = 16;
+const BINS: usize = BANDS * SECTORS;
+/// Camera rays along each side of the image that size the cells.
+const PROBES: usize = 32;
+
+/// The learned light of a scene, looked up by position in a hash grid of cells.
+pub struct Guide {
+    cell_size: f32,
+    /// Radiance arriving from each bin of every cell, as `f32` bits, summed over the samples so far.
+    learned: Vec<AtomicU32>,
+    /// Running sums of the learned bins of every cell ending at 1, up to the last pass.
+    /// `None` for the cells no light was recorded in yet.
+    sampling: Arc<Vec<Option<[f32; BINS]>>>,
+}
+
+impl Guide {
+    /// An untrained guide for `scene`, with cells sized after the surfaces `camera` sees.
+    pub fn new(scene: &Scene, camera: &Camera) -> Self {
+        let world = scene.world.read().unwrap();
+        let hits: Vec<_> = (0..PROBES * PROBES).filter_map(|i| {
+            let [s, t] = [i % PROBES, i / PROBES].map(|c| (c as f32 + 0.5) / PROBES as f32);
+            world.hit(&camera.get_ray(s, t), 0., f32::INFINITY).map(|hit| hit.point)
+        }).collect();
+        let seen = hits.iter().fold(None, |bbox, &point| {
+            AxisAlignedBoundingBox::surrounding_box(bbox, Some(AxisAlignedBoundingBox::new(point, point)))
+        });
+        let extent = seen.map_or(0., |bbox| (bbox.maximum - bbox.minimum).norm());
+        let extent = if extent.is_finite() && extent > 0. { extent } else { scene.extent };
+        Self {
+            cell_size: extent / RESOLUTION,
+            learned: (0..CELLS * BINS).map(|_| AtomicU32::new(0)).collect(),
+            sampling: Arc::new(vec![None; CELLS]),
+        }
+    }
+
+    fn cell(&self, point: Point3<f32>) -> usize {
+        let [x, y, z] = [0, 1, 2].map(|axis| (point[axis] / self.cell_size).floor() as i64 as u64);
+        let hash = x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663) ^ z.wrapping_mul(83492791);
+        (hash % CELLS as u64) as usize
+    }
+
+    /// Learn that `radiance`, over the density the direction was drawn with, arrived at `point` along `direction`.
+    pub fn record(&self, point: Point3<f32>, direction: UnitVector3<f32>, radiance: f32) {
+        if !(radiance.is_finite() && radiance > 0.) {
+            return
+        }
+        let bin = &self.learned[self.cell(point) * BINS + bin(direction)];
+        let _ = bin.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f32::from_bits(bits) + radiance).to_bits())
+        });
+    }
+
+    /// Draw from what was learned so far from now on, after a pass.
+    pub fn update(&mut self) {
+        let sampling = self.learned.chunks_exact(BINS).map(|cell| {
+            let mut cdf = [0.; BINS];
+            let mut sum = 0.;
+            for (running, bin) in cdf.iter_mut().zip(cell) {
+                sum += f32::from_bits(bin.load(Ordering::Relaxed));
+                *running = sum;
+            }
+            (sum > 0.).then(|| cdf.map(|running| running / sum))
+        }).collect();
+        self.sampling = Arc::new(sampling);
+    }
+
+    /// Directions from `point` following the light learned around it, if any was.
+    pub fn pdf(&self, point: Point3<f32>) -> Option<GuidePDF> {
+        let cell = self.cell(point);
+        self.sampling[cell].is_some().then(|| GuidePDF { sampling: self.sampling.clone(), cell })
+    }
+}
+
+/// The bin of `direction`.
+fn bin(direction: UnitVector3<f32>) -> usize {
+    let band = (((direction.z + 1.) / 2. * BANDS as f32) as usize).min(BANDS - 1);
+    let phi = direction.y.atan2(direction.x) + PI;
+    let sector = ((phi / (2. * PI) * SECTORS as f32) as usize).min(SECTORS - 1);
+    band * SECTORS + sector
+}
+
+/// Directions drawn bin by bin in proportion to the light learned in a cell.
+pub struct GuidePDF {
+    sampling: Arc<Vec<Option<[f32; BINS]>>>,
+    cell: usize,
+}
+
+impl GuidePDF {
+    fn cdf(&self) -> &[f32; BINS] {
+        self.sampling[self.cell].as_ref().expect("only built for trained cells")
+    }
+}
+
+impl PDF for GuidePDF {
+    fn value(&self, direction: UnitVector3<f32>) -> f32 {
+        let cdf = self.cdf();
+        let bin = bin(direction);
+        let probability = cdf[bin] - if bin > 0 { cdf[bin - 1] } else { 0. };
+        probability * BINS as f32 / (4. * PI)
+    }
+
+    fn generate(&self) -> UnitVector3<f32> {
+        let cdf = self.cdf();
+        let u = get_rand();
+        let bin = cdf.partition_point(|&running| running <= u).min(BINS - 1);
+        let (band, sector) = (bin / SECTORS, bin % SECTORS);
+        let z = ((band as f32 + get_rand()) / BANDS as f32) * 2. - 1.;
+        let phi = (sector as f32 + get_rand()) / SECTORS as f32 * 2. * PI - PI;
+        let r = (1. - z * z).max(0.).sqrt();
+        UnitVector3::new_normalize(Vector3::new(r * phi.cos(), r * phi.sin(), z))
+    }
+}
@@ -0,0 +1,71 @@
+//! IES LM-63 photometric profiles, the angular light distribution published for real fixtures.
+use crate::error::{Error, Result};
+
+/// Relative intensity of a light by the angle to its axis, averaged around the axis.
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    /// Vertical angles in degrees, 0 along the axis of the light, ascending.
+    angles: Vec<f32>,
+    /// Intensity at every angle, scaled so the brightest direction is 1.
+    intensities: Vec<f32>,
+}
+
+impl IesProfile {
+    /// Parse the text of an `.ies` file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::Ies(reason.into());
+        let mut lines = text.lines();
+        let tilt = lines
+            .by_ref()
+            .find(|line| line.trim_start().starts_with("TILT="))
+            .ok_or_else(|| invalid("no TILT line"))?;
+        let mut numbers = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|word| !word.is_empty())
+            .map(|word| word.parse::<f32>().map_err(|_| invalid("not a number")));
+        let mut next = || numbers.next().unwrap_or_else(|| Err(invalid("too short")));
+        if tilt.trim() == "TILT=INCLUDE" {
+            // Lamp orientation, a tilt angle count with as many angles and factors
+            next()?;
+            let tilts = next()? as usize;
+            (0..2 * tilts).try_for_each(|_| next().map(|_| ()))?;
+        }
+        // Lamps, lumens per lamp, multiplier
+        (0..3).try_for_each(|_| next().map(|_| ()))?;
+        let (vertical, horizontal) = (next()? as usize, next()? as usize);
+        if vertical == 0 || horizontal == 0 {
+            return Err(invalid("no angles"))
+        }
+        // Photometric type, units, width, length, height, ballast factor, future use, input watts
+        (0..8).try_for_each(|_| next().map(|_| ()))?;
+        let angles = (0..vertical).map(|_| next()).collect::<Result<Vec<_>>>()?;
+        (0..horizontal).try_for_each(|_| next().map(|_| ()))?;
+        let mut intensities = vec![0.; vertical];
+        for _ in 0..horizontal {
+            for intensity in intensities.iter_mut() {
+                *intensity += next()? / horizontal as f32;
+            }
+        }
+        let max = intensities.iter().cloned().fold(0., f32::max);
+        if max <= 0. {
+            return Err(invalid("no light"))
+        }
+        intensities.iter_mut().for_each(|intensity| *intensity /= max);
+        Ok(Self { angles, intensities })
+    }
+
+    /// Relative intensity at `cos_theta` from the axis, interpolated between the measured angles.
+    pub fn intensity(&self, cos_theta: f32) -> f32 {
+        let angle = cos_theta.clamp(-1., 1.).acos().to_degrees();
+        let i = self.angles.partition_point(|&a| a <= angle);
+        if i == 0 {
+            return self.intensities[0]
+        }
+        if i == self.angles.len() {
+            // Beyond the last angle measured, e.g. above a downlight
+            return if angle - self.angles[i - 1] < f32::EPSILON { self.intensities[i - 1] } else { 0. }
+        }
+        let t = (angle - self.angles[i - 1]) / (self.angles[i] - self.angles[i - 1]);
+        self.intensities[i - 1] + t * (self.intensities[i] - self.intensities[i - 1])
+    }
+}
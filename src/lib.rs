@@ -1,3 +1,27 @@
+//! A path tracer following the *Ray Tracing in One Weekend* series.
+//!
+//! Besides the GUI ([`run`], feature `window`) and the command line renderer (`cli::image_mode`,
+//! feature `cli`), the crate can be used as a library: build a [`Scene`] from the
+//! [`geo`] shapes and [`material`]s, look at it with a [`Camera`] and let a [`Renderer`] fill an RGBA8 frame.
+//!
+//! ```no_run
+//! use ray_tracing::{Camera, CameraSettings, Renderer, Scene, SharedHittable};
+//! use ray_tracing::geo::Sphere;
+//! use ray_tracing::material::{DiffuseLight, Lambertian};
+//!
+//! let (width, height) = (400, 225);
+//! let world: Vec<SharedHittable> = vec![
+//!     Sphere::new([0., -1000., 0.], 1000., Lambertian::from_color([0.5; 3])),
+//!     Sphere::new([0., 2., 0.], 2., Lambertian::from_color([0.8, 0.3, 0.3])),
+//! ];
+//! let lights: Vec<SharedHittable> = vec![Sphere::new([0., 7., 0.], 2., DiffuseLight::from_color([4.; 3]))];
+//! let scene = Scene::new(lights, world, [0.; 3].into(), "example");
+//! let camera = Camera::from_settings(&CameraSettings::select(""), width as f32 / height as f32);
+//! let mut renderer = Renderer::new(width, height, scene, camera);
+//! renderer.set_samples(16);
+//! let mut frame = vec![0; (width * height * 4) as usize];
+//! renderer.draw(&mut frame);
+//! ```
 use cfg_if::cfg_if;
 
 cfg_if! {
@@ -5,44 +29,87 @@ cfg_if! {
 use std::rc::Rc;
 use pixels::{Pixels, SurfaceTexture};
 use winit::dpi::{LogicalSize, PhysicalSize};
-use winit::event::{Event, VirtualKeyCode};
+use winit::event::{Event, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 mod gui;
-use gui::Framework;
+use gui::{Framework, Settings};
 mod winit_egui;
-use crate::renderer::Renderer;
-use crate::camera::Camera;
     }
 }
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-use crate::ray::{Hittable, Ray};
+pub use crate::ray::{HitRecord, Hittable, HittableList, Ray};
+pub use crate::camera::{Camera, CameraSettings};
+pub use crate::renderer::Renderer;
+pub use crate::scene::{select_scene, Scene};
+pub use crate::scene_file::SceneFile;
+pub use crate::types::{Color, Shared, SharedHittable, SharedMaterial, SharedTexture};
 
-mod aabb;
-mod camera;
+pub mod aabb;
+pub mod background;
+pub mod camera;
+mod accumulation;
+mod error;
+pub use error::{Error, Result};
 #[cfg(feature = "cli")]
 pub mod cli;
-mod geo;
-mod material;
+#[cfg(feature = "cli")]
+mod preview;
+#[cfg(feature = "cli")]
+mod daemon;
+pub mod geo;
+mod guiding;
+mod probes;
+pub mod ies;
+pub mod material;
 mod rand_gen;
-mod ray;
-mod renderer;
-mod scene;
-mod texture;
-mod types;
+mod sampler;
+pub mod ray;
+pub mod renderer;
+pub mod scene;
+pub mod scene_file;
+pub mod texture;
+pub mod types;
 mod perlin;
-mod resource;
+pub mod resource;
 mod onb;
-mod pdf;
+pub mod mesh;
+pub mod memory;
+pub mod output;
+pub mod pdf;
+pub mod plugin;
+#[cfg(feature = "enum-dispatch")]
+pub mod primitive;
+pub mod post;
+pub mod diff;
+#[cfg(test)]
+mod fuzz;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(all(target_arch = "wasm32", feature = "web"))]
+pub mod web_api;
+#[cfg(all(target_arch = "wasm32", feature = "window"))]
+mod web_store;
+#[cfg(all(target_arch = "wasm32", feature = "web-threads"))]
+mod web_threads;
+#[cfg(all(target_arch = "wasm32", feature = "web-threads"))]
+pub use web_threads::init_thread_pool;
 
 extern crate nalgebra as na;
 const WIDTH: u32 = 1920;
 const HEIGHT: u32 = 1080;
+/// How often the render in the browser is saved to be resumed after a reload.
+#[cfg(all(target_arch = "wasm32", feature = "window"))]
+const CHECKPOINT_INTERVAL: instant::Duration = instant::Duration::from_secs(30);
 
-#[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
+// Every worker of the thread pool would run the start function as well,
+// so with threads JavaScript calls `run` itself once the pool is up.
+// Pages embedding `web_api::RtRenderer` do not want the demo at all.
+#[cfg_attr(all(target_arch = "wasm32", not(any(feature = "web-threads", feature = "embed"))), wasm_bindgen(start))]
+#[cfg_attr(all(target_arch = "wasm32", any(feature = "web-threads", feature = "embed")), wasm_bindgen)]
 #[cfg(feature = "window")]
 pub async fn run() {
     #[cfg(target_arch = "wasm32")]
@@ -52,7 +119,12 @@ pub async fn run() {
     }
 
     let event_loop = EventLoop::new();
-    let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
+    let settings = Settings::load();
+    let size = settings
+        .as_ref()
+        .map_or(LogicalSize::new(WIDTH as f64, HEIGHT as f64), |settings| {
+            LogicalSize::new(settings.window_size[0], settings.window_size[1])
+        });
     let window = WindowBuilder::new()
         .with_inner_size(size)
         .with_title("ray_tracing")
@@ -60,6 +132,10 @@ pub async fn run() {
         .expect("WindowBuilder error");
     let window = Rc::new(window);
 
+    #[cfg(target_arch = "wasm32")]
+    let dropped = listen_for_drops(&window.canvas());
+    #[cfg(target_arch = "wasm32")]
+    let touches = listen_for_touches(&window.canvas());
     #[cfg(target_arch = "wasm32")]
     {
         use wasm_bindgen::JsCast;
@@ -111,12 +187,39 @@ pub async fn run() {
             .await
             .expect("Pixels error")
     };
-    let mut framework = Framework::new(&window, &pixels);
+    #[allow(unused_mut)]
+    let mut gui = settings.map(|settings| settings.gui);
+    // Demo configurations shared as links, e.g. `?scene=cornell&spp=16`
+    #[cfg(target_arch = "wasm32")]
+    if let Some(query) = web_sys::window()
+        .and_then(|win| win.location().search().ok())
+        .filter(|query| query.len() > 1)
+    {
+        let mut from_url = gui.unwrap_or_default();
+        from_url.apply_query(query.trim_start_matches('?'));
+        gui = Some(from_url);
+    }
+    // Carry on with the render of the last visit, unless the link asks for another one
+    #[cfg(target_arch = "wasm32")]
+    let saved_render = {
+        let saved = Rc::new(std::cell::RefCell::new(None));
+        if gui.is_none() {
+            let slot = Rc::clone(&saved);
+            wasm_bindgen_futures::spawn_local(async move { *slot.borrow_mut() = web_store::load().await });
+        }
+        saved
+    };
+    #[cfg(target_arch = "wasm32")]
+    let mut last_checkpoint = (instant::Instant::now(), 0);
+    let mut framework = Framework::new(&window, &pixels, gui);
 
+    // Fetch the textures of the first scene instead of showing it without them
+    #[cfg(target_arch = "wasm32")]
+    resource::preload(&["earthmap.jpg"]).await;
     use crate::scene::select_scene;
-    let mut renderer = Renderer::new(WIDTH, HEIGHT, select_scene("random"),
+    let mut renderer = Renderer::new(WIDTH, HEIGHT, select_scene("random", None),
                                      Camera::select_camera(WIDTH as f32 / HEIGHT as f32, "random"));
-    renderer.update_from_gui(&framework.gui, &mut pixels);
+    renderer.update_from_gui(&mut framework.gui, &mut pixels);
     let mut input = WinitInputHelper::new();
     // let mut last = instant::Instant::now();
     event_loop.run(move |event, _, control_flow| {
@@ -132,7 +235,39 @@ pub async fn run() {
                 framework.scale_factor = scale_factor as f32;
             }
 
+            framework.handle_shortcuts(&input);
+            if resource::take_fetched() {
+                framework.actions.reload_scene = true;
+            }
+            #[cfg(target_arch = "wasm32")]
+            dropped.borrow_mut().drain(..).for_each(|(name, bytes)| framework.open_dropped(&name, bytes));
+            #[cfg(target_arch = "wasm32")]
+            touches.borrow_mut().drain(..).for_each(|(id, phase, position)| framework.handle_touch(id, phase, position));
             framework.save_img(&renderer, &mut pixels);
+            framework.snapshot(&mut renderer, &mut pixels);
+            framework.control(&mut renderer);
+            framework.drag_region(&input, &pixels, &renderer);
+            // Probe the pixel under the cursor while Ctrl is held
+            framework.probe = if input.held_control() {
+                input.mouse()
+                    .and_then(|pos| pixels.window_pos_to_pixel(pos).ok())
+                    .and_then(|(x, y)| renderer.probe(x, y))
+            } else {
+                None
+            };
+            let cursor = input.mouse().and_then(|pos| pixels.window_pos_to_pixel(pos).ok());
+            framework.magnify(cursor, &renderer, pixels.get_frame());
+            // Refine the region or the part of the image under the cursor first
+            renderer.focus = match renderer.region {
+                Some(region) => Some(((region.x0 + region.x1) / 2, (region.y0 + region.y1) / 2)),
+                None => cursor.filter(|_| !framework.wants_pointer()).map(|(x, y)| (x as u32, y as u32)),
+            };
+            // Pick the object under the cursor
+            if input.mouse_pressed(0) && !framework.wants_pointer() {
+                if let Some((x, y)) = input.mouse().and_then(|pos| pixels.window_pos_to_pixel(pos).ok()) {
+                    framework.actions.selected_object = renderer.pick(x, y);
+                }
+            }
             // Resize the window
             if let Some(PhysicalSize { width, height }) = input.window_resized() {
                 renderer.resize(width, height, &mut pixels);
@@ -148,6 +283,18 @@ pub async fn run() {
         }
         match event {
             Event::WindowEvent { event, .. } => {
+                match &event {
+                    WindowEvent::DroppedFile(path) => match std::fs::read(path) {
+                        Ok(bytes) => framework.open_dropped(&path.to_string_lossy(), bytes),
+                        Err(e) => log::error!("Failed to read {:?}: {}", path, e),
+                    },
+                    WindowEvent::Touch(touch) => framework.handle_touch(
+                        touch.id,
+                        touch.phase,
+                        [touch.location.x as f32, touch.location.y as f32],
+                    ),
+                    _ => {}
+                }
                 // Update egui inputs
                 framework.handle_event(&event);
             }
@@ -155,11 +302,35 @@ pub async fn run() {
             Event::RedrawRequested(_) => {
                 // let now = instant::Instant::now();
                 // let dt = now - last;
-                renderer.draw(pixels.get_frame());
+                if renderer.draw_progressive(pixels.get_frame()) {
+                    framework.update_histogram(pixels.get_frame());
+                    framework.composite_snapshot(&renderer, pixels.get_frame());
+                }
+                #[cfg(target_arch = "wasm32")]
+                {
+                    if let Some((gui, render)) = saved_render.borrow_mut().take() {
+                        framework.restore_gui(gui);
+                        renderer.update_from_gui(&mut framework.gui, &mut pixels);
+                        framework.gui.updated();
+                        if renderer.resume(&render) {
+                            renderer.write_frame(pixels.get_frame());
+                            log::info!("Resumed the last render at {} spp", renderer.samples_done);
+                        } else {
+                            renderer.dirty = true;
+                        }
+                    }
+                    let (at, samples) = last_checkpoint;
+                    if renderer.samples_done != samples && at.elapsed() >= CHECKPOINT_INTERVAL {
+                        web_store::save(&framework.gui, renderer.checkpoint());
+                        last_checkpoint = (instant::Instant::now(), renderer.samples_done);
+                    }
+                }
                 renderer.dirty = framework.gui.updated();
+                framework.follow_camera(&mut renderer, &mut pixels);
 
                 // Prepare egui
-                framework.prepare(&window);
+                framework.stats = renderer.stats();
+                framework.prepare(&window, &renderer);
                 let render_result = pixels.render_with(|encoder, render_target, context| {
                     // Render the world texture
                     context.scaling_renderer.render(encoder, render_target);
@@ -169,7 +340,7 @@ pub async fn run() {
                 });
                 // Render everything together
                 if renderer.dirty {
-                    renderer.update_from_gui(&framework.gui, &mut pixels);
+                    renderer.update_from_gui(&mut framework.gui, &mut pixels);
                 }
                 // Basic error handling
                 if render_result
@@ -179,7 +350,71 @@ pub async fn run() {
                     *control_flow = ControlFlow::Exit;
                 }
             }
+            Event::LoopDestroyed => framework.save_settings(&window),
             _ => (),
         }
     });
 }
+
+/// Files dropped onto the canvas, read asynchronously and picked up by the event loop.
+#[cfg(all(target_arch = "wasm32", feature = "window"))]
+fn listen_for_drops(canvas: &web_sys::HtmlCanvasElement) -> Rc<std::cell::RefCell<Vec<(String, Vec<u8>)>>> {
+    use wasm_bindgen::JsCast;
+    let dropped = Rc::new(std::cell::RefCell::new(vec![]));
+    // The browser only fires drop events on elements that cancel dragover
+    let on_dragover = Closure::wrap(Box::new(|e: web_sys::DragEvent| e.prevent_default()) as Box<dyn FnMut(_)>);
+    let queue = Rc::clone(&dropped);
+    let on_drop = Closure::wrap(Box::new(move |e: web_sys::DragEvent| {
+        e.prevent_default();
+        let files = match e.data_transfer().and_then(|transfer| transfer.files()) {
+            Some(files) => files,
+            None => return,
+        };
+        (0..files.length()).filter_map(|i| files.get(i)).for_each(|file| {
+            let queue = Rc::clone(&queue);
+            wasm_bindgen_futures::spawn_local(async move {
+                match wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await {
+                    Ok(buffer) => queue.borrow_mut().push((file.name(), js_sys::Uint8Array::new(&buffer).to_vec())),
+                    Err(e) => log::error!("Failed to read {}: {:?}", file.name(), e),
+                }
+            });
+        });
+    }) as Box<dyn FnMut(_)>);
+    canvas.add_event_listener_with_callback("dragover", on_dragover.as_ref().unchecked_ref()).unwrap();
+    canvas.add_event_listener_with_callback("drop", on_drop.as_ref().unchecked_ref()).unwrap();
+    on_dragover.forget();
+    on_drop.forget();
+    dropped
+}
+
+/// Touch points of the canvas in physical pixels, which winit does not report on the web.
+#[cfg(all(target_arch = "wasm32", feature = "window"))]
+fn listen_for_touches(canvas: &web_sys::HtmlCanvasElement) -> Rc<std::cell::RefCell<Vec<(u64, winit::event::TouchPhase, [f32; 2])>>> {
+    use wasm_bindgen::JsCast;
+    use winit::event::TouchPhase;
+    let touches = Rc::new(std::cell::RefCell::new(vec![]));
+    [
+        ("touchstart", TouchPhase::Started),
+        ("touchmove", TouchPhase::Moved),
+        ("touchend", TouchPhase::Ended),
+        ("touchcancel", TouchPhase::Cancelled),
+    ].into_iter().for_each(|(event, phase)| {
+        let queue = Rc::clone(&touches);
+        let target = canvas.clone();
+        let listener = Closure::wrap(Box::new(move |e: web_sys::TouchEvent| {
+            let rect = target.get_bounding_client_rect();
+            let ratio = web_sys::window().map_or(1., |win| win.device_pixel_ratio());
+            let changed = e.changed_touches();
+            (0..changed.length()).filter_map(|i| changed.get(i)).for_each(|touch| {
+                let position = [
+                    ((touch.client_x() as f64 - rect.left()) * ratio) as f32,
+                    ((touch.client_y() as f64 - rect.top()) * ratio) as f32,
+                ];
+                queue.borrow_mut().push((touch.identifier() as u64, phase, position));
+            });
+        }) as Box<dyn FnMut(_)>);
+        canvas.add_event_listener_with_callback(event, listener.as_ref().unchecked_ref()).unwrap();
+        listener.forget();
+    });
+    touches
+}
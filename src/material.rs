@@ -1,31 +1,110 @@
 use std::f32::consts::PI;
+use std::sync::Arc;
+use crate::ies::IesProfile;
 use crate::rand_gen::{get_rand, rand_vec3_in_unit_sphere, rand_vec3_on_unit_sphere};
-use crate::ray::HitRecord;
+use crate::ray::{HitRecord, RayKind};
 use crate::texture::SolidColor;
-use crate::types::{Color, create_shared_mut, RGB, Shared, SharedTexture};
+use crate::types::{blackbody, Color, create_shared_mut, RGB, Shared, SharedTexture};
 use crate::Ray;
 use na::UnitVector3;
-use crate::pdf::{CosinePDF, PDF};
+use crate::pdf::{CosinePDF, Distribution2D, PDF};
+use crate::memory::MemoryCounter;
 
 pub trait Material: Sync + Send {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord>;
-    fn emit(&self, _hit_record: &HitRecord) -> Option<Color> {
+    /// Radiance leaving the hit back along `ray_in`.
+    fn emit(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<Color> {
         None
     }
+    /// Where on the surface, in texture coordinates, light is emitted, for sampling textured lights.
+    fn emission_distribution(&self) -> Option<Arc<Distribution2D>> {
+        None
+    }
+    /// Set for surfaces showing what is behind them, only darkened by shadows.
+    fn shadow_catcher(&self) -> Option<&ShadowCatcher> {
+        None
+    }
+    /// Whether the emission is seen by the camera and in mirrors, or only lights diffuse surfaces.
+    fn directly_visible(&self) -> bool {
+        true
+    }
+    /// Index of the light group the emission is written to, see [`crate::scene::Scene::light_groups`].
+    fn light_group(&self) -> usize {
+        0
+    }
+    /// Density of scattering towards `scattered`; times the attenuation it is the BSDF value
+    /// of a [`ScatterType::Diffuse`] scatter. Unused by the other scatter types.
     fn scattering_pdf(&self, _ray_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> f32 {
        1.
     }
+    fn params(&self) -> MaterialParams {
+        MaterialParams::default()
+    }
+    fn set_params(&mut self, _params: &MaterialParams) {}
+    /// Add the textures of the material to `counter`.
+    fn memory(&self, _counter: &mut MemoryCounter) {}
+}
+
+/// The editable parameters of a material, `None` for the ones it does not have.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct MaterialParams {
+    pub albedo: Option<RGB>,
+    pub fuzz: Option<f32>,
+    pub ior: Option<f32>,
+    pub emission: Option<f32>,
+    /// See [`Material::directly_visible`].
+    pub visible: Option<bool>,
+    /// Darkness of the shadows of a [`ShadowCatcher`].
+    pub shadow: Option<f32>,
 }
 pub enum ScatterType {
     Specular(Ray),
     Diffuse(Box<dyn PDF>),
-    ISO(Ray)
+    ISO(Ray),
+    /// The path goes on as if nothing was hit, from elsewhere, e.g. out of the other side of a portal.
+    Through(Ray),
 }
 pub struct ScatterRecord {
     pub s_type: ScatterType,
     pub attenuation: Color,
 }
 
+/// A scattered ray and what the light coming back along it is multiplied by,
+/// with the sampling density kept apart so the integrator can weight and terminate paths.
+pub struct ScatterSample {
+    pub ray: Ray,
+    /// Density the direction was drawn with, `None` when it was the only possible one
+    /// (mirrors, glass), which then already is part of `weight`.
+    pub pdf: Option<f32>,
+    /// BSDF value times cosine over `pdf`.
+    pub weight: Color,
+}
+
+impl ScatterSample {
+    /// Draw the direction of a diffuse scatter from `pdf`, which may also aim at lights.
+    pub fn diffuse(
+        material: &dyn Material,
+        ray_in: &Ray,
+        hit_record: &HitRecord,
+        attenuation: Color,
+        pdf: &dyn PDF,
+    ) -> Option<Self> {
+        let ray = hit_record.spawn_ray(pdf.generate(), ray_in)
+            .with_kind(RayKind::Diffuse)
+            .with_media(ray_in.media);
+        let pdf = pdf.value(ray.direction);
+        if pdf <= 0. || !pdf.is_finite() {
+            return None
+        }
+        let weight = attenuation * material.scattering_pdf(ray_in, hit_record, &ray) / pdf;
+        Some(Self { ray, pdf: Some(pdf), weight })
+    }
+
+    pub fn delta(ray: Ray, attenuation: Color) -> Self {
+        Self { ray: ray.with_kind(RayKind::Reflection), pdf: None, weight: attenuation }
+    }
+}
+
 impl ScatterRecord {
     pub fn new(s_type: ScatterType,attenuation: Color) -> Option<Self> {
         Some(Self {
@@ -36,20 +115,38 @@ impl ScatterRecord {
 }
 pub struct Lambertian {
     albedo: SharedTexture,
+    /// Light given off where it is not black, e.g. lit windows on a facade.
+    emission: Option<SharedTexture>,
 }
 
 impl Lambertian {
+    /// A matte surface scattering in all directions.
     pub fn new(albedo: SharedTexture) -> Shared<Self> {
-        create_shared_mut(Lambertian { albedo })
+        create_shared_mut(Lambertian { albedo, emission: None })
     }
     pub fn from_color(color: RGB) -> Shared<Self> {
         Self::new(SolidColor::new(color))
     }
+    /// A matte surface glowing with `emission` in places.
+    pub fn glowing(albedo: SharedTexture, emission: SharedTexture) -> Shared<Self> {
+        create_shared_mut(Lambertian { albedo, emission: Some(emission) })
+    }
 }
 
 impl Material for Lambertian {
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.texture(&self.albedo);
+        if let Some(emission) = &self.emission {
+            counter.texture(emission);
+        }
+    }
+    // `None` rather than black where it does not glow, so the surface is not taken for a light
+    fn emit(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<Color> {
+        let emission = self.emission.as_ref()?.read().unwrap().value(hit_record.uv, hit_record.point);
+        (hit_record.front_face && emission.max() > 0.).then(|| emission)
+    }
     fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
-        let s_type = ScatterType::Diffuse(CosinePDF::new(hit_record.normal));
+        let s_type = ScatterType::Diffuse(CosinePDF::new(hit_record.frame()));
         ScatterRecord::new(
                 s_type,
                 self.albedo
@@ -64,6 +161,19 @@ impl Material for Lambertian {
         let cosine = hit_record.normal.dot(&scattered.direction);
         cosine.max(0.001) / PI
     }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            albedo: self.albedo.read().unwrap().color().map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(albedo) = params.albedo {
+            self.albedo = SolidColor::new(albedo);
+        }
+    }
 }
 
 pub struct Metal {
@@ -75,6 +185,7 @@ impl Metal {
     fn reflect(v: UnitVector3<f32>, n: UnitVector3<f32>) -> UnitVector3<f32> {
         UnitVector3::new_unchecked(v.into_inner() - 2. * n.dot(&v) * n.into_inner())
     }
+    /// A mirror, blurred more the closer `fuzz` gets to 1.
     pub fn new(albedo: RGB, fuzz: f32) -> Shared<Self> {
         create_shared_mut(Metal {
             albedo: Color::from(albedo),
@@ -86,8 +197,9 @@ impl Material for Metal {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
         let reflected = Self::reflect(ray_in.direction, hit_record.normal);
         let scatter_dir = reflected.into_inner() + self.fuzz * rand_vec3_in_unit_sphere();
-        let scattered =
-            Ray::new(hit_record.point, UnitVector3::new_normalize(scatter_dir), ray_in.time);
+        let scattered = hit_record
+            .spawn_ray(UnitVector3::new_normalize(scatter_dir), ray_in)
+            .with_media(ray_in.media);
         let s_type = ScatterType::Specular(scattered);
         if hit_record.normal.dot(&scatter_dir) > 0. {
             ScatterRecord::new(
@@ -98,6 +210,23 @@ impl Material for Metal {
             None
         }
     }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            albedo: Some(self.albedo.into()),
+            fuzz: Some(self.fuzz),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(albedo) = params.albedo {
+            self.albedo = Color::from(albedo);
+        }
+        if let Some(fuzz) = params.fuzz {
+            self.fuzz = fuzz.min(1.);
+        }
+    }
 }
 
 pub struct Dielectric {
@@ -105,6 +234,7 @@ pub struct Dielectric {
 }
 
 impl Dielectric {
+    /// Glass or water, e.g. 1.5 for glass.
     pub fn new(index_of_refraction: f32) -> Shared<Self> {
         assert!(index_of_refraction > 0.);
         create_shared_mut(Self {
@@ -127,64 +257,154 @@ impl Dielectric {
 
 impl Material for Dielectric {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
-        let refraction_ratio = if hit_record.front_face {
-            1. / self.index_of_refraction
+        // Against the medium on the other side, e.g. water around an ice cube
+        let ior = self.index_of_refraction;
+        let (refraction_ratio, media) = if hit_record.front_face {
+            (ray_in.media.current() / ior, ray_in.media.entered(ior))
         } else {
-            self.index_of_refraction
+            let media = ray_in.media.exited(ior);
+            (ior / media.current(), media)
         };
         let cos_theta = (-ray_in.direction.dot(&hit_record.normal)).min(1.);
         let sin_theta = (1. - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.;
-        let direction =
+        let (direction, media) =
             if cannot_refract || Self::reflectance(cos_theta, refraction_ratio) > get_rand() {
-                Metal::reflect(ray_in.direction, hit_record.normal)
+                (Metal::reflect(ray_in.direction, hit_record.normal), ray_in.media)
             } else {
-                Self::refract(ray_in.direction, hit_record.normal, refraction_ratio)
+                (Self::refract(ray_in.direction, hit_record.normal, refraction_ratio), media)
             };
-        let scattered =
-            Ray::new(hit_record.point, direction, ray_in.time);
+        let scattered = hit_record.spawn_ray(direction, ray_in).with_media(media);
         let s_type = ScatterType::Specular(scattered);
         ScatterRecord::new(
             s_type,
             Color::repeat(1.)
         )
     }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            ior: Some(self.index_of_refraction),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(ior) = params.ior.filter(|&ior| ior > 0.) {
+            self.index_of_refraction = ior;
+        }
+    }
 }
 
 pub struct DiffuseLight {
-    texture: SharedTexture
+    texture: SharedTexture,
+    strength: f32,
+    visible: bool,
+    /// Shape of the emission around the surface normal, the same in all directions if `None`.
+    profile: Option<Arc<IesProfile>>,
+    /// Brightness of the texels of an image texture, `None` for uniform emission.
+    distribution: Option<Arc<Distribution2D>>,
+    group: usize,
 }
 
 impl DiffuseLight {
+    /// An emitter, the only source of light besides the background.
+    /// Image textures turn the light into a picture, e.g. a screen, sampled where it is bright.
     pub fn new(texture: SharedTexture) -> Shared<Self> {
-        create_shared_mut(Self{texture})
+        let distribution = Self::distribution(&texture);
+        create_shared_mut(Self{texture, strength: 1., visible: true, profile: None, distribution, group: 0})
+    }
+    /// An emitter shaped like a real fixture, with the axis of `profile` along the surface normal.
+    pub fn with_profile(texture: SharedTexture, profile: IesProfile) -> Shared<Self> {
+        let distribution = Self::distribution(&texture);
+        create_shared_mut(Self{texture, strength: 1., visible: true, profile: Some(Arc::new(profile)), distribution, group: 0})
+    }
+
+    /// Write the emission to the light group `group` as well as to the image.
+    pub fn set_group(&mut self, group: usize) {
+        self.group = group;
+    }
+
+    fn distribution(texture: &SharedTexture) -> Option<Arc<Distribution2D>> {
+        let (width, luminance) = texture.read().unwrap().luminance()?;
+        Distribution2D::new(width, &luminance).map(Arc::new)
     }
     pub fn from_color(color: RGB) -> Shared<Self> {
         Self::new(SolidColor::new(color))
     }
+
+    /// An emitter of the color of a blackbody at `kelvin`, see [`blackbody`].
+    pub fn from_temperature(kelvin: f32) -> Shared<Self> {
+        Self::from_color(blackbody(kelvin))
+    }
 }
 
 impl Material for DiffuseLight {
     fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
         None
     }
-    fn emit(&self, hit_record: &HitRecord) -> Option<Color> {
+    fn emit(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<Color> {
         if hit_record.front_face {
-            Some(self.texture.read().unwrap().value(hit_record.uv, hit_record.point))
+            let shape = self.profile
+                .as_ref()
+                .map_or(1., |profile| profile.intensity(-ray_in.direction.dot(&hit_record.normal)));
+            Some(shape * self.strength * self.texture.read().unwrap().value(hit_record.uv, hit_record.point))
         } else {None}
     }
+    fn directly_visible(&self) -> bool {
+        self.visible
+    }
+    fn light_group(&self) -> usize {
+        self.group
+    }
+    fn emission_distribution(&self) -> Option<Arc<Distribution2D>> {
+        self.distribution.clone()
+    }
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.texture(&self.texture);
+        counter.usage.textures += self.distribution.as_ref().map_or(0, |distribution| distribution.memory());
+    }
+
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            albedo: self.texture.read().unwrap().color().map(Into::into),
+            emission: Some(self.strength),
+            visible: Some(self.visible),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(color) = params.albedo {
+            self.texture = SolidColor::new(color);
+            self.distribution = None;
+        }
+        if let Some(strength) = params.emission {
+            self.strength = strength.max(0.);
+        }
+        if let Some(visible) = params.visible {
+            self.visible = visible;
+        }
+    }
 }
 
 pub struct Isotropic {
-    albedo: SharedTexture
+    albedo: SharedTexture,
+    /// Radiance of glowing media, such as fire, reached where the medium is optically thick.
+    emission: Option<SharedTexture>,
 }
 
 impl Isotropic {
+    /// Scatters uniformly in all directions, the material of a [`crate::geo::ConstantMedium`].
     pub fn new(albedo: SharedTexture) -> Shared<Self> {
-        create_shared_mut(Self{albedo})
+        create_shared_mut(Self{albedo, emission: None})
+    }
+
+    /// A medium that glows with `emission`, which may vary from point to point.
+    pub fn emissive(albedo: SharedTexture, emission: SharedTexture) -> Shared<Self> {
+        create_shared_mut(Self{albedo, emission: Some(emission)})
     }
 
-    #[allow(dead_code)]
     pub fn from_color(color: RGB) -> Shared<Self> {
         Self::new(SolidColor::new(color))
     }
@@ -192,9 +412,70 @@ impl Isotropic {
 
 impl Material for Isotropic {
     fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
-        let scattered = Ray::new(hit_record.point, rand_vec3_on_unit_sphere(), ray_in.time);
+        let scattered = hit_record.spawn_ray(rand_vec3_on_unit_sphere(), ray_in);
         let color = self.albedo.read().unwrap().value(hit_record.uv, hit_record.point);
         let s_type = ScatterType::ISO(scattered);
         ScatterRecord::new(s_type, color)
     }
-}
\ No newline at end of file
+    /// Added at every collision the free-flight sampling of the medium picks, so a path through
+    /// the medium gathers it in proportion to the density along the way.
+    fn emit(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<Color> {
+        self.emission.as_ref().map(|emission| emission.read().unwrap().value(hit_record.uv, hit_record.point))
+    }
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.texture(&self.albedo);
+        if let Some(emission) = &self.emission {
+            counter.texture(emission);
+        }
+    }
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            albedo: self.albedo.read().unwrap().color().map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(albedo) = params.albedo {
+            self.albedo = SolidColor::new(albedo);
+        }
+    }
+}
+
+/// Transparent except for the shadows falling onto it, to composite renders onto photographs.
+pub struct ShadowCatcher {
+    /// How dark a full shadow is, from 0 to 1.
+    strength: f32,
+}
+
+impl ShadowCatcher {
+    pub fn new(strength: f32) -> Shared<Self> {
+        create_shared_mut(Self { strength: strength.clamp(0., 1.) })
+    }
+
+    /// What is left of the light behind the surface when a share `occluded` of the light is blocked.
+    pub fn transmit(&self, occluded: f32) -> f32 {
+        1. - self.strength * occluded
+    }
+}
+
+impl Material for ShadowCatcher {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
+        None
+    }
+    fn shadow_catcher(&self) -> Option<&ShadowCatcher> {
+        Some(self)
+    }
+    fn params(&self) -> MaterialParams {
+        MaterialParams {
+            shadow: Some(self.strength),
+            ..Default::default()
+        }
+    }
+
+    fn set_params(&mut self, params: &MaterialParams) {
+        if let Some(strength) = params.shadow {
+            self.strength = strength.clamp(0., 1.);
+        }
+    }
+}
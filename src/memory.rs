@@ -0,0 +1,68 @@
+//! Rough accounting of the memory a scene holds, to refuse scenes too large for the machine
+//! before rendering instead of running out of memory halfway.
+
+use std::collections::HashSet;
+use serde::Serialize;
+use crate::types::{shared_id, Shared, SharedHittable, SharedMaterial, SharedTexture};
+
+/// Approximate bytes held by a scene, by kind.
+#[derive(Clone, Copy, PartialEq, Default, Debug, Serialize)]
+pub struct MemoryUsage {
+    /// Shapes, triangles and the instances and lists holding them.
+    pub primitives: usize,
+    pub bvh: usize,
+    /// Texels, and the distributions sampling textured lights.
+    pub textures: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.primitives + self.bvh + self.textures
+    }
+
+    /// Fail if more than `budget` bytes are used.
+    pub fn check(&self, budget: Option<usize>) -> crate::Result<()> {
+        match budget {
+            Some(budget) if self.total() > budget => Err(crate::Error::MemoryBudget { used: self.total(), budget }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Budget in MiB from the `MEMORY_BUDGET` environment variable, as bytes.
+pub fn memory_budget() -> Option<usize> {
+    std::env::var("MEMORY_BUDGET").ok()?.parse::<usize>().ok().map(|mib| mib << 20)
+}
+
+/// Walks a scene adding up its memory, counting objects shared by several parents once,
+/// e.g. the mesh of many instances.
+#[derive(Default)]
+pub struct MemoryCounter {
+    seen: HashSet<usize>,
+    pub usage: MemoryUsage,
+}
+
+impl MemoryCounter {
+    /// Whether `shared` has not been counted yet.
+    fn first_visit<T: ?Sized>(&mut self, shared: &Shared<T>) -> bool {
+        self.seen.insert(shared_id(shared))
+    }
+
+    pub fn hittable(&mut self, hittable: &SharedHittable) {
+        if self.first_visit(hittable) {
+            hittable.read().unwrap().memory(self);
+        }
+    }
+
+    pub fn material(&mut self, material: &SharedMaterial) {
+        if self.first_visit(material) {
+            material.read().unwrap().memory(self);
+        }
+    }
+
+    pub fn texture(&mut self, texture: &SharedTexture) {
+        if self.first_visit(texture) {
+            self.usage.textures += texture.read().unwrap().memory();
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use na::{Point3, UnitVector3, Vector3};
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::perlin::Perlin;
+use crate::ray::{HitRecord, Hittable, HittableList, SurfacePoint};
+use crate::rand_gen::get_rand;
+use crate::types::{create_shared_mut, Shared, SharedHittable, SharedMaterial};
+use crate::Ray;
+#[cfg(feature = "enum-dispatch")]
+use crate::primitive::Primitive;
+
+#[derive(Clone)]
+pub struct Triangle {
+    vertices: [Point3<f32>; 3],
+    /// Shading normals at the vertices, interpolated over the face. Faceted without them.
+    normals: Option<[UnitVector3<f32>; 3]>,
+    material: SharedMaterial,
+}
+
+impl Triangle {
+    pub fn new(vertices: [Point3<f32>; 3], material: SharedMaterial) -> Shared<Self> {
+        create_shared_mut(Self { vertices, normals: None, material })
+    }
+
+    /// A triangle shaded as a smooth surface with `normals` at its vertices.
+    pub fn smooth(vertices: [Point3<f32>; 3], normals: [UnitVector3<f32>; 3], material: SharedMaterial) -> Shared<Self> {
+        create_shared_mut(Self { vertices, normals: Some(normals), material })
+    }
+
+    fn edges(&self) -> [Vector3<f32>; 2] {
+        [self.vertices[1] - self.vertices[0], self.vertices[2] - self.vertices[0]]
+    }
+}
+
+impl Hittable for Triangle {
+    /// Watertight intersection of Woop et al.: in a frame where the ray runs along z from the
+    /// origin, the edge functions of two triangles sharing an edge agree exactly on its points,
+    /// so no ray slips through between them. The barycentric coordinates of the second and third
+    /// vertex are the uv.
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        // Make z the largest component of the direction, so dividing by it is safe
+        let kz = ray.direction.iamax();
+        let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        let d = ray.direction;
+        let shear = [-d[kx] / d[kz], -d[ky] / d[kz], 1. / d[kz]];
+        let [p0, p1, p2] = self.vertices.map(|v| {
+            let p = v - ray.origin;
+            [p[kx] + shear[0] * p[kz], p[ky] + shear[1] * p[kz], p[kz]]
+        });
+        let edge = |a: [f32; 3], b: [f32; 3]| a[0] * b[1] - a[1] * b[0];
+        let mut e = [edge(p1, p2), edge(p2, p0), edge(p0, p1)];
+        // Exactly on an edge the products cancel, decide with the rounding of f32 out of the way
+        if e.contains(&0.) {
+            let edge = |a: [f32; 3], b: [f32; 3]| (a[0] as f64 * b[1] as f64 - a[1] as f64 * b[0] as f64) as f32;
+            e = [edge(p1, p2), edge(p2, p0), edge(p0, p1)];
+        }
+        if e.iter().any(|&e| e < 0.) && e.iter().any(|&e| e > 0.) {
+            return None
+        }
+        let det = e[0] + e[1] + e[2];
+        if det == 0. {
+            return None
+        }
+        let t = shear[2] * (e[0] * p0[2] + e[1] * p1[2] + e[2] * p2[2]) / det;
+        if !(t_min..=t_max).contains(&t) {
+            return None
+        }
+        let b = e.map(|e| e / det);
+        let mut hit_record = HitRecord::new(ray, t, self.material.clone());
+        // Interpolated on the triangle, more precise than along the ray
+        hit_record.point = Point3::from(
+            self.vertices[0].coords * b[0] + self.vertices[1].coords * b[1] + self.vertices[2].coords * b[2],
+        );
+        let [e1, e2] = self.edges();
+        hit_record.uv = [b[1], b[2]];
+        hit_record.dpdu = Some(e1);
+        hit_record.set_face_normal(ray, UnitVector3::new_normalize(e1.cross(&e2)));
+        // The side is the one of the face, only the shading follows the vertex normals
+        if let Some(n) = &self.normals {
+            let smooth = n[0].into_inner() * b[0] + n[1].into_inner() * b[1] + n[2].into_inner() * b[2];
+            if let Some(smooth) = UnitVector3::try_new(smooth, f32::EPSILON) {
+                hit_record.normal = if hit_record.front_face { smooth } else { -smooth };
+            }
+        }
+        Some(hit_record)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> Option<AxisAlignedBoundingBox> {
+        let [a, b, c] = self.vertices;
+        // Padded, a triangle in an axis plane has a flat box
+        let pad = Vector3::repeat(1e-4);
+        Some(AxisAlignedBoundingBox::new(
+            a.inf(&b).inf(&c) - pad,
+            a.sup(&b).sup(&c) + pad,
+        ))
+    }
+
+    fn material(&self) -> Option<SharedMaterial> {
+        Some(self.material.clone())
+    }
+
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        Some(Primitive::Triangle(self.clone()))
+    }
+
+    fn area(&self) -> f32 {
+        let [e1, e2] = self.edges();
+        e1.cross(&e2).norm() / 2.
+    }
+
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        // Folding the unit square onto the triangle would skew it, the square root keeps it uniform
+        let (root, split) = (get_rand().sqrt(), get_rand());
+        let (u, v) = (root * (1. - split), root * split);
+        let [e1, e2] = self.edges();
+        let normal = UnitVector3::try_new(e1.cross(&e2), f32::EPSILON)?;
+        Some(SurfacePoint { point: self.vertices[0] + u * e1 + v * e2, normal })
+    }
+}
+
+/// Indexed triangles, to be shaped before they are turned into a [`Triangle`] BVH.
+pub struct Mesh {
+    pub vertices: Vec<Point3<f32>>,
+    pub faces: Vec<[usize; 3]>,
+}
+
+impl Mesh {
+    /// A unit sphere made of an icosahedron whose faces are split in four `subdivisions` times.
+    pub fn icosphere(subdivisions: usize) -> Self {
+        let t = (1. + 5f32.sqrt()) / 2.;
+        let vertices = [
+            [-1., t, 0.], [1., t, 0.], [-1., -t, 0.], [1., -t, 0.],
+            [0., -1., t], [0., 1., t], [0., -1., -t], [0., 1., -t],
+            [t, 0., -1.], [t, 0., 1.], [-t, 0., -1.], [-t, 0., 1.],
+        ].map(|v: [f32; 3]| Point3::from(Vector3::from(v).normalize()));
+        let faces = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+        let mut mesh = Self { vertices: vertices.to_vec(), faces };
+        for _ in 0..subdivisions {
+            mesh.subdivide();
+        }
+        mesh
+    }
+
+    /// Split every face in four at the midpoints of its edges, pushed out onto the unit sphere.
+    fn subdivide(&mut self) {
+        let mut midpoints = HashMap::new();
+        let vertices = &mut self.vertices;
+        let mut midpoint = |a: usize, b: usize| *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+            let mid = (vertices[a].coords + vertices[b].coords).normalize();
+            vertices.push(Point3::from(mid));
+            vertices.len() - 1
+        });
+        self.faces = self.faces.iter().flat_map(|&[a, b, c]| {
+            let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+            [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+        }).collect();
+    }
+
+    /// A lumpy rock: the vertices of a unit sphere moved in and out by turbulence,
+    /// `roughness` being the largest change of the radius.
+    pub fn rock(subdivisions: usize, roughness: f32, noise: &Perlin, offset: Vector3<f32>) -> Self {
+        let mut mesh = Self::icosphere(subdivisions);
+        mesh.vertices.iter_mut().for_each(|p| {
+            let bump = noise.turb(Point3::from(p.coords * 1.5 + offset), Some(5));
+            *p = Point3::from(p.coords * (1. - roughness + 2. * roughness * bump.min(1.)));
+        });
+        mesh
+    }
+
+    /// The triangles scaled by `scale`, in a BVH of their own so the mesh can be instanced.
+    pub fn build(&self, scale: f32, material: SharedMaterial) -> SharedHittable {
+        let triangles = self.faces
+            .iter()
+            .map(|face| Triangle::new(face.map(|i| self.vertices[i] * scale), material.clone()) as SharedHittable)
+            .collect();
+        HittableList::new_bvh(triangles, 0., 1., None)
+    }
+
+    /// Like [`Self::build`], but shaded smooth across the edges whose faces meet at less than
+    /// `smooth_angle` degrees and faceted along the sharper ones, as auto smooth does in modelling tools.
+    pub fn build_smooth(&self, scale: f32, smooth_angle: f32, material: SharedMaterial) -> SharedHittable {
+        let triangles = self.faces
+            .iter()
+            .zip(self.corner_normals(smooth_angle))
+            .map(|(face, normals)| Triangle::smooth(face.map(|i| self.vertices[i] * scale), normals, material.clone()) as SharedHittable)
+            .collect();
+        HittableList::new_bvh(triangles, 0., 1., None)
+    }
+
+    /// The normal at every corner of every face: the normals of the faces around the vertex that
+    /// are within `smooth_angle` degrees of the face, weighted by their area.
+    fn corner_normals(&self, smooth_angle: f32) -> Vec<[UnitVector3<f32>; 3]> {
+        // Not normalized, the length is twice the area
+        let face_normals: Vec<Vector3<f32>> = self.faces.iter().map(|&[a, b, c]| {
+            let [a, b, c] = [a, b, c].map(|i| self.vertices[i]);
+            (b - a).cross(&(c - a))
+        }).collect();
+        let mut around = vec![vec![]; self.vertices.len()];
+        self.faces.iter().enumerate().for_each(|(f, face)| face.iter().for_each(|&v| around[v].push(f)));
+        let cos_max = smooth_angle.to_radians().cos();
+        self.faces.iter().zip(&face_normals).map(|(face, normal)| {
+            let facet = UnitVector3::try_new(*normal, f32::EPSILON).unwrap_or_else(Vector3::y_axis);
+            face.map(|v| {
+                let sum: Vector3<f32> = around[v]
+                    .iter()
+                    .map(|&f| face_normals[f])
+                    .filter(|other| other.normalize().dot(&facet) >= cos_max)
+                    .sum();
+                UnitVector3::try_new(sum, f32::EPSILON).unwrap_or(facet)
+            })
+        }).collect()
+    }
+}
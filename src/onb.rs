@@ -1,5 +1,6 @@
 use na::{Rotation3, Unit, UnitVector3, Matrix3, Vector3};
 
+#[derive(Clone, Copy)]
 pub struct ONB {
     rotation: Rotation3<f32>,
 }
@@ -26,7 +27,6 @@ impl ONB {
         self.get_base(2)
     }
 
-    #[allow(dead_code)]
     pub fn local(&self, coords: [f32; 3]) -> Vector3<f32> {
         self.rotation * Vector3::from(coords)
     }
@@ -35,6 +35,21 @@ impl ONB {
         self.rotation * coords
     }
 
+    /// A frame around `w` with `u` along `tangent`, so that anisotropic materials and normal maps
+    /// line up with the texture coordinates. Falls back to [`ONB::build_from_w`] if `tangent` is parallel to `w`.
+    pub fn from_tangent(w: UnitVector3<f32>, tangent: &Vector3<f32>) -> Self {
+        let projected = tangent - w.into_inner() * w.dot(tangent);
+        match Unit::try_new(projected, 1e-6 * tangent.norm()) {
+            Some(u) => {
+                let v = w.cross(&u);
+                Self {
+                    rotation: Rotation3::from_basis_unchecked(&[u.into_inner(), v, w.into_inner()]),
+                }
+            }
+            None => Self::build_from_w(w),
+        }
+    }
+
     pub fn build_from_w(w: UnitVector3<f32>) -> Self {
         let x_axis = Vector3::x_axis();
         let a = if w.dot(&x_axis).abs() > 0.9 {
@@ -0,0 +1,533 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use crate::error::{Error, Result};
+use strum::EnumIter;
+use crate::types::Color;
+use na::Point3;
+use crate::post::Transfer;
+
+/// Key/value pairs describing how an image was rendered.
+pub type Metadata = Vec<(String, String)>;
+
+/// Identifies the APP1 segment of a JPEG file holding an XMP packet.
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Exr,
+    /// Always 16 bits per channel.
+    Tiff,
+}
+
+impl ImageFormat {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Png => "PNG",
+            Self::Jpeg => "JPEG",
+            Self::Exr => "EXR",
+            Self::Tiff => "TIFF",
+        }
+    }
+
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Self::Png => &["png"],
+            Self::Jpeg => &["jpg", "jpeg"],
+            Self::Exr => &["exr"],
+            Self::Tiff => &["tif", "tiff"],
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        [Self::Png, Self::Jpeg, Self::Exr, Self::Tiff]
+            .into_iter()
+            .find(|format| format.extensions().contains(&extension.as_str()))
+    }
+}
+
+/// How the two eyes of a stereo pair are put into one image.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StereoLayout {
+    /// Left eye on the left half, for VR headsets and cross-eyed viewing.
+    SideBySide,
+    /// Red from the left eye and cyan from the right, for red-cyan glasses.
+    Anaglyph,
+}
+
+impl std::str::FromStr for StereoLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "side-by-side" => Ok(Self::SideBySide),
+            "anaglyph" => Ok(Self::Anaglyph),
+            _ => Err(format!("expected side-by-side or anaglyph, got {}", s)),
+        }
+    }
+}
+
+/// A rendered frame in display (`rgba`) and linear (`linear`) form, see [`Image`].
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+    pub linear: Vec<Color>,
+}
+
+impl StereoLayout {
+    /// Put the frames of the `left` and `right` eye, of the same size, into one.
+    pub fn combine(&self, left: &Frame, right: &Frame) -> Frame {
+        match self {
+            Self::SideBySide => {
+                let rows = |l: usize| left.rgba.chunks_exact(l).zip(right.rgba.chunks_exact(l));
+                let linear_rows = |l: usize| left.linear.chunks_exact(l).zip(right.linear.chunks_exact(l));
+                let width = left.width as usize;
+                Frame {
+                    width: left.width * 2,
+                    height: left.height,
+                    rgba: rows(width * 4).flat_map(|(l, r)| l.iter().chain(r)).copied().collect(),
+                    linear: linear_rows(width).flat_map(|(l, r)| l.iter().chain(r)).copied().collect(),
+                }
+            }
+            Self::Anaglyph => Frame {
+                width: left.width,
+                height: left.height,
+                rgba: left.rgba
+                    .chunks_exact(4)
+                    .zip(right.rgba.chunks_exact(4))
+                    .flat_map(|(l, r)| [l[0], r[1], r[2], 0xff])
+                    .collect(),
+                linear: left.linear.iter().zip(&right.linear).map(|(l, r)| Color::new(l.x, r.y, r.z)).collect(),
+            },
+        }
+    }
+}
+
+/// An image ready to be written, in display (`rgba`) and linear (`linear`) form.
+pub struct Image<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: &'a [u8],
+    /// The same with 16 bits per channel, written to PNG files instead of `rgba` when not empty.
+    pub rgba16: &'a [u16],
+    pub linear: &'a [Color],
+    /// Coverage of every pixel, empty for opaque images.
+    pub alpha: &'a [f32],
+    /// Linear light of every light group, saved as extra layers of EXR images.
+    pub light_groups: &'a [(String, Vec<Color>)],
+    /// Render passes besides the beauty, saved as extra layers of EXR images.
+    pub aovs: &'a [Aov],
+    /// Object ID mattes, saved as Cryptomatte layers of EXR images.
+    pub mattes: Option<&'a ObjectMattes>,
+    /// How `rgba` is encoded, recorded in PNG files when set.
+    pub transfer: Option<Transfer>,
+    pub metadata: Metadata,
+}
+
+impl Image<'_> {
+    /// Write the image in the format given by the file extension.
+    /// PNG keeps the metadata as text chunks, EXR as header attributes and JPEG as XMP.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let _span = tracing::info_span!("encode_image", path = %path.display()).entered();
+        match ImageFormat::from_path(path).ok_or_else(|| Error::UnknownImageFormat(path.into()))? {
+            ImageFormat::Png => self.save_png(path),
+            ImageFormat::Jpeg => self.save_jpeg(path),
+            ImageFormat::Exr => self.save_exr(path),
+            ImageFormat::Tiff => {
+                let rgba16 = if self.rgba16.is_empty() {
+                    self.rgba.iter().map(|&c| c as u16 * 257).collect()
+                } else {
+                    self.rgba16.to_vec()
+                };
+                let rgba = image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(self.width, self.height, rgba16)
+                    .ok_or(Error::FrameSize { width: self.width, height: self.height })?;
+                rgba.save(path)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn save_png(&self, path: &Path) -> Result<()> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+
+    /// The PNG file in memory, for targets without a filesystem.
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let _span = tracing::info_span!("encode_png").entered();
+        let mut bytes = vec![];
+        self.write_png(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_png<W: Write>(&self, writer: W) -> Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(if self.rgba16.is_empty() { png::BitDepth::Eight } else { png::BitDepth::Sixteen });
+        match self.transfer {
+            Some(Transfer::Srgb) => encoder.set_srgb(png::SrgbRenderingIntent::Perceptual),
+            Some(transfer) => encoder.set_source_gamma(png::ScaledFloat::new(1. / transfer.gamma())),
+            None => {}
+        }
+        for (key, value) in &self.metadata {
+            encoder.add_text_chunk(key.clone(), value.clone())?;
+        }
+        if self.rgba16.is_empty() {
+            encoder.write_header()?.write_image_data(self.rgba)?;
+        } else {
+            // PNG stores 16 bit samples big-endian
+            let bytes: Vec<u8> = self.rgba16.iter().flat_map(|c| c.to_be_bytes()).collect();
+            encoder.write_header()?.write_image_data(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// JPEG with the metadata in an XMP packet right after the JFIF header, which has to come
+    /// first, where photo tools look for it.
+    fn save_jpeg(&self, path: &Path) -> Result<()> {
+        let rgba = image::RgbaImage::from_raw(self.width, self.height, self.rgba.to_vec())
+            .ok_or(Error::FrameSize { width: self.width, height: self.height })?;
+        let rgb = image::DynamicImage::ImageRgba8(rgba).to_rgb8();
+        let mut jpeg = vec![];
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg).encode_image(&rgb)?;
+        let payload = [XMP_NAMESPACE, self.xmp().as_bytes()].concat();
+        // The segment length counts itself but not the marker
+        match u16::try_from(payload.len() + 2) {
+            Ok(length) => {
+                let segment = [&[0xff, 0xe1][..], &length.to_be_bytes(), &payload].concat();
+                let at = jfif_end(&jpeg);
+                jpeg.splice(at..at, segment);
+            }
+            Err(_) => log::warn!("Metadata too long for a JPEG segment, saving {:?} without it", path),
+        }
+        std::fs::write(path, jpeg)?;
+        Ok(())
+    }
+
+    /// The metadata as an XMP packet, the software as the creator tool and everything else
+    /// in a namespace of its own.
+    fn xmp(&self) -> String {
+        let escape = |text: &str| text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;");
+        let properties: String = self.metadata.iter().map(|(key, value)| {
+            let value = escape(value);
+            if key == "Software" {
+                format!("<xmp:CreatorTool>{}</xmp:CreatorTool>\n", value)
+            } else {
+                // XML names cannot hold spaces, "Max depth" becomes "MaxDepth"
+                let name: String = key.split_whitespace()
+                    .flat_map(|word| {
+                        let mut chars = word.chars();
+                        chars.next().into_iter().flat_map(char::to_uppercase).chain(chars)
+                    })
+                    .filter(|c| c.is_ascii_alphanumeric())
+                    .collect();
+                // Nor start with a digit or be empty, "16 bit" becomes "_16Bit"
+                let name = match name.chars().next() {
+                    Some(c) if c.is_ascii_alphabetic() => name,
+                    _ => format!("_{}", name),
+                };
+                format!("<rt:{0}>{1}</rt:{0}>\n", name, value)
+            }
+        }).collect();
+        format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+                "<rdf:Description rdf:about=\"\"\n",
+                " xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n",
+                " xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n",
+                " xmlns:rt=\"https://github.com/KaminariOS/ray_tracing/ns/1.0/\">\n",
+                "<exif:PixelXDimension>{}</exif:PixelXDimension>\n",
+                "<exif:PixelYDimension>{}</exif:PixelYDimension>\n",
+                "{}",
+                "</rdf:Description>\n",
+                "</rdf:RDF>\n",
+                "</x:xmpmeta>\n",
+                "<?xpacket end=\"w\"?>",
+            ),
+            self.width,
+            self.height,
+            properties,
+        )
+    }
+
+    fn save_exr(&self, path: &Path) -> Result<()> {
+        use exr::prelude::*;
+        let width = self.width as usize;
+        let other: HashMap<_, _> = self.metadata
+            .iter()
+            .map(|(key, value)| (Text::from(key.as_str()), AttributeValue::Text(Text::from(value.as_str()))))
+            .collect();
+        let size = (width, self.height as usize);
+        let attributes = LayerAttributes { other, ..LayerAttributes::named("main") };
+        if !self.light_groups.is_empty() || !self.aovs.is_empty() || self.mattes.is_some() {
+            // Layers of different channels only go together with channels chosen at runtime
+            let channels = |colors: &[Color], alpha: &[f32]| {
+                let mut channels: Vec<_> = ["R", "G", "B"]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, name)| AnyChannel::new(name, FlatSamples::F32(colors.iter().map(|c| c[i]).collect())))
+                    .collect();
+                if !alpha.is_empty() {
+                    channels.push(AnyChannel::new("A", FlatSamples::F32(alpha.to_vec())));
+                }
+                AnyChannels::sort(channels.into())
+            };
+            let mut attributes = attributes;
+            if let Some(mattes) = self.mattes {
+                attributes.other.extend(mattes.attributes().into_iter().map(|(key, value)| {
+                    (Text::from(key.as_str()), AttributeValue::Text(Text::from(value.as_str())))
+                }));
+            }
+            let mut layers = vec![Layer::new(size, attributes, Encoding::FAST_LOSSLESS, channels(self.linear, self.alpha))];
+            layers.extend(self.light_groups.iter().map(|(name, colors)| {
+                Layer::new(size, LayerAttributes::named(name.as_str()), Encoding::FAST_LOSSLESS, channels(colors, &[]))
+            }));
+            layers.extend(self.aovs.iter().map(|aov| {
+                let channels: Vec<_> = aov.channels.iter().enumerate().map(|(i, &name)| {
+                    let samples = aov.values.iter().skip(i).step_by(aov.channels.len()).copied().collect();
+                    AnyChannel::new(name, FlatSamples::F32(samples))
+                }).collect();
+                Layer::new(size, LayerAttributes::named(aov.name.as_str()), Encoding::FAST_LOSSLESS, AnyChannels::sort(channels.into()))
+            }));
+            if let Some(mattes) = self.mattes {
+                layers.extend(mattes.layers(size));
+            }
+            let attributes = ImageAttributes::new(IntegerBounds::from_dimensions(size));
+            exr::image::Image::from_layers(attributes, layers).write().to_file(path)?;
+        } else if self.alpha.is_empty() {
+            let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+                let color = self.linear[y * width + x];
+                (color.x, color.y, color.z)
+            });
+            let layer = Layer::new(size, attributes, Encoding::FAST_LOSSLESS, channels);
+            exr::image::Image::from_layer(layer).write().to_file(path)?;
+        } else {
+            let channels = SpecificChannels::rgba(|Vec2(x, y)| {
+                let (color, alpha) = (self.linear[y * width + x], self.alpha[y * width + x]);
+                (color.x, color.y, color.z, alpha)
+            });
+            let layer = Layer::new(size, attributes, Encoding::FAST_LOSSLESS, channels);
+            exr::image::Image::from_layer(layer).write().to_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A render pass besides the beauty image, such as the normals or the depth.
+pub struct Aov {
+    pub name: String,
+    /// Names of the channels, e.g. `X`, `Y` and `Z` for a direction.
+    pub channels: &'static [&'static str],
+    /// The channels of every pixel in turn, row by row from the top.
+    pub values: Vec<f32>,
+}
+
+/// Name of the Cryptomatte layers, which are numbered after it.
+const MATTE_LAYER: &str = "CryptoObject";
+
+/// How much of every pixel each object covers, written as Cryptomatte ID mattes.
+pub struct ObjectMattes {
+    /// Names of the objects, hashed into their IDs.
+    pub names: Vec<String>,
+    /// Per pixel, row by row from the top, the index of every object seen in it and its
+    /// coverage, most covering first.
+    pub coverage: Vec<Vec<(usize, f32)>>,
+}
+
+impl ObjectMattes {
+    /// The most objects seen in a single pixel.
+    fn ranks(&self) -> usize {
+        self.coverage.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// Two ranks of ID and coverage per layer, in the RGBA channels.
+    fn layers(&self, size: (usize, usize)) -> Vec<exr::prelude::Layer<exr::prelude::AnyChannels<exr::prelude::FlatSamples>>> {
+        use exr::prelude::*;
+        (0..(self.ranks() + 1) / 2).map(|layer| {
+            let channels: Vec<_> = ["R", "G", "B", "A"].into_iter().enumerate().map(|(i, name)| {
+                let rank = layer * 2 + i / 2;
+                let samples = self.coverage.iter().map(|pixel| match pixel.get(rank) {
+                    Some(&(object, coverage)) => if i % 2 == 0 { self.id(object) } else { coverage },
+                    None => 0.,
+                }).collect();
+                AnyChannel::new(name, FlatSamples::F32(samples))
+            }).collect();
+            let name = format!("{}{:02}", MATTE_LAYER, layer);
+            Layer::new(size, LayerAttributes::named(name.as_str()), Encoding::FAST_LOSSLESS, AnyChannels::sort(channels.into()))
+        }).collect()
+    }
+
+    /// The Cryptomatte ID of `object`: the hash of its name read as a float, kept off
+    /// infinities, NaNs and denormals.
+    fn id(&self, object: usize) -> f32 {
+        f32::from_bits(Self::hash(&self.names[object]))
+    }
+
+    fn hash(name: &str) -> u32 {
+        let hash = murmur3_32(name.as_bytes(), 0);
+        let exponent = (hash >> 23) & 0xff;
+        if exponent == 0 || exponent == 0xff { hash ^ (1 << 23) } else { hash }
+    }
+
+    /// The header attributes telling compositors how the layers are laid out and which object
+    /// every ID stands for.
+    fn attributes(&self) -> Metadata {
+        let key = &format!("{:08x}", murmur3_32(MATTE_LAYER.as_bytes(), 0))[..7];
+        let manifest: serde_json::Map<_, _> = self.names
+            .iter()
+            .map(|name| (name.clone(), format!("{:08x}", Self::hash(name)).into()))
+            .collect();
+        [
+            ("name", MATTE_LAYER.to_string()),
+            ("hash", "MurmurHash3_32".into()),
+            ("conversion", "uint32_to_float32".into()),
+            ("manifest", serde_json::Value::Object(manifest).to_string()),
+        ].into_iter().map(|(field, value)| (format!("cryptomatte/{}/{}", key, field), value)).collect()
+    }
+}
+
+/// The 32 bit MurmurHash3 of `bytes`, which Cryptomatte derives the IDs from.
+fn murmur3_32(bytes: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let scramble = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    let chunks = bytes.chunks_exact(4);
+    let tail = chunks.remainder();
+    let mut hash = chunks.fold(seed, |hash, chunk| {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        (hash ^ scramble(k)).rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64)
+    });
+    if !tail.is_empty() {
+        hash ^= scramble(tail.iter().rev().fold(0, |k, &b| (k << 8) | b as u32));
+    }
+    hash ^= bytes.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Side in pixels of the tiles of a streamed EXR, and height of the strips of rows it is made in.
+pub const STREAM_TILE: usize = 64;
+
+/// Write a `width` by `height` EXR to `path` in tiles, asking `strip` for the colors and alphas
+/// of the [`STREAM_TILE`] rows of every strip in turn from the top, so that no more than a strip
+/// of the image is ever in memory.
+pub fn stream_exr(
+    path: &Path,
+    width: u32,
+    height: u32,
+    alpha: bool,
+    metadata: &[(String, String)],
+    strip: impl Fn(usize) -> Vec<(Color, f32)> + Sync,
+) -> Result<()> {
+    use exr::prelude::*;
+    let size = (width as usize, height as usize);
+    let other = metadata
+        .iter()
+        .map(|(key, value)| (Text::from(key.as_str()), AttributeValue::Text(Text::from(value.as_str()))))
+        .collect();
+    let attributes = LayerAttributes { other, ..LayerAttributes::named("main") };
+    let encoding = Encoding { blocks: Blocks::Tiles(Vec2(STREAM_TILE, STREAM_TILE)), ..Encoding::FAST_LOSSLESS };
+    // The tiles are written in increasing order one at a time, so a strip is traced once, when
+    // the first tile of its row is asked for
+    let current = std::sync::Mutex::new((usize::MAX, vec![]));
+    let pixel = |Vec2(x, y): Vec2<usize>| {
+        let mut current = current.lock().unwrap();
+        if current.0 != y / STREAM_TILE {
+            *current = (y / STREAM_TILE, strip(y / STREAM_TILE));
+        }
+        current.1[y % STREAM_TILE * size.0 + x]
+    };
+    if alpha {
+        let channels = SpecificChannels::rgba(|position| {
+            let (color, alpha): (Color, f32) = pixel(position);
+            (color.x, color.y, color.z, alpha)
+        });
+        let layer = Layer::new(size, attributes, encoding, channels);
+        exr::image::Image::from_layer(layer).write().non_parallel().to_file(path)?;
+    } else {
+        let channels = SpecificChannels::rgb(|position| {
+            let (color, _): (Color, f32) = pixel(position);
+            (color.x, color.y, color.z)
+        });
+        let layer = Layer::new(size, attributes, encoding, channels);
+        exr::image::Image::from_layer(layer).write().non_parallel().to_file(path)?;
+    }
+    Ok(())
+}
+
+/// Write one float per pixel, `values` row by row from the top, as a grayscale PFM file.
+pub fn save_pfm(path: &Path, width: u32, height: u32, values: &[f32]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    // A negative scale marks little-endian floats, stored from the bottom row up
+    write!(writer, "Pf\n{} {}\n-1.0\n", width, height)?;
+    for row in values.chunks_exact(width as usize).rev() {
+        row.iter().try_for_each(|value| writer.write_all(&value.to_le_bytes()))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write `points` and their 8 bit colors as a binary PLY point cloud.
+pub fn save_ply(path: &Path, points: &[(Point3<f32>, [u8; 3])]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(
+        writer,
+        "ply\nformat binary_little_endian 1.0\nelement vertex {}\n\
+        property float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\nend_header\n",
+        points.len(),
+    )?;
+    for (point, color) in points {
+        point.coords.iter().try_for_each(|c| writer.write_all(&c.to_le_bytes()))?;
+        writer.write_all(color)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hand `bytes` to the browser as a file download.
+#[cfg(target_arch = "wasm32")]
+pub fn download(bytes: &[u8], file_name: &str, mime: &str) -> Result<()> {
+    use wasm_bindgen::JsCast;
+    let js_err = |e: wasm_bindgen::JsValue| Error::Web(format!("{:?}", e));
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(
+        &parts,
+        web_sys::BlobPropertyBag::new().type_(mime),
+    ).map_err(js_err)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(js_err)?;
+    let document = web_sys::window()
+        .and_then(|win| win.document())
+        .ok_or_else(|| Error::Web("No document".into()))?;
+    let anchor = document
+        .create_element("a")
+        .map_err(js_err)?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .map_err(|_| Error::Web("Not an anchor element".into()))?;
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url).map_err(js_err)
+}
+
+/// Where the segments after the start of image marker and the JFIF header of `jpeg` begin,
+/// right after the start of image marker if it has no such header.
+fn jfif_end(jpeg: &[u8]) -> usize {
+    match jpeg.get(2..6) {
+        Some(&[0xff, 0xe0, high, low]) => (4 + u16::from_be_bytes([high, low]) as usize).min(jpeg.len()),
+        _ => 2,
+    }
+}
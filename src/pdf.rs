@@ -1,5 +1,7 @@
 use std::f32::consts::PI;
-use na::{Point3, UnitVector3};
+use std::sync::Arc;
+use na::{Point3, UnitVector3, Vector3};
+use crate::background::{direction_to_map, map_to_direction};
 use crate::onb::ONB;
 use crate::rand_gen::{get_rand, random_cosine_direction};
 use crate::types::SharedHittable;
@@ -14,10 +16,9 @@ pub struct CosinePDF {
 }
 
 impl CosinePDF {
-    pub fn new(w: UnitVector3<f32>) -> Box<Self> {
-        Box::new(Self {
-            uvw: ONB::build_from_w(w)
-        })
+    /// Cosine weighted about `w` of the shading frame `uvw`.
+    pub fn new(uvw: ONB) -> Box<Self> {
+        Box::new(Self { uvw })
     }
 }
 
@@ -39,10 +40,15 @@ pub struct HittablePDF {
 
 impl HittablePDF {
     pub fn new(o: Point3<f32>, obj: SharedHittable) -> Box<Self> {
-        Box::new(Self {
+        Box::new(Self::at(o, obj))
+    }
+
+    /// [`Self::new`] on the stack, for the PDFs of a single bounce.
+    pub fn at(o: Point3<f32>, obj: SharedHittable) -> Self {
+        Self {
             o,
             obj: {let x = obj.read().unwrap().get_one(); x}.unwrap_or(obj)
-        })
+        }
     }
 }
 
@@ -56,29 +62,329 @@ impl PDF for HittablePDF {
     }
 }
 
+/// Directions towards a sphere, drawn uniformly from the cone it fills as seen from `origin`.
+pub struct SpherePDF {
+    uvw: ONB,
+    /// Cosine of the half-angle of the cone, -1 from inside the sphere where every direction hits it.
+    cos_theta_max: f32,
+}
+
+impl SpherePDF {
+    pub fn new(origin: Point3<f32>, center: Point3<f32>, radius: f32) -> Box<Self> {
+        let direction = center - origin;
+        let sin_sq = radius * radius / direction.norm_squared();
+        Box::new(Self {
+            uvw: ONB::build_from_w(UnitVector3::new_normalize(direction)),
+            cos_theta_max: if sin_sq < 1. { (1. - sin_sq).sqrt() } else { -1. },
+        })
+    }
+
+    fn solid_angle(&self) -> f32 {
+        2. * PI * (1. - self.cos_theta_max)
+    }
+}
+
+impl PDF for SpherePDF {
+    fn value(&self, direction: UnitVector3<f32>) -> f32 {
+        if direction.dot(&self.uvw.w()) >= self.cos_theta_max { 1. / self.solid_angle() } else { 0. }
+    }
+
+    fn generate(&self) -> UnitVector3<f32> {
+        let z = 1. + get_rand() * (self.cos_theta_max - 1.);
+        let phi = 2. * PI * get_rand();
+        let r = (1. - z * z).max(0.).sqrt();
+        self.uvw.local_dir(UnitVector3::new_unchecked(Vector3::new(phi.cos() * r, phi.sin() * r, z)))
+    }
+}
+
+/// Directions towards the parallelogram spanned by `edges` from `corner`, through points drawn
+/// uniformly over its area, or following a [`Distribution2D`] of its texture coordinates.
+pub struct RectPDF {
+    origin: Point3<f32>,
+    corner: Point3<f32>,
+    edges: [Vector3<f32>; 2],
+    distribution: Option<Arc<Distribution2D>>,
+}
+
+impl RectPDF {
+    pub fn new(origin: Point3<f32>, corner: Point3<f32>, edges: [Vector3<f32>; 2]) -> Box<Self> {
+        Box::new(Self { origin, corner, edges, distribution: None })
+    }
+
+    /// Draw the points where `distribution` is high, uv `(0, 0)` being `corner`.
+    pub fn with_distribution(mut self: Box<Self>, distribution: Option<Arc<Distribution2D>>) -> Box<Self> {
+        self.distribution = distribution;
+        self
+    }
+}
+
+impl PDF for RectPDF {
+    fn value(&self, direction: UnitVector3<f32>) -> f32 {
+        let normal = self.edges[0].cross(&self.edges[1]);
+        let area = normal.norm();
+        let denominator = direction.dot(&normal);
+        if area == 0. || denominator == 0. {
+            return 0.
+        }
+        let t = (self.corner - self.origin).dot(&normal) / denominator;
+        if t <= 0. {
+            return 0.
+        }
+        // Coordinates of the hit point along the edges, solved with the dual basis
+        let offset = self.origin + t * direction.into_inner() - self.corner;
+        let uv = [
+            offset.dot(&self.edges[1].cross(&normal)) / (area * area),
+            offset.dot(&normal.cross(&self.edges[0])) / (area * area),
+        ];
+        if uv.iter().any(|c| !(0. ..=1.).contains(c)) {
+            return 0.
+        }
+        let cosine = (denominator / area).abs();
+        let density = self.distribution.as_ref().map_or(1., |dist| dist.value(uv));
+        density * t * t / (cosine * area)
+    }
+
+    fn generate(&self) -> UnitVector3<f32> {
+        let [u, v] = self.distribution
+            .as_ref()
+            .map_or_else(|| [get_rand(), get_rand()], |dist| dist.sample().0);
+        UnitVector3::new_normalize(self.corner + u * self.edges[0] + v * self.edges[1] - self.origin)
+    }
+}
+
+/// Environment map directions, drawn from the brightness of the map.
+pub struct EnvironmentPDF {
+    distribution: Arc<Distribution2D>,
+    /// Turn of the map around the y axis, in degrees.
+    rotation: f32,
+}
+
+impl EnvironmentPDF {
+    pub fn new(distribution: Arc<Distribution2D>, rotation: f32) -> Box<Self> {
+        Box::new(Self { distribution, rotation })
+    }
+}
+
+impl PDF for EnvironmentPDF {
+    fn value(&self, direction: UnitVector3<f32>) -> f32 {
+        let uv = direction_to_map(direction, self.rotation);
+        let sin_theta = (PI * uv[1]).sin();
+        if sin_theta <= 0. {
+            return 0.
+        }
+        // The map stretches 2π by π radians over the unit square, rows shrinking with sin θ
+        self.distribution.value(uv) / (2. * PI * PI * sin_theta)
+    }
+
+    fn generate(&self) -> UnitVector3<f32> {
+        map_to_direction(self.distribution.sample().0, self.rotation)
+    }
+}
+
+/// Draws from one of `pdfs` picked at random. Its density is the average of theirs,
+/// which weights the strategies by the balance heuristic of multiple importance sampling.
 pub struct MixPDF {
-    pdfs: [Box<dyn PDF>; 2]
+    pdfs: Vec<Box<dyn PDF>>,
 }
 
 impl MixPDF {
     pub fn new(p0: Box<dyn PDF>, p1: Box<dyn PDF>) -> Box<Self> {
-        Box::new(Self {
-            pdfs: [p0, p1]
-        })
+        Self::uniform(vec![p0, p1])
+    }
+
+    /// Pick each of `pdfs`, at least one, with the same chance.
+    pub fn uniform(pdfs: Vec<Box<dyn PDF>>) -> Box<Self> {
+        assert!(!pdfs.is_empty());
+        Box::new(Self { pdfs })
     }
 }
 
 
 impl PDF for MixPDF {
     fn value(&self, direction: UnitVector3<f32>) -> f32 {
-        0.5 * self.pdfs[0].value(direction) + 0.5 * self.pdfs[1].value(direction)
+        self.pdfs.iter().map(|pdf| pdf.value(direction)).sum::<f32>() / self.pdfs.len() as f32
+    }
+
+    fn generate(&self) -> UnitVector3<f32> {
+        let i = ((get_rand() * self.pdfs.len() as f32) as usize).min(self.pdfs.len() - 1);
+        self.pdfs[i].generate()
+    }
+}
+
+/// PDFs mixed at most by [`WeightedMix`].
+const MAX_MIXED: usize = 4;
+
+/// Draws from one of up to [`MAX_MIXED`] borrowed PDFs picked by their weights, which add up
+/// to 1, so the strategies of a bounce are mixed without boxing them. Its density is the
+/// weighted sum of theirs, as with [`MixPDF`].
+pub struct WeightedMix<'a> {
+    pdfs: [Option<(&'a dyn PDF, f32)>; MAX_MIXED],
+}
+
+impl<'a> WeightedMix<'a> {
+    /// Mix `pdfs`, at least one, with their weights.
+    pub fn new(pdfs: impl IntoIterator<Item = (&'a dyn PDF, f32)>) -> Self {
+        let mut mixed = [None; MAX_MIXED];
+        mixed.iter_mut().zip(pdfs).for_each(|(slot, pdf)| *slot = Some(pdf));
+        assert!(mixed[0].is_some());
+        Self { pdfs: mixed }
+    }
+}
+
+impl PDF for WeightedMix<'_> {
+    fn value(&self, direction: UnitVector3<f32>) -> f32 {
+        self.pdfs.iter().flatten().map(|(pdf, weight)| weight * pdf.value(direction)).sum()
     }
 
     fn generate(&self) -> UnitVector3<f32> {
-        if get_rand() < 0.5 {
-            self.pdfs[0].generate()
-        } else {
-            self.pdfs[1].generate()
+        let mut r = get_rand();
+        let mut last = None;
+        for (pdf, weight) in self.pdfs.iter().flatten() {
+            if r < *weight {
+                return pdf.generate()
+            }
+            r -= weight;
+            last = Some(pdf);
         }
+        // The weights fell short of 1 by rounding
+        last.expect("at least one PDF is mixed").generate()
+    }
+}
+
+/// Piecewise constant density over the unit square, one cell per texel of an image,
+/// to pick points of textured lights in proportion to their brightness.
+pub struct Distribution2D {
+    width: usize,
+    height: usize,
+    /// Running sums of the rows, then of the cells of every row, each ending at 1.
+    row_cdf: Vec<f32>,
+    cell_cdf: Vec<f32>,
+    /// Density of every cell, averaging 1 over the square.
+    density: Vec<f32>,
+}
+
+impl Distribution2D {
+    /// Bytes held by the tables.
+    pub fn memory(&self) -> usize {
+        std::mem::size_of_val(self)
+            + (self.row_cdf.len() + self.cell_cdf.len() + self.density.len()) * std::mem::size_of::<f32>()
+    }
+
+    /// A distribution following the non-negative `weights` of `width` cells per row, `None` if all are 0.
+    pub fn new(width: usize, weights: &[f32]) -> Option<Self> {
+        let height = weights.len() / width.max(1);
+        let weights = &weights[..width * height];
+        let total: f32 = weights.iter().sum();
+        if width == 0 || height == 0 || total <= 0. || !total.is_finite() {
+            return None
+        }
+        let cumulative = |values: &[f32]| {
+            let sum: f32 = values.iter().sum();
+            values.iter().scan(0., |acc, &w| {
+                *acc += w;
+                Some(if sum > 0. { *acc / sum } else { 0. })
+            }).collect::<Vec<_>>()
+        };
+        let row_sums: Vec<f32> = weights.chunks_exact(width).map(|row| row.iter().sum()).collect();
+        Some(Self {
+            width,
+            height,
+            row_cdf: cumulative(&row_sums),
+            cell_cdf: weights.chunks_exact(width).flat_map(cumulative).collect(),
+            density: weights.iter().map(|&w| w * (width * height) as f32 / total).collect(),
+        })
     }
-}
\ No newline at end of file
+
+    /// A point of the unit square and its density.
+    pub fn sample(&self) -> ([f32; 2], f32) {
+        let pick = |cdf: &[f32], r: f32| cdf.partition_point(|&c| c <= r).min(cdf.len() - 1);
+        let y = pick(&self.row_cdf, get_rand());
+        let x = pick(&self.cell_cdf[y * self.width..(y + 1) * self.width], get_rand());
+        let uv = [(x as f32 + get_rand()) / self.width as f32, (y as f32 + get_rand()) / self.height as f32];
+        (uv, self.density[y * self.width + x])
+    }
+
+    /// Density at the point `uv` of the unit square.
+    pub fn value(&self, uv: [f32; 2]) -> f32 {
+        let cell = |t: f32, n: usize| ((t.clamp(0., 1.) * n as f32) as usize).min(n - 1);
+        self.density[cell(uv[1], self.height) * self.width + cell(uv[0], self.width)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_gen::{rand_vec3_on_unit_sphere, seed, unseed};
+
+    /// Directions drawn for every estimate.
+    const SAMPLES: usize = 400_000;
+    /// Relative error the Monte Carlo estimates may be off by.
+    const TOLERANCE: f32 = 0.03;
+
+    /// A weighting of the directions to compare the draws of a PDF to its density by.
+    fn test_function(direction: UnitVector3<f32>) -> f32 {
+        (1. + direction.x + 0.5 * direction.y).powi(2)
+    }
+
+    /// Check that `pdf` integrates to 1 over the sphere and that its draws follow its density:
+    /// the mean of the test function over them is its integral weighted by the density.
+    fn check(name: &str, pdf: &dyn PDF) {
+        seed(7);
+        let uniform: Vec<_> = (0..SAMPLES).map(|_| rand_vec3_on_unit_sphere()).collect();
+        let total = uniform.iter().map(|&d| pdf.value(d)).sum::<f32>() * 4. * PI / SAMPLES as f32;
+        assert!((total - 1.).abs() < TOLERANCE, "{} integrates to {}", name, total);
+        let expected = uniform.iter().map(|&d| test_function(d) * pdf.value(d)).sum::<f32>() * 4. * PI / SAMPLES as f32;
+        let drawn = (0..SAMPLES)
+            .map(|_| test_function(pdf.generate()))
+            .sum::<f32>() / SAMPLES as f32;
+        unseed();
+        assert!((drawn - expected).abs() < TOLERANCE * expected, "{}: draws average {}, density {}", name, drawn, expected);
+    }
+
+    /// Weights of a 4 by 4 image, brighter towards one corner.
+    fn gradient() -> Arc<Distribution2D> {
+        let weights: Vec<f32> = (0..16).map(|i| 1. + (i % 4) as f32 + 2. * (i / 4) as f32).collect();
+        Arc::new(Distribution2D::new(4, &weights).unwrap())
+    }
+
+    #[test]
+    fn sphere_outside() {
+        check("sphere seen from outside", SpherePDF::new(Point3::origin(), Point3::new(0., 1., 2.), 1.5).as_ref());
+    }
+
+    #[test]
+    fn sphere_inside() {
+        check("sphere seen from inside", SpherePDF::new(Point3::new(0.2, -0.1, 0.), Point3::origin(), 1.).as_ref());
+    }
+
+    #[test]
+    fn rect() {
+        let edges = [Vector3::new(2., 0., 0.), Vector3::new(0., 0., 2.)];
+        check("rect", RectPDF::new(Point3::origin(), Point3::new(-1., 1., -1.), edges).as_ref());
+    }
+
+    #[test]
+    fn rect_with_image() {
+        let edges = [Vector3::new(2., 0., 0.), Vector3::new(0., 0., 2.)];
+        let pdf = RectPDF::new(Point3::origin(), Point3::new(-1., 1., -1.), edges).with_distribution(Some(gradient()));
+        check("rect with an image", pdf.as_ref());
+    }
+
+    #[test]
+    fn weighted_mix() {
+        let edges = [Vector3::new(2., 0., 0.), Vector3::new(0., 0., 2.)];
+        let rect = RectPDF::new(Point3::origin(), Point3::new(-1., 1., -1.), edges);
+        let sphere = SpherePDF::new(Point3::origin(), Point3::new(2., 0., 0.), 1.);
+        check("weighted mix", &WeightedMix::new([(rect.as_ref() as &dyn PDF, 0.25), (sphere.as_ref() as &dyn PDF, 0.75)]));
+    }
+
+    #[test]
+    fn mix() {
+        let edges = [Vector3::new(2., 0., 0.), Vector3::new(0., 0., 2.)];
+        let pdf = MixPDF::new(
+            RectPDF::new(Point3::origin(), Point3::new(-1., 1., -1.), edges),
+            SpherePDF::new(Point3::origin(), Point3::new(2., 0., 0.), 1.),
+        );
+        check("mix", pdf.as_ref());
+    }
+}
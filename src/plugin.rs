@@ -0,0 +1,92 @@
+//! Registries of material, texture and shape kinds that scene files can name besides the built-in
+//! ones, so other crates extend the renderer with their own [`Material`](crate::material::Material),
+//! [`Texture`](crate::texture::Texture) and [`Hittable`](crate::Hittable) types without forking it.
+//!
+//! A factory gets the parameters of the JSON object naming its kind under `type`, without `type`:
+//!
+//! ```no_run
+//! use ray_tracing::plugin::{self, Params};
+//! use ray_tracing::material::Lambertian;
+//!
+//! #[derive(serde::Deserialize)]
+//! struct Gray { value: f32 }
+//!
+//! plugin::register_material("gray", |params: &Params| {
+//!     let gray: Gray = plugin::parse(params)?;
+//!     Ok(Lambertian::from_color([gray.value; 3]))
+//! });
+//! // A scene file can now declare { "type": "gray", "value": 0.3 } among its materials
+//! ```
+//!
+//! Textures are used through materials of type `textured`, e.g.
+//! `{ "type": "textured", "texture": { "type": "my_texture", ... } }`, a Lambertian of the texture.
+
+use std::collections::BTreeMap;
+use std::sync::{PoisonError, RwLock};
+use serde::de::DeserializeOwned;
+use crate::error::{Error, Result};
+use crate::types::{SharedHittable, SharedMaterial, SharedTexture};
+
+/// The parameters of a plugin kind in a scene file.
+pub type Params = serde_json::Map<String, serde_json::Value>;
+
+type Factory<T> = Box<dyn Fn(&Params) -> Result<T> + Send + Sync>;
+type ShapeFactory = Box<dyn Fn(&Params, SharedMaterial, Option<String>) -> Result<SharedHittable> + Send + Sync>;
+
+static MATERIALS: RwLock<BTreeMap<String, Factory<SharedMaterial>>> = RwLock::new(BTreeMap::new());
+static TEXTURES: RwLock<BTreeMap<String, Factory<SharedTexture>>> = RwLock::new(BTreeMap::new());
+static SHAPES: RwLock<BTreeMap<String, ShapeFactory>> = RwLock::new(BTreeMap::new());
+
+/// Build materials of type `kind` with `factory`, replacing a built-in kind of the same name.
+pub fn register_material(kind: &str, factory: impl Fn(&Params) -> Result<SharedMaterial> + Send + Sync + 'static) {
+    MATERIALS.write().unwrap_or_else(PoisonError::into_inner).insert(kind.into(), Box::new(factory));
+}
+
+/// Build the textures of `textured` materials of type `kind` with `factory`.
+pub fn register_texture(kind: &str, factory: impl Fn(&Params) -> Result<SharedTexture> + Send + Sync + 'static) {
+    TEXTURES.write().unwrap_or_else(PoisonError::into_inner).insert(kind.into(), Box::new(factory));
+}
+
+/// Build objects of type `kind` with `factory`, from their parameters, material and label.
+/// The units and up axis of the file are not applied to them, only to their `translate`.
+pub fn register_shape(
+    kind: &str,
+    factory: impl Fn(&Params, SharedMaterial, Option<String>) -> Result<SharedHittable> + Send + Sync + 'static,
+) {
+    SHAPES.write().unwrap_or_else(PoisonError::into_inner).insert(kind.into(), Box::new(factory));
+}
+
+/// The parameters as a type of the plugin, reporting what does not fit as an error of the scene file.
+pub fn parse<T: DeserializeOwned>(params: &Params) -> Result<T> {
+    Ok(serde_json::from_value(serde_json::Value::Object(params.clone()))?)
+}
+
+fn not_registered(kind: &str) -> Error {
+    Error::Plugin { kind: kind.into(), reason: "no such kind registered".into() }
+}
+
+pub(crate) fn has_material(kind: &str) -> bool {
+    MATERIALS.read().unwrap_or_else(PoisonError::into_inner).contains_key(kind)
+}
+
+pub(crate) fn has_shape(kind: &str) -> bool {
+    SHAPES.read().unwrap_or_else(PoisonError::into_inner).contains_key(kind)
+}
+
+pub(crate) fn build_material(kind: &str, params: &Params) -> Result<SharedMaterial> {
+    let materials = MATERIALS.read().unwrap_or_else(PoisonError::into_inner);
+    let factory = materials.get(kind).ok_or_else(|| not_registered(kind))?;
+    factory(params)
+}
+
+pub(crate) fn build_texture(kind: &str, params: &Params) -> Result<SharedTexture> {
+    let textures = TEXTURES.read().unwrap_or_else(PoisonError::into_inner);
+    let factory = textures.get(kind).ok_or_else(|| not_registered(kind))?;
+    factory(params)
+}
+
+pub(crate) fn build_shape(kind: &str, params: &Params, material: SharedMaterial, label: Option<String>) -> Result<SharedHittable> {
+    let shapes = SHAPES.read().unwrap_or_else(PoisonError::into_inner);
+    let factory = shapes.get(kind).ok_or_else(|| not_registered(kind))?;
+    factory(params, material, label)
+}
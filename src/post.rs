@@ -0,0 +1,427 @@
+use strum::EnumIter;
+use serde::{Deserialize, Serialize};
+use crate::types::Color;
+use crate::rand_gen::hash_seed;
+
+/// Curve mapping linear radiance into the displayable range.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Tonemap {
+    Clamp,
+    Reinhard,
+    ACES,
+}
+
+impl Default for Tonemap {
+    fn default() -> Self {
+        Self::Clamp
+    }
+}
+
+impl Tonemap {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Clamp => "clamp",
+            Self::Reinhard => "reinhard",
+            Self::ACES => "aces",
+        }
+    }
+
+    pub fn apply(&self, color: Color) -> Color {
+        match self {
+            Self::Clamp => color,
+            Self::Reinhard => color.map(|c| c / (1. + c)),
+            // Krzysztof Narkowicz's fit of the ACES filmic curve
+            Self::ACES => color.map(|c| (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)),
+        }
+    }
+}
+
+/// Encoding of the displayed values, applied after tonemapping.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug)]
+pub enum Transfer {
+    /// The square root of the book, a gamma of 2.
+    Gamma2,
+    Gamma22,
+    /// The piecewise curve of sRGB, what most image viewers expect.
+    Srgb,
+    Linear,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self::Gamma2
+    }
+}
+
+impl Transfer {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Gamma2 => "gamma 2",
+            Self::Gamma22 => "gamma 2.2",
+            Self::Srgb => "sRGB",
+            Self::Linear => "linear",
+        }
+    }
+
+    /// Encode a linear value in `0..=1`.
+    pub fn encode(&self, c: f32) -> f32 {
+        match self {
+            Self::Gamma2 => c.sqrt(),
+            Self::Gamma22 => c.powf(1. / 2.2),
+            Self::Srgb => if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1. / 2.4) - 0.055 },
+            Self::Linear => c,
+        }
+    }
+
+    /// The exponent decoding the values, as stored in the `gAMA` chunk of PNG files.
+    pub fn gamma(&self) -> f32 {
+        match self {
+            Self::Gamma2 => 2.,
+            Self::Gamma22 | Self::Srgb => 2.2,
+            Self::Linear => 1.,
+        }
+    }
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gamma2" => Ok(Self::Gamma2),
+            "gamma2.2" => Ok(Self::Gamma22),
+            "srgb" => Ok(Self::Srgb),
+            "linear" => Ok(Self::Linear),
+            _ => Err(format!("expected gamma2, gamma2.2, srgb or linear, got {}", s)),
+        }
+    }
+}
+
+/// Post-processing applied to the averaged samples when the frame is written,
+/// so changing it never requires tracing rays again.
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct PostProcess {
+    /// Exposure in stops.
+    pub exposure: f32,
+    pub tonemap: Tonemap,
+    /// Paint pixels that saturate after tonemapping in [`CLIPPED_COLOR`].
+    pub show_clipping: bool,
+    /// Filter the accumulated image with [`denoise`] before display.
+    pub denoise: bool,
+    /// Strength of the glow [`bloom`] adds around lights and highlights, 0 for none.
+    pub bloom: f32,
+    /// Darkening of the corners by [`lens_effects`], as the tangent of the angle of view out
+    /// there with the cos⁴ law of a simple lens, 0 for none.
+    pub vignette: f32,
+    /// Lateral chromatic aberration of [`lens_effects`]: the red and blue images scaled apart by
+    /// this fraction in the corners, 0 for none.
+    pub chromatic_aberration: f32,
+    pub transfer: Transfer,
+    /// Record `transfer` in saved images, so other tools decode them the same way.
+    pub tag_color_space: bool,
+    /// Add blue noise before rounding to 8 bits, trading the banding of smooth gradients
+    /// such as skies for noise too fine to notice.
+    pub dither: bool,
+}
+
+pub const CLIPPED_COLOR: [u8; 4] = [0xff, 0, 0xff, 0xff];
+
+impl PostProcess {
+    pub fn apply(&self, color: Color) -> Color {
+        self.tonemap.apply(color * 2f32.powf(self.exposure))
+    }
+
+    /// A tonemapped color with the film grain of the pixel at `x`, `y` laid over it. Like on film,
+    /// the grain shows most in the midtones and clumps into larger specks in the shadows.
+    pub fn film_grain(&self, color: Color, x: usize, y: usize) -> Color {
+        if self.grain <= 0. {
+            return color
+        }
+        let luminance = (0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z).clamp(0., 1.);
+        let speed = self.grain / GRAIN_BASE_ISO;
+        let size = (0.5 + 0.5 * speed.sqrt()) * (2. - luminance);
+        let strength = GRAIN_STRENGTH * speed.sqrt() * 2. * (luminance * (1. - luminance)).sqrt();
+        color.add_scalar(strength * value_noise(x as f32 / size, y as f32 / size))
+    }
+
+    /// A tonemapped value as an 8 bit channel.
+    #[inline]
+    pub fn to_u8(&self, c: f32) -> u8 {
+        (self.transfer.encode(c.max(0.).min(0.999)) * 256.) as u8
+    }
+
+    /// A tonemapped value as an 8 bit channel of the pixel at `x`, `y`, dithered if enabled.
+    #[inline]
+    pub fn to_u8_at(&self, c: f32, x: usize, y: usize, channel: usize) -> u8 {
+        if !self.dither {
+            return self.to_u8(c)
+        }
+        // Shift the mask for every channel, so the noise is not gray
+        let threshold = BLUE_NOISE.with(|mask| mask[blue_noise_index(x + 23 * channel, y + 41 * channel)]);
+        (self.transfer.encode(c.clamp(0., 1.)) * 255. + threshold).floor().min(255.) as u8
+    }
+
+    /// A tonemapped value as a 16 bit channel.
+    #[inline]
+    pub fn to_u16(&self, c: f32) -> u16 {
+        (self.transfer.encode(c.clamp(0., 1.)) * 65535.).round() as u16
+    }
+
+    /// The transfer function to tag saved images with, if any.
+    pub fn output_transfer(&self) -> Option<Transfer> {
+        self.tag_color_space.then(|| self.transfer)
+    }
+
+    /// Whether a tonemapped color falls outside the displayable range.
+    pub fn is_clipped(&self, color: &Color) -> bool {
+        self.show_clipping && color.max() >= 1.
+    }
+}
+
+/// ISO speed of the film the grain strength is given for.
+const GRAIN_BASE_ISO: f32 = 400.;
+/// Standard deviation of the grain of that film in the midtones.
+const GRAIN_STRENGTH: f32 = 0.04;
+
+/// Smooth noise in `[-1, 1]` of features about 1 apart, interpolated between random values at
+/// the integer points.
+fn value_noise(x: f32, y: f32) -> f32 {
+    let lattice = |x: i64, y: i64| {
+        let hash = hash_seed(&[x as u64, y as u64, 0x6772_6169]);
+        (hash >> 40) as f32 / (1u64 << 24) as f32 * 2. - 1.
+    };
+    let (x0, y0) = (x.floor(), y.floor());
+    let smooth = |t: f32| t * t * (3. - 2. * t);
+    let (fx, fy) = (smooth(x - x0), smooth(y - y0));
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = lattice(x0, y0) + (lattice(x0 + 1, y0) - lattice(x0, y0)) * fx;
+    let bottom = lattice(x0, y0 + 1) + (lattice(x0 + 1, y0 + 1) - lattice(x0, y0 + 1)) * fx;
+    top + (bottom - top) * fy
+}
+
+/// Side of the tile of blue noise repeated over the image.
+const BLUE_NOISE_SIZE: usize = 64;
+
+thread_local! {
+    /// Thresholds in `[0, 1)`, built once per thread that quantizes frames.
+    static BLUE_NOISE: Vec<f32> = blue_noise();
+}
+
+fn blue_noise_index(x: usize, y: usize) -> usize {
+    (y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE
+}
+
+/// A tile of blue noise by the void-and-cluster method: texels are ranked by adding each next
+/// one where the ones so far leave the largest gap, so every threshold spreads its texels evenly
+/// and the noise has no low frequencies to show up as blotches.
+fn blue_noise() -> Vec<f32> {
+    const N: usize = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+    const SIGMA: f32 = 1.5;
+    // Gaussian falloff over the wrapped distance, so the tile repeats seamlessly
+    let kernel: Vec<f32> = (0..N)
+        .map(|i| {
+            let wrap = |d: usize| d.min(BLUE_NOISE_SIZE - d) as f32;
+            let (dx, dy) = (wrap(i % BLUE_NOISE_SIZE), wrap(i / BLUE_NOISE_SIZE));
+            (-(dx * dx + dy * dy) / (2. * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let splat = |energy: &mut [f32], i: usize, sign: f32| {
+        let (x, y) = (i % BLUE_NOISE_SIZE, i / BLUE_NOISE_SIZE);
+        energy.iter_mut().enumerate().for_each(|(j, e)| {
+            let dx = (j % BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - x) % BLUE_NOISE_SIZE;
+            let dy = (j / BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - y) % BLUE_NOISE_SIZE;
+            *e += sign * kernel[dy * BLUE_NOISE_SIZE + dx];
+        });
+    };
+    let extreme = |energy: &[f32], on: &[bool], want: bool, largest: bool| {
+        (0..N)
+            .filter(|&i| on[i] == want)
+            .max_by(|&a, &b| {
+                let order = energy[a].partial_cmp(&energy[b]).unwrap();
+                if largest { order } else { order.reverse() }
+            })
+            .unwrap()
+    };
+    // A tenth of the texels at random, then moved from the tightest cluster to the largest void
+    // until they are evenly spread
+    let mut on: Vec<bool> = (0..N).map(|i| hash_seed(&[i as u64]) % 10 == 0).collect();
+    let mut energy = vec![0.; N];
+    (0..N).filter(|&i| on[i]).for_each(|i| splat(&mut energy, i, 1.));
+    loop {
+        let cluster = extreme(&energy, &on, true, true);
+        on[cluster] = false;
+        splat(&mut energy, cluster, -1.);
+        let void = extreme(&energy, &on, false, false);
+        on[void] = true;
+        splat(&mut energy, void, 1.);
+        if void == cluster {
+            break
+        }
+    }
+    let mut rank = vec![0; N];
+    let initial = on.iter().filter(|&&on| on).count();
+    // The initial texels get the lowest ranks, the tightest cluster first to go the highest of them
+    let (mut pattern, mut pattern_energy) = (on.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = extreme(&pattern_energy, &pattern, true, true);
+        pattern[cluster] = false;
+        splat(&mut pattern_energy, cluster, -1.);
+        rank[cluster] = r;
+    }
+    for r in initial..N {
+        let void = extreme(&energy, &on, false, false);
+        on[void] = true;
+        splat(&mut energy, void, 1.);
+        rank[void] = r;
+    }
+    rank.into_iter().map(|r| (r as f32 + 0.5) / N as f32).collect()
+}
+
+const DENOISE_RADIUS: isize = 3;
+const DENOISE_SIGMA_SPACE: f32 = 2.;
+const DENOISE_SIGMA_COLOR: f32 = 0.3;
+
+/// Edge-preserving bilateral filter over a linear image stored row by row.
+/// Neighbors are weighted by distance and by how close their color is,
+/// which smooths Monte Carlo noise while keeping silhouettes sharp.
+pub fn denoise(image: &[Color], width: usize, height: usize) -> Vec<Color> {
+    let space = -0.5 / (DENOISE_SIGMA_SPACE * DENOISE_SIGMA_SPACE);
+    let range = -0.5 / (DENOISE_SIGMA_COLOR * DENOISE_SIGMA_COLOR);
+    (0..image.len()).map(|i| {
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let center = image[i];
+        let (sum, weights) = (-DENOISE_RADIUS..=DENOISE_RADIUS)
+            .flat_map(|dy| (-DENOISE_RADIUS..=DENOISE_RADIUS).map(move |dx| (dx, dy)))
+            .filter_map(|(dx, dy)| {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                    return None
+                }
+                let neighbor = image[ny as usize * width + nx as usize];
+                let weight = (space * (dx * dx + dy * dy) as f32
+                    + range * (neighbor - center).norm_squared()).exp();
+                Some((neighbor * weight, weight))
+            })
+            .fold((Color::zeros(), 0.), |(sum, weights), (color, weight)| (sum + color, weights + weight));
+        sum / weights
+    }).collect()
+}
+
+/// Radiance above which pixels start to glow, the brightest shown without tonemapping.
+const BLOOM_THRESHOLD: f32 = 1.;
+/// Halvings of the resolution the glow is blurred at, each spreading it twice as wide.
+const BLOOM_LEVELS: usize = 6;
+
+/// A linear image row by row, with its size.
+struct Level {
+    pixels: Vec<Color>,
+    width: usize,
+    height: usize,
+}
+
+impl Level {
+    #[inline]
+    fn at(&self, x: isize, y: isize) -> Color {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Half the size, averaging blocks of 2 by 2 pixels.
+    fn downsample(&self) -> Self {
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let pixels = (0..width * height).map(|i| {
+            let (x, y) = ((i % width) as isize * 2, (i / width) as isize * 2);
+            (self.at(x, y) + self.at(x + 1, y) + self.at(x, y + 1) + self.at(x + 1, y + 1)) / 4.
+        }).collect();
+        Self { pixels, width, height }
+    }
+
+    /// Blurred with the binomial approximation of a Gaussian, one direction at a time.
+    fn blur(&self) -> Self {
+        const WEIGHTS: [f32; 5] = [1. / 16., 4. / 16., 6. / 16., 4. / 16., 1. / 16.];
+        let pass = |level: &Self, [dx, dy]: [isize; 2]| {
+            let pixels = (0..level.pixels.len()).map(|i| {
+                let (x, y) = ((i % level.width) as isize, (i / level.width) as isize);
+                WEIGHTS.iter().enumerate().fold(Color::zeros(), |sum, (k, weight)| {
+                    let offset = k as isize - 2;
+                    sum + level.at(x + offset * dx, y + offset * dy) * *weight
+                })
+            }).collect();
+            Self { pixels, width: level.width, height: level.height }
+        };
+        pass(&pass(self, [1, 0]), [0, 1])
+    }
+
+    /// Bilinear lookup at `u`, `v` in `0..1` across the image.
+    fn sample(&self, u: f32, v: f32) -> Color {
+        let (x, y) = (u * self.width as f32 - 0.5, v * self.height as f32 - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = self.at(x0, y0).lerp(&self.at(x0 + 1, y0), fx);
+        let bottom = self.at(x0, y0 + 1).lerp(&self.at(x0 + 1, y0 + 1), fx);
+        top.lerp(&bottom, fy)
+    }
+}
+
+/// Glow around the parts of a linear image brighter than the display can show, as a lens and the
+/// eye see around bright lights. The bright parts are blurred over a pyramid of ever smaller
+/// copies and added back, weighted by `strength`.
+pub fn bloom(image: &[Color], width: usize, height: usize, strength: f32) -> Vec<Color> {
+    let bright = image.iter().map(|color| {
+        let luminance = 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+        if luminance > BLOOM_THRESHOLD { color * ((luminance - BLOOM_THRESHOLD) / luminance) } else { Color::zeros() }
+    }).collect();
+    let mut level = Level { pixels: bright, width, height }.blur();
+    let mut levels = vec![];
+    for _ in 0..BLOOM_LEVELS {
+        let next = level.downsample().blur();
+        levels.push(level);
+        level = next;
+    }
+    levels.push(level);
+    let weight = strength / levels.len() as f32;
+    (0..image.len()).map(|i| {
+        let (u, v) = (((i % width) as f32 + 0.5) / width as f32, ((i / width) as f32 + 0.5) / height as f32);
+        let glow = levels.iter().fold(Color::zeros(), |sum, level| sum + level.sample(u, v));
+        image[i] + glow * weight
+    }).collect()
+}
+
+/// Lens flaws seen in photographs, on a linear image: corners dimmed by `vignette`, and red
+/// and blue magnified differently by `aberration`, fringing edges away from the center.
+pub fn lens_effects(image: &[Color], width: usize, height: usize, vignette: f32, aberration: f32) -> Vec<Color> {
+    let level = Level { pixels: image.to_vec(), width, height };
+    let aspect = width as f32 / height as f32;
+    // Half the diagonal, so the corners are at radius 1
+    let half_diagonal = (aspect * aspect + 1.).sqrt();
+    (0..image.len()).map(|i| {
+        let (u, v) = (((i % width) as f32 + 0.5) / width as f32, ((i / width) as f32 + 0.5) / height as f32);
+        let offset = [u - 0.5, v - 0.5];
+        let mut color = if aberration != 0. {
+            let at = |scale: f32| level.sample(0.5 + offset[0] * scale, 0.5 + offset[1] * scale);
+            Color::new(at(1. - aberration).x, image[i].y, at(1. + aberration).z)
+        } else {
+            image[i]
+        };
+        if vignette > 0. {
+            let radius = 2. * (offset[0] * offset[0] * aspect * aspect + offset[1] * offset[1]).sqrt() / half_diagonal;
+            // cos⁴ of the angle off the axis, whose tangent grows with the radius
+            let tangent = vignette * radius;
+            color /= (1. + tangent * tangent).powi(2);
+        }
+        color
+    }).collect()
+}
+
+/// Count the pixels of an RGBA8 frame in `bins` equal luminance ranges.
+pub fn luminance_histogram(frame: &[u8], bins: usize) -> Vec<u32> {
+    let mut histogram = vec![0; bins];
+    frame.chunks_exact(4).for_each(|pixel| {
+        let luminance = 0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32;
+        let bin = (luminance / 256. * bins as f32) as usize;
+        histogram[bin.min(bins - 1)] += 1;
+    });
+    histogram
+}
@@ -0,0 +1,160 @@
+//! A tiny HTTP server showing the image of a headless render while it accumulates,
+//! and its progress as Prometheus metrics for monitoring render farms.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::renderer::RenderStats;
+
+/// How long a client may take to send its request or read the response before it is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Page reloading the image every second, swapping it in once loaded so it does not flicker.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>ray_tracing preview</title></head>
+<body style="margin: 0; background: #222; color: #ccc; font-family: sans-serif">
+<img id="frame" src="/frame.png" style="display: block; margin: auto; max-width: 100%">
+<script>
+setInterval(() => {
+    const next = new Image();
+    next.onload = () => document.getElementById("frame").src = next.src;
+    next.src = "/frame.png?" + Date.now();
+}, 1000);
+</script>
+</body>
+</html>
+"#;
+
+/// The latest PNG and metrics of the render, served to every client asking for them.
+#[derive(Clone, Default)]
+pub struct Preview {
+    png: Arc<Mutex<Vec<u8>>>,
+    metrics: Arc<Mutex<String>>,
+    /// Whether the page and the image are served.
+    serves_frames: bool,
+    /// Whether the metrics are served.
+    serves_metrics: bool,
+}
+
+impl Preview {
+    /// Serve on `port` of `host` from a background thread, the page showing the image if `frames`
+    /// and the metrics on `/metrics` if `metrics`. Every client is answered on a thread of its own,
+    /// so one that stalls holds up neither the others nor the render.
+    pub fn start(host: &str, port: u16, frames: bool, metrics: bool) -> crate::Result<Self> {
+        let listener = TcpListener::bind((host, port))?;
+        let preview = Self { serves_frames: frames, serves_metrics: metrics, ..Self::default() };
+        let served = preview.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("Preview request failed: {}", e);
+                        continue
+                    }
+                };
+                let served = served.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = served.respond(stream) {
+                        log::warn!("Preview request failed: {}", e);
+                    }
+                });
+            }
+        });
+        match (frames, metrics) {
+            (true, true) => log::info!("Serving the preview on http://{}:{} and metrics on /metrics", host, port),
+            (true, false) => log::info!("Serving the preview on http://{}:{}", host, port),
+            (false, _) => log::info!("Serving metrics on http://{}:{}/metrics", host, port),
+        }
+        Ok(preview)
+    }
+
+    /// Show `png` from now on.
+    pub fn publish(&self, png: Vec<u8>) {
+        *self.png.lock().unwrap() = png;
+    }
+
+    /// Report `stats` from now on.
+    pub fn publish_stats(&self, stats: &RenderStats) {
+        *self.metrics.lock().unwrap() = metrics(stats);
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Skip the headers, nothing in them matters
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let path = path.split('?').next().unwrap_or(path);
+        match path {
+            "/" if self.serves_frames => write_response(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+            "/frame.png" if self.serves_frames => {
+                let png = self.png.lock().unwrap().clone();
+                if png.is_empty() {
+                    write_response(&mut stream, "503 Service Unavailable", "text/plain", b"No image yet")
+                } else {
+                    write_response(&mut stream, "200 OK", "image/png", &png)
+                }
+            }
+            "/metrics" if self.serves_metrics => {
+                let metrics = self.metrics.lock().unwrap().clone();
+                write_response(&mut stream, "200 OK", "text/plain; version=0.0.4", metrics.as_bytes())
+            }
+            _ => write_response(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+        }
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// `stats` in the Prometheus text format.
+fn metrics(stats: &RenderStats) -> String {
+    let mut text = String::new();
+    let mut gauge = |name: &str, help: &str, value: f64| {
+        text += &format!("# HELP ray_tracing_{0} {1}\n# TYPE ray_tracing_{0} gauge\nray_tracing_{0} {2}\n", name, help, value);
+    };
+    gauge("samples_done", "Samples per pixel accumulated so far.", stats.samples_done as f64);
+    gauge("samples_target", "Samples per pixel the render stops at.", stats.target as f64);
+    gauge("render_seconds", "Time spent tracing rays.", stats.elapsed.as_secs_f64());
+    gauge("rays", "Rays traced so far.", stats.rays as f64);
+    gauge("rays_per_second", "Average rays traced per second.", stats.rays_per_sec());
+    if let Some(eta) = stats.eta() {
+        gauge("eta_seconds", "Estimated time left.", eta.as_secs_f64());
+    }
+    if let Some(noise) = stats.noise {
+        gauge("noise", "Average relative standard error of the pixels.", noise as f64);
+    }
+    gauge("primitive_bytes", "Approximate memory of the shapes.", stats.memory.primitives as f64);
+    gauge("bvh_bytes", "Approximate memory of the BVH nodes.", stats.memory.bvh as f64);
+    gauge("texture_bytes", "Approximate memory of the textures.", stats.memory.textures as f64);
+    if let Some(bytes) = resident_memory() {
+        gauge("resident_memory_bytes", "Memory held by the process.", bytes as f64);
+    }
+    text
+}
+
+/// Resident set size of the process, where `/proc` tells.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
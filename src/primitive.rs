@@ -0,0 +1,42 @@
+//! The basic shapes as a closed enum, for BVH leaves to trace with a match the compiler can inline
+//! instead of a virtual call behind a lock. Built with feature `enum-dispatch`.
+
+use crate::geo::{AxisAlignedRect, Cuboid, Disk, Sphere};
+use crate::mesh::Triangle;
+use crate::ray::{HitRecord, Hittable};
+use crate::Ray;
+
+/// A copy of a shape, taken by [`Hittable::primitive`]. Materials stay shared with the original,
+/// changes of its shape only show once the copy is taken again by [`Hittable::refit`].
+#[derive(Clone)]
+pub enum Primitive {
+    Sphere(Sphere),
+    Rect(AxisAlignedRect),
+    Disk(Disk),
+    Cuboid(Cuboid),
+    Triangle(Triangle),
+}
+
+impl Primitive {
+    #[inline]
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        match self {
+            Self::Sphere(sphere) => sphere.hit(ray, t_min, t_max),
+            Self::Rect(rect) => rect.hit(ray, t_min, t_max),
+            Self::Disk(disk) => disk.hit(ray, t_min, t_max),
+            Self::Cuboid(cuboid) => cuboid.hit(ray, t_min, t_max),
+            Self::Triangle(triangle) => triangle.hit(ray, t_min, t_max),
+        }
+    }
+
+    #[inline]
+    pub fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        match self {
+            Self::Sphere(sphere) => sphere.hit_any(ray, t_min, t_max),
+            Self::Rect(rect) => rect.hit_any(ray, t_min, t_max),
+            Self::Disk(disk) => disk.hit_any(ray, t_min, t_max),
+            Self::Cuboid(cuboid) => cuboid.hit_any(ray, t_min, t_max),
+            Self::Triangle(triangle) => triangle.hit_any(ray, t_min, t_max),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Irradiance probes: small spheres hovering over the surfaces the camera sees, each colored by
+//! the light arriving at its center from every direction, to see how a scene is lit apart from
+//! its materials. The light is gathered in a prepass and kept as spherical harmonics.
+
+use std::f32::consts::PI;
+use cfg_if::cfg_if;
+use na::{Point3, UnitVector3, Vector3};
+use crate::camera::Camera;
+use crate::ray::Hittable;
+use crate::scene::Scene;
+use crate::types::Color;
+use crate::Ray;
+
+/// Rays gathering the light of every probe.
+const PROBE_RAYS: usize = 256;
+/// Radius of a probe over the distance it is seen at.
+const PROBE_SIZE: f32 = 0.015;
+/// Distance of a probe from the surface it hovers over, in radii.
+const HOVER: f32 = 2.;
+/// Spherical harmonics of the first three bands.
+const COEFFICIENTS: usize = 9;
+
+/// A probe sphere and the light reaching its center.
+struct Probe {
+    center: Point3<f32>,
+    radius: f32,
+    /// Incoming radiance projected onto the spherical harmonics.
+    radiance: [Color; COEFFICIENTS],
+}
+
+/// The probes of the image being rendered.
+pub struct Probes {
+    probes: Vec<Probe>,
+}
+
+impl Probes {
+    /// `across` probes along the width of the image seen by `camera` and as many along its
+    /// height as keeps them evenly spaced, their light gathered by `radiance`, the light arriving
+    /// back along a ray.
+    pub fn new(scene: &Scene, camera: &Camera, across: usize, radiance: impl Fn(&Ray) -> Color + Sync) -> Self {
+        let down = ((across as f32 / camera.aspect_ratio).round() as usize).max(1);
+        let world = scene.world.read().unwrap();
+        let spots: Vec<_> = (0..across * down).filter_map(|i| {
+            let s = ((i % across) as f32 + 0.5) / across as f32;
+            let t = ((i / across) as f32 + 0.5) / down as f32;
+            let ray = camera.get_pinhole_ray(s, t);
+            world.hit(&ray, 0., f32::INFINITY).filter(|hit| hit.t.is_finite()).map(|hit| {
+                let radius = hit.t * PROBE_SIZE;
+                (hit.point + hit.normal.into_inner() * radius * HOVER, radius)
+            })
+        }).collect();
+        drop(world);
+        let gather = |&(center, radius): &(Point3<f32>, f32)| {
+            let mut projected = [Color::zeros(); COEFFICIENTS];
+            fibonacci_sphere(PROBE_RAYS).for_each(|direction| {
+                let incoming = radiance(&Ray::new(center, direction, 0.));
+                projected.iter_mut().zip(basis(&direction)).for_each(|(sum, y)| *sum += incoming * y);
+            });
+            // Every direction stands for an equal share of the sphere
+            let radiance = projected.map(|sum| sum * 4. * PI / PROBE_RAYS as f32);
+            Probe { center, radius, radiance }
+        };
+        cfg_if! {
+            if #[cfg(feature = "rayon")] {
+                use rayon::prelude::*;
+                let probes = spots.par_iter().map(gather).collect();
+            } else {
+                let probes = spots.iter().map(gather).collect();
+            }
+        }
+        Self { probes }
+    }
+
+    /// How far along `ray` the nearest probe is, and its color there: the irradiance reaching a
+    /// surface facing that way, as the radiance of a white diffuse surface.
+    pub fn hit(&self, ray: &Ray) -> Option<(f32, Color)> {
+        self.probes
+            .iter()
+            .filter_map(|probe| {
+                let oc = ray.origin - probe.center;
+                let half_b = oc.dot(&ray.direction);
+                let discriminant = half_b * half_b - (oc.norm_squared() - probe.radius * probe.radius);
+                let t = -half_b - discriminant.sqrt();
+                (discriminant >= 0. && t > 0.).then(|| (t, probe))
+            })
+            .min_by(|(t0, _), (t1, _)| t0.total_cmp(t1))
+            .map(|(t, probe)| {
+                let normal = UnitVector3::new_normalize(ray.at(t) - probe.center);
+                (t, probe.irradiance(&normal) / PI)
+            })
+    }
+}
+
+impl Probe {
+    /// Light falling on a surface facing `normal` at the center of the probe: the radiance
+    /// convolved with the clamped cosine, whose bands are scaled by π, 2π/3 and π/4.
+    fn irradiance(&self, normal: &UnitVector3<f32>) -> Color {
+        const BANDS: [f32; COEFFICIENTS] = [PI, 2. * PI / 3., 2. * PI / 3., 2. * PI / 3., PI / 4., PI / 4., PI / 4., PI / 4., PI / 4.];
+        let irradiance = self.radiance
+            .iter()
+            .zip(basis(normal))
+            .zip(BANDS)
+            .fold(Color::zeros(), |sum, ((radiance, y), band)| sum + radiance * y * band);
+        irradiance.map(|c| c.max(0.))
+    }
+}
+
+/// The real spherical harmonics of the first three bands at `direction`.
+fn basis(direction: &UnitVector3<f32>) -> [f32; COEFFICIENTS] {
+    let (x, y, z) = (direction.x, direction.y, direction.z);
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3. * z * z - 1.),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
+
+/// `n` directions spread evenly over the sphere along a golden spiral.
+fn fibonacci_sphere(n: usize) -> impl Iterator<Item = UnitVector3<f32>> {
+    let golden_angle = PI * (3. - 5f32.sqrt());
+    (0..n).map(move |i| {
+        let z = 1. - (2 * i + 1) as f32 / n as f32;
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = golden_angle * i as f32;
+        UnitVector3::new_unchecked(Vector3::new(r * phi.cos(), r * phi.sin(), z))
+    })
+}
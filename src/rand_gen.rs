@@ -1,21 +1,105 @@
+use std::cell::Cell;
 use std::f32::consts::PI;
 use cfg_if::cfg_if;
 use na::{UnitVector3, Vector3};
 
+/// PCG32 (XSH RR), a small and fast generator with good statistics.
+#[derive(Clone, Copy)]
+struct Pcg32 {
+    state: u64,
+}
 
-#[inline]
-pub fn get_rand() -> f32 {
-    cfg_if!{
-        if #[cfg(feature = "web")] {
-            let mut buf = [0; 4];
-            getrandom::getrandom(&mut buf).expect("Failed to generate random number");
-            unsafe { std::mem::transmute::<[u8; 4], u32>(buf) }.to_be() as f32 / (u32::MAX as f32 + 1.)
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const INCREMENT: u64 = 1442695040888963407;
+
+    fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(Self::MULTIPLIER).wrapping_add(Self::INCREMENT);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+thread_local! {
+    /// Generator of this thread, started once from system entropy.
+    static RNG: Cell<Pcg32> = Cell::new(Pcg32::new(entropy()));
+    /// The entropy seeded generator, put aside while drawing from a [`seed`].
+    static UNSEEDED: Cell<Option<Pcg32>> = Cell::new(None);
+}
+
+/// A seed nobody can predict, for the generator of a new thread.
+/// In the browser every Web Worker asks `crypto` once, instead of once per number.
+fn entropy() -> u64 {
+    cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            let mut buf = [0; 8];
+            match getrandom::getrandom(&mut buf) {
+                Ok(()) => u64::from_le_bytes(buf),
+                Err(e) => {
+                    // Without `crypto`, e.g. in some worker scopes, the numbers only have to look random
+                    log::warn!("No system randomness, seeding from the clock: {}", e);
+                    let unit = (1u64 << 53) as f64;
+                    hash_seed(&[(js_sys::Math::random() * unit) as u64, (instant::now() * 1e3) as u64])
+                }
+            }
         } else {
             rand::random()
         }
     }
 }
 
+/// Draw the following numbers of this thread from `seed`, until [`unseed`].
+pub fn seed(seed: u64) {
+    let rng = RNG.with(|rng| rng.replace(Pcg32::new(seed)));
+    UNSEEDED.with(|unseeded| if unseeded.get().is_none() { unseeded.set(Some(rng)) });
+}
+
+/// Go back to the numbers drawn from system entropy.
+pub fn unseed() {
+    if let Some(rng) = UNSEEDED.with(Cell::take) {
+        RNG.with(|current| current.set(rng));
+    }
+}
+
+/// A fresh seed from the system generator.
+pub fn random_seed() -> u64 {
+    entropy()
+}
+
+/// Mix `values` into a seed, the same on every platform.
+pub fn hash_seed(values: &[u64]) -> u64 {
+    values.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &value| splitmix64(hash ^ value))
+}
+
+/// Finalizer of SplitMix64, a bijection scrambling the bits of `x`.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[inline]
+pub fn get_rand() -> f32 {
+    let bits = RNG.with(|cell| {
+        let mut rng = cell.get();
+        let bits = rng.next_u32();
+        cell.set(rng);
+        bits
+    });
+    // The upper 24 bits, which an f32 below 1 holds exactly
+    (bits >> 8) as f32 / (1u32 << 24) as f32
+}
+
 #[inline]
 pub fn get_rand_range(min: f32, max: f32) -> f32 {
     // assert!(min <= max);
@@ -1,13 +1,108 @@
-use crate::aabb::{AxisAlignedBoundingBox, BVHNode};
-use crate::material::Lambertian;
-use crate::types::{create_shared_mut, Shared, SharedHittable, SharedMaterial};
+use crate::aabb::{leaf_size, AxisAlignedBoundingBox, BVHNode};
+use crate::types::{create_shared_mut, shared_id, Shared, SharedHittable, SharedMaterial};
 use na::{Point3, UnitVector3, Vector3};
-use crate::rand_gen::get_rand_usize_range;
+use crate::rand_gen::get_rand;
+use crate::onb::ONB;
+use crate::memory::MemoryCounter;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "enum-dispatch")]
+use crate::primitive::Primitive;
 
 pub struct Ray {
     pub origin: Point3<f32>,
     pub direction: UnitVector3<f32>,
     pub time: f32,
+    pub kind: RayKind,
+    /// The dielectrics the ray travels inside of.
+    pub media: IorStack,
+    /// Surfaces the path bounced off before this ray, 0 for camera rays.
+    pub bounces: u32,
+    /// Offsets of the renderer tracing it, for its hits and the rays leaving them.
+    pub bias: Bias,
+    /// Whether the objects of lists counting rays count it, for a renderer collecting statistics,
+    /// see [`HittableList::count_rays`].
+    pub counted: bool,
+}
+
+/// Most dielectrics a path can be inside of at once, the outermost are forgotten beyond.
+const IOR_STACK_DEPTH: usize = 4;
+
+/// Refractive indices of the nested dielectrics a path has entered, innermost last, so that
+/// the ratio at an interface is taken against the medium on its other side instead of air.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct IorStack {
+    iors: [f32; IOR_STACK_DEPTH],
+    len: usize,
+}
+
+impl IorStack {
+    /// Index of the medium the ray is in, 1 for vacuum.
+    pub fn current(&self) -> f32 {
+        self.len.checked_sub(1).map_or(1., |top| self.iors[top])
+    }
+
+    /// Whether the ray is outside of every dielectric.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The stack after entering a dielectric of index `ior`.
+    pub fn entered(mut self, ior: f32) -> Self {
+        if self.len == IOR_STACK_DEPTH {
+            self.iors.rotate_left(1);
+            self.len -= 1;
+        }
+        self.iors[self.len] = ior;
+        self.len += 1;
+        self
+    }
+
+    /// The stack after leaving the innermost dielectric of index `ior`, which need not be the
+    /// last one entered when objects overlap.
+    pub fn exited(mut self, ior: f32) -> Self {
+        if let Some(i) = self.iors[..self.len].iter().rposition(|&x| x == ior) {
+            self.iors.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+        }
+        self
+    }
+}
+
+/// What a ray is traced for, which decides the objects it sees, see [`VisibilityFlags`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RayKind {
+    Camera,
+    /// Off mirrors, glass and other specular surfaces.
+    Reflection,
+    /// Off diffuse surfaces and media; what such rays cannot hit casts no shadow.
+    Diffuse,
+}
+
+/// Which rays see an object, all of them by default.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct VisibilityFlags {
+    pub camera: bool,
+    pub shadows: bool,
+    pub reflections: bool,
+}
+
+impl Default for VisibilityFlags {
+    fn default() -> Self {
+        Self { camera: true, shadows: true, reflections: true }
+    }
+}
+
+impl VisibilityFlags {
+    pub fn sees(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Reflection => self.reflections,
+            RayKind::Diffuse => self.shadows,
+        }
+    }
 }
 
 impl Ray {
@@ -20,8 +115,29 @@ impl Ray {
             origin,
             direction,
             time,
+            kind: RayKind::Camera,
+            media: IorStack::default(),
+            bounces: 0,
+            bias: Bias::default(),
+            counted: false,
         }
     }
+
+    pub fn with_kind(self, kind: RayKind) -> Self {
+        Self { kind, ..self }
+    }
+
+    pub fn with_media(self, media: IorStack) -> Self {
+        Self { media, ..self }
+    }
+
+    pub fn with_bias(self, bias: Bias) -> Self {
+        Self { bias, ..self }
+    }
+
+    pub fn with_counted(self, counted: bool) -> Self {
+        Self { counted, ..self }
+    }
 }
 impl Default for Ray {
     fn default() -> Self {
@@ -31,21 +147,172 @@ impl Default for Ray {
 
 pub trait Hittable: Send + Sync {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    /// Whether anything is hit between `t_min` and `t_max`, for occlusion tests that need
+    /// neither the closest hit nor its record.
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox>;
     fn get_label(&self) -> Option<&String> {
         None
     }
-    fn pdf_val(&self, _origin: Point3<f32>, _v: UnitVector3<f32>) -> f32 {
-        0.
+    /// Density of [`Hittable::random`] over solid angle. By default that of picking points
+    /// uniformly on the surface, 0 for shapes without [`Hittable::sample_point`].
+    fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
+        let area = self.area();
+        if area <= 0. || self.sample_point().is_none() {
+            return 0.
+        }
+        match self.hit(&Ray::new(origin, v, 0.), 0., f32::INFINITY) {
+            Some(rec) => {
+                let cosine = v.dot(&rec.normal).abs();
+                if cosine > 0. { rec.t * rec.t / (cosine * area) } else { 0. }
+            }
+            None => 0.,
+        }
     }
-    fn random(&self, _origin: Point3<f32>) -> UnitVector3<f32> {
-        Vector3::x_axis()
+    /// A direction from `origin` towards the object, for sampling it as a light.
+    fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
+        self.sample_point()
+            .and_then(|sample| UnitVector3::try_new(sample.point - origin, f32::EPSILON))
+            .unwrap_or_else(Vector3::x_axis)
     }
     fn get_one(&self) -> Option<SharedHittable> {
         None
     }
+    /// The objects this one is made of, as listed in the outliner.
+    fn children(&self) -> Vec<SharedHittable> {
+        vec![]
+    }
+    /// Leave `child`, one of [`Hittable::children`], out of the render or bring it back.
+    /// Returns whether the child was found.
+    fn set_child_hidden(&mut self, _child: &SharedHittable, _hidden: bool) -> bool {
+        false
+    }
+    fn is_child_hidden(&self, _child: &SharedHittable) -> bool {
+        false
+    }
+    /// Put `id` into the records of the hits on `child`, one of [`Hittable::children`].
+    /// Returns whether the child was found.
+    fn set_child_id(&mut self, _child: &SharedHittable, _id: u32) -> bool {
+        false
+    }
+    /// Put `child` among the children, returns whether the object takes any.
+    fn add_child(&mut self, _child: SharedHittable) -> bool {
+        false
+    }
+    /// Take `child` out of the children, or out of theirs for trees. Returns whether it was found.
+    fn remove_child(&mut self, _child: &SharedHittable) -> bool {
+        false
+    }
+    /// The material shared by the whole object, if it has a single one.
+    fn material(&self) -> Option<SharedMaterial> {
+        None
+    }
+    /// A copy of the object to trace without dynamic dispatch, `None` for all but the basic shapes.
+    #[cfg(feature = "enum-dispatch")]
+    fn primitive(&self) -> Option<Primitive> {
+        None
+    }
+    /// Swap the single material of the object for `material`, returns whether it has one.
+    fn set_material(&mut self, _material: SharedMaterial) -> bool {
+        false
+    }
+    fn shape_params(&self) -> ShapeParams {
+        ShapeParams::default()
+    }
+    fn set_shape_params(&mut self, _params: &ShapeParams) {}
+    /// Surface area, 0 when unknown.
+    fn area(&self) -> f32 {
+        0.
+    }
+    /// A point drawn uniformly over the surface, so of density `1 / area`, `None` when the
+    /// shape cannot draw one.
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        None
+    }
+    /// The point of the surface at texture coordinates `uv`, with its outward normal, `None`
+    /// when the shape does not map them back to a single point.
+    fn surface_at(&self, _uv: [f32; 2]) -> Option<SurfacePoint> {
+        None
+    }
+    /// Bring the cached bounding boxes inside the object up to date after parts of it were moved
+    /// or resized. Returns the summed area of the BVH nodes it holds, 0 without any.
+    fn refit(&mut self) -> f32 {
+        0.
+    }
+    /// Add the bytes held by the object, its children and materials to `counter`.
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self);
+        if let Some(material) = self.material() {
+            counter.material(&material);
+        }
+    }
+}
+
+/// A point on a surface with the normal pointing out of it there.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfacePoint {
+    pub point: Point3<f32>,
+    pub normal: UnitVector3<f32>,
+}
+
+/// The editable dimensions of a shape, `None` for the ones it does not have.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct ShapeParams {
+    pub radius: Option<f32>,
+    /// Where the object is, moving it along.
+    pub center: Option<[f32; 3]>,
+    /// Opposite corners of a rectangle in its own plane.
+    pub corners: Option<[[f32; 2]; 2]>,
+}
+
+/// Offsets keeping rays clear of the surfaces they start on, in ULPs of the magnitudes involved
+/// so they hold at any scale. Larger ones fix acne on huge scenes, smaller ones leaks at seams.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bias {
+    /// Rounding error of a hit point in ULPs of its magnitude, which rays leaving it have to clear.
+    pub origin_ulps: u32,
+    /// Distance past the entry into a medium before its exit is searched, in ULPs of the ray.
+    pub medium_ulps: u32,
+    /// Fraction of the distance to a light that shadow rays stop short of, to not hit the light.
+    pub shadow: f32,
+}
+
+impl Default for Bias {
+    fn default() -> Self {
+        Self { origin_ulps: 16, medium_ulps: 1024, shadow: 0.001 }
+    }
+}
+
+impl Bias {
+    /// Rounding error of a point of coordinates up to `magnitude`.
+    pub(crate) fn origin_error(&self, magnitude: f32) -> f32 {
+        magnitude * self.origin_ulps as f32 * f32::EPSILON
+    }
+
+    /// Gap along a ray from `origin` beyond `t` where it has surely left the surface it hit at `t`.
+    pub(crate) fn medium_gap(&self, origin: Point3<f32>, t: f32) -> f32 {
+        (origin.coords.amax() + t.abs()).max(1.) * self.medium_ulps as f32 * f32::EPSILON
+    }
 }
 
+impl std::str::FromStr for Bias {
+    type Err = String;
+
+    /// Parse `origin_ulps,medium_ulps,shadow`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(',').map(str::trim).collect::<Vec<_>>()[..] {
+            [origin, medium, shadow] => Ok(Self {
+                origin_ulps: origin.parse().map_err(|e| format!("{}: {}", origin, e))?,
+                medium_ulps: medium.parse().map_err(|e| format!("{}: {}", medium, e))?,
+                shadow: shadow.parse().map_err(|e| format!("{}: {}", shadow, e))?,
+            }),
+            _ => Err(format!("expected origin_ulps,medium_ulps,shadow, got {}", s)),
+        }
+    }
+}
 
 pub struct HitRecord {
     pub(crate) point: Point3<f32>,
@@ -54,22 +321,51 @@ pub struct HitRecord {
     pub uv: [f32; 2],
     pub front_face: bool,
     pub material: SharedMaterial,
+    /// How far `point` may be off the surface, along the normal.
+    pub(crate) error: f32,
+    /// Change of `point` with the texture coordinate `u`, for shapes with a parametrization.
+    pub(crate) dpdu: Option<Vector3<f32>>,
+    /// ID of the top-level object of the scene hit, see [`crate::scene::Scene::object_id`].
+    pub object: Option<u32>,
 }
 
-impl Default for HitRecord {
-    fn default() -> Self {
+impl HitRecord {
+    /// A hit at `t` along `ray`, facing up until [`HitRecord::set_face_normal`] is called.
+    /// The material is required so no placeholder has to be allocated for every intersection.
+    pub fn new(ray: &Ray, t: f32, material: SharedMaterial) -> Self {
         Self {
-            point: Point3::origin(),
+            point: ray.at(t),
             normal: Vector3::y_axis(),
-            t: f32::MAX,
+            t,
             uv: [0.; 2],
             front_face: false,
-            material: Lambertian::from_color([0.8, 0.8, 0.]),
+            material,
+            // The hit point is no more precise than the ray origin and the distance travelled
+            error: ray.bias.origin_error(ray.origin.coords.amax() + t),
+            dpdu: None,
+            object: None,
         }
     }
-}
 
-impl HitRecord {
+    /// The shading frame around the normal, with `u` following the texture coordinates when known.
+    pub(crate) fn frame(&self) -> ONB {
+        match &self.dpdu {
+            Some(dpdu) => ONB::from_tangent(self.normal, dpdu),
+            None => ONB::build_from_w(self.normal),
+        }
+    }
+
+    /// A ray leaving the surface towards `direction`, started off it by the error of the hit point,
+    /// so it cannot hit the same surface again right away, whatever the scale of the scene. It is
+    /// traced at the time of `ray_in`, the ray that hit, with its bias and counted if it was.
+    pub fn spawn_ray(&self, direction: UnitVector3<f32>, ray_in: &Ray) -> Ray {
+        let error = self.error.max(ray_in.bias.origin_error(self.point.coords.amax()));
+        let offset = if direction.dot(&self.normal) < 0. { -error } else { error };
+        Ray::new(self.point + offset * self.normal.into_inner(), direction, ray_in.time)
+            .with_bias(ray_in.bias)
+            .with_counted(ray_in.counted)
+    }
+
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: UnitVector3<f32>) {
         self.front_face = ray.direction.dot(&outward_normal) < 0.;
         self.normal = if self.front_face {
@@ -80,42 +376,184 @@ impl HitRecord {
     }
 }
 
+/// Rays an object of the scene stopped, counted for the rays that are [`Ray::counted`].
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ObjectRays {
+    /// Rays the object was the closest hit of.
+    pub hits: usize,
+    /// Occlusion tests the object blocked.
+    pub occlusions: usize,
+}
+
+impl ObjectRays {
+    pub fn total(&self) -> usize {
+        self.hits + self.occlusions
+    }
+}
+
+thread_local! {
+    /// Rays stopped by the objects of the scene on this thread, by [`shared_id`].
+    static OBJECT_RAYS: RefCell<HashMap<usize, ObjectRays>> = RefCell::new(HashMap::new());
+}
+
+/// Return and reset the rays counted on this thread, by [`shared_id`] of the objects.
+pub fn take_object_rays() -> HashMap<usize, ObjectRays> {
+    OBJECT_RAYS.with(|counts| counts.take())
+}
+
+fn record_object_ray(object: &SharedHittable, occlusion: bool) {
+    OBJECT_RAYS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = counts.entry(shared_id(object)).or_default();
+        if occlusion { count.occlusions += 1 } else { count.hits += 1 }
+    });
+}
+
 pub struct HittableList {
     pub objects: Vec<SharedHittable>,
-    label: Option<String>
+    label: Option<String>,
+    /// Probability of sampling each object towards, uniform if `None`.
+    weights: Option<Vec<f32>>,
+    /// Objects left out of the render from the outliner, by [`shared_id`].
+    hidden: HashSet<usize>,
+    /// IDs put into the hit records of the objects, by [`shared_id`], for the lists of a scene's objects.
+    ids: HashMap<usize, u32>,
+    /// Copies of the objects traced in their place, in BVH leaves only; `None` for the objects
+    /// that are not basic shapes.
+    #[cfg(feature = "enum-dispatch")]
+    primitives: Option<Vec<Option<Primitive>>>,
+    /// Whether the rays stopped by the objects are counted, for the lists of a scene's objects.
+    counted: bool,
 }
 
 impl HittableList {
     #[allow(dead_code)]
     pub fn clear(&mut self) {
-        self.objects.clear()
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &mut self.primitives {
+            primitives.clear();
+        }
+        self.objects.clear();
+        self.weights = None;
+        self.hidden.clear();
+        self.ids.clear();
     }
     #[allow(dead_code)]
     pub fn add(&mut self, object: SharedHittable) {
-        self.objects.push(object)
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &mut self.primitives {
+            primitives.push(object.read().unwrap().primitive());
+        }
+        self.objects.push(object);
+        self.weights = None;
     }
 
     pub fn new(objects: Vec<SharedHittable>, label: Option<String>) -> Shared<Self> {
-        create_shared_mut(Self { objects, label})
+        create_shared_mut(Self { objects, label, ..Default::default() })
+    }
+
+    /// Count the rays stopped by each of the objects, those that are [`Ray::counted`].
+    pub fn count_rays(&mut self) {
+        self.counted = true;
+    }
+
+    /// The objects of a BVH leaf, traced through copies of the basic shapes among them with
+    /// feature `enum-dispatch`.
+    pub fn leaf(objects: Vec<SharedHittable>, label: Option<String>) -> Shared<Self> {
+        let mut list = Self { objects, label, ..Default::default() };
+        list.copy_primitives();
+        create_shared_mut(list)
     }
 
     pub fn new_bvh(objects: Vec<SharedHittable>, time0: f32, time1: f32, label: Option<String>) -> SharedHittable {
-        if option_env!("BVH")
-            .unwrap_or("true")
-            .parse::<bool>()
-            .unwrap()
+        if std::env::var("BVH")
+            .ok()
+            .and_then(|bvh| bvh.parse::<bool>().ok())
+            .unwrap_or(true)
         {
-            log::info!("Building BVH for {} objects", objects.len());
-            BVHNode::new(&objects, time0, time1, label)
+            let leaf_size = leaf_size();
+            let _span = tracing::info_span!("build_bvh", objects = objects.len(), leaf_size).entered();
+            tracing::info!("Building BVH for {} objects, up to {} per leaf", objects.len(), leaf_size);
+            match BVHNode::new(&objects, time0, time1, leaf_size, label.clone()) {
+                Ok(bvh) => bvh,
+                Err(e) => {
+                    log::warn!("{}, using a plain list instead", e);
+                    Self::new(objects, label)
+                }
+            }
         } else {
             Self::new(objects, label)
         }
     }
 }
 
+impl HittableList {
+    /// The objects not hidden from the outliner.
+    fn visible(&self) -> impl Iterator<Item = &SharedHittable> {
+        self.objects.iter().filter(|object| self.hidden.is_empty() || !self.hidden.contains(&shared_id(object)))
+    }
+
+    /// The objects not hidden from the outliner, each with its share of the samples towards the
+    /// objects, not yet scaled for the hidden ones left out.
+    fn weighted_visible(&self) -> impl Iterator<Item = (&SharedHittable, f32)> {
+        let weights = self.weights.iter().flatten().copied().chain(std::iter::repeat(1.));
+        self.objects.iter().zip(weights).filter(|(object, _)| self.hidden.is_empty() || !self.hidden.contains(&shared_id(object)))
+    }
+
+    /// Copy the basic shapes among the objects anew, to trace them without going through the
+    /// objects. Only with feature `enum-dispatch`.
+    fn copy_primitives(&mut self) {
+        #[cfg(feature = "enum-dispatch")]
+        {
+            self.primitives = Some(self.objects.iter().map(|object| object.read().unwrap().primitive()).collect());
+        }
+    }
+
+    /// The objects not hidden from the outliner, each with its copy if it has one.
+    #[cfg(feature = "enum-dispatch")]
+    fn visible_primitives<'a>(&'a self, primitives: &'a [Option<Primitive>]) -> impl Iterator<Item = (&'a SharedHittable, &'a Option<Primitive>)> {
+        self.objects.iter().zip(primitives).filter(|(object, _)| self.hidden.is_empty() || !self.hidden.contains(&shared_id(object)))
+    }
+
+    /// Sample the objects in proportion to the power they emit, so a small bright bulb is not
+    /// sampled as often as a large dim panel. Objects that are sampled without emitting,
+    /// like glass spheres for caustics, get the average share of the emitters.
+    pub fn weight_by_power(&mut self) {
+        let powers: Vec<f32> = self.objects.iter().map(|obj| {
+            let obj = obj.read().unwrap();
+            let params = obj.material().map(|material| material.read().unwrap().params());
+            let emission = params.and_then(|params| {
+                let color = params.albedo.map_or(1., |[r, g, b]| (r + g + b) / 3.);
+                params.emission.map(|strength| strength * color)
+            });
+            emission.unwrap_or(0.) * obj.area()
+        }).collect();
+        let emitters: Vec<_> = powers.iter().copied().filter(|&power| power > 0. && power.is_finite()).collect();
+        self.weights = if emitters.is_empty() {
+            None
+        } else {
+            let average = emitters.iter().sum::<f32>() / emitters.len() as f32;
+            let powers: Vec<_> = powers.into_iter()
+                .map(|power| if power > 0. && power.is_finite() { power } else { average })
+                .collect();
+            let total: f32 = powers.iter().sum();
+            Some(powers.into_iter().map(|power| power / total).collect())
+        };
+    }
+}
+
 impl Default for HittableList {
     fn default() -> Self {
-        Self { objects: vec![], label: None }
+        Self {
+            objects: vec![],
+            label: None,
+            weights: None,
+            hidden: HashSet::new(),
+            ids: HashMap::new(),
+            #[cfg(feature = "enum-dispatch")]
+            primitives: None,
+            counted: false,
+        }
     }
 }
 
@@ -123,15 +561,55 @@ impl Hittable for HittableList {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let mut hit_temp = None;
         let mut closest_so_far = t_max;
-        for object in &self.objects {
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &self.primitives {
+            for (object, primitive) in self.visible_primitives(primitives) {
+                let new_hit = match primitive {
+                    Some(primitive) => primitive.hit(ray, t_min, closest_so_far),
+                    None => object.read().unwrap().hit(ray, t_min, closest_so_far),
+                };
+                if let Some(new_hit) = new_hit {
+                    closest_so_far = new_hit.t;
+                    hit_temp = Some(new_hit);
+                }
+            }
+            return hit_temp
+        }
+        let mut hit_object = None;
+        for object in self.visible() {
             if let Some(new_hit) = object.read().unwrap().hit(ray, t_min, closest_so_far) {
                 closest_so_far = new_hit.t;
                 hit_temp = Some(new_hit);
+                hit_object = Some(object);
+            }
+        }
+        if let Some(object) = hit_object.filter(|_| self.counted && ray.counted) {
+            record_object_ray(object, false);
+        }
+        if let (Some(hit), Some(object)) = (&mut hit_temp, hit_object.filter(|_| !self.ids.is_empty())) {
+            if let Some(&id) = self.ids.get(&shared_id(object)) {
+                hit.object = Some(id);
             }
         }
         hit_temp
     }
 
+    fn hit_any(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &self.primitives {
+            return self.visible_primitives(primitives).any(|(object, primitive)| match primitive {
+                Some(primitive) => primitive.hit_any(ray, t_min, t_max),
+                None => object.read().unwrap().hit_any(ray, t_min, t_max),
+            })
+        }
+        if self.counted && ray.counted {
+            let blocker = self.visible().find(|object| object.read().unwrap().hit_any(ray, t_min, t_max));
+            blocker.map(|object| record_object_ray(object, true)).is_some()
+        } else {
+            self.visible().any(|object| object.read().unwrap().hit_any(ray, t_min, t_max))
+        }
+    }
+
     fn bounding_box(&self, time0: f32, time1: f32) -> Option<AxisAlignedBoundingBox> {
         self.objects.iter().fold(None, |acc, cur| {
             let bbox = cur.read().unwrap().bounding_box(time0, time1);
@@ -142,14 +620,104 @@ impl Hittable for HittableList {
     fn get_label(&self) -> Option<&String> {
         self.label.as_ref()
     }
-    // fn get_one(&self) -> Option<SharedHittable> {
-    //     Some(self.objects[get_rand_usize_range(0, self.objects.len())].clone())
-    // }
+    fn children(&self) -> Vec<SharedHittable> {
+        self.objects.clone()
+    }
+    fn set_child_hidden(&mut self, child: &SharedHittable, hidden: bool) -> bool {
+        let id = shared_id(child);
+        if !self.objects.iter().any(|object| shared_id(object) == id) {
+            return false
+        }
+        if hidden {
+            self.hidden.insert(id);
+        } else {
+            self.hidden.remove(&id);
+        }
+        true
+    }
+    fn is_child_hidden(&self, child: &SharedHittable) -> bool {
+        self.hidden.contains(&shared_id(child))
+    }
+    fn set_child_id(&mut self, child: &SharedHittable, id: u32) -> bool {
+        let key = shared_id(child);
+        if !self.objects.iter().any(|object| shared_id(object) == key) {
+            return false
+        }
+        self.ids.insert(key, id);
+        true
+    }
+    fn add_child(&mut self, child: SharedHittable) -> bool {
+        self.add(child);
+        true
+    }
+    fn remove_child(&mut self, child: &SharedHittable) -> bool {
+        let id = shared_id(child);
+        let index = match self.objects.iter().position(|object| shared_id(object) == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.objects.remove(index);
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &mut self.primitives {
+            primitives.remove(index);
+        }
+        self.hidden.remove(&id);
+        self.ids.remove(&id);
+        self.weights = None;
+        true
+    }
+    fn memory(&self, counter: &mut MemoryCounter) {
+        counter.usage.primitives += std::mem::size_of_val(self)
+            + self.objects.capacity() * std::mem::size_of::<SharedHittable>();
+        #[cfg(feature = "enum-dispatch")]
+        if let Some(primitives) = &self.primitives {
+            counter.usage.primitives += primitives.capacity() * std::mem::size_of::<Option<Primitive>>();
+        }
+        self.objects.iter().for_each(|object| counter.hittable(object));
+    }
+    // Leaves copy their shapes again, moved or resized as they may be
+    fn refit(&mut self) -> f32 {
+        let area = self.objects.iter().map(|object| object.write().unwrap().refit()).sum();
+        #[cfg(feature = "enum-dispatch")]
+        if self.primitives.is_some() {
+            self.copy_primitives();
+        }
+        area
+    }
+    fn area(&self) -> f32 {
+        self.visible().map(|object| object.read().unwrap().area()).sum()
+    }
+    /// A point of an object picked in proportion to its area.
+    fn sample_point(&self) -> Option<SurfacePoint> {
+        let objects: Vec<_> = self.visible().collect();
+        let areas: Vec<_> = objects.iter().map(|object| object.read().unwrap().area()).collect();
+        let mut target = get_rand() * areas.iter().sum::<f32>();
+        let index = areas.iter().position(|area| {
+            target -= area;
+            target < 0.
+        })?;
+        objects[index].read().unwrap().sample_point()
+    }
 
+    // The hidden objects are neither sampled nor counted in the density
     fn pdf_val(&self, origin: Point3<f32>, v: UnitVector3<f32>) -> f32 {
-        self.objects.iter().map(|x| x.read().unwrap().pdf_val(origin, v)).sum::<f32>() / self.objects.len() as f32
+        let (sum, total) = self.weighted_visible().fold((0., 0.), |(sum, total), (object, weight)| {
+            (sum + object.read().unwrap().pdf_val(origin, v) * weight, total + weight)
+        });
+        if total > 0. { sum / total } else { 0. }
     }
     fn random(&self, origin: Point3<f32>) -> UnitVector3<f32> {
-        self.objects[get_rand_usize_range(0, self.objects.len())].read().unwrap().random(origin)
+        let objects: Vec<_> = self.weighted_visible().collect();
+        let mut target = get_rand() * objects.iter().map(|(_, weight)| weight).sum::<f32>();
+        let object = objects.iter()
+            .find(|(_, weight)| {
+                target -= weight;
+                target < 0.
+            })
+            .or(objects.last());
+        match object {
+            Some((object, _)) => object.read().unwrap().random(origin),
+            None => Vector3::x_axis(),
+        }
     }
 }
@@ -1,20 +1,39 @@
 use std::f32::consts::PI;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use crate::camera::Camera;
-use crate::types::Color;
+use crate::types::{shared_id, Color, SharedHittable, SharedMaterial};
 use crate::Ray;
+use crate::ray::{take_object_rays, Bias, HitRecord, Hittable, ObjectRays, RayKind};
 use cfg_if::cfg_if;
 use derivative::Derivative;
-use na::{Vector3, Vector4};
-use crate::material::{ScatterRecord, ScatterType};
-use crate::pdf::{HittablePDF, MixPDF};
+use na::{Point3, Vector4};
+use crate::material::{Lambertian, Material, ScatterRecord, ScatterSample, ScatterType};
+use crate::pdf::{HittablePDF, WeightedMix, PDF};
+use crate::guiding::Guide;
+use crate::probes::Probes;
+use crate::accumulation::Accumulation;
+pub use crate::accumulation::Precision;
+use crate::post::{bloom, CLIPPED_COLOR, denoise, lens_effects, PostProcess};
+use crate::output::{Aov, Image, ImageFormat, Metadata, ObjectMattes};
 cfg_if! {
     if #[cfg(feature = "window")] {
 use pixels::Pixels;
-use crate::gui::Gui;
+use crate::gui::{BackgroundSettings, FogSettings, Gui, SceneSource};
+use crate::camera::CameraSettings;
+use crate::scene_file::SceneFile;
     }
 }
-use crate::rand_gen::get_rand;
+use crate::rand_gen::{self, get_rand, hash_seed, random_cosine_direction};
+use crate::sampler::Sampler;
+use crate::aabb::take_box_tests;
+use crate::onb::ONB;
+use strum::EnumIter;
+use serde::{Deserialize, Serialize};
 use crate::scene::Scene;
+use crate::memory::MemoryUsage;
 #[cfg(feature = "window")]
 use crate::scene::select_scene;
 
@@ -29,27 +48,513 @@ pub struct Renderer {
     scale: u32,
     actual_width: u32,
     actual_height: u32,
+    fixed_size: Option<(u32, u32)>,
+    /// Window size the image keeps being rendered for while the window is resized, which then
+    /// shows it letterboxed.
+    locked_size: Option<(u32, u32)>,
     #[derivative(Debug = "ignore")]
     scene: Scene,
+    /// Held by `scene`, measured when it is loaded.
+    memory: MemoryUsage,
     pub(crate) multisample: usize,
     pub(crate) max_depth: usize,
+    /// Bounces beyond [`Self::max_depth`] paths refracting through glass with most of their light
+    /// may go on for, so nested glass does not go black where diffuse paths are cut off.
+    pub(crate) glass_depth: usize,
+    /// Trace the paths by the materials alone, up to [`REFERENCE_DEPTH`] bounces, without light
+    /// sampling, guiding or Russian roulette: the slow and simple integrator the image of the
+    /// others is checked against.
+    reference: bool,
+    /// Threads with stacks deep enough for the paths of [`Self::reference`], made when first needed.
+    #[cfg(all(feature = "rayon", not(target_arch = "wasm32")))]
+    #[derivative(Debug = "ignore")]
+    deep_pool: Option<rayon::ThreadPool>,
     #[derivative(Debug = "ignore")]
     pub dirty: bool,
+    #[derivative(Debug = "ignore")]
+    accumulated: Accumulation,
+    /// Pass traced over several frames, the order its rows are traced in, counted from the
+    /// bottom, and how many of them are done.
+    #[derivative(Debug = "ignore")]
+    partial_pass: Vec<Sample>,
+    #[derivative(Debug = "ignore")]
+    row_order: Vec<usize>,
+    #[derivative(Debug = "ignore")]
+    next_row: usize,
+    pub(crate) samples_done: usize,
+    /// Passes added up into the image, each of the same number of samples.
+    #[derivative(Debug = "ignore")]
+    passes: usize,
+    /// Sum over the passes of the squared luminance of every pixel, for estimating the noise.
+    #[derivative(Debug = "ignore")]
+    squared: Vec<f32>,
+    /// Stop refining the image once [`Self::noise`] drops below this.
+    pub(crate) noise_threshold: Option<f32>,
+    /// Time a pixel may take per sample before it is given up on and shown in [`STALLED_COLOR`].
+    pub(crate) pixel_budget: Option<instant::Duration>,
+    /// Pixels given up on in the pass being traced.
+    #[derivative(Debug = "ignore")]
+    stalled_pixels: AtomicUsize,
+    #[derivative(Debug = "ignore")]
+    cancelled: AtomicBool,
+    pub(crate) paused: bool,
+    #[derivative(Debug = "ignore")]
+    render_time: instant::Duration,
+    #[derivative(Debug = "ignore")]
+    rays_traced: AtomicUsize,
+    pub(crate) region: Option<Region>,
+    /// Pixel the user is looking at, counted from the top left corner. The tiles and rows
+    /// around it are traced first, so that part of the image shows up before the rest.
+    pub(crate) focus: Option<(u32, u32)>,
+    pub(crate) debug_view: DebugView,
+    pub(crate) light_paths: LightPaths,
+    pub(crate) material_override: MaterialOverride,
+    /// Neutral gray replacing the materials of [`MaterialOverride::Clay`].
+    #[derivative(Debug = "ignore")]
+    clay: SharedMaterial,
+    /// Scattering events before the light counted by [`LightPaths::Bounce`].
+    pub(crate) bounce: usize,
+    /// Leave the background out of the alpha channel, for compositing.
+    pub(crate) transparent: bool,
+    /// Learn where the light comes from while rendering and aim diffuse scatters there.
+    pub(crate) guiding: bool,
+    /// What was learned for the current image, when guiding.
+    #[derivative(Debug = "ignore")]
+    guide: Option<Guide>,
+    /// Irradiance probes shown along the width of the image, none when 0.
+    pub(crate) probe_count: usize,
+    /// The probes of the current image, their light gathered before the first pass.
+    #[derivative(Debug = "ignore")]
+    probes: Option<Probes>,
+    /// Directions towards the bright parts of the background, the same at every hit, so they are
+    /// made once per image rather than at every bounce.
+    #[derivative(Debug = "ignore")]
+    environment: Option<Box<dyn PDF + Send + Sync>>,
+    /// Count the rays every object of the scene stops, see [`Self::object_stats`].
+    pub(crate) object_stats: bool,
+    /// Rays stopped by the objects so far, by [`shared_id`].
+    #[derivative(Debug = "ignore")]
+    object_rays: Mutex<HashMap<usize, ObjectRays>>,
+    /// Save PNG files with 16 bits per channel, as TIFF files always are.
+    pub(crate) png16: bool,
+    /// Add object ID mattes to EXR files.
+    pub(crate) cryptomatte: bool,
+    /// Add the normal, depth and albedo layers and the object ID mattes to EXR files.
+    pub(crate) aovs: bool,
+    bias: Bias,
+    /// Largest side in texels of the images of the scenes loaded, `None` for no limit.
+    max_texture_size: Option<u32>,
+    /// Every pixel and pass draws its random numbers from this, so a render can be repeated exactly.
+    pub(crate) seed: u64,
+    post: PostProcess,
+    /// The post-processing changed and the frame has to be written again.
+    #[derivative(Debug = "ignore")]
+    refresh: bool,
+    /// Fade from the image before a restart into the new one instead of showing the first noisy passes.
+    pub(crate) blend: bool,
+    /// Linear radiance of the image before the last restart, faded out as samples come in.
+    #[derivative(Debug = "ignore")]
+    previous: Vec<Color>,
+    /// Camera and scene of the current image, which the next one only fades from if it has them too.
+    #[derivative(Debug = "ignore")]
+    blend_view: Option<(crate::camera::CameraSettings, usize)>,
+    /// The GUI fog last applied to the scene.
+    #[cfg(feature = "window")]
+    #[derivative(Debug = "ignore")]
+    fog: Option<FogSettings>,
+    /// The GUI background last applied to the scene.
+    #[cfg(feature = "window")]
+    #[derivative(Debug = "ignore")]
+    background: Option<BackgroundSettings>,
+    /// Where the GUI scene was last loaded from.
+    #[cfg(feature = "window")]
+    #[derivative(Debug = "ignore")]
+    scene_source: Option<SceneSource>,
+    /// Following a camera being moved, at [`INTERACTIVE_SCALE`] times the scale.
+    #[cfg(feature = "window")]
+    #[derivative(Debug = "ignore")]
+    interactive: bool,
+}
+
+/// What the renderer shows instead of the path traced image.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum DebugView {
+    Off,
+    Normals,
+    UV,
+    Depth,
+    BVHHeat,
+    AO,
+}
+
+impl DebugView {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Normals => "normals",
+            Self::UV => "UV",
+            Self::Depth => "depth",
+            Self::BVHHeat => "BVH heat",
+            Self::AO => "ambient occlusion",
+        }
+    }
+}
+
+/// Which light paths the path tracer adds up, to see where the energy and noise of an image come from.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum LightPaths {
+    All,
+    /// Light reaching the camera after exactly `bounce` scattering events:
+    /// 0 shows the emitters, 1 direct lighting and 2 the first indirect bounce.
+    Bounce,
+    /// Light focused onto diffuse surfaces by mirrors, glass and other specular materials.
+    Caustics,
+}
+
+impl LightPaths {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Bounce => "single bounce",
+            Self::Caustics => "caustics",
+        }
+    }
+}
+
+/// Materials the scene is shown with instead of its own, to check the lighting and geometry.
+#[derive(PartialEq, EnumIter, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum MaterialOverride {
+    Off,
+    /// Every surface turned into the same light gray, except for the lights.
+    Clay,
+    /// Only the lights, everything else let through as if it was not there.
+    Emitters,
+}
+
+impl MaterialOverride {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Clay => "clay",
+            Self::Emitters => "emitters only",
+        }
+    }
+}
+
+impl std::str::FromStr for MaterialOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "clay" => Ok(Self::Clay),
+            "emitters" => Ok(Self::Emitters),
+            _ => Err(format!("expected off, clay or emitters, got {}", s)),
+        }
+    }
+}
+
+/// Albedo of the clay material.
+const CLAY_ALBEDO: f32 = 0.7;
+
+/// How a path looks from the camera so far, to tell caustics apart.
+#[derive(Clone, Copy, PartialEq)]
+enum PathClass {
+    /// Only specular bounces, or none yet.
+    Camera,
+    /// The last bounce was the first diffuse one.
+    Diffuse,
+    /// Specular bounces after a single diffuse one, light arriving now is a caustic.
+    Caustic,
+    Other,
+}
+
+impl PathClass {
+    fn next(self, specular: bool) -> Self {
+        match (self, specular) {
+            (Self::Camera, true) => Self::Camera,
+            (Self::Camera, false) => Self::Diffuse,
+            (Self::Diffuse | Self::Caustic, true) => Self::Caustic,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Rays per side of the grid through each pixel the object mattes are measured with.
+const MATTE_GRID: usize = 4;
+/// Bounding box tests shown as the hottest color in the BVH heat view.
+const HEAT_MAX_TESTS: f32 = 64.;
+/// Time per sample after which a pixel is given up on, far beyond what a healthy pixel takes.
+const DEFAULT_PIXEL_BUDGET: instant::Duration = instant::Duration::from_secs(1);
+/// Pixels given up on by the watchdog, in the linear radiance of the image.
+const STALLED_COLOR: Color = Color::new(1., 0., 1.);
+/// Rays between two looks at the clock of the watchdog.
+const WATCHDOG_RAYS: usize = 64;
+/// Time spent tracing per frame in the single threaded browser build.
+#[cfg(all(target_arch = "wasm32", not(feature = "web-threads")))]
+const WEB_TIME_SLICE: instant::Duration = instant::Duration::from_millis(12);
+/// Radiance and alpha of a pixel, summed over its samples.
+pub(crate) type Sample = Vector4<f32>;
+/// Start of a [`Renderer::checkpoint`], changed along with its layout.
+const CHECKPOINT_MAGIC: u32 = u32::from_le_bytes(*b"RTC1");
+/// Words before the sums of a checkpoint.
+const CHECKPOINT_HEADER: usize = 7;
+/// Bounces before paths may be ended by Russian roulette.
+const ROULETTE_BOUNCES: usize = 3;
+/// Bounces glass paths may go on for beyond the maximum depth unless told otherwise.
+pub const DEFAULT_GLASS_DEPTH: usize = 16;
+/// Bounces of the paths of the reference integrator, past which next to no light is left.
+const REFERENCE_DEPTH: usize = 1024;
+/// Stack of the threads tracing reference paths, which recurse once per bounce and would run
+/// out of the 2 MiB threads get by default long before [`REFERENCE_DEPTH`].
+#[cfg(not(target_arch = "wasm32"))]
+const REFERENCE_STACK: usize = 256 << 20;
+/// Luminance of the throughput a path needs to go on through glass beyond the maximum depth.
+const GLASS_THROUGHPUT: f32 = 0.2;
+/// Lowest chance of a dim path to go on, so the few survivors do not turn into fireflies.
+const ROULETTE_MIN_SURVIVAL: f32 = 0.05;
+/// Rec. 709 weights of the color channels in the perceived brightness of the path.
+const LUMINANCE: Color = Color::new(0.2126, 0.7152, 0.0722);
+/// Side in pixels of the square tiles a pass is traced in.
+#[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+const TILE_SIZE: u32 = 16;
+/// Tiles traced at a time before they go into the pass or the image, so their samples are
+/// not held a second time for the whole image.
+#[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+const BAND_TILES: usize = 1024;
+/// Samples after a restart until the previous image has faded out when blending.
+const BLEND_SAMPLES: usize = 8;
+/// How much coarser the image is while the camera is being moved.
+#[cfg(feature = "window")]
+const INTERACTIVE_SCALE: u32 = 4;
+/// Passes before the noise of the image is estimated.
+const MIN_NOISE_PASSES: usize = 4;
+/// Luminance added to that of a pixel when relating its noise to it, so that the noise in
+/// nearly black pixels, which is hard to see, does not hold the render back.
+const NOISE_FLOOR: f32 = 0.05;
+/// Occlusion distance of the AO view, relative to the scene extent.
+const AO_RADIUS: f32 = 0.05;
+
+/// A single pixel of the current image, as shown by the GUI probe.
+#[derive(Clone, Debug)]
+pub struct PixelProbe {
+    pub x: usize,
+    pub y: usize,
+    /// Average of the accumulated samples.
+    pub radiance: Color,
+    /// Displayed value after post-processing.
+    pub rgb: [u8; 3],
+    /// Distance along the camera ray to the first hit.
+    pub depth: Option<f32>,
+    pub label: Option<String>,
+}
+
+/// A rectangle of frame pixels counted from the top left corner, `x1` and `y1` excluded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Region {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Region {
+    /// Scale a region given in fractions of the frame, `[x0, y0, x1, y1]`, to pixels.
+    pub fn from_normalized(rect: [f32; 4], width: u32, height: u32) -> Self {
+        let [x0, y0, x1, y1] = rect;
+        let to_pixels = |c: f32, d: u32| (c.clamp(0., 1.) * d as f32).round() as u32;
+        Self {
+            x0: to_pixels(x0.min(x1), width),
+            y0: to_pixels(y0.min(y1), height),
+            x1: to_pixels(x0.max(x1), width),
+            y1: to_pixels(y0.max(y1), height),
+        }
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    /// Parse `x0,y0,x1,y1` in pixels.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|c| c.trim().parse::<u32>().map_err(|e| format!("{}: {}", c, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let [x0, y0, x1, y1] = coords[..] {
+            if x0 < x1 && y0 < y1 {
+                return Ok(Self { x0, y0, x1, y1 })
+            }
+        }
+        Err(format!("expected x0,y0,x1,y1 with x0 < x1 and y0 < y1, got {}", s))
+    }
+}
+
+thread_local! {
+    /// When the pixel being traced on this thread has to be done by, and whether it ran late.
+    static WATCHDOG: Cell<(Option<instant::Instant>, bool)> = Cell::new((None, false));
+}
+
+/// Whether the pixel being traced ran past its deadline, looking at the clock every
+/// [`WATCHDOG_RAYS`] rays.
+fn stalled(rays: usize) -> bool {
+    WATCHDOG.with(|watchdog| {
+        let (deadline, stalled) = watchdog.get();
+        if stalled || rays % WATCHDOG_RAYS != 0 {
+            return stalled
+        }
+        let stalled = deadline.map_or(false, |deadline| instant::Instant::now() > deadline);
+        watchdog.set((deadline, stalled));
+        stalled
+    })
+}
+
+/// Progress of the image being accumulated, shown live in the GUI.
+#[derive(Clone, Copy, Default)]
+pub struct RenderStats {
+    pub samples_done: usize,
+    pub target: usize,
+    pub elapsed: instant::Duration,
+    pub rays: usize,
+    pub memory: MemoryUsage,
+    /// Relative noise of the image, once there are enough passes to estimate it.
+    pub noise: Option<f32>,
+}
+
+impl RenderStats {
+    pub fn rays_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0. { self.rays as f64 / secs } else { 0. }
+    }
+
+    /// Time left to reach the target, extrapolated from the average pass so far.
+    pub fn eta(&self) -> Option<instant::Duration> {
+        if self.samples_done == 0 {
+            return None
+        }
+        let remaining = self.target.saturating_sub(self.samples_done);
+        Some(self.elapsed.mul_f64(remaining as f64 / self.samples_done as f64))
+    }
+}
+
+pub fn format_duration(duration: instant::Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}min {}s", seconds / 60, seconds % 60)
+}
+
+/// Buffers reused by every pixel traced on a thread, so tracing does not allocate per pixel.
+#[derive(Default)]
+struct Scratch {
+    /// Light reaching the camera from every light group along the paths of the current pixel.
+    groups: Vec<Color>,
+}
+
+/// Pixels of the image from `x0, y0` up to `x1, y1`, rows counted from the bottom.
+#[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+/// Position of `(x, y)` along the Z-order curve, which keeps neighbouring pixels and tiles
+/// close in the order they are traced in, so consecutive rays walk the same BVH nodes.
+#[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+fn morton(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = v as u64;
+        v = (v | v << 16) & 0x0000_ffff_0000_ffff;
+        v = (v | v << 8) & 0x00ff_00ff_00ff_00ff;
+        v = (v | v << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | v << 2) & 0x3333_3333_3333_3333;
+        (v | v << 1) & 0x5555_5555_5555_5555
+    };
+    spread(x) | spread(y) << 1
+}
+
+/// The offsets within a tile in Z-order.
+#[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+fn morton_offsets() -> Vec<(u32, u32)> {
+    let mut offsets: Vec<_> = (0..TILE_SIZE).flat_map(|y| (0..TILE_SIZE).map(move |x| (x, y))).collect();
+    offsets.sort_by_key(|&(x, y)| morton(x, y));
+    offsets
 }
 
 impl Renderer {
+    /// Render `scene` seen through `camera` into frames of `width` by `height` RGBA8 pixels.
     pub fn new(width: u32, height: u32, scene: Scene, camera: Camera) -> Self {
         Self {
+            memory: scene.memory(),
             width,
             height,
             actual_width: width,
             scale: 1,
             camera,
             actual_height: height,
+            fixed_size: None,
+            locked_size: None,
             scene,
             multisample: 4,
             max_depth: 10,
+            glass_depth: DEFAULT_GLASS_DEPTH,
+            reference: false,
+            #[cfg(all(feature = "rayon", not(target_arch = "wasm32")))]
+            deep_pool: None,
             dirty: true,
+            accumulated: Accumulation::new(Precision::Full, 0),
+            partial_pass: vec![],
+            row_order: vec![],
+            next_row: 0,
+            samples_done: 0,
+            passes: 0,
+            squared: vec![],
+            noise_threshold: None,
+            pixel_budget: Some(DEFAULT_PIXEL_BUDGET),
+            stalled_pixels: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            paused: false,
+            render_time: instant::Duration::ZERO,
+            rays_traced: AtomicUsize::new(0),
+            region: None,
+            focus: None,
+            debug_view: DebugView::Off,
+            light_paths: LightPaths::All,
+            material_override: MaterialOverride::Off,
+            clay: Lambertian::from_color([CLAY_ALBEDO; 3]),
+            bounce: 1,
+            transparent: false,
+            guiding: false,
+            guide: None,
+            probe_count: 0,
+            probes: None,
+            environment: None,
+            object_stats: false,
+            object_rays: Mutex::new(HashMap::new()),
+            png16: false,
+            cryptomatte: false,
+            aovs: false,
+            bias: Bias::default(),
+            max_texture_size: None,
+            seed: rand_gen::random_seed(),
+            post: PostProcess::default(),
+            refresh: false,
+            blend: false,
+            previous: vec![],
+            blend_view: None,
+            #[cfg(feature = "window")]
+            fog: None,
+            #[cfg(feature = "window")]
+            background: None,
+            #[cfg(feature = "window")]
+            scene_source: None,
+            #[cfg(feature = "window")]
+            interactive: false,
         }
     }
 
@@ -58,68 +563,1168 @@ impl Renderer {
             return
         }
         self.dirty = false;
-        log::info!("{:?}", self);
+        let _span = tracing::info_span!("draw", samples = self.multisample).entered();
+        tracing::info!("{:?}", self);
+        let now = instant::Instant::now();
+        assert_eq!((frame.len() / 4) as u32, self.width * self.height);
+
+        self.reset_accumulation();
+        if self.guiding {
+            // One sample per pass, each drawing on what the ones before learned
+            while self.samples_done < self.multisample && self.accumulate(1) {}
+        } else {
+            self.accumulate(self.multisample);
+        }
+        self.write_frame(frame);
+
+        // Rays per second to compare builds by, e.g. with and without feature `enum-dispatch`
+        tracing::info!("Time: {}, {:.2} Mrays/s", format_duration(now.elapsed()), self.stats().rays_per_sec() / 1e6);
+    }
+
+    /// Samples per pixel to refine the image up to.
+    pub fn set_samples(&mut self, samples: usize) {
+        self.multisample = samples.max(1);
+    }
+
+    /// Bounces of a path before it is cut off, restarting the image.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+        self.dirty = true;
+    }
+
+    /// Only add up the light paths of `light_paths`, restarting the image.
+    pub fn set_light_paths(&mut self, light_paths: LightPaths, bounce: usize) {
+        self.light_paths = light_paths;
+        self.bounce = bounce;
+        self.dirty = true;
+    }
+
+    /// Show the scene with `material_override` instead of its materials, restarting the image.
+    pub fn set_material_override(&mut self, material_override: MaterialOverride) {
+        self.material_override = material_override;
+        self.dirty = true;
+    }
+
+    /// Offset rays off surfaces by `bias` instead, restarting the image.
+    pub fn set_bias(&mut self, bias: Bias) {
+        self.bias = bias;
+        self.dirty = true;
+    }
+
+    /// Look through `camera` instead, restarting the image.
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+        self.dirty = true;
+    }
+
+    /// Repeat the random numbers of an earlier render, restarting the image.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.dirty = true;
+    }
+
+    /// Write alpha 0 where the camera sees the background, restarting the image.
+    pub fn set_transparent(&mut self, transparent: bool) {
+        self.transparent = transparent;
+        self.dirty = true;
+    }
+
+    /// Accumulate the image in `precision`, e.g. [`Precision::Half`] to render large images in the
+    /// browser or on phones with half the memory.
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.accumulated = Accumulation::new(precision, 0);
+        self
+    }
+
+    /// Accumulate the image in `precision`, starting it over if that is another one.
+    pub fn set_precision(&mut self, precision: Precision) {
+        if precision != self.accumulated.precision() {
+            self.accumulated = Accumulation::new(precision, 0);
+            self.dirty = true;
+        }
+    }
+
+    /// Trace with the reference integrator or the usual one, see [`Self::reference`].
+    pub fn set_reference(&mut self, reference: bool) {
+        if reference != self.reference {
+            self.reference = reference;
+            self.dirty = true;
+        }
+        #[cfg(all(feature = "rayon", not(target_arch = "wasm32")))]
+        if reference && self.deep_pool.is_none() {
+            match rayon::ThreadPoolBuilder::new().stack_size(REFERENCE_STACK).build() {
+                Ok(pool) => self.deep_pool = Some(pool),
+                Err(e) => log::warn!("Tracing the reference paths on the default threads, which may run out of stack: {}", e),
+            }
+        }
+    }
+
+    /// Drop every accumulated sample, e.g. after the image size changed.
+    pub fn reset_accumulation(&mut self) {
+        self.accumulated = Accumulation::new(self.accumulated.precision(), self.buffer_len());
+        self.squared = vec![0.; (self.width * self.height) as usize];
+        self.partial_pass = vec![];
+        self.next_row = 0;
+        self.samples_done = 0;
+        self.passes = 0;
+        self.render_time = instant::Duration::ZERO;
+        self.rays_traced.store(0, Ordering::Relaxed);
+        self.guide = (self.guiding && !self.reference).then(|| Guide::new(&self.scene, &self.camera));
+        self.prepare();
+    }
+
+    /// Gather the light of the probes and start counting the rays of the objects anew.
+    fn prepare(&mut self) {
+        self.environment = self.scene.background.importance();
+        self.probes = None;
+        if self.probe_count > 0 {
+            let _span = tracing::info_span!("probes", count = self.probe_count).entered();
+            let radiance = |ray: &Ray| {
+                self.ray_color(ray, self.path_depth(), PathClass::Camera, Color::repeat(1.), &mut [], &mut 0)
+            };
+            let probes = self.on_tracing_threads(|| Probes::new(&self.scene, &self.camera, self.probe_count, radiance));
+            self.probes = Some(probes);
+        }
+        self.object_rays.lock().unwrap().clear();
+    }
+
+    /// Samples stored per pixel: the image, then one per light group.
+    #[inline]
+    fn stride(&self) -> usize {
+        1 + self.scene.light_groups.len()
+    }
+
+    fn buffer_len(&self) -> usize {
+        (self.width * self.height) as usize * self.stride()
+    }
+
+    pub(crate) fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// The scene to add objects to or remove them from; the image starts over.
+    pub(crate) fn scene_mut(&mut self) -> &mut Scene {
+        self.dirty = true;
+        &mut self.scene
+    }
+
+    pub(crate) fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// The ray through the center of the frame pixel `(x, y)`, counted from the top left corner.
+    fn pixel_ray(&self, x: usize, y: usize) -> Ray {
+        let u = x as f32 / (self.width - 1) as f32;
+        let v = (self.height as usize - 1 - y) as f32 / (self.height - 1) as f32;
+        self.camera.get_pinhole_ray(u, v)
+    }
+
+    /// Averaged radiance of the frame pixel `(x, y)`, counted from the top left corner.
+    pub fn radiance(&self, x: usize, y: usize) -> Option<Color> {
+        if x >= self.width as usize {
+            return None
+        }
+        let index = (y * self.width as usize + x) * self.stride();
+        Some(self.accumulated.average(index, self.samples_done)?.xyz())
+    }
+
+    /// Everything known about the frame pixel `(x, y)`, counted from the top left corner.
+    pub fn probe(&self, x: usize, y: usize) -> Option<PixelProbe> {
+        let radiance = self.radiance(x, y)?;
+        let hit = self.scene.pick(&self.pixel_ray(x, y));
+        Some(PixelProbe {
+            x,
+            y,
+            radiance,
+            rgb: self.post.apply(radiance).map(|c| self.post.to_u8(c)).into(),
+            depth: hit.as_ref().map(|(_, hit_record)| hit_record.t),
+            label: hit.map(|(i, _)| self.scene.object_name(i)),
+        })
+    }
+
+    /// The scene object under the frame pixel `(x, y)`, counted from the top left corner.
+    pub fn pick(&self, x: usize, y: usize) -> Option<usize> {
+        self.scene.pick(&self.pixel_ray(x, y)).map(|(i, hit_record)| {
+            log::info!(
+                "Picked {}: point {:?}, normal {:?}",
+                self.scene.object_name(i),
+                hit_record.point,
+                hit_record.normal
+            );
+            i
+        })
+    }
+
+    pub fn stats(&self) -> RenderStats {
+        RenderStats {
+            samples_done: self.samples_done,
+            target: self.multisample,
+            elapsed: self.render_time,
+            rays: self.rays_traced.load(Ordering::Relaxed),
+            memory: self.memory,
+            noise: self.noise(),
+        }
+    }
+
+    /// Count the rays every object stops from now on, or stop counting and forget the counts.
+    pub fn set_object_stats(&mut self, on: bool) {
+        self.object_stats = on;
+        self.object_rays.lock().unwrap().clear();
+    }
+
+    /// The objects of the scene by the rays they stopped so far, most first, when counted.
+    pub fn object_stats(&self) -> Vec<(String, ObjectRays)> {
+        let counts = self.object_rays.lock().unwrap();
+        if counts.is_empty() {
+            return vec![]
+        }
+        let mut stats: Vec<_> = self.scene.objects.iter().enumerate().filter_map(|(i, obj)| {
+            counts.get(&shared_id(obj)).map(|&count| (self.scene.object_name(i), count))
+        }).collect();
+        stats.sort_by_key(|(_, count)| std::cmp::Reverse(count.total()));
+        stats
+    }
+
+    /// Add the rays stopped by the objects on this thread to the counts, when counted.
+    fn gather_object_rays(&self) {
+        if !self.object_stats {
+            return
+        }
+        let counted = take_object_rays();
+        let mut counts = self.object_rays.lock().unwrap();
+        for (id, count) in counted {
+            let total = counts.entry(id).or_default();
+            total.hits += count.hits;
+            total.occlusions += count.occlusions;
+        }
+    }
+
+    /// Add one sample per pixel to the current image, restarting it first when dirty.
+    /// Returns whether `frame` was updated.
+    pub fn draw_progressive(&mut self, frame: &mut [u8]) -> bool {
+        if self.dirty {
+            self.dirty = false;
+            self.cancelled.store(false, Ordering::Relaxed);
+            // Only tweaks seen from the same view fade, a new one would show the old one as a ghost
+            let view = (self.camera.settings(), shared_id(&self.scene.world));
+            self.previous = if self.blend_view.as_ref() == Some(&view) { self.blend_source() } else { vec![] };
+            self.blend_view = Some(view);
+            self.reset_accumulation();
+        }
+        let refresh = std::mem::take(&mut self.refresh);
+        let idle = self.paused || self.is_cancelled() || self.samples_done >= self.multisample || self.converged();
+        if (!idle && self.accumulate_step()) || refresh {
+            self.show_frame(frame);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_post_process(&mut self, post: PostProcess) {
+        if post != self.post {
+            self.post = post;
+            self.refresh = true;
+        }
+    }
+
+    /// Write the frame again on the next draw even if no sample is added.
+    pub fn request_refresh(&mut self) {
+        self.refresh = true;
+    }
+
+    /// Abort the pass in flight and stop refining the current image until it is restarted.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Trace `samples` more rays per pixel and add them to the accumulation buffer.
+    /// A cancelled pass is discarded as a whole and `false` is returned, except in half precision,
+    /// where the pixels it reached keep their samples.
+    pub fn accumulate(&mut self, samples: usize) -> bool {
+        if self.accumulated.len() != self.buffer_len() {
+            self.reset_accumulation();
+        }
+        let _span = tracing::info_span!("accumulate", samples).entered();
         let now = instant::Instant::now();
-        let pixel_count = frame.len() / 4;
+        // Averages in half precision take the tiles as they come, without a pass beside them
+        let in_place = self.accumulated.precision() == Precision::Half
+            && cfg!(not(all(target_arch = "wasm32", feature = "web-threads")));
+        let mut pass = if in_place { vec![] } else { vec![Sample::zeros(); self.accumulated.len()] };
+        let row_len = self.width as usize * self.stride();
+        cfg_if! {
+            if #[cfg(all(target_arch = "wasm32", feature = "web-threads"))] {
+                crate::web_threads::for_each_row(&mut pass, row_len, |y, row| self.trace_row(y, row, samples));
+            } else {
+                let tiles = self.tiles();
+                let offsets = morton_offsets();
+                let stride = self.stride();
+                cfg_if! {
+                    if #[cfg(feature = "progress")] {
+                        use indicatif::{ProgressBar, ProgressStyle};
+                        let pb = ProgressBar::new(tiles.len() as u64);
+                        pb.set_style(
+                            ProgressStyle::default_bar().template("{spinner:.green} [{elapsed_precise}] {wide_bar} {per_sec} {pos}/{len} tiles eta: {eta}")
+                        );
+                        pb.set_draw_delta(if tiles.len() > 500 {10} else {1});
+                    }
+                }
+                // Every band is traced into the same buffer, a tile's worth for each of its tiles
+                let tile_len = (TILE_SIZE * TILE_SIZE) as usize * stride;
+                let mut traced = vec![Sample::zeros(); tile_len * BAND_TILES.min(tiles.len())];
+                for band in tiles.chunks(BAND_TILES) {
+                    self.trace_tiles(band, &offsets, samples, &mut traced);
+                    // The tiles begun after a cancel are blank
+                    if self.is_cancelled() {
+                        break
+                    }
+                    for (tile, sums) in band.iter().zip(traced.chunks(tile_len)) {
+                        let tile_row = (tile.x1 - tile.x0) as usize * stride;
+                        for (y, row) in (tile.y0..tile.y1).zip(sums.chunks_exact(tile_row)) {
+                            let start = (self.height - 1 - y) as usize * row_len + tile.x0 as usize * stride;
+                            if in_place {
+                                self.add_squared(start / stride, row, samples);
+                                self.accumulated.fold(start, row, self.samples_done, samples);
+                            } else {
+                                pass[start..start + tile_row].copy_from_slice(row);
+                            }
+                        }
+                    }
+                    #[cfg(feature = "progress")]
+                    pb.inc(band.len() as u64);
+                }
+            }
+        }
+        self.render_time += now.elapsed();
+        if self.is_cancelled() {
+            return false
+        }
+        if in_place {
+            self.finish_pass(samples);
+        } else {
+            self.add_pass(pass, samples);
+        }
+        true
+    }
 
-        assert_eq!(pixel_count as u32, self.width * self.height);
+    /// Add a finished pass of `samples` samples per pixel to the image.
+    fn add_pass(&mut self, pass: Vec<Sample>, samples: usize) {
+        self.add_squared(0, &pass, samples);
+        self.accumulated.add(pass, self.samples_done, samples);
+        self.finish_pass(samples);
+    }
+
+    /// Add the squared luminances of the pixels from the `start`th on, of which `sums` holds the
+    /// samples of a pass of `samples` per pixel, to those of the passes before.
+    fn add_squared(&mut self, start: usize, sums: &[Sample], samples: usize) {
+        let stride = self.stride();
+        self.squared[start..].iter_mut().zip(sums.chunks_exact(stride)).for_each(|(squared, pixel)| {
+            let luminance = pixel[0].xyz().dot(&LUMINANCE) / samples as f32;
+            *squared += luminance * luminance;
+        });
+    }
+
+    /// Count a pass of `samples` samples per pixel added to the image.
+    fn finish_pass(&mut self, samples: usize) {
+        let stalled = self.stalled_pixels.swap(0, Ordering::Relaxed);
+        if stalled > 0 {
+            log::warn!(
+                "Gave up on {} pixels taking more than {:?} per sample, shown in magenta",
+                stalled,
+                self.pixel_budget.unwrap_or_default()
+            );
+        }
+        self.samples_done += samples;
+        self.passes += 1;
+        if let Some(guide) = &mut self.guide {
+            guide.update();
+        }
+    }
+
+    /// The accumulated image, to carry on with by [`Self::resume`] after a restart.
+    pub fn checkpoint(&self) -> Vec<u8> {
+        let header = [
+            CHECKPOINT_MAGIC,
+            self.width,
+            self.height,
+            self.stride() as u32,
+            self.samples_done as u32,
+            self.passes as u32,
+            self.render_time.as_millis() as u32,
+        ];
+        let samples = self.samples_done as f32;
+        let sums = self.accumulated
+            .averages(self.samples_done)
+            .flat_map(|average| {
+                let sum = average * samples;
+                [sum.x, sum.y, sum.z, sum.w]
+            })
+            .chain(self.squared.iter().copied());
+        header.into_iter().chain(sums.map(f32::to_bits)).flat_map(u32::to_le_bytes).collect()
+    }
+
+    /// Carry on with the image of a [`Self::checkpoint`], if it was made at the same size and
+    /// with the same light groups. Returns whether it was.
+    pub fn resume(&mut self, checkpoint: &[u8]) -> bool {
+        let words: Vec<u32> = checkpoint.chunks_exact(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        let (header, sums) = words.split_at(words.len().min(CHECKPOINT_HEADER));
+        let expected = [CHECKPOINT_MAGIC, self.width, self.height, self.stride() as u32];
+        let pixels = (self.width * self.height) as usize;
+        if header.len() < CHECKPOINT_HEADER || header[..4] != expected || sums.len() != self.buffer_len() * 4 + pixels {
+            return false
+        }
+        let (accumulated, squared) = sums.split_at(self.buffer_len() * 4);
+        let sums = accumulated.chunks_exact(4).map(|sample| Sample::from_fn(|i, _| f32::from_bits(sample[i])));
+        self.accumulated = Accumulation::from_sums(self.accumulated.precision(), sums, header[4] as usize);
+        self.squared = squared.iter().copied().map(f32::from_bits).collect();
+        self.samples_done = header[4] as usize;
+        self.passes = header[5] as usize;
+        self.render_time = instant::Duration::from_millis(header[6] as u64);
+        self.partial_pass = vec![];
+        self.next_row = 0;
+        self.dirty = false;
+        true
+    }
 
-        let row_len = 4 * self.width as usize;
+    /// Average standard error of the pixel luminances relative to their brightness, estimated
+    /// from how much the passes differ. `None` until there are enough passes to tell.
+    pub fn noise(&self) -> Option<f32> {
+        if self.passes < MIN_NOISE_PASSES || self.squared.is_empty() {
+            return None
+        }
+        let passes = self.passes as f32;
+        let pixels = self.accumulated.averages(self.samples_done).step_by(self.stride());
+        let total: f32 = pixels.zip(&self.squared).map(|(pixel, squared)| {
+            // The passes have the same number of samples, so their mean is that of all the samples
+            let mean = pixel.xyz().dot(&LUMINANCE);
+            let variance = (squared / passes - mean * mean).max(0.) * passes / (passes - 1.);
+            (variance / passes).sqrt() / (mean.abs() + NOISE_FLOOR)
+        }).sum();
+        Some(total / self.squared.len() as f32)
+    }
+
+    /// Whether the image is as clean as the noise threshold asks for.
+    fn converged(&self) -> bool {
+        self.noise_threshold.map_or(false, |threshold| self.noise().map_or(false, |noise| noise <= threshold))
+    }
+
+    /// Whether the image reached its target and no more samples are added.
+    pub fn finished(&self) -> bool {
+        !self.dirty && self.samples_done > 0 && (self.samples_done >= self.multisample || self.converged())
+    }
+
+    /// Add one sample per pixel. Without worker threads the browser would freeze
+    /// for a whole pass, so there the pass is spread over several frames.
+    fn accumulate_step(&mut self) -> bool {
+        cfg_if! {
+            if #[cfg(all(target_arch = "wasm32", not(feature = "web-threads")))] {
+                // The rows of the first pass are shown as they come, the focus first
+                self.accumulate_sliced(WEB_TIME_SLICE) || self.samples_done == 0
+            } else {
+                self.accumulate(1)
+            }
+        }
+    }
+
+    /// Trace rows of a one sample pass until `budget` runs out, picking up where the last call stopped,
+    /// the rows nearest the focus first. Until the first pass is complete the image holds its rows traced so far.
+    /// Returns whether the pass was completed and added to the image.
+    #[allow(dead_code)]
+    pub fn accumulate_sliced(&mut self, budget: instant::Duration) -> bool {
+        let len = self.buffer_len();
+        if self.accumulated.len() != len {
+            self.reset_accumulation();
+        }
+        // Averages in half precision take the rows as they come, without a pass beside them
+        let in_place = self.accumulated.precision() == Precision::Half;
+        if self.next_row == 0 {
+            self.partial_pass = if in_place { vec![] } else { vec![Sample::zeros(); len] };
+            self.row_order = self.row_order();
+        }
+        let now = instant::Instant::now();
+        let (stride, rows) = (self.stride(), self.height as usize);
+        let row_len = self.width as usize * stride;
+        let mut pass = std::mem::take(&mut self.partial_pass);
+        while self.next_row < rows && now.elapsed() < budget && !self.is_cancelled() {
+            let y = self.row_order[self.next_row];
+            let start = (rows - 1 - y) * row_len;
+            if in_place {
+                let mut row = vec![Sample::zeros(); row_len];
+                self.trace_row(y, &mut row, 1);
+                self.add_squared(start / stride, &row, 1);
+                self.accumulated.fold(start, &row, self.samples_done, 1);
+            } else {
+                self.trace_row(y, &mut pass[start..start + row_len], 1);
+            }
+            self.next_row += 1;
+        }
+        self.render_time += now.elapsed();
+        if self.is_cancelled() {
+            self.next_row = 0;
+            return false
+        }
+        if self.samples_done == 0 && !in_place {
+            self.accumulated.show(&pass);
+        }
+        if self.next_row < rows {
+            self.partial_pass = pass;
+            return false
+        }
+        self.next_row = 0;
+        if in_place {
+            self.finish_pass(1);
+        } else {
+            if self.samples_done == 0 {
+                self.accumulated.clear();
+            }
+            self.add_pass(pass, 1);
+        }
+        true
+    }
+
+    /// The rows of the image counted from the bottom, nearest the focus first.
+    fn row_order(&self) -> Vec<usize> {
+        let mut rows: Vec<_> = (0..self.height as usize).collect();
+        if let Some((_, y)) = self.focus {
+            let focus = self.height.saturating_sub(1 + y) as usize;
+            rows.sort_by_key(|&row| (row as isize - focus as isize).abs());
+        }
+        rows
+    }
+
+    /// Trace `samples` rays through every pixel of the row `y`, counted from the bottom.
+    fn trace_row(&self, y: usize, row: &mut [Sample], samples: usize) {
+        let _span = tracing::trace_span!("trace_row", y, samples).entered();
+        if self.is_cancelled() {
+            return
+        }
+        let mut rays = 0;
+        let mut scratch = Scratch::default();
+        row.chunks_exact_mut(self.stride()).enumerate().for_each(|(x, pixel)|
+            self.trace_pixel(x as u32, y as u32, pixel, samples, &mut scratch, &mut rays)
+        );
+        rand_gen::unseed();
+        self.rays_traced.fetch_add(rays, Ordering::Relaxed);
+        self.gather_object_rays();
+    }
+
+    /// Trace [`Self::multisample`] rays through every pixel a strip of rows at a time, writing the
+    /// strips to a tiled EXR at `path` as they are done, for images far too large to be kept in
+    /// memory. Only the image and its alpha are written: neither light groups, AOVs nor the
+    /// post-processing of the whole frame, and paths are not guided.
+    #[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+    pub fn stream_exr(&mut self, path: &std::path::Path) -> crate::Result<()> {
+        use crate::output::STREAM_TILE;
+        let _span = tracing::info_span!("stream_exr", width = self.width, height = self.height).entered();
+        let now = instant::Instant::now();
+        self.samples_done = 0;
+        self.guide = None;
+        self.prepare();
+        let samples = self.multisample;
+        let mut metadata = self.metadata();
+        metadata.retain(|(key, _)| key != "SPP");
+        metadata.push(("SPP".into(), samples.to_string()));
+        let offsets = morton_offsets();
+        let (width, height, stride) = (self.width, self.height, self.stride());
+        let this = &*self;
+        let strip = |index: usize| {
+            let _span = tracing::info_span!("strip", index).entered();
+            // Rows of the strip counted from the top, and from the bottom as the tiles count them
+            let top = index as u32 * STREAM_TILE as u32;
+            let bottom = (top + STREAM_TILE as u32).min(height);
+            let (y0, y1) = (height - bottom, height - top);
+            let tiles: Vec<_> = (y0..y1).step_by(TILE_SIZE as usize).flat_map(|y| {
+                (0..width).step_by(TILE_SIZE as usize).map(move |x| Tile {
+                    x0: x,
+                    y0: y,
+                    x1: (x + TILE_SIZE).min(width),
+                    y1: (y + TILE_SIZE).min(y1),
+                })
+            }).collect();
+            let tile_len = (TILE_SIZE * TILE_SIZE) as usize * stride;
+            let mut traced = vec![Sample::zeros(); tile_len * tiles.len()];
+            this.trace_tiles(&tiles, &offsets, samples, &mut traced);
+            let mut pixels = vec![(Color::zeros(), 0.); (width * (bottom - top)) as usize];
+            for (tile, sums) in tiles.iter().zip(traced.chunks(tile_len)) {
+                let tile_width = (tile.x1 - tile.x0) as usize;
+                for (y, row) in (tile.y0..tile.y1).zip(sums.chunks_exact(tile_width * stride)) {
+                    let start = (height - 1 - y - top) as usize * width as usize + tile.x0 as usize;
+                    pixels[start..start + tile_width].iter_mut().zip(row.chunks_exact(stride)).for_each(|(pixel, sum)| {
+                        let average = sum[0] / samples.max(1) as f32;
+                        *pixel = (average.xyz(), average.w);
+                    });
+                }
+            }
+            pixels
+        };
+        crate::output::stream_exr(path, width, height, self.transparent, &metadata, strip)?;
+        self.render_time += now.elapsed();
+        let stalled = self.stalled_pixels.swap(0, Ordering::Relaxed);
+        if stalled > 0 {
+            log::warn!("Gave up on {} pixels taking more than {:?} per sample", stalled, self.pixel_budget.unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// The tiles covering the image, in Z-order, or nearest the focus first if there is one.
+    #[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+    fn tiles(&self) -> Vec<Tile> {
+        let [columns, rows] = [self.width, self.height].map(|size| (size + TILE_SIZE - 1) / TILE_SIZE);
+        let mut tiles: Vec<_> = (0..rows).flat_map(|j| (0..columns).map(move |i| (i, j))).collect();
+        tiles.sort_by_key(|&(i, j)| morton(i, j));
+        if let Some((x, y)) = self.focus {
+            let focus = [x / TILE_SIZE, self.height.saturating_sub(1 + y) / TILE_SIZE];
+            // Stable, so tiles as far from the focus stay in Z-order
+            tiles.sort_by_key(|&(i, j)| (i as i64 - focus[0] as i64).pow(2) + (j as i64 - focus[1] as i64).pow(2));
+        }
+        tiles.into_iter().map(|(i, j)| Tile {
+            x0: i * TILE_SIZE,
+            y0: j * TILE_SIZE,
+            x1: ((i + 1) * TILE_SIZE).min(self.width),
+            y1: ((j + 1) * TILE_SIZE).min(self.height),
+        }).collect()
+    }
+
+    /// Trace [`Self::trace_tile`] every one of `tiles` into its share of `traced`, a full tile's
+    /// worth each, on the threads of the pool with a [`Scratch`] per thread.
+    #[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+    fn trace_tiles(&self, tiles: &[Tile], offsets: &[(u32, u32)], samples: usize, traced: &mut [Sample]) {
+        let tile_len = (TILE_SIZE * TILE_SIZE) as usize * self.stride();
+        self.on_tracing_threads(|| {
+            cfg_if! {
+                if #[cfg(feature = "rayon")] {
+                    use rayon::prelude::*;
+                    tiles.par_iter().zip(traced.par_chunks_mut(tile_len)).for_each_init(Scratch::default, |scratch, (tile, traced)| {
+                        self.trace_tile(tile, offsets, samples, scratch, traced)
+                    });
+                } else {
+                    let mut scratch = Scratch::default();
+                    tiles.iter().zip(traced.chunks_mut(tile_len)).for_each(|(tile, traced)| {
+                        self.trace_tile(tile, offsets, samples, &mut scratch, traced)
+                    });
+                }
+            }
+        })
+    }
+
+    /// Run `trace` where the paths it traces have the stack they need: for [`Self::reference`]
+    /// paths, on the threads of [`Self::deep_pool`] or a thread of [`REFERENCE_STACK`] without
+    /// rayon, for the others right here. In the browser the paths stay on the threads at hand.
+    fn on_tracing_threads<R: Send>(&self, trace: impl FnOnce() -> R + Send) -> R {
+        if !self.reference {
+            return trace()
+        }
+        cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                trace()
+            } else if #[cfg(feature = "rayon")] {
+                match &self.deep_pool {
+                    Some(pool) => pool.install(trace),
+                    None => trace(),
+                }
+            } else {
+                std::thread::scope(|scope| {
+                    std::thread::Builder::new()
+                        .stack_size(REFERENCE_STACK)
+                        .spawn_scoped(scope, trace)
+                        .expect("a thread to trace the reference paths on")
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+            }
+        }
+    }
+
+    /// Trace `samples` rays through every pixel of `tile`, visiting them in the Z-order of
+    /// `offsets`, into `traced` row by row from the bottom of the tile.
+    #[cfg(not(all(target_arch = "wasm32", feature = "web-threads")))]
+    fn trace_tile(&self, tile: &Tile, offsets: &[(u32, u32)], samples: usize, scratch: &mut Scratch, traced: &mut [Sample]) {
+        let _span = tracing::trace_span!("trace_tile", x = tile.x0, y = tile.y0, samples).entered();
+        let stride = self.stride();
+        let width = (tile.x1 - tile.x0) as usize;
+        // The buffer is that of the tile traced before, and pixels out of the region are skipped
+        let traced = &mut traced[..width * (tile.y1 - tile.y0) as usize * stride];
+        traced.fill(Sample::zeros());
+        if self.is_cancelled() {
+            return
+        }
+        let mut rays = 0;
+        for &(dx, dy) in offsets {
+            let (x, y) = (tile.x0 + dx, tile.y0 + dy);
+            if x >= tile.x1 || y >= tile.y1 {
+                continue
+            }
+            let start = (dy as usize * width + dx as usize) * stride;
+            self.trace_pixel(x, y, &mut traced[start..start + stride], samples, scratch, &mut rays);
+        }
+        rand_gen::unseed();
+        self.rays_traced.fetch_add(rays, Ordering::Relaxed);
+        self.gather_object_rays();
+    }
+
+    /// Trace `samples` rays through the pixel `(x, y)`, counted from the bottom left corner,
+    /// into its image and light group sums.
+    fn trace_pixel(&self, x: u32, y: u32, pixel: &mut [Sample], samples: usize, scratch: &mut Scratch, rays: &mut usize) {
+        if !self.in_region(x, self.height - 1 - y) {
+            return
+        }
+        let sanitize = |x: Sample| x.map(|c| if c.is_nan() {0.} else if c.is_infinite() {1.} else {c});
+        // The rotation of the sequence stays with the pixel, so every pass carries on its stratification
+        rand_gen::seed(hash_seed(&[self.seed, x as u64, y as u64]));
+        let mut sampler = Sampler::new(self.samples_done);
+        rand_gen::seed(hash_seed(&[self.seed, self.samples_done as u64, x as u64, y as u64]));
+        let groups = &mut scratch.groups;
+        groups.clear();
+        groups.resize(pixel.len() - 1, Color::zeros());
+        // A budget too long to add up is no deadline at all
+        let deadline = self.pixel_budget
+            .and_then(|budget| budget.checked_mul(u32::try_from(samples).ok()?))
+            .and_then(|budget| instant::Instant::now().checked_add(budget));
+        WATCHDOG.with(|watchdog| watchdog.set((deadline, false)));
+        pixel[0] = (0..samples)
+            .map(|_| {
+                let sample = sampler.next_camera();
+                let [u, v] = self.cal_norm_coords(x, y, sample.film);
+                let ray = self.camera.sample_ray(u, v, sample.lens, sample.time)
+                    .with_bias(self.bias)
+                    .with_counted(self.object_stats);
+                let probe = self.probes
+                    .as_ref()
+                    .and_then(|probes| probes.hit(&ray))
+                    .filter(|&(t, _)| !self.scene.world.read().unwrap().hit_any(&ray, 0., t));
+                let color = if let Some((_, irradiance)) = probe {
+                    *rays += 1;
+                    irradiance
+                } else if self.debug_view == DebugView::Off {
+                    self.ray_color(&ray, self.path_depth(), PathClass::Camera, Color::repeat(1.), groups, rays)
+                } else {
+                    *rays += 1;
+                    self.debug_color(&ray)
+                };
+                let alpha = if self.transparent { self.coverage(&ray) } else { 1. };
+                color.push(alpha)
+            })
+            .map(sanitize)
+            .fold(Sample::zeros(), |acc, next| acc + next);
+        if WATCHDOG.with(|watchdog| watchdog.replace((None, false)).1) {
+            self.stalled_pixels.fetch_add(1, Ordering::Relaxed);
+            pixel[0] = STALLED_COLOR.push(1.) * samples as f32;
+            groups.iter_mut().for_each(|group| *group = Color::zeros());
+        }
+        pixel[1..].iter_mut().zip(groups.iter()).for_each(|(sum, group)| *sum = sanitize(group.push(0.)));
+    }
+
+    /// `f` of every pixel `(x, y)`, counted from the bottom left corner, row by row from the top.
+    fn per_pixel<T: Send>(&self, f: impl Fn(u32, u32) -> T + Sync + Send) -> Vec<T> {
+        let width = self.width as usize;
+        let pixel = |i: usize| f((i % width) as u32, self.height - 1 - (i / width) as u32);
+        let pixels = 0..width * self.height as usize;
         cfg_if! {
             if #[cfg(feature = "rayon")] {
                 use rayon::prelude::*;
-                log::info!("Rayon enabled.");
-                let iter = frame.par_chunks_exact_mut(row_len);
+                let values = pixels.into_par_iter().map(pixel).collect();
             } else {
-                let iter = frame.chunks_exact_mut(row_len);
+                let values = pixels.map(pixel).collect();
             }
         }
+        values
+    }
 
-        cfg_if! {
-            if #[cfg(feature = "progress")] {
-                use indicatif::{ProgressBar, ProgressStyle};
-                cfg_if! {
-                    if #[cfg(feature = "rayon")] {
-                        use indicatif::ParallelProgressIterator;
-                    } else {
-                        use indicatif::ProgressIterator;
+    /// The ray through the pixel `(x, y)` from the center of the lens, when the shutter opens.
+    fn center_ray(&self, x: u32, y: u32) -> Ray {
+        let [u, v] = self.cal_norm_coords(x, y, [0.; 2]);
+        self.camera.sample_ray(u, v, [0.; 2], 0.)
+    }
+
+    /// Where the ray through each pixel from the center of the lens first meets the scene and
+    /// how far it went, row by row from the top; `None` where it sees the background.
+    pub fn primary_hits(&self) -> Vec<Option<(f32, Point3<f32>)>> {
+        self.per_pixel(|x, y| {
+            self.scene.world.read().unwrap().hit(&self.center_ray(x, y), 0., f32::INFINITY).map(|rec| (rec.t, rec.point))
+        })
+    }
+
+    /// Average radiance of the pixels seeing an object rather than the background. Under the white
+    /// environment of the `furnace` scene it is the share of the light the object sends back,
+    /// at most 1 for materials conserving energy.
+    pub fn surface_average(&self) -> Color {
+        let hits = self.primary_hits();
+        let (sum, count) = self.linear_frame()
+            .into_iter()
+            .zip(hits)
+            .filter(|(_, hit)| hit.is_some())
+            .fold((Color::zeros(), 0), |(sum, count), (color, _)| (sum + color, count + 1));
+        sum / count.max(1) as f32
+    }
+
+    /// The normal, depth, albedo and object ID of the first surface seen through every pixel, as
+    /// extra layers of EXR files. The background has no normal, albedo and object and is infinitely far.
+    pub fn aovs(&self) -> Vec<Aov> {
+        let _span = tracing::info_span!("aovs").entered();
+        let surfaces = self.per_pixel(|x, y| {
+            let ray = self.center_ray(x, y);
+            let hit_record = self.scene.world.read().unwrap().hit(&ray, 0., f32::INFINITY)?;
+            let albedo = hit_record.material.read().unwrap()
+                .scatter(&ray, &hit_record)
+                .map_or(Color::zeros(), |scatter| scatter.attenuation);
+            Some((hit_record.normal.into_inner(), hit_record.t, albedo, hit_record.object))
+        });
+        let normal = surfaces.iter().flat_map(|surface| surface.map_or([0.; 3], |(normal, _, _, _)| normal.into())).collect();
+        let depth = surfaces.iter().map(|surface| surface.map_or(f32::INFINITY, |(_, t, _, _)| t)).collect();
+        let albedo = surfaces.iter().flat_map(|surface| surface.map_or([0.; 3], |(_, _, albedo, _)| albedo.into())).collect();
+        // 0 for the background, the scene IDs counted from 1
+        let object = surfaces.iter().map(|surface| surface.and_then(|(_, _, _, id)| id).map_or(0., |id| (id + 1) as f32)).collect();
+        vec![
+            Aov { name: "normal".into(), channels: &["X", "Y", "Z"], values: normal },
+            Aov { name: "depth".into(), channels: &["Z"], values: depth },
+            Aov { name: "albedo".into(), channels: &["R", "G", "B"], values: albedo },
+            Aov { name: "object".into(), channels: &["ID"], values: object },
+        ]
+    }
+
+    /// How much of every pixel each visible top-level object covers, from a grid of
+    /// [`MATTE_GRID`] by [`MATTE_GRID`] rays through it.
+    pub fn object_mattes(&self) -> ObjectMattes {
+        let _span = tracing::info_span!("object_mattes").entered();
+        let samples = MATTE_GRID * MATTE_GRID;
+        let coverage = self.per_pixel(|x, y| {
+            let mut seen: Vec<(usize, f32)> = vec![];
+            for sample in 0..samples {
+                let jitter = [sample % MATTE_GRID, sample / MATTE_GRID].map(|j| (j as f32 + 0.5) / MATTE_GRID as f32);
+                let [u, v] = self.cal_norm_coords(x, y, jitter);
+                let ray = self.camera.sample_ray(u, v, [0.; 2], 0.);
+                let closest = self.scene.world.read().unwrap().hit(&ray, 0., f32::INFINITY);
+                if let Some(object) = closest.and_then(|rec| self.scene.object_index(rec.object?)) {
+                    match seen.iter_mut().find(|(seen, _)| *seen == object) {
+                        Some((_, coverage)) => *coverage += 1. / samples as f32,
+                        None => seen.push((object, 1. / samples as f32)),
                     }
                 }
-                let pb = ProgressBar::new(self.height as u64);
-                pb.set_style(
-                    ProgressStyle::default_bar().template("{spinner:.green} [{elapsed_precise}] {wide_bar} {per_sec} {pos}/{len} rows eta: {eta}")
-                );
-                pb.set_draw_delta(if self.height > 500 {10} else {1});
-                let iter = iter.progress_with(pb);
-            }
-        }
-
-        iter.rev().enumerate().for_each(|(y, row)| {
-            row.chunks_exact_mut(4).enumerate().for_each(|(x, pixel)|
-                {
-                    let rgba_float = (0..self.multisample)
-                        .map(|_| {
-                            let [u, v] = self.cal_norm_coords(x as u32, y as u32);
-                            let ray = self.camera.get_ray(u, v);
-                            self.ray_color(&ray, self.max_depth)
-                        })
-                        .map(|x|
-                            Vector3::from_vec(x.iter().map(|&c| if c.is_nan() {0.} else if c.is_infinite() {1.} else {c}).collect::<Vec<_>>())
-                        )
-                        .fold(Vector3::zeros(), |acc, next| acc + next)
-                        / self.multisample as f32;
-                    let mut rgb = rgba_float
-                        .into_iter()
-                        .map(Self::float_to_rgb)
-                        .collect::<Vec<_>>();
-                    rgb.push(0xff);
-                    pixel.copy_from_slice(&rgb);
+            }
+            seen.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            seen
+        });
+        ObjectMattes {
+            names: (0..self.scene.objects.len()).map(|i| self.scene.object_name(i)).collect(),
+            coverage,
+        }
+    }
+
+    /// Bake the light falling on `object` into a `width` by `height` map of its texture coordinates,
+    /// row by row from the top as in images. Each texel holds the radiance a white diffuse surface
+    /// would reflect there, from `multisample` rays leaving the point under it; texels the shape
+    /// cannot place stay black.
+    pub fn bake(&self, object: &SharedHittable, width: u32, height: u32) -> Vec<Color> {
+        let _span = tracing::info_span!("bake", width, height).entered();
+        let bake_texel = |i: usize| {
+            let (x, y) = (i % width as usize, i / width as usize);
+            rand_gen::seed(hash_seed(&[self.seed, x as u64, y as u64]));
+            let mut groups = vec![Color::zeros(); self.stride() - 1];
+            let mut rays = 0;
+            let sum = (0..self.multisample).fold(Color::zeros(), |sum, _| {
+                let uv = [(x as f32 + get_rand()) / width as f32, 1. - (y as f32 + get_rand()) / height as f32];
+                let surface = match object.read().unwrap().surface_at(uv) {
+                    Some(surface) => surface,
+                    None => return sum,
+                };
+                let direction = ONB::build_from_w(surface.normal).local_dir(random_cosine_direction());
+                let offset = self.bias.origin_error(surface.point.coords.amax()) * surface.normal.into_inner();
+                let ray = Ray::new(surface.point + offset, direction, 0.).with_kind(RayKind::Diffuse).with_bias(self.bias);
+                let color = self.ray_color(&ray, self.path_depth(), PathClass::Diffuse, Color::repeat(1.), &mut groups, &mut rays);
+                sum + color.map(|c| if c.is_finite() { c } else { 0. })
+            });
+            rand_gen::unseed();
+            self.rays_traced.fetch_add(rays, Ordering::Relaxed);
+            sum / self.multisample.max(1) as f32
+        };
+        let texels = 0..(width * height) as usize;
+        self.on_tracing_threads(|| {
+            cfg_if! {
+                if #[cfg(feature = "rayon")] {
+                    use rayon::prelude::*;
+                    texels.into_par_iter().map(bake_texel).collect()
+                } else {
+                    texels.map(bake_texel).collect()
                 }
-            )
+            }
+        })
+    }
+
+    #[inline]
+    fn in_region(&self, x: u32, y: u32) -> bool {
+        self.region.map_or(true, |region| region.contains(x, y))
+    }
+
+    /// The averaged samples in linear radiance, row by row from the top.
+    pub fn linear_frame(&self) -> Vec<Color> {
+        self.group_frame(0)
+    }
+
+    /// Averaged samples of the `slot`th sample of every pixel: the image or a light group.
+    fn group_frame(&self, slot: usize) -> Vec<Color> {
+        self.accumulated.averages(self.samples_done).skip(slot).step_by(self.stride()).map(|sample| sample.xyz()).collect()
+    }
+
+    /// The light reaching the camera from every light group, named after the group.
+    /// Added up they make [`Self::linear_frame`], without the shadow catcher shadows in the alpha.
+    pub fn light_group_frames(&self) -> Vec<(String, Vec<Color>)> {
+        self.scene.light_groups
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), self.group_frame(i + 1)))
+            .collect()
+    }
+
+    /// The averaged coverage of every pixel, empty unless the background is transparent.
+    pub fn alpha_frame(&self) -> Vec<f32> {
+        if !self.transparent {
+            return vec![]
+        }
+        self.accumulated.averages(self.samples_done).step_by(self.stride()).map(|sample| sample.w).collect()
+    }
+
+    /// Render settings stored next to saved images.
+    pub fn metadata(&self) -> Metadata {
+        vec![
+            ("Software".into(), format!("ray_tracing {}", env!("CARGO_PKG_VERSION"))),
+            ("Scene".into(), self.scene.label.clone()),
+            ("SPP".into(), self.samples_done.to_string()),
+            ("Max depth".into(), self.max_depth.to_string()),
+            ("Materials".into(), self.material_override.to_str().into()),
+            ("Light paths".into(), match self.light_paths {
+                LightPaths::Bounce => format!("bounce {}", self.bounce),
+                paths => paths.to_str().into(),
+            }),
+            ("Camera".into(), format!("{:?}", self.camera.settings())),
+            ("Seed".into(), self.seed.to_string()),
+            ("Bias".into(), format!("{:?}", self.bias)),
+            ("Light groups".into(), self.scene.light_groups.join(", ")),
+        ]
+    }
+
+    /// Everything needed to render the image again, saved as JSON next to it.
+    pub fn sidecar(&self) -> serde_json::Value {
+        serde_json::json!({
+            "software": "ray_tracing",
+            "version": env!("CARGO_PKG_VERSION"),
+            "scene": self.scene.label,
+            "scene_hash": self.scene.hash.map(|hash| format!("{:016x}", hash)),
+            "scene_seed": self.scene.seed,
+            "seed": self.seed,
+            "width": self.width,
+            "height": self.height,
+            "samples": self.samples_done,
+            "max_depth": self.max_depth,
+            "glass_depth": self.glass_depth,
+            "light_paths": self.light_paths,
+            "material_override": self.material_override,
+            "bias": self.bias,
+            "bounce": self.bounce,
+            "debug_view": self.debug_view,
+            "transparent": self.transparent,
+            "guiding": self.guiding,
+            "reference": self.reference,
+            "probes": self.probe_count,
+            "light_groups": self.scene.light_groups,
+            "region": self.region.map(|r| [r.x0, r.y0, r.x1, r.y1]),
+            "camera": self.camera.settings(),
+            "post": format!("{:?}", self.post),
+            "render_time_secs": self.render_time.as_secs_f64(),
+            "rays": self.rays_traced.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Save `frame` with its linear counterpart and render settings.
+    pub fn save_image(&self, frame: &[u8], path: &std::path::Path) -> crate::Result<()> {
+        let linear = self.linear_frame();
+        let alpha = self.alpha_frame();
+        let light_groups = self.light_group_frames();
+        let rgba16 = if self.png16 || ImageFormat::from_path(path) == Some(ImageFormat::Tiff) {
+            self.frame16()
+        } else {
+            vec![]
+        };
+        let exr = ImageFormat::from_path(path) == Some(ImageFormat::Exr);
+        let aovs = if self.aovs && exr { self.aovs() } else { vec![] };
+        let mattes = ((self.cryptomatte || self.aovs) && exr).then(|| self.object_mattes());
+        Image {
+            width: self.width,
+            height: self.height,
+            rgba: frame,
+            rgba16: &rgba16,
+            linear: &linear,
+            alpha: &alpha,
+            light_groups: &light_groups,
+            aovs: &aovs,
+            mattes: mattes.as_ref(),
+            transfer: self.post.output_transfer(),
+            metadata: self.metadata(),
+        }.save(path)?;
+        let sidecar = format!("{}.json", path.display());
+        std::fs::write(&sidecar, serde_json::to_vec_pretty(&self.sidecar())?)
+            .map_err(|e| crate::Error::from(e).in_file(sidecar))
+    }
+
+    /// Write a lightmap from [`Self::bake`] of `object`, post-processed as the renders are.
+    pub fn save_bake(&self, object: &str, baked: &[Color], width: u32, height: u32, path: &std::path::Path) -> crate::Result<()> {
+        let colors: Vec<_> = baked.iter().map(|&c| self.post.apply(c)).collect();
+        let rgba: Vec<_> = colors.iter().flat_map(|c| [self.post.to_u8(c.x), self.post.to_u8(c.y), self.post.to_u8(c.z), 0xff]).collect();
+        let rgba16: Vec<_> = if self.png16 || ImageFormat::from_path(path) == Some(ImageFormat::Tiff) {
+            colors.iter().flat_map(|c| [self.post.to_u16(c.x), self.post.to_u16(c.y), self.post.to_u16(c.z), u16::MAX]).collect()
+        } else {
+            vec![]
+        };
+        // Nothing of the camera or of its accumulated image goes into the lightmap
+        let mut metadata = self.metadata();
+        metadata.retain(|(key, _)| key != "SPP" && key != "Camera");
+        metadata.push(("SPP".into(), self.multisample.to_string()));
+        metadata.push(("Bake".into(), format!("{}, {}x{} texels", object, width, height)));
+        Image {
+            width,
+            height,
+            rgba: &rgba,
+            rgba16: &rgba16,
+            linear: baked,
+            alpha: &[],
+            light_groups: &[],
+            aovs: &[],
+            mattes: None,
+            transfer: self.post.output_transfer(),
+            metadata,
+        }.save(path)
+    }
+
+    /// Render the image and encode it as PNG entirely in memory, 16 bit with `png16`,
+    /// for embedding without a filesystem.
+    pub fn render_png_bytes(&mut self) -> crate::Result<Vec<u8>> {
+        let mut frame = vec![0; (self.width * self.height * 4) as usize];
+        self.dirty = true;
+        self.draw(&mut frame);
+        let rgba16 = if self.png16 { self.frame16() } else { vec![] };
+        self.png_image(&frame, &rgba16).to_png()
+    }
+
+    /// Encode `frame` as PNG with the render settings.
+    #[allow(dead_code)]
+    pub fn encode_png(&self, frame: &[u8]) -> crate::Result<Vec<u8>> {
+        self.png_image(frame, &[]).to_png()
+    }
+
+    fn png_image<'a>(&self, frame: &'a [u8], rgba16: &'a [u16]) -> Image<'a> {
+        Image {
+            width: self.width,
+            height: self.height,
+            rgba: frame,
+            rgba16,
+            linear: &[],
+            alpha: &[],
+            light_groups: &[],
+            aovs: &[],
+            mattes: None,
+            transfer: self.post.output_transfer(),
+            metadata: self.metadata(),
+        }
+    }
+
+    /// Average the accumulated samples into an RGBA8 frame.
+    /// Pixels outside the render region are left as they are.
+    pub fn write_frame(&self, frame: &mut [u8]) {
+        self.fill_frame(frame, false)
+    }
+
+    /// [`Self::write_frame`] for the window, fading from the image before the last restart when
+    /// blending. Saved images only ever have the samples of their own.
+    fn show_frame(&self, frame: &mut [u8]) {
+        self.fill_frame(frame, true)
+    }
+
+    fn fill_frame(&self, frame: &mut [u8], blend: bool) {
+        let width = self.width as usize;
+        let (colors, alpha) = self.display_frame(blend);
+        frame.chunks_exact_mut(4).zip(colors).enumerate().for_each(|(i, (pixel, color))| {
+            if !self.in_region((i % width) as u32, (i / width) as u32) {
+                return
+            }
+            if self.post.is_clipped(&color) {
+                pixel.copy_from_slice(&CLIPPED_COLOR);
+                return
+            }
+            let (x, y) = (i % width, i / width);
+            let mut rgb = color
+                .into_iter()
+                .enumerate()
+                .map(|(channel, c)| self.post.to_u8_at(*c, x, y, channel))
+                .collect::<Vec<_>>();
+            rgb.push(alpha.get(i).map_or(0xff, |&a| (a.clamp(0., 1.) * 255.).round() as u8));
+            pixel.copy_from_slice(&rgb);
         });
+    }
 
-        let seconds = now.elapsed().as_secs();
-        log::info!("Time: {}min {}s", seconds / 60, seconds % 60);
+    /// The post-processed colors before they are quantized, in straight alpha, and the alpha,
+    /// faded from the image before the last restart if `blend`.
+    fn display_frame(&self, blend: bool) -> (Vec<Color>, Vec<f32>) {
+        let mut linear = self.linear_frame();
+        if blend {
+            self.blend_previous(&mut linear);
+        }
+        if self.post.denoise {
+            linear = denoise(&linear, self.width as usize, self.height as usize);
+        }
+        if self.post.bloom > 0. {
+            linear = bloom(&linear, self.width as usize, self.height as usize, self.post.bloom);
+        }
+        if self.post.vignette > 0. || self.post.chromatic_aberration != 0. {
+            let (width, height) = (self.width as usize, self.height as usize);
+            linear = lens_effects(&linear, width, height, self.post.vignette, self.post.chromatic_aberration);
+        }
+        let alpha = self.alpha_frame();
+        let width = self.width as usize;
+        let colors = linear.into_iter().enumerate().map(|(i, radiance)| {
+            // PNG wants straight alpha
+            let radiance = match alpha.get(i) {
+                Some(&a) if a > 0. => radiance / a,
+                _ => radiance,
+            };
+            self.post.film_grain(self.post.apply(radiance), i % width, i / width)
+        }).collect();
+        (colors, alpha)
+    }
+
+    /// The image to fade out from after a restart: the one shown, itself maybe still blending
+    /// when restarts follow each other quickly.
+    fn blend_source(&self) -> Vec<Color> {
+        if !self.blend || (self.samples_done == 0 && self.previous.is_empty()) {
+            return vec![]
+        }
+        let mut linear = self.linear_frame();
+        self.blend_previous(&mut linear);
+        linear
+    }
+
+    /// Mix the image from before the last restart into `linear` as if it were worth a few samples,
+    /// fewer with every pass, so small tweaks do not flash the preview back to heavy noise.
+    fn blend_previous(&self, linear: &mut [Color]) {
+        let weight = BLEND_SAMPLES.saturating_sub(self.samples_done) as f32;
+        if !self.blend || weight == 0. || self.previous.len() != linear.len() {
+            return
+        }
+        let samples = self.samples_done as f32;
+        linear.iter_mut().zip(&self.previous).for_each(|(color, previous)| {
+            *color = (*color * samples + previous * weight) / (samples + weight);
+        });
+    }
+
+    /// The whole frame with 16 bits per channel, keeping the gradations of dark areas.
+    pub fn frame16(&self) -> Vec<u16> {
+        let (colors, alpha) = self.display_frame(false);
+        colors.iter().enumerate().flat_map(|(i, color)| {
+            let a = alpha.get(i).map_or(u16::MAX, |&a| (a.clamp(0., 1.) * 65535.).round() as u16);
+            [self.post.to_u16(color.x), self.post.to_u16(color.y), self.post.to_u16(color.z), a]
+        }).collect()
     }
 
     #[allow(dead_code)]
@@ -131,11 +1736,6 @@ impl Renderer {
         Vector4::from([color, color, color, 1.])
     }
 
-    #[inline]
-    fn float_to_rgb(num: &f32) -> u8 {
-        (num.max(0.).min(0.999).sqrt() * 256.) as u8
-    }
-
     #[inline]
     fn norm_to_integer(&self, u: f32, v: f32) -> (u32, u32) {
         let x = (u * (self.width as f32 - 1.)) as u32;
@@ -155,8 +1755,17 @@ impl Renderer {
         pixels.resize_surface(width, height);
         self.actual_height = height;
         self.actual_width = width;
-        self.width = width / self.scale;
-        self.height = height / self.scale;
+        // The surface alone follows a locked window, scaling the same image into it
+        let (width, height) = self.locked_size.unwrap_or((width, height));
+        let (width, height) = self.fixed_size.unwrap_or((width / self.scale, height / self.scale));
+        let factor = if self.interactive { INTERACTIVE_SCALE } else { 1 };
+        let (width, height) = ((width / factor).max(2), (height / factor).max(2));
+        if (width, height) == (self.width, self.height) {
+            // The frame shown and the samples behind it stay
+            return
+        }
+        self.width = width;
+        self.height = height;
         pixels.resize_buffer(self.width, self.height);
         self.camera.aspect_ratio = self.width as f32 / self.height as f32;
         self.camera.rebuild();
@@ -170,13 +1779,50 @@ impl Renderer {
         }
     }
 
+    /// Follow `camera` at a lower resolution while it is being moved, so navigating stays smooth
+    /// on slow machines, and go back to the full resolution once it is `None`.
+    #[cfg(feature = "window")]
+    pub(crate) fn set_interactive(&mut self, camera: Option<CameraSettings>, pixels: &mut Pixels) {
+        if camera.is_some() != self.interactive {
+            self.interactive = camera.is_some();
+            self.resize(self.actual_width, self.actual_height, pixels);
+            self.dirty = true;
+        }
+        if let Some(settings) = camera.filter(|settings| *settings != self.camera.settings()) {
+            self.camera = Camera::from_settings(&settings, self.camera.aspect_ratio);
+            self.dirty = true;
+        }
+    }
+
+    #[cfg(feature = "window")]
+    pub(crate) fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    /// Render at a fixed size instead of following the window. A buffer larger than
+    /// the window is shown cropped around its center, but saved in full.
+    #[cfg(feature = "window")]
+    pub(crate) fn update_fixed_size(&mut self, size: Option<(u32, u32)>, pixels: &mut Pixels) {
+        if size != self.fixed_size {
+            self.fixed_size = size;
+            self.resize(self.actual_width, self.actual_height, pixels);
+        }
+    }
+
+    /// Keep the size and so the aspect ratio of the image as they are now when the window is
+    /// resized, showing it letterboxed without starting it over, instead of following the window.
+    #[cfg(feature = "window")]
+    pub(crate) fn lock_aspect(&mut self, lock: bool, pixels: &mut Pixels) {
+        if lock != self.locked_size.is_some() {
+            self.locked_size = lock.then(|| (self.actual_width, self.actual_height));
+            self.resize(self.actual_width, self.actual_height, pixels);
+        }
+    }
+
+    /// Normalized image coordinates of the point `jitter` inside the pixel `(x, y)`.
     #[inline]
-    fn cal_norm_coords(&self, x: u32, y: u32) -> [f32; 2] {
-        let (x_offset, y_offset) = if self.multisample != 1 {
-            (get_rand(), get_rand())
-        } else {
-            (0., 0.)
-        };
+    fn cal_norm_coords(&self, x: u32, y: u32, jitter: [f32; 2]) -> [f32; 2] {
+        let [x_offset, y_offset] = if self.multisample != 1 { jitter } else { [0., 0.] };
         [
             (x as f32 + x_offset) / (self.width - 1) as f32,
             (y as f32 + y_offset) / (self.height - 1) as f32,
@@ -184,55 +1830,352 @@ impl Renderer {
     }
 
     #[cfg(feature = "window")]
-    pub fn update_from_gui(&mut self, gui: &Gui, pixels: &mut Pixels) {
+    pub fn update_from_gui(&mut self, gui: &mut Gui, pixels: &mut Pixels) {
         self.update_scale(gui.scale, pixels);
+        self.update_fixed_size(gui.resolution.size(gui.custom_size), pixels);
+        self.lock_aspect(gui.lock_aspect, pixels);
         self.multisample = gui.sample_count;
         self.max_depth = gui.max_depth;
-        let scene = gui.scene.to_str();
-        if self.scene.world.read().unwrap().get_label().filter(|&label| label == scene).is_none() {
-            self.camera = Camera::select_camera(self.camera.aspect_ratio, scene);
-            self.scene = select_scene(scene);
+        self.glass_depth = gui.glass_depth;
+        self.debug_view = gui.debug_view;
+        self.light_paths = gui.light_paths;
+        self.material_override = gui.material_override;
+        if self.bias != gui.bias {
+            self.set_bias(gui.bias);
+        }
+        self.bounce = gui.bounce;
+        self.transparent = gui.transparent;
+        self.guiding = gui.guiding;
+        self.probe_count = gui.probes;
+        self.set_precision(gui.precision);
+        self.region = gui.region.map(|rect| Region::from_normalized(rect, self.width, self.height));
+        if self.max_texture_size != gui.max_texture_size {
+            self.max_texture_size = gui.max_texture_size;
+            // The textures already loaded keep their size until the scene is loaded again
+            if self.scene_source.is_some() {
+                self.load_scene(gui);
+            }
+        }
+        if self.scene_source.as_ref() != Some(&gui.scene_source()) {
+            // Keep the camera of a restored session on the first load
+            let restored = self.scene_source.is_none().then(|| gui.camera);
+            self.load_scene(gui);
+            if let Some(camera) = restored {
+                self.camera = Camera::from_settings(&camera, self.camera.aspect_ratio);
+                gui.set_camera(camera);
+            }
+        } else if !self.interactive && gui.camera != self.camera.settings() {
+            self.camera = Camera::from_settings(&gui.camera, self.camera.aspect_ratio);
+        }
+        if self.background.as_ref() != Some(&gui.background) {
+            let rotated = self.background.as_ref()
+                .map_or(false, |previous| gui.background.rotate(previous, &mut self.scene.background));
+            if !rotated {
+                self.scene.background = gui.background.build(&self.scene.default_background);
+            }
+            self.background = Some(gui.background.clone());
+        }
+        if self.fog != Some(gui.fog) {
+            self.scene.fog = gui.fog.build(&self.scene.default_fog);
+            self.fog = Some(gui.fog);
         }
     }
-    fn ray_color(&self, r: &Ray, depth: usize) -> Color {
-        if depth == 0 {
+    /// (Re)build the scene selected in the GUI and reset the camera to the one it comes with.
+    #[cfg(feature = "window")]
+    pub(crate) fn load_scene(&mut self, gui: &mut Gui) {
+        let source = gui.scene_source();
+        let (scene, camera) = match &source {
+            SceneSource::Builtin(name, seed) => {
+                // The random scenes come out the same for the same seed
+                rand_gen::seed(*seed);
+                let mut scene = select_scene(name, self.max_texture_size);
+                rand_gen::unseed();
+                scene.seed = Some(*seed);
+                (scene, CameraSettings::select(name))
+            }
+            SceneSource::File(path) => match SceneFile::load(path, crate::memory::memory_budget(), self.max_texture_size) {
+                Ok((scene, camera)) => (scene, camera.unwrap_or_else(|| CameraSettings::select(""))),
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    gui.scene_file = None;
+                    return
+                }
+            },
+        };
+        let memory = scene.memory();
+        if let Err(e) = memory.check(crate::memory::memory_budget()) {
+            // Keep the current scene, without retrying the built-in one every frame
+            log::error!("{}: {}", scene.label, e);
+            gui.scene_file = None;
+            self.scene_source = Some(source);
+            return
+        }
+        self.memory = memory;
+        self.scene = scene;
+        self.camera = Camera::from_settings(&camera, self.camera.aspect_ratio);
+        self.scene.background = gui.background.build(&self.scene.default_background);
+        self.background = Some(gui.background.clone());
+        self.scene.fog = gui.fog.build(&self.scene.default_fog);
+        self.fog = Some(gui.fog);
+        self.scene_source = Some(source);
+        self.dirty = true;
+        gui.set_camera(camera);
+    }
+
+    fn debug_color(&self, r: &Ray) -> Color {
+        take_box_tests();
+        let hit = self.scene.world.read().unwrap().hit(r, 0., f32::INFINITY);
+        if self.debug_view == DebugView::BVHHeat {
+            let heat = (take_box_tests() as f32 / HEAT_MAX_TESTS).min(1.);
+            return Color::from([heat, 4. * heat * (1. - heat), 1. - heat]);
+        }
+        let hit_record = match hit {
+            Some(hit_record) => hit_record,
+            None => return Color::zeros(),
+        };
+        match self.debug_view {
+            DebugView::Normals => (hit_record.normal.into_inner() + Color::repeat(1.)) / 2.,
+            DebugView::UV => Color::from([hit_record.uv[0], hit_record.uv[1], 0.]),
+            DebugView::Depth => Color::repeat(1. - (hit_record.t / self.scene.extent).min(1.)),
+            DebugView::AO => {
+                let direction = hit_record.frame().local_dir(random_cosine_direction());
+                let occluded = self.scene.world.read().unwrap().hit_any(
+                    &hit_record.spawn_ray(direction, r).with_kind(RayKind::Diffuse),
+                    0.,
+                    AO_RADIUS * self.scene.extent,
+                );
+                Color::repeat(if occluded { 0. } else { 1. })
+            }
+            DebugView::Off | DebugView::BVHHeat => unreachable!(),
+        }
+    }
+
+    /// Bounces left to a camera path, those of [`Self::max_depth`] and of [`Self::glass_depth`].
+    #[inline]
+    fn path_depth(&self) -> usize {
+        if self.reference { REFERENCE_DEPTH } else { self.max_depth + self.glass_depth }
+    }
+
+    /// Whether a path with `depth` bounces left goes on to the next one: any path until
+    /// [`Self::max_depth`] bounces, beyond that only `glass` paths.
+    #[inline]
+    fn goes_on(&self, depth: usize, glass: bool) -> bool {
+        let glass_depth = if self.reference { 0 } else { self.glass_depth };
+        depth > glass_depth + 1 || (glass && depth > 1)
+    }
+
+    /// Whether light arriving at the end of a path of `class` after `depth` bounces are left is counted.
+    #[inline]
+    fn counts(&self, depth: usize, class: PathClass) -> bool {
+        match self.light_paths {
+            LightPaths::All => true,
+            LightPaths::Bounce => self.path_depth() - depth == self.bounce,
+            LightPaths::Caustics => class == PathClass::Caustic,
+        }
+    }
+
+    /// Alpha of a camera ray: 0 for the background, the shadow on shadow catchers and 1 for the rest.
+    fn coverage(&self, r: &Ray) -> f32 {
+        let hit_record = match self.scene.world.read().unwrap().hit(r, 0., f32::INFINITY) {
+            Some(hit_record) => hit_record,
+            None => return 0.,
+        };
+        let material = hit_record.material.read().unwrap();
+        match material.shadow_catcher() {
+            Some(catcher) => 1. - catcher.transmit(self.occlusion(&hit_record, r)),
+            None => 1.,
+        }
+    }
+
+    /// 1 if the light sampled from `hit_record` is blocked, else 0, for the shadows of shadow catchers.
+    fn occlusion(&self, hit_record: &HitRecord, r: &Ray) -> f32 {
+        let lights = self.scene.lights.read().unwrap();
+        let (direction, t_max) = if lights.objects.is_empty() {
+            // Lit by the background, the shadow is where the sky is blocked
+            (hit_record.frame().local_dir(random_cosine_direction()), f32::INFINITY)
+        } else {
+            let direction = lights.random(hit_record.point);
+            let to_light = hit_record.spawn_ray(direction, r);
+            // Stop short of the light, which is part of the world as well
+            let t = lights.hit(&to_light, 0., f32::INFINITY).map_or(f32::INFINITY, |rec| rec.t * (1. - self.bias.shadow));
+            (direction, t)
+        };
+        drop(lights);
+        let ray = hit_record.spawn_ray(direction, r).with_kind(RayKind::Diffuse);
+        if self.scene.world.read().unwrap().hit_any(&ray, 0., t_max) { 1. } else { 0. }
+    }
+
+    /// Draw where the light at a hit comes from, aiming diffuse scatters at the lights too.
+    fn sample_scatter(&self, r: &Ray, hit_record: &HitRecord, material: &dyn Material) -> Option<ScatterSample> {
+        let ScatterRecord { s_type, attenuation } = material.scatter(r, hit_record)?;
+        match s_type {
+            ScatterType::Diffuse(cosine_pdf) if self.reference => {
+                ScatterSample::diffuse(material, r, hit_record, attenuation, cosine_pdf.as_ref())
+            }
+            ScatterType::Diffuse(cosine_pdf) => {
+                let lights = &self.scene.lights;
+                let lights = (!lights.read().unwrap().objects.is_empty()).then(|| HittablePDF::at(hit_record.point, lights.clone()));
+                let guide = self.guide.as_ref().and_then(|guide| guide.pdf(hit_record.point));
+                let light_pdfs = [lights.as_ref().map(|pdf| pdf as &dyn PDF), self.environment.as_deref().map(|pdf| pdf as &dyn PDF)];
+                let light_count = light_pdfs.iter().flatten().count();
+                // Half of the directions follow the light learned around the hit, if any was yet.
+                // Half of the rest go to the lights, split evenly between the area lights and the
+                // environment map, so that neither one's noise dominates when both light the scene
+                let unguided = if guide.is_some() { 0.5 } else { 1. };
+                let material_share = if light_count > 0 { unguided / 2. } else { unguided };
+                let light_share = (unguided - material_share) / light_count.max(1) as f32;
+                let mixed_pdf = WeightedMix::new(
+                    std::iter::once((cosine_pdf.as_ref(), material_share))
+                        .chain(light_pdfs.into_iter().flatten().map(|pdf| (pdf, light_share)))
+                        .chain(guide.as_ref().map(|pdf| (pdf as &dyn PDF, 0.5))),
+                );
+                ScatterSample::diffuse(material, r, hit_record, attenuation, &mixed_pdf)
+            }
+            ScatterType::Specular(scattered) => Some(ScatterSample::delta(scattered, attenuation)),
+            ScatterType::Through(through) => Some(ScatterSample {
+                ray: through.with_kind(r.kind).with_media(r.media),
+                pdf: None,
+                weight: attenuation,
+            }),
+            // The phase function of isotropic media cancels with the density it is sampled with
+            ScatterType::ISO(scattered) => Some(ScatterSample {
+                ray: scattered.with_kind(RayKind::Diffuse).with_media(r.media),
+                pdf: Some(1. / (4. * PI)),
+                weight: attenuation,
+            }),
+        }
+    }
+
+    /// Radiance arriving along `r`, for a path that has carried `throughput` of it so far.
+    /// What reaches the camera from every light group is added to `groups`, if there are any.
+    fn ray_color(&self, r: &Ray, depth: usize, class: PathClass, throughput: Color, groups: &mut [Color], rays: &mut usize) -> Color {
+        *rays += 1;
+        if depth == 0 || stalled(*rays) {
             return Color::zeros();
         }
-        if let Some(hit_record) = self.scene.world.read().unwrap().hit(r, 0.001, f32::INFINITY) {
-            // let target = hit_record.normal + rand_vec3_on_unit_sphere();
-            let emitted = hit_record.material.read().unwrap().emit(&hit_record).unwrap_or(Color::zeros());
+        // For the objects picking their level of detail by how deep in the path they are seen
+        let r = &Ray { bounces: (self.path_depth() - depth) as u32, bias: self.bias, counted: self.object_stats, ..*r };
+        let hit = self.scene.world.read().unwrap().hit(r, 0., f32::INFINITY);
+        // Scattered by the atmosphere on the way to the hit
+        let hit = match &self.scene.fog {
+            Some(fog) => fog.hit(r, hit.as_ref().map_or(f32::INFINITY, |hit| hit.t)).or(hit),
+            None => hit,
+        };
+        if let Some(hit_record) = hit {
             let material = hit_record.material.read().unwrap();
-            let scattered = if let Some(ScatterRecord {s_type, attenuation}) = material.scatter(r, &hit_record) {
-                let (scattered, pdf_val) = match s_type {
-                 ScatterType::Diffuse(cosine_pdf) => {
-                     let mixed_pdf = if self.scene.lights.read().unwrap().objects.is_empty() {
-                         cosine_pdf
-                     } else {
-                         let hittable_pdf = HittablePDF::new(
-                             hit_record.point,
-                             self.scene.lights.clone()
-                         );
-                         MixPDF::new(cosine_pdf, hittable_pdf)
-                     };
-                     // let mixed_pdf = hittable_pdf;
-                    let scattered = Ray::new(hit_record.point, mixed_pdf.generate(), r.time);
-                     let cosine = scattered.direction.dot(&hit_record.normal).max(0.0001);
-                    let pdf_val = mixed_pdf.value(scattered.direction);
-                    (scattered, pdf_val / cosine * PI * 2.)
+            if let Some(catcher) = material.shadow_catcher() {
+                // The shadow is all in the alpha channel, to be laid over the photograph
+                if self.transparent && r.kind == RayKind::Camera {
+                    return Color::zeros()
+                }
+                if !self.goes_on(depth, false) {
+                    return Color::zeros()
+                }
+                let transmit = catcher.transmit(self.occlusion(&hit_record, r));
+                let through = hit_record.spawn_ray(r.direction, r).with_kind(r.kind).with_media(r.media);
+                return self.ray_color(&through, depth - 1, class, throughput * transmit, groups, rays) * transmit
+            }
+            // Lights hidden from the camera let its rays through, as if they were not there
+            if class == PathClass::Camera && !material.directly_visible() {
+                if !self.goes_on(depth, false) {
+                    return Color::zeros()
+                }
+                let through = hit_record.spawn_ray(r.direction, r).with_kind(r.kind).with_media(r.media);
+                return self.ray_color(&through, depth - 1, class, throughput, groups, rays)
+            }
+            let emission = material.emit(r, &hit_record);
+            let clay;
+            let material = match self.material_override {
+                MaterialOverride::Clay if emission.is_none() => {
+                    clay = self.clay.read().unwrap();
+                    &*clay
+                }
+                MaterialOverride::Emitters if emission.is_none() => {
+                    if !self.goes_on(depth, false) {
+                        return Color::zeros()
+                    }
+                    let through = hit_record.spawn_ray(r.direction, r).with_kind(r.kind).with_media(r.media);
+                    return self.ray_color(&through, depth - 1, class, throughput, groups, rays)
                 }
-                ScatterType::Specular(scattered) | ScatterType::ISO(scattered) => (scattered, 1.)
+                _ => &*material,
             };
-                // log::info!("pdf_m: {:?}; pdf_val: {}", pdf_m, pdf_val);
-           // pdf_m *
-               attenuation.component_mul(&self.ray_color(&scattered, depth - 1)) / pdf_val
+            let emitted = if self.counts(depth, class) {
+                emission.unwrap_or(Color::zeros())
             } else {
                 Color::zeros()
             };
-
-            emitted + scattered
+            if let Some(group) = groups.get_mut(material.light_group()) {
+                *group += throughput.component_mul(&emitted);
+            }
+            // Nothing further down the path is counted
+            let done = match self.light_paths {
+                LightPaths::Bounce => self.path_depth() - depth >= self.bounce,
+                LightPaths::Caustics => class == PathClass::Other,
+                LightPaths::All => false,
+            };
+            let sample = match (!done).then(|| self.sample_scatter(r, &hit_record, material)).flatten() {
+                Some(sample) => sample,
+                None => return emitted,
+            };
+            let mut weight = sample.weight;
+            // Russian roulette: end paths whose throughput has grown dim early, the survivors make up
+            // for them. Luminance rather than the brightest channel, so saturated dark albedos end soon too
+            if !self.reference && self.path_depth() - depth >= ROULETTE_BOUNCES {
+                let survival = throughput.component_mul(&weight).dot(&LUMINANCE).clamp(ROULETTE_MIN_SURVIVAL, 1.);
+                if get_rand() >= survival {
+                    return emitted
+                }
+                weight /= survival;
+            }
+            let throughput = throughput.component_mul(&weight);
+            let glass = sample.pdf.is_none()
+                && !(r.media.is_empty() && sample.ray.media.is_empty())
+                && throughput.dot(&LUMINANCE) >= GLASS_THROUGHPUT;
+            if !self.goes_on(depth, glass) {
+                return emitted
+            }
+            let class = class.next(sample.pdf.is_none());
+            let incoming = self.ray_color(&sample.ray, depth - 1, class, throughput, groups, rays);
+            if let (Some(guide), Some(pdf)) = (&self.guide, sample.pdf) {
+                guide.record(hit_record.point, sample.ray.direction, incoming.dot(&LUMINANCE) / pdf);
+            }
+            emitted + weight.component_mul(&incoming)
+        } else if self.counts(depth, class) && !(self.transparent && r.kind == RayKind::Camera) {
+            // Transparent pixels stay black, the color is premultiplied with the alpha
+            let background = self.scene.background.value(r.direction);
+            if let Some(group) = groups.first_mut() {
+                *group += throughput.component_mul(&background);
+            }
+            background
         } else {
-            self.scene.background
+            Color::zeros()
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use na::Vector3;
+    use crate::geo::{Cuboid, FlipFace};
+    use crate::texture::SolidColor;
 
+    /// Inside a closed box of walls reflecting `ALBEDO` and giving off `1 - ALBEDO`, the light
+    /// seen everywhere adds up to 1, over paths ending only at the bounce limit.
+    #[test]
+    fn reference_closed_box() {
+        const ALBEDO: f32 = 0.9;
+        let walls = Lambertian::glowing(SolidColor::new([ALBEDO; 3]), SolidColor::new([1. - ALBEDO; 3]));
+        let box_ = FlipFace::new(Cuboid::new([-1.; 3], [1.; 3], walls));
+        let scene = Scene::new(vec![], vec![box_], Color::zeros(), "closed box");
+        let camera = Camera::new(Point3::origin(), -Vector3::z_axis(), Vector3::y(), 90., 1., 0., 1., 0., 0.);
+        let (width, height) = (4, 4);
+        let mut renderer = Renderer::new(width, height, scene, camera);
+        renderer.multisample = 4;
+        renderer.set_reference(true);
+        renderer.draw(&mut vec![0; (width * height * 4) as usize]);
+        for color in renderer.linear_frame() {
+            assert!((color - Color::repeat(1.)).amax() < 0.01, "{:?} instead of 1", color);
+        }
     }
 }
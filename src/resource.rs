@@ -1,36 +1,251 @@
-use cfg_if::cfg_if;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock};
+use crate::error::{Error, Result};
 
 const STATIC_PATH: &str = "static";
+
+pub type ResourceFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>>>>;
+
+/// Where the static assets (textures, environment maps) come from.
+pub trait ResourceProvider: Send + Sync {
+    /// Read a file right away, for providers that can.
+    fn load(&self, file_name: &str) -> Result<Vec<u8>>;
+
+    fn fetch(&self, file_name: &str) -> ResourceFuture {
+        let result = self.load(file_name);
+        Box::pin(async move { result })
+    }
+}
+
+/// Files of the static directory next to the binary.
+pub struct FileProvider {
+    pub root: PathBuf,
+}
+
+impl Default for FileProvider {
+    fn default() -> Self {
+        Self {
+            root: Path::new(option_env!("OUT_DIR").unwrap_or(".")).join(STATIC_PATH),
+        }
+    }
+}
+
+impl ResourceProvider for FileProvider {
+    fn load(&self, file_name: &str) -> Result<Vec<u8>> {
+        let path = self.root.join(file_name);
+        log::info!("Resource path: {:?}", path);
+        std::fs::read(&path).map_err(|e| Error::from(e).in_file(path))
+    }
+}
+
+/// Files served next to the page, only available asynchronously.
+#[cfg(target_arch = "wasm32")]
+pub struct HttpProvider;
+
 #[cfg(target_arch = "wasm32")]
-fn format_url(file_name: &str) -> reqwest::Url {
-    let window = web_sys::window().unwrap();
-    let location = window.location();
-    let base = reqwest::Url::parse(&format!(
-        "{}/{}/",
-        location.origin().unwrap(),
-        option_env!("RES_PATH").unwrap_or(STATIC_PATH),
-    ))
-        .unwrap();
-    base.join(file_name).unwrap()
-}
-
-pub fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    cfg_if! {
-        if #[cfg(target_arch = "wasm32")] {
-            let url = format_url(file_name);
+impl HttpProvider {
+    fn url(file_name: &str) -> Result<reqwest::Url> {
+        let origin = web_sys::window()
+            .and_then(|win| win.location().origin().ok())
+            .ok_or_else(|| Error::Web("No page origin".into()))?;
+        reqwest::Url::parse(&format!(
+            "{}/{}/",
+            origin,
+            option_env!("RES_PATH").unwrap_or(STATIC_PATH),
+        ))
+            .and_then(|base| base.join(file_name))
+            .map_err(|e| resource_error(file_name, e))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl ResourceProvider for HttpProvider {
+    fn load(&self, file_name: &str) -> Result<Vec<u8>> {
+        Err(resource_error(file_name, "has to be fetched first"))
+    }
+
+    fn fetch(&self, file_name: &str) -> ResourceFuture {
+        let url = Self::url(file_name);
+        let file_name = file_name.to_string();
+        Box::pin(async move {
+            let url = url?;
             log::info!("URL: {:?}", url);
-            // let data = reqwest::get(url)?
-            //     .bytes()?
-            //     .to_vec();
-            let data = include_bytes!("../static/earthmap.jpg").to_vec();
-        } else {
-            let path = std::path::Path::new(option_env!("OUT_DIR").unwrap_or("."))
-                .join(STATIC_PATH)
-                .join(file_name);
-            log::info!("Texture path: {:?}", path);
-            let data = std::fs::read(path)?;
+            let get = async {
+                let response = reqwest::get(url).await?.error_for_status()?;
+                response.bytes().await
+            };
+            get.await.map(|bytes| bytes.to_vec()).map_err(|e| resource_error(&file_name, e))
+        })
+    }
+}
+
+/// Files compiled into the binary.
+#[cfg_attr(not(feature = "embedded-assets"), allow(dead_code))]
+pub struct EmbeddedProvider {
+    pub files: &'static [(&'static str, &'static [u8])],
+}
+
+impl ResourceProvider for EmbeddedProvider {
+    fn load(&self, file_name: &str) -> Result<Vec<u8>> {
+        self.files
+            .iter()
+            .find(|(name, _)| *name == file_name)
+            .map(|(_, bytes)| bytes.to_vec())
+            .ok_or_else(|| resource_error(file_name, "is not embedded"))
+    }
+}
+
+/// Try `0` and fall back to `1` for the files it does not have.
+#[cfg_attr(not(feature = "embedded-assets"), allow(dead_code))]
+pub struct Fallback(pub Box<dyn ResourceProvider>, pub Box<dyn ResourceProvider>);
+
+impl ResourceProvider for Fallback {
+    fn load(&self, file_name: &str) -> Result<Vec<u8>> {
+        self.0.load(file_name).or_else(|_| self.1.load(file_name))
+    }
+
+    fn fetch(&self, file_name: &str) -> ResourceFuture {
+        match self.0.load(file_name) {
+            Ok(bytes) => Box::pin(async move { Ok(bytes) }),
+            Err(_) => self.1.fetch(file_name),
         }
     }
+}
 
-    Ok(data)
-}
\ No newline at end of file
+/// The static directory as of the build, with the `embedded-assets` feature.
+#[cfg(feature = "embedded-assets")]
+const EMBEDDED: &[(&str, &[u8])] = &[
+    ("earthmap.jpg", include_bytes!("../static/earthmap.jpg")),
+    ("studio.hdr", include_bytes!("../static/studio.hdr")),
+];
+
+static PROVIDER: RwLock<Option<Box<dyn ResourceProvider>>> = RwLock::new(None);
+/// Every file loaded or handed over at runtime, e.g. dropped onto the window.
+static CACHE: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+/// Names of the files in [`CACHE`] in the order they were added.
+static ADDED: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+/// Bytes [`CACHE`] holds at most. The files added first are let go of first, to be read again
+/// or, for files dropped onto the window, dropped again.
+const CACHE_BUDGET: usize = 512 << 20;
+/// Files being fetched in the background.
+#[cfg(target_arch = "wasm32")]
+static PENDING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// A background fetch finished since the last call to [`take_fetched`].
+static FETCHED: AtomicBool = AtomicBool::new(false);
+
+/// Replace the default provider of the target.
+#[allow(dead_code)]
+pub fn set_provider(provider: Box<dyn ResourceProvider>) {
+    *PROVIDER.write().unwrap_or_else(PoisonError::into_inner) = Some(provider);
+}
+
+fn with_provider<T>(f: impl FnOnce(&dyn ResourceProvider) -> T) -> T {
+    let mut provider = PROVIDER.write().unwrap_or_else(PoisonError::into_inner);
+    let default = || {
+        #[cfg(target_arch = "wasm32")]
+        let provider: Box<dyn ResourceProvider> = Box::new(HttpProvider);
+        #[cfg(not(target_arch = "wasm32"))]
+        let provider: Box<dyn ResourceProvider> = Box::new(FileProvider::default());
+        // Assets added to the static directory after the build are still found
+        #[cfg(feature = "embedded-assets")]
+        let provider: Box<dyn ResourceProvider> = Box::new(Fallback(Box::new(EmbeddedProvider { files: EMBEDDED }), provider));
+        provider
+    };
+    f(provider.get_or_insert_with(default).as_ref())
+}
+
+fn resource_error(file_name: &str, reason: impl ToString) -> Error {
+    Error::Resource { name: file_name.into(), reason: reason.to_string() }
+}
+
+/// The cache stays usable after a panic while it was locked, every entry in it is complete.
+fn cache() -> MutexGuard<'static, BTreeMap<String, Vec<u8>>> {
+    CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn add_file(file_name: &str, bytes: Vec<u8>) {
+    let mut cache = cache();
+    let mut added = ADDED.lock().unwrap_or_else(PoisonError::into_inner);
+    added.retain(|name| name != file_name);
+    added.push_back(file_name.into());
+    cache.insert(file_name.into(), bytes);
+    let mut total: usize = cache.values().map(Vec::len).sum();
+    // The file just added stays, however large
+    while total > CACHE_BUDGET && added.len() > 1 {
+        let oldest = added.pop_front().unwrap();
+        if let Some(bytes) = cache.remove(&oldest) {
+            log::info!("Let go of {} ({} bytes) to keep the loaded files under {} MiB", oldest, bytes.len(), CACHE_BUDGET >> 20);
+            total -= bytes.len();
+        }
+    }
+}
+
+fn cached(file_name: &str) -> Option<Vec<u8>> {
+    cache().get(file_name).cloned()
+}
+
+/// Read a file given by the user, which in the browser only exists if it was added.
+pub fn read_file(path: &Path) -> Result<Vec<u8>> {
+    if let Some(bytes) = cached(&path.to_string_lossy()) {
+        return Ok(bytes)
+    }
+    std::fs::read(path).map_err(|e| Error::from(e).in_file(path))
+}
+
+/// Fetch a static file without blocking and keep it for later loads.
+pub async fn load_async(file_name: &str) -> Result<Vec<u8>> {
+    if let Some(bytes) = cached(file_name) {
+        return Ok(bytes)
+    }
+    let bytes = with_provider(|provider| provider.fetch(file_name)).await?;
+    add_file(file_name, bytes.clone());
+    Ok(bytes)
+}
+
+/// Fetch static files ahead of building the scenes that use them.
+#[allow(dead_code)]
+pub async fn preload(file_names: &[&str]) {
+    for file_name in file_names {
+        if let Err(e) = load_async(file_name).await {
+            log::warn!("Failed to preload {}: {}", file_name, e);
+        }
+    }
+}
+
+/// A static file from the cache or the provider. When the provider cannot read it right away,
+/// it is fetched in the background and [`take_fetched`] tells when to build the scene again.
+pub fn load_binary(file_name: &str) -> Result<Vec<u8>> {
+    if let Some(bytes) = cached(file_name) {
+        return Ok(bytes)
+    }
+    match with_provider(|provider| provider.load(file_name)) {
+        Ok(bytes) => {
+            add_file(file_name, bytes.clone());
+            Ok(bytes)
+        }
+        Err(e) => {
+            #[cfg(target_arch = "wasm32")]
+            if PENDING.lock().unwrap_or_else(PoisonError::into_inner).insert(file_name.to_string()) {
+                let file_name = file_name.to_string();
+                wasm_bindgen_futures::spawn_local(async move {
+                    match load_async(&file_name).await {
+                        Ok(_) => FETCHED.store(true, Ordering::Relaxed),
+                        Err(e) => log::error!("Failed to fetch {}: {}", file_name, e),
+                    }
+                    PENDING.lock().unwrap_or_else(PoisonError::into_inner).remove(&file_name);
+                });
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Whether a background fetch finished since the last call.
+#[allow(dead_code)]
+pub fn take_fetched() -> bool {
+    FETCHED.swap(false, Ordering::Relaxed)
+}
@@ -0,0 +1,63 @@
+use std::f32::consts::PI;
+use crate::rand_gen::get_rand;
+
+/// Prime bases of the Halton sequence, one per dimension of a camera sample.
+const BASES: [u64; DIMENSIONS] = [2, 3, 5, 7, 11];
+const DIMENSIONS: usize = 5;
+
+/// Where a camera ray starts: the position in the pixel, on the lens and in the shutter interval.
+pub struct CameraSample {
+    /// Offset inside the pixel, both in `0..1`.
+    pub film: [f32; 2],
+    /// Point on the unit disk.
+    pub lens: [f32; 2],
+    /// Fraction of the shutter interval.
+    pub time: f32,
+}
+
+/// The camera samples of one pixel, taken together from a Halton sequence instead of
+/// independent random numbers, so the pixel, lens and time dimensions are stratified
+/// against each other and depth of field and motion blur converge faster.
+pub struct Sampler {
+    index: u64,
+    /// Random shift of every dimension, so neighbouring pixels do not repeat the same pattern.
+    rotation: [f32; DIMENSIONS],
+}
+
+impl Sampler {
+    /// The samples of a pixel following the `first` ones already taken.
+    pub fn new(first: usize) -> Self {
+        Self {
+            index: first as u64,
+            rotation: [(); DIMENSIONS].map(|_| get_rand()),
+        }
+    }
+
+    pub fn next_camera(&mut self) -> CameraSample {
+        let index = self.index;
+        self.index += 1;
+        let [x, y, r, phi, time] = [0, 1, 2, 3, 4].map(|dim| {
+            let value = radical_inverse(BASES[dim], index) + self.rotation[dim];
+            if value >= 1. { value - 1. } else { value }
+        });
+        // Uniform over the disk, keeping the stratification of the square
+        let (r, phi) = (r.sqrt(), 2. * PI * phi);
+        CameraSample {
+            film: [x, y],
+            lens: [r * phi.cos(), r * phi.sin()],
+            time,
+        }
+    }
+}
+
+/// The digits of `index` in `base` mirrored around the decimal point.
+fn radical_inverse(base: u64, mut index: u64) -> f32 {
+    let inv_base = 1. / base as f64;
+    let (mut reversed, mut scale) = (0., inv_base);
+    while index > 0 {
+        reversed += (index % base) as f64 * scale;
+        index /= base;
+        scale *= inv_base;
+    }
+    (reversed as f32).min(1. - f32::EPSILON)
+}
@@ -1,24 +1,38 @@
-use crate::geo::{Sphere, AxisAlignedRect, AlignedAxis, Cuboid, RotationY, Translation, ConstantMedium, FlipFace};
-use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
-use crate::rand_gen::{get_rand, get_rand_range, get_rand_vec3_range};
-use crate::ray::HittableList;
-use crate::texture::{CheckerTexture, ImageTexture, NoiseTexture};
-use crate::types::{Color, Shared, SharedHittable, SharedMaterial, SharedSphere};
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::geo::{Sphere, AxisAlignedRect, AlignedAxis, Cuboid, RotationY, Translation, ConstantMedium, FlipFace, Lod, LOD_DEFAULT_SIZE, Portal, Quad};
+use crate::material::{Dielectric, DiffuseLight, Isotropic, Lambertian, Metal};
+use crate::rand_gen::{get_rand, get_rand_range, get_rand_usize_range, get_rand_vec3_range};
+use crate::mesh::Mesh;
+use crate::perlin::Perlin;
+use crate::ray::{HitRecord, Hittable, HittableList, ShapeParams};
+use crate::Ray;
+use crate::texture::{CheckerTexture, ImageTexture, NoiseTexture, SolidColor, WindowTexture};
+use crate::types::{blackbody, shared_id, Color, RGB, Shared, SharedHittable, SharedMaterial, SharedSphere};
 use na::{Point3, Vector3};
-use crate::aabb::BVHNode;
+use crate::background::{Background, TimeOfDay};
+use crate::memory::{MemoryCounter, MemoryUsage};
+use serde::Deserialize;
 
 
-pub fn select_scene(name: &str) -> Scene {
-    log::info!("Building scene: {}", name);
+/// One of the built-in scenes by name, e.g. `cornell` or `random`, with images shrunk to at
+/// most `max_texture_size` texels a side.
+pub fn select_scene(name: &str, max_texture_size: Option<u32>) -> Scene {
+    let _span = tracing::info_span!("build_scene", name).entered();
+    tracing::info!("Building scene: {}", name);
     match name {
-        "random" => create_random_scene(name),
-        "2psp" => two_perlin_spheres(name),
-        "earth" => earth(name),
-        "simplelight" => simplelight(name),
-        "cornell" => cornell_box(name),
-        "final" => final_scene(name),
-        "smoke" => cornell_smoke(name),
-        "2sp" | _ => two_spheres(name),
+        "random" => create_random_scene(name).with_recommended(Recommended::new(100, 50, [960, 540])),
+        "2psp" => two_perlin_spheres(name).with_recommended(Recommended::new(32, 10, [960, 540])),
+        "earth" => earth(name, max_texture_size).with_recommended(Recommended::new(32, 10, [960, 540])),
+        "simplelight" => simplelight(name).with_recommended(Recommended::new(400, 20, [960, 540])),
+        "cornell" => cornell_box(name).with_recommended(Recommended::new(1000, 50, [600, 600])),
+        "final" => final_scene(name, max_texture_size).with_recommended(Recommended::new(4000, 50, [800, 800])),
+        "smoke" => cornell_smoke(name).with_recommended(Recommended::new(1000, 50, [600, 600])),
+        "asteroids" => asteroids(name).with_recommended(Recommended::new(256, 20, [960, 540])),
+        "matpreview" => material_preview(name).with_recommended(Recommended::new(256, 20, [512, 512])),
+        "furnace" => furnace(name).with_recommended(Recommended::new(1000, 50, [384, 216])),
+        "portal" => portal(name).with_recommended(Recommended::new(256, 50, [960, 540])),
+        "city" => city(name).with_recommended(Recommended::new(256, 20, [1280, 720])),
+        "2sp" | _ => two_spheres(name).with_recommended(Recommended::new(32, 10, [960, 540])),
     }
 }
 
@@ -37,8 +51,7 @@ fn create_objs() -> Shared<HittableList> {
          vec![
             Sphere::new([0., 0., -1.], 0.5, material_center),
             Sphere::new([0., -100.5, -1.], 100., material_ground),
-            Sphere::new([-1., 0., -1.], 0.5, material_left.clone()),
-            Sphere::new([-1., 0., -1.], -0.45, material_left),
+            Sphere::hollow([-1., 0., -1.], 0.5, 0.05, material_left),
             Sphere::new([1., 0., -1.], 0.5, material_right),
         ],  None)
 }
@@ -70,7 +83,7 @@ fn create_random_scene(name: &str) -> Scene {
     Scene::new(lights,
     objects,
          Color::from([0.7, 0.8, 1.]),
-        name)
+        name).with_background(Background::sky())
 }
 
 fn create_random_sphere(a: i32, b: i32) -> Option<SharedSphere> {
@@ -124,8 +137,8 @@ fn two_perlin_spheres(name: &str) -> Scene {
         Color::from([0.7, 0.8, 1.]), name)
 }
 
-fn earth(name: &str) -> Scene {
-   let earth_texture = ImageTexture::new("earthmap.jpg");
+fn earth(name: &str, max_texture_size: Option<u32>) -> Scene {
+   let earth_texture = ImageTexture::new("earthmap.jpg", max_texture_size);
     let earth_surface = Lambertian::new(earth_texture);
    Scene::new(vec![], vec![Sphere::new_with_label([0.; 3], 2., earth_surface, Some(name.into()))], Color::from([0.7, 0.8, 1.]), name)
 }
@@ -220,7 +233,7 @@ fn cornell_smoke(label: &str) -> Scene {
     )
 }
 
-fn final_scene(label: &str) -> Scene {
+fn final_scene(label: &str, max_texture_size: Option<u32>) -> Scene {
     let ground = Lambertian::from_color([0.48, 0.83, 0.53]);
     let boxes_per_side = 20usize;
     let w = 100.;
@@ -233,7 +246,7 @@ fn final_scene(label: &str) -> Scene {
             }
         ).collect::<Vec<_>>()
     ).flatten().collect();
-    let boxes = BVHNode::new(&boxes, 0., 1., None);
+    let boxes = HittableList::new_bvh(boxes, 0., 1., None);
 
     let light = DiffuseLight::from_color([15.; 3]);
     let xz = FlipFace::new(AxisAlignedRect::new(light, 554., [123., 147.], [423., 412.], AlignedAxis::XZ));
@@ -248,16 +261,14 @@ fn final_scene(label: &str) -> Scene {
 
     let boundary = Sphere::new([360., 150., 145.], 70., Dielectric::new(1.5));
     let dielectric_medium = ConstantMedium::new_c(boundary.clone(), 0.2, [0.2, 0.4, 0.9]);
-    let boundary1 = Sphere::new([0.; 3], 500., Dielectric::new(1.5));
-    let dielectric_medium2 = ConstantMedium::new_c(boundary1, 0.00001, [1.; 3]);
 
-    let earth = Sphere::new([400., 200., 400.], 100., Lambertian::new(ImageTexture::new("earthmap.jpg")));
+    let earth = Sphere::new([400., 200., 400.], 100., Lambertian::new(ImageTexture::new("earthmap.jpg", max_texture_size)));
     let pertext = NoiseTexture::new(0.1);
     let pertext = Sphere::new([220., 280., 300.], 80., Lambertian::new(pertext));
 
     let white = Lambertian::from_color([0.73; 3]);
     let boxes2: Vec<_> = (0..1000).map(|_| Sphere::new(get_rand_vec3_range(0., 165.).into(), 10., white.clone()) as SharedHittable).collect();
-    let boxes2 = BVHNode::new(&boxes2, 0., 1., None);
+    let boxes2 = HittableList::new_bvh(boxes2, 0., 1., None);
     let boxes2 = RotationY::new(boxes2, 15.);
     let boxes2 = Translation::new(boxes2, [-100., 270., 395.]);
     let lights: Vec<SharedHittable> =vec![xz,
@@ -265,31 +276,436 @@ fn final_scene(label: &str) -> Scene {
     ];
     let world: Vec<SharedHittable> = vec![
         boxes, moving_sphere, metal_sphere, dielectric_medium,
-        dielectric_medium2,
         earth, pertext, boxes2
     ];
     Scene::new(
         lights,
         world,
-        Color::zeros(), label)
+        Color::zeros(), label).with_fog(Fog::new(0.00001, [1.; 3]))
 }
 
+/// A belt of rocks around a distant sun, hundreds of instances of a few meshes.
+fn asteroids(label: &str) -> Scene {
+    let noise = Perlin::new();
+    let materials = [[0.35, 0.31, 0.27], [0.25, 0.24, 0.23], [0.42, 0.36, 0.3]].map(Lambertian::from_color);
+    // Each shape at a few sizes, as instances can only be moved and turned. The rocks far away
+    // and those seen late in a path are traced as coarser meshes or their bounding spheres
+    let rocks: Vec<SharedHittable> = (0..5).flat_map(|i| {
+        let offset = get_rand_vec3_range(0., 100.);
+        let (mesh, proxy) = (Mesh::rock(3, 0.3, &noise, offset), Mesh::rock(1, 0.3, &noise, offset));
+        let material = materials[i % materials.len()].clone();
+        [0.25, 0.5, 0.9].map(|scale| {
+            let rock = mesh.build(scale, material.clone());
+            let proxies = std::iter::once(proxy.build(scale, material.clone())).chain(Lod::bounding_sphere(&rock)).collect();
+            Lod::new(rock, proxies, LOD_DEFAULT_SIZE) as SharedHittable
+        })
+    }).collect();
+    let instances: Vec<SharedHittable> = (0..400).map(|_| {
+        let rock = rocks[get_rand_usize_range(0, rocks.len())].clone();
+        let angle = get_rand_range(0., 2. * std::f32::consts::PI);
+        let distance = get_rand_range(6., 18.);
+        let position = [distance * angle.cos(), get_rand_range(-1., 1.), distance * angle.sin()];
+        Translation::new(RotationY::new(rock, get_rand_range(0., 360.)), position) as SharedHittable
+    }).collect();
+    let sun: SharedHittable = Sphere::new([-60., 20., -80.], 10., DiffuseLight::from_color([60., 55., 45.]));
+    Scene::new(
+        vec![sun],
+        vec![HittableList::new_bvh(instances, 0., 1., Some("asteroids".into()))],
+        Color::from([0.005, 0.005, 0.01]), label)
+}
+
+/// Label of the ball of the `matpreview` and `furnace` scenes, which previewed materials are put on.
+pub const PREVIEW_BALL: &str = "ball";
+
+/// Environment of the `matpreview` scene, neutral in color.
+const MATPREVIEW_HDRI: &str = "studio.hdr";
+
+fn material_preview(label: &str) -> Scene {
+    let floor = Lambertian::new(CheckerTexture::new([0.2; 3], [0.6; 3]));
+    let pedestal = Lambertian::from_color([0.5; 3]);
+    let ball = Lambertian::from_color([0.8; 3]);
+    Scene::new(
+        vec![],
+        vec![
+            Sphere::new([0., -1000., 0.], 1000., floor),
+            Cuboid::new([-0.8, 0., -0.8], [0.8, 0.5, 0.8], pedestal),
+            Sphere::new_with_label([0., 1.5, 0.], 1., ball, Some(PREVIEW_BALL.into())),
+        ],
+        Color::repeat(0.5), label)
+        // A gray studio with a key and a rim softbox, lighting the ball without tinting it
+        .with_background(Background::hdri(MATPREVIEW_HDRI, 0.))
+}
+
+/// A ball alone in a white environment of radiance 1, the white furnace test: a material that
+/// keeps to energy conservation never shows brighter than the background, and a white lossless one
+/// vanishes into it.
+fn furnace(label: &str) -> Scene {
+    let ball = Lambertian::from_color([1.; 3]);
+    Scene::new(
+        vec![],
+        vec![Sphere::new_with_label([0.; 3], 1., ball, Some(PREVIEW_BALL.into()))],
+        Color::repeat(1.), label)
+}
+
+/// A doorway facing the camera that looks out of another one, turned sideways next to it,
+/// onto balls that are also in plain sight behind the back of the second doorway.
+fn portal(label: &str) -> Scene {
+    let ground = Lambertian::new(CheckerTexture::new([0.2, 0.3, 0.1], [0.9; 3]));
+    let [a, b] = Portal::pair(
+        Quad::new([-3., 0., 0.], [2., 0., 0.], [0., 3., 0.]),
+        Quad::new([4., 0., 1.], [0., 0., -2.], [0., 3., 0.]),
+        [1.; 3],
+        Some("portal".into()),
+    );
+    Scene::new(
+        vec![],
+        vec![
+            Sphere::new([0., -1000., 0.], 1000., ground),
+            a,
+            b,
+            Sphere::new([7., 1., 0.], 1., Lambertian::from_color([0.7, 0.2, 0.2])),
+            Sphere::new([9., 1.5, -2.5], 1.5, Metal::new([0.8, 0.8, 0.9], 0.05)),
+            Sphere::new([-1., 0.7, -3.], 0.7, Lambertian::from_color([0.1, 0.2, 0.5])),
+        ],
+        Color::from([0.7, 0.8, 1.]), label)
+}
+
+/// Height of a storey of the city buildings.
+const CITY_FLOOR: f32 = 0.35;
+
+/// Blocks of towers at dusk, a few building shapes instanced over a grid of lots between streets,
+/// lit by the setting sun, their windows and the street lamps.
+fn city(label: &str) -> Scene {
+    const BLOCKS: i32 = 6;
+    const LOTS: i32 = 3;
+    const LOT: f32 = 1.2;
+    const STREET: f32 = 1.;
+    let block = LOTS as f32 * LOT + STREET;
+    let facades = [[0.55, 0.52, 0.48], [0.35, 0.36, 0.4], [0.6, 0.45, 0.35], [0.25, 0.27, 0.3]];
+    // A few of each footprint and height, shared by the lots
+    let buildings: Vec<SharedHittable> = (0..24).map(|i| {
+        let floors = get_rand_usize_range(3, 12) as f32 * if i % 4 == 0 { 3. } else { 1. };
+        let side = LOT * get_rand_range(0.6, 0.9);
+        let material = Lambertian::glowing(
+            SolidColor::new(facades[i % facades.len()]),
+            WindowTexture::new(CITY_FLOOR, 0.25, get_rand_range(0.2, 0.6), 3.),
+        );
+        Cuboid::new([-side / 2., 0., -side / 2.], [side / 2., floors * CITY_FLOOR, side / 2.], material) as SharedHittable
+    }).collect();
+    let half = BLOCKS as f32 * block / 2.;
+    let lots = (0..BLOCKS * LOTS).flat_map(|x| (0..BLOCKS * LOTS).map(move |z| (x, z)));
+    let instances: Vec<SharedHittable> = lots.map(|(x, z)| {
+        // Lots along a block, the street after every few
+        let at = |i: i32| (i / LOTS) as f32 * block + (i % LOTS) as f32 * LOT + LOT / 2. - half;
+        let building = buildings[get_rand_usize_range(0, buildings.len())].clone();
+        Translation::new(building, [at(x), 0., at(z)]) as SharedHittable
+    }).collect();
+    // At the crossings
+    let lamp = DiffuseLight::from_color((Color::from(blackbody(3000.)) * 40.).into());
+    let lamps: Vec<SharedHittable> = (0..=BLOCKS).flat_map(|x| (0..=BLOCKS).map(move |z| (x, z))).map(|(x, z)| {
+        let at = |i: i32| i as f32 * block - STREET / 2. - half;
+        Sphere::new([at(x), 0.6, at(z)], 0.05, lamp.clone()) as SharedHittable
+    }).collect();
+    let ground = Lambertian::from_color([0.08; 3]);
+    let world = vec![
+        Sphere::new([0., -1000., 0.], 1000., ground) as SharedHittable,
+        HittableList::new_bvh(instances, 0., 1., Some("buildings".into())),
+    ];
+    let dusk = TimeOfDay { latitude: 40., day: 290., hour: 17.6 };
+    Scene::new(lamps, world, Color::zeros(), label).with_background(Background::time_of_day(dusk))
+}
+
+/// White versions of the materials that do not glow, for the furnace test.
+pub fn furnace_materials() -> Vec<(&'static str, SharedMaterial)> {
+    vec![
+        ("lambertian", Lambertian::from_color([1.; 3])),
+        ("metal", Metal::new([1.; 3], 0.)),
+        ("rough metal", Metal::new([1.; 3], 0.5)),
+        ("glass", Dielectric::new(1.5)),
+        ("isotropic", Isotropic::from_color([1.; 3])),
+    ]
+}
+
+/// A homogeneous atmosphere filling the whole scene, giving distant objects a haze.
+#[derive(Clone)]
+pub struct Fog {
+    pub density: f32,
+    phase: SharedMaterial,
+}
+
+impl Fog {
+    /// Fog scattering light of `color` with a mean free path of `1 / density`.
+    pub fn new(density: f32, color: RGB) -> Self {
+        Self { density, phase: Isotropic::from_color(color) }
+    }
+
+    /// Where `ray` is scattered by the fog before reaching `t_max`, if it is.
+    pub fn hit(&self, ray: &Ray, t_max: f32) -> Option<HitRecord> {
+        let distance = -(1. - get_rand()).ln() / self.density;
+        (distance < t_max).then(|| HitRecord::new(ray, distance, self.phase.clone()))
+    }
+}
+
+/// Name of the light group of the background and ungrouped lights.
+pub const DEFAULT_LIGHT_GROUP: &str = "default";
+
 pub struct Scene {
     pub lights: Shared<HittableList>,
     pub world: SharedHittable,
-    pub background: Color,
-    pub label: String
+    pub background: Background,
+    /// The background the scene was built with, restored from the GUI.
+    pub default_background: Background,
+    pub label: String,
+    /// Top-level objects and lights in insertion order, as listed in the GUI.
+    pub objects: Vec<SharedHittable>,
+    /// IDs of `objects`, put into the records of their hits. An object keeps its ID for as long
+    /// as it is in the scene, whatever is added or removed around it.
+    ids: Vec<u32>,
+    next_id: u32,
+    /// Diagonal of the world bounding box, the length scale of the debug views.
+    pub extent: f32,
+    /// Hash of the scene file the scene was built from, recorded to reproduce renders.
+    pub hash: Option<u64>,
+    /// Seed the random numbers were drawn from while building the scene, recorded to reproduce its layout.
+    pub seed: Option<u64>,
+    pub fog: Option<Fog>,
+    /// The fog the scene was built with, restored from the GUI.
+    pub default_fog: Option<Fog>,
+    /// Names of the light groups rendered to images of their own, to rebalance the lights afterwards.
+    /// Group 0 holds the background and every light not put into another group.
+    pub light_groups: Vec<String>,
+    /// Settings the scene comes out clean with in reasonable time.
+    pub recommended: Recommended,
+}
+
+/// Samples per pixel, path depth and image size a scene is meant to be rendered at.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct Recommended {
+    pub samples: usize,
+    pub max_depth: usize,
+    pub size: [u32; 2],
+}
+
+impl Default for Recommended {
+    fn default() -> Self {
+        Self { samples: 100, max_depth: 50, size: [crate::WIDTH / 2, crate::HEIGHT / 2] }
+    }
+}
+
+impl Recommended {
+    pub fn new(samples: usize, max_depth: usize, size: [u32; 2]) -> Self {
+        Self { samples, max_depth, size }
+    }
 }
 
 impl Scene {
+    /// A scene of `world` lit by `lights`, which are importance sampled and rendered as well.
     pub fn new(lights: Vec<SharedHittable>, mut world: Vec<SharedHittable>, background: Color, label: &str) -> Self {
+        let objects = world.iter().chain(lights.iter()).cloned().collect();
         let lights = HittableList::new(lights, None);
+        lights.write().unwrap().weight_by_power();
+        lights.write().unwrap().count_rays();
         world.push(lights.clone());
-        Self {
+        let world = HittableList::new(world, Some(label.into()));
+        world.write().unwrap().count_rays();
+        let world: SharedHittable = world;
+        let extent = Self::extent_of(&world);
+        let mut scene = Self {
             lights,
-            world: HittableList::new(world, Some(label.into())),
-            background,
-            label: label.into()
+            world,
+            extent,
+            background: Background::Solid(background),
+            default_background: Background::Solid(background),
+            label: label.into(),
+            objects,
+            hash: None,
+            seed: None,
+            fog: None,
+            default_fog: None,
+            light_groups: vec![],
+            recommended: Recommended::default(),
+            ids: vec![],
+            next_id: 0,
+        };
+        for object in scene.objects.clone() {
+            scene.assign_id(&object);
+        }
+        scene
+    }
+
+    /// Give the top-level `object` an ID no other object of the scene had before.
+    fn assign_id(&mut self, object: &SharedHittable) {
+        let id = self.next_id;
+        self.next_id += 1;
+        if !self.world.write().unwrap().set_child_id(object, id) {
+            self.lights.write().unwrap().set_child_id(object, id);
+        }
+        self.ids.push(id);
+    }
+
+    /// The ID the hits on the top-level object at `index` are tagged with, the same from one
+    /// frame to the next for picking and the object ID mattes.
+    pub fn object_id(&self, index: usize) -> u32 {
+        self.ids[index]
+    }
+
+    /// Index in `objects` of the object with `id`, if it is still in the scene.
+    pub fn object_index(&self, id: u32) -> Option<usize> {
+        self.ids.iter().position(|&object| object == id)
+    }
+
+    /// The scene rendered at `recommended` by `--auto-quality`.
+    pub fn with_recommended(mut self, recommended: Recommended) -> Self {
+        self.recommended = recommended;
+        self
+    }
+
+    /// The scene filled with `fog`.
+    pub fn with_fog(mut self, fog: Fog) -> Self {
+        self.fog = Some(fog.clone());
+        self.default_fog = Some(fog);
+        self
+    }
+
+    /// The scene in front of `background` instead of its solid color.
+    pub fn with_background(mut self, background: Background) -> Self {
+        self.background = background.clone();
+        self.default_background = background;
+        self
+    }
+
+    /// Approximate bytes held by the objects, their textures and the background.
+    pub fn memory(&self) -> MemoryUsage {
+        let mut counter = MemoryCounter::default();
+        counter.hittable(&self.world);
+        let lights: SharedHittable = self.lights.clone();
+        counter.hittable(&lights);
+        counter.usage.textures += self.background.memory();
+        counter.usage
+    }
+
+    /// The scene with the lights split into `groups`, named after group 0.
+    pub fn with_light_groups(mut self, groups: Vec<String>) -> Self {
+        self.light_groups = groups;
+        self.light_groups.insert(0, DEFAULT_LIGHT_GROUP.into());
+        self
+    }
+
+    /// The index of the closest top-level object hit by `ray`.
+    pub fn pick(&self, ray: &Ray) -> Option<(usize, HitRecord)> {
+        let hit_record = self.world.read().unwrap().hit(ray, 0., f32::INFINITY)?;
+        Some((self.object_index(hit_record.object?)?, hit_record))
+    }
+
+    /// Leave the top-level `object` out of the render or bring it back.
+    pub fn set_hidden(&self, object: &SharedHittable, hidden: bool) {
+        if !self.world.write().unwrap().set_child_hidden(object, hidden) {
+            self.lights.write().unwrap().set_child_hidden(object, hidden);
         }
     }
+
+    pub fn is_hidden(&self, object: &SharedHittable) -> bool {
+        self.world.read().unwrap().is_child_hidden(object) || self.lights.read().unwrap().is_child_hidden(object)
+    }
+
+    /// Bring the bounding boxes up to date after objects were moved or resized, rebuilding the
+    /// BVHs they left too loose.
+    pub fn refit(&self) {
+        self.world.write().unwrap().refit();
+    }
+
+    /// Center of the bounding box of the objects of the scene no larger than `size` across, to
+    /// leave out ground planes and spheres and sky domes which dwarf the rest. `None` if there are
+    /// no such objects.
+    pub fn center(&self, size: f32) -> Option<Point3<f32>> {
+        self.objects.iter()
+            .filter_map(|obj| obj.read().unwrap().bounding_box(0., 1.))
+            .filter(|bbox| (bbox.maximum - bbox.minimum).norm() <= size)
+            .fold(None, |bbox, other| AxisAlignedBoundingBox::surrounding_box(bbox, Some(other)))
+            .map(|bbox| na::center(&bbox.minimum, &bbox.maximum))
+            .filter(|center| center.iter().all(|c| c.is_finite()))
+    }
+
+    /// Diagonal of the bounding box of `world`, 1 if it has none.
+    fn extent_of(world: &SharedHittable) -> f32 {
+        world.read().unwrap()
+            .bounding_box(0., 1.)
+            .map(|bbox| (bbox.maximum - bbox.minimum).norm())
+            .filter(|extent| extent.is_finite() && *extent > 0.)
+            .unwrap_or(1.)
+    }
+
+    /// Add `object` at the top level of the scene, sampled as a light if `light`, leaving the
+    /// other objects and their BVHs as they are.
+    pub fn add_object(&mut self, object: SharedHittable, light: bool) {
+        if light {
+            let mut lights = self.lights.write().unwrap();
+            lights.add(object.clone());
+            lights.weight_by_power();
+        } else {
+            self.world.write().unwrap().add_child(object.clone());
+        }
+        self.assign_id(&object);
+        self.objects.push(object);
+        self.extent = Self::extent_of(&self.world);
+    }
+
+    /// Add `object` to `parent`, e.g. a BVH, which grows its boxes around it rather than being
+    /// built anew. Returns whether `parent` took it.
+    pub fn add_to(&mut self, parent: &SharedHittable, object: SharedHittable) -> bool {
+        let added = parent.write().unwrap().add_child(object);
+        if added {
+            self.extent = Self::extent_of(&self.world);
+        }
+        added
+    }
+
+    /// Take `object` out of the scene, from the top level or a BVH or list there.
+    /// Returns whether it was found.
+    pub fn remove_object(&mut self, object: &SharedHittable) -> bool {
+        let id = shared_id(object);
+        let removed = match self.objects.iter().position(|obj| shared_id(obj) == id) {
+            Some(index) => {
+                self.objects.remove(index);
+                self.ids.remove(index);
+                self.world.write().unwrap().remove_child(object) || {
+                    let mut lights = self.lights.write().unwrap();
+                    let removed = lights.remove_child(object);
+                    lights.weight_by_power();
+                    removed
+                }
+            }
+            None => self.objects.iter().any(|obj| obj.write().unwrap().remove_child(object)),
+        };
+        if removed {
+            self.extent = Self::extent_of(&self.world);
+        }
+        removed
+    }
+
+    /// Change the dimensions or position of `object`, anywhere in the scene, and refit the boxes
+    /// around it.
+    pub fn edit_object(&self, object: &SharedHittable, params: &ShapeParams) {
+        object.write().unwrap().set_shape_params(params);
+        self.refit();
+    }
+
+    /// Put `material` on the top-level object labelled `label`, returns whether there is one taking it.
+    pub fn set_material(&self, label: &str, material: SharedMaterial) -> bool {
+        self.objects
+            .iter()
+            .find(|obj| obj.read().unwrap().get_label().map(String::as_str) == Some(label))
+            .map_or(false, |obj| obj.write().unwrap().set_material(material))
+    }
+
+    /// The label of an object, or its position in the scene if it has none.
+    pub fn object_name(&self, index: usize) -> String {
+        self.objects[index]
+            .read()
+            .unwrap()
+            .get_label()
+            .cloned()
+            .unwrap_or_else(|| format!("Object {}", index))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde::{de, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use crate::camera::CameraSettings;
+use crate::geo::{AlignedAxis, ConstantMedium, Cuboid, FlipFace, RotationY, Sphere, Translation, AxisAlignedRect, Disk, Lod, LOD_DEFAULT_SIZE, Portal, Quad, Visibility};
+use crate::ray::VisibilityFlags;
+use crate::material::{Dielectric, DiffuseLight, Isotropic, Lambertian, Material, MaterialParams, Metal, ShadowCatcher};
+use crate::error::{Error, Result};
+use crate::resource;
+use crate::plugin::{self, Params};
+use crate::ies::IesProfile;
+use crate::scene::{Fog, Recommended, Scene};
+use crate::background::Background;
+use crate::texture::{CheckerTexture, ImageTexture, NoiseTexture, SolidColor, TiledTexture, DEFAULT_TILE_CACHE};
+use crate::types::{blackbody, Color, RGB, SharedHittable, SharedMaterial, SharedTexture};
+use crate::mesh::{Mesh, Triangle};
+use crate::aabb::BVHNode;
+use crate::memory::MemoryUsage;
+use na::Point3;
+
+/// A scene description read from JSON.
+///
+/// Materials are declared once by name and referenced by the objects using them.
+#[derive(Deserialize)]
+pub struct SceneFile {
+    pub label: Option<String>,
+    #[serde(default)]
+    pub background: RGB,
+    /// Bottom and top color of a sky blending with the height of the direction, replacing `background`.
+    pub sky: Option<[RGB; 2]>,
+    /// Six images of a skybox replacing `background` and `sky`: right, left, top, bottom, front
+    /// and back, the x, y and z faces in the OpenGL layout.
+    pub skybox: Option<[String; 6]>,
+    pub fog: Option<FogDesc>,
+    pub camera: Option<CameraSettings>,
+    /// Unit of every length in the file, scaled to the meters of the built-in scenes.
+    #[serde(default)]
+    pub units: Units,
+    /// Axis pointing up in the file, turned into the y axis of the renderer.
+    #[serde(default)]
+    pub up: UpAxis,
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialEntry>,
+    pub objects: Vec<ObjectDesc>,
+    /// Openings such as windows that the light from outside falls in through, unseen but
+    /// sampled like the lights, e.g. `{"origin": [0, 1, -2], "u": [1, 0, 0], "v": [0, 1, 0]}`.
+    #[serde(default)]
+    pub windows: Vec<WindowDesc>,
+    /// Samples per pixel, depth and size the scene is meant for, e.g.
+    /// `{"samples": 1000, "max_depth": 50, "size": [600, 600]}`.
+    pub recommended: Option<Recommended>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MaterialDesc {
+    Lambertian { albedo: RGB },
+    Checker { even: RGB, odd: RGB },
+    Noise { scale: f32 },
+    Image { file: String },
+    /// A huge tiled TIFF file, read a tile at a time keeping `cache_tiles` in memory.
+    TiledImage { file: PathBuf, #[serde(default = "tile_cache")] cache_tiles: usize },
+    Metal { albedo: RGB, #[serde(default)] fuzz: f32 },
+    Dielectric { ior: f32 },
+    /// `visible: false` lights the scene without showing up to the camera and in mirrors.
+    /// `ies` names a photometric profile file shaping the light like a real fixture.
+    /// `image` shows a picture instead of `color`, brightened by `strength`.
+    /// `group` names the light group the light is also rendered to, for mixing the lights afterwards.
+    DiffuseLight {
+        #[serde(default = "white")] color: ColorDesc,
+        #[serde(default = "visible")] visible: bool,
+        ies: Option<PathBuf>,
+        image: Option<String>,
+        #[serde(default = "one")] strength: f32,
+        group: Option<String>,
+    },
+    Isotropic { albedo: RGB },
+    /// Transparent ground only showing the shadows cast onto it.
+    ShadowCatcher { #[serde(default = "one")] strength: f32 },
+    /// Lambertian with a texture of a kind registered with [`plugin::register_texture`].
+    Textured { texture: PluginDesc },
+}
+
+/// The kind named by `type` and the other parameters, for kinds registered by plugins.
+#[derive(Deserialize)]
+pub struct PluginDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub params: Params,
+}
+
+impl PluginDesc {
+    /// `params` as a plugin kind, if their `type` is one that was `registered`.
+    fn registered(params: &Params, registered: fn(&str) -> bool) -> Option<Self> {
+        let kind = params.get("type")?.as_str().filter(|kind| registered(kind))?;
+        let mut params = params.clone();
+        params.remove("type");
+        Some(Self { kind: kind.into(), params })
+    }
+}
+
+/// `params` as a built-in description, with the errors serde would give for them.
+fn builtin<T: DeserializeOwned, E: de::Error>(params: Params) -> std::result::Result<T, E> {
+    T::deserialize(serde_json::Value::Object(params)).map_err(E::custom)
+}
+
+/// A built-in material, or one of a kind registered with [`plugin::register_material`].
+pub enum MaterialEntry {
+    Builtin(MaterialDesc),
+    Plugin(PluginDesc),
+}
+
+impl<'de> Deserialize<'de> for MaterialEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+        match PluginDesc::registered(&params, plugin::has_material) {
+            Some(desc) => Ok(Self::Plugin(desc)),
+            None => builtin(params).map(Self::Builtin),
+        }
+    }
+}
+
+impl MaterialEntry {
+    fn build(&self, light_groups: &mut Vec<String>, max_texture_size: Option<u32>) -> Result<SharedMaterial> {
+        match self {
+            Self::Builtin(desc) => desc.build(light_groups, max_texture_size),
+            Self::Plugin(PluginDesc { kind, params }) => plugin::build_material(kind, params),
+        }
+    }
+}
+
+impl MaterialDesc {
+    /// `light_groups` collects the group names, a light in group `i` goes to image `i + 1`.
+    /// Images are shrunk to at most `max_texture_size` texels a side.
+    fn build(&self, light_groups: &mut Vec<String>, max_texture_size: Option<u32>) -> Result<SharedMaterial> {
+        Ok(match self {
+            Self::Lambertian { albedo } => Lambertian::from_color(*albedo),
+            Self::Checker { even, odd } => Lambertian::new(CheckerTexture::new(*even, *odd)),
+            Self::Noise { scale } => Lambertian::new(NoiseTexture::new(*scale)),
+            Self::Image { file } => Lambertian::new(ImageTexture::new(file, max_texture_size)),
+            Self::TiledImage { file, cache_tiles } => Lambertian::new(TiledTexture::new(file, *cache_tiles)),
+            Self::Metal { albedo, fuzz } => Metal::new(*albedo, *fuzz),
+            Self::Dielectric { ior } => Dielectric::new(*ior),
+            Self::DiffuseLight { color, visible, ies, image, strength, group } => {
+                let profile = ies.as_ref().and_then(|path| {
+                    resource::read_file(path)
+                        .and_then(|bytes| IesProfile::parse(&String::from_utf8_lossy(&bytes)).map_err(|e| e.in_file(path)))
+                        .map_err(|e| log::error!("Failed to load the light profile: {}", e))
+                        .ok()
+                });
+                let texture: SharedTexture = match image {
+                    Some(file) => ImageTexture::new(file, max_texture_size),
+                    None => SolidColor::new(color.rgb()),
+                };
+                let light = match profile {
+                    Some(profile) => DiffuseLight::with_profile(texture, profile),
+                    None => DiffuseLight::new(texture),
+                };
+                light.write().unwrap().set_params(&MaterialParams {
+                    visible: Some(*visible),
+                    emission: Some(*strength),
+                    ..Default::default()
+                });
+                if let Some(group) = group {
+                    let index = light_groups.iter().position(|name| name == group).unwrap_or_else(|| {
+                        light_groups.push(group.clone());
+                        light_groups.len() - 1
+                    });
+                    light.write().unwrap().set_group(index + 1);
+                }
+                light
+            }
+            Self::Isotropic { albedo } => Isotropic::from_color(*albedo),
+            Self::ShadowCatcher { strength } => ShadowCatcher::new(*strength),
+            Self::Textured { texture: PluginDesc { kind, params } } => Lambertian::new(plugin::build_texture(kind, params)?),
+        })
+    }
+}
+
+/// FNV-1a, a hash that stays the same across platforms and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// A color as RGB, or as the temperature of a blackbody in Kelvin, e.g. `{ "kelvin": 2700 }`.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum ColorDesc {
+    Rgb(RGB),
+    Blackbody { kelvin: f32 },
+}
+
+impl ColorDesc {
+    fn rgb(&self) -> RGB {
+        match self {
+            Self::Rgb(rgb) => *rgb,
+            Self::Blackbody { kelvin } => blackbody(*kelvin),
+        }
+    }
+}
+
+fn white() -> ColorDesc {
+    ColorDesc::Rgb([1.; 3])
+}
+
+fn visible() -> bool {
+    true
+}
+
+fn tile_cache() -> usize {
+    DEFAULT_TILE_CACHE
+}
+
+fn one() -> f32 {
+    1.
+}
+
+/// Length unit of a scene file, for assets made with tools of different scales to fit together.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// Taken as they are, for files without a real-world scale.
+    Arbitrary,
+    Meters,
+    Centimeters,
+    Millimeters,
+    Inches,
+    Feet,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Self::Arbitrary
+    }
+}
+
+impl Units {
+    fn meters(&self) -> f32 {
+        match self {
+            Self::Arbitrary | Self::Meters => 1.,
+            Self::Centimeters => 0.01,
+            Self::Millimeters => 0.001,
+            Self::Inches => 0.0254,
+            Self::Feet => 0.3048,
+        }
+    }
+}
+
+/// Axis pointing up in a scene file: y as in the renderer, or z as in many CAD and DCC tools.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+impl Default for UpAxis {
+    fn default() -> Self {
+        Self::Y
+    }
+}
+
+/// The change from the units and axes of a file to those of the renderer: a scale and, for
+/// z up, a turn around the x axis taking `(x, y, z)` to `(x, z, -y)`.
+#[derive(Clone, Copy)]
+struct Convention {
+    scale: f32,
+    z_up: bool,
+}
+
+impl Convention {
+    fn new(units: Units, up: UpAxis) -> Self {
+        Self { scale: units.meters(), z_up: matches!(up, UpAxis::Z) }
+    }
+
+    /// A point or offset.
+    fn point(&self, [x, y, z]: [f32; 3]) -> [f32; 3] {
+        let p = [x, y, z].map(|c| c * self.scale);
+        if self.z_up { [p[0], p[2], -p[1]] } else { p }
+    }
+
+    /// A direction, which is not scaled.
+    fn direction(&self, [x, y, z]: [f32; 3]) -> [f32; 3] {
+        if self.z_up { [x, z, -y] } else { [x, y, z] }
+    }
+
+    fn length(&self, length: f32) -> f32 {
+        length * self.scale
+    }
+
+    /// A rectangle in the converted frame, and whether the turn flipped its face.
+    fn rect(&self, axis: AlignedAxis, k: f32, c0: [f32; 2], c1: [f32; 2]) -> (AlignedAxis, f32, [f32; 2], [f32; 2], bool) {
+        let [k, c0x, c0y, c1x, c1y] = [k, c0[0], c0[1], c1[0], c1[1]].map(|c| c * self.scale);
+        if !self.z_up {
+            return (axis, k, [c0x, c0y], [c1x, c1y], false)
+        }
+        // The second coordinate of the plane becomes minus the third one, swapping the corners
+        match axis {
+            AlignedAxis::XY => (AlignedAxis::XZ, k, [c0x, -c1y], [c1x, -c0y], false),
+            AlignedAxis::XZ => (AlignedAxis::XY, -k, [c0x, c0y], [c1x, c1y], true),
+            AlignedAxis::YZ => (AlignedAxis::YZ, k, [c0y, -c1x], [c1y, -c0x], false),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShapeDesc {
+    Sphere { center: [f32; 3], radius: f32 },
+    MovingSphere { center0: [f32; 3], center1: [f32; 3], radius: f32 },
+    /// A shell of `thickness` inside `radius`, e.g. a soap bubble of glass.
+    HollowSphere { center: [f32; 3], radius: f32, thickness: f32 },
+    Rect { axis: AlignedAxis, k: f32, c0: [f32; 2], c1: [f32; 2] },
+    Disk { center: [f32; 3], normal: [f32; 3], radius: f32 },
+    Cuboid { min: [f32; 3], max: [f32; 3] },
+    /// Constant density smoke of albedo `color` filling `boundary`, glowing with `emission` if given,
+    /// as RGB or a blackbody temperature.
+    Medium { boundary: Box<ShapeDesc>, density: f32, color: RGB, emission: Option<ColorDesc> },
+    /// Triangles as indices into `vertices`, counter-clockwise seen from the front. Shaded smooth
+    /// across the edges where the faces meet at less than `smooth_angle` degrees, faceted without it.
+    Mesh { vertices: Vec<[f32; 3]>, faces: Vec<[usize; 3]>, smooth_angle: Option<f32> },
+}
+
+/// A parallelogram from `origin` along the edges `u` and `v`.
+#[derive(Deserialize)]
+pub struct WindowDesc {
+    pub origin: [f32; 3],
+    pub u: [f32; 3],
+    pub v: [f32; 3],
+}
+
+/// A homogeneous atmosphere filling the whole scene.
+#[derive(Deserialize)]
+pub struct FogDesc {
+    pub density: f32,
+    pub color: RGB,
+}
+
+impl FogDesc {
+    /// Fail on a density that is not a positive number, which would make every ray stop at once
+    /// or never.
+    fn validate(&self) -> Result<()> {
+        if self.density > 0. && self.density.is_finite() {
+            Ok(())
+        } else {
+            Err(Error::InvalidScene(format!("a fog of density {} does not scatter", self.density)))
+        }
+    }
+}
+
+/// A built-in shape, or one of a kind registered with [`plugin::register_shape`].
+pub enum ShapeEntry {
+    Builtin(ShapeDesc),
+    Plugin(PluginDesc),
+}
+
+impl<'de> Deserialize<'de> for ShapeEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let params = Params::deserialize(deserializer)?;
+        match PluginDesc::registered(&params, plugin::has_shape) {
+            Some(desc) => Ok(Self::Plugin(desc)),
+            None => builtin(params).map(Self::Builtin),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ObjectDesc {
+    #[serde(flatten)]
+    pub shape: ShapeEntry,
+    pub material: String,
+    pub label: Option<String>,
+    /// Sampled directly as a light source.
+    #[serde(default)]
+    pub light: bool,
+    #[serde(default)]
+    pub flip_face: bool,
+    /// Rotation around the up axis in degrees, applied before the translation.
+    pub rotate_y: Option<f32>,
+    /// Rotation when the shutter closes, for objects spinning from `rotate_y` during the exposure.
+    pub rotate_y_end: Option<f32>,
+    pub translate: Option<[f32; 3]>,
+    /// E.g. `{ "camera": false }` for a light blocker the camera does not see.
+    #[serde(default)]
+    pub visibility: VisibilityFlags,
+    /// Simpler shapes to trace instead where the object looks small or is seen late in a path.
+    pub lod: Option<LodDesc>,
+}
+
+/// Proxies of an object, e.g. `{ "proxies": [...], "bounding_sphere": true }`, see [`Lod`].
+#[derive(Deserialize)]
+pub struct LodDesc {
+    /// Ever coarser shapes in the material of the object.
+    #[serde(default)]
+    pub proxies: Vec<ShapeDesc>,
+    /// Finish with the sphere around the object.
+    #[serde(default)]
+    pub bounding_sphere: bool,
+    /// Angular radius in radians below which the first proxy is used, [`LOD_DEFAULT_SIZE`] if not given.
+    pub size: Option<f32>,
+}
+
+impl ShapeDesc {
+    /// The shape in the units and axes of the renderer, and whether its face was flipped by the turn.
+    fn convert(&self, convention: &Convention) -> (Self, bool) {
+        let shape = match self {
+            Self::Sphere { center, radius } =>
+                Self::Sphere { center: convention.point(*center), radius: convention.length(*radius) },
+            Self::MovingSphere { center0, center1, radius } => Self::MovingSphere {
+                center0: convention.point(*center0),
+                center1: convention.point(*center1),
+                radius: convention.length(*radius),
+            },
+            Self::HollowSphere { center, radius, thickness } => Self::HollowSphere {
+                center: convention.point(*center),
+                radius: convention.length(*radius),
+                thickness: convention.length(*thickness),
+            },
+            Self::Rect { axis, k, c0, c1 } => {
+                let (axis, k, c0, c1, flipped) = convention.rect(*axis, *k, *c0, *c1);
+                return (Self::Rect { axis, k, c0, c1 }, flipped)
+            }
+            Self::Disk { center, normal, radius } => Self::Disk {
+                center: convention.point(*center),
+                normal: convention.direction(*normal),
+                radius: convention.length(*radius),
+            },
+            Self::Cuboid { min, max } => {
+                let [a, b] = [convention.point(*min), convention.point(*max)];
+                Self::Cuboid {
+                    min: [0, 1, 2].map(|i| a[i].min(b[i])),
+                    max: [0, 1, 2].map(|i| a[i].max(b[i])),
+                }
+            }
+            // Density is per unit of length
+            Self::Medium { boundary, density, color, emission } => Self::Medium {
+                boundary: Box::new(boundary.convert(convention).0),
+                density: density / convention.scale,
+                color: *color,
+                emission: *emission,
+            },
+            Self::Mesh { vertices, faces, smooth_angle } => Self::Mesh {
+                vertices: vertices.iter().map(|&v| convention.point(v)).collect(),
+                faces: faces.clone(),
+                smooth_angle: *smooth_angle,
+            },
+        };
+        (shape, false)
+    }
+
+    /// Fail on parameters the shape cannot be built with, which would otherwise panic.
+    fn validate(&self) -> Result<()> {
+        match self {
+            Self::HollowSphere { radius, thickness, .. } if !(*thickness > 0. && thickness < radius) =>
+                Err(Error::InvalidScene(format!("a shell of thickness {} does not fit in a radius of {}", thickness, radius))),
+            Self::Medium { boundary, .. } => boundary.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add the triangles of meshes and the BVH nodes over them to `usage`.
+    fn estimate(&self, usage: &mut MemoryUsage) {
+        match self {
+            Self::Mesh { vertices, faces, .. } => {
+                usage.primitives += vertices.len() * std::mem::size_of::<Point3<f32>>()
+                    + faces.len() * (std::mem::size_of::<Triangle>() + std::mem::size_of::<SharedHittable>());
+                // A binary tree has a node less than it has leaves
+                usage.bvh += faces.len() * std::mem::size_of::<BVHNode>();
+            }
+            Self::Medium { boundary, .. } => boundary.estimate(usage),
+            _ => {}
+        }
+    }
+
+    fn build(&self, material: SharedMaterial, label: Option<String>) -> SharedHittable {
+        match self {
+            Self::Sphere { center, radius } => Sphere::new_with_label(*center, *radius, material, label),
+            Self::MovingSphere { center0, center1, radius } =>
+                Sphere::new_moving(*center0, *center1, 0., 1., *radius, material),
+            Self::HollowSphere { center, radius, thickness } => Sphere::hollow(*center, *radius, *thickness, material),
+            Self::Rect { axis, k, c0, c1 } => AxisAlignedRect::new(material, *k, *c0, *c1, *axis),
+            Self::Disk { center, normal, radius } => Disk::new(*center, *normal, *radius, material),
+            Self::Cuboid { min, max } => Cuboid::new(*min, *max, material),
+            Self::Medium { boundary, density, color, emission: None } =>
+                ConstantMedium::new_c(boundary.build(material, None), *density, *color),
+            Self::Medium { boundary, density, color, emission: Some(emission) } => ConstantMedium::new_emissive(
+                boundary.build(material, None),
+                *density,
+                SolidColor::new(*color),
+                SolidColor::new(emission.rgb()),
+            ),
+            Self::Mesh { vertices, faces, smooth_angle } => {
+                let valid: Vec<_> = faces.iter().copied().filter(|face| face.iter().all(|&i| i < vertices.len())).collect();
+                if valid.len() < faces.len() {
+                    log::warn!("Dropped {} faces of a mesh pointing beyond its {} vertices", faces.len() - valid.len(), vertices.len());
+                }
+                let mesh = Mesh { vertices: vertices.iter().map(|&v| Point3::from(v)).collect(), faces: valid };
+                match smooth_angle {
+                    Some(angle) => mesh.build_smooth(1., *angle, material),
+                    None => mesh.build(1., material),
+                }
+            }
+        }
+    }
+}
+
+impl SceneFile {
+    /// Build the scene of the file at `path`, refused before it is built if it would need more
+    /// than `budget` bytes, with images shrunk to at most `max_texture_size` texels a side.
+    pub fn load(path: &Path, budget: Option<usize>, max_texture_size: Option<u32>) -> Result<(Scene, Option<CameraSettings>)> {
+        let bytes = resource::read_file(path)?;
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        Self::parse(&bytes, &stem, budget, max_texture_size).map_err(|e| e.in_file(path))
+    }
+
+    /// Build a scene from JSON, labelled `default_label` unless the file names itself.
+    pub fn parse(bytes: &[u8], default_label: &str, budget: Option<usize>, max_texture_size: Option<u32>) -> Result<(Scene, Option<CameraSettings>)> {
+        let file: SceneFile = serde_json::from_slice(bytes)?;
+        let label = file.label.clone().unwrap_or_else(|| default_label.into());
+        let estimate = file.estimate(max_texture_size);
+        log::info!("Estimated scene memory: {} MiB", estimate.total() >> 20);
+        estimate.check(budget)?;
+        let (mut scene, camera) = file.build(&label, max_texture_size)?;
+        scene.hash = Some(fnv1a(bytes));
+        Ok((scene, camera))
+    }
+
+    /// The memory of the meshes, their BVHs and the images of the scene, told from the file and
+    /// the headers of the images before any of it is built. The other shapes take next to nothing.
+    fn estimate(&self, max_texture_size: Option<u32>) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for object in &self.objects {
+            if let ShapeEntry::Builtin(shape) = &object.shape {
+                shape.estimate(&mut usage);
+            }
+            object.lod.iter().flat_map(|lod| &lod.proxies).for_each(|proxy| proxy.estimate(&mut usage));
+        }
+        for material in self.materials.values() {
+            match material {
+                MaterialEntry::Builtin(MaterialDesc::Image { file })
+                | MaterialEntry::Builtin(MaterialDesc::DiffuseLight { image: Some(file), .. }) =>
+                    usage.textures += texels(file, max_texture_size) * std::mem::size_of::<[u8; 4]>(),
+                _ => {}
+            }
+        }
+        for face in self.skybox.iter().flatten() {
+            usage.textures += texels(face, None) * std::mem::size_of::<[f32; 3]>();
+        }
+        usage
+    }
+
+    fn build(self, label: &str, max_texture_size: Option<u32>) -> Result<(Scene, Option<CameraSettings>)> {
+        let _span = tracing::info_span!("build_scene", label).entered();
+        tracing::info!("Building scene file: {}", label);
+        let mut light_groups = vec![];
+        // In name order, so the groups are numbered the same every time
+        let mut descs: Vec<_> = self.materials.iter().collect();
+        descs.sort_by_key(|(name, _)| name.as_str());
+        let materials = descs
+            .into_iter()
+            .map(|(name, desc)| Ok((name.as_str(), desc.build(&mut light_groups, max_texture_size)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        let convention = Convention::new(self.units, self.up);
+        let mut lights = vec![];
+        let mut world = vec![];
+        for object in &self.objects {
+            let material = materials
+                .get(object.material.as_str())
+                .ok_or_else(|| Error::UnknownMaterial(object.material.clone()))?
+                .clone();
+            let (mut obj, flipped) = match &object.shape {
+                ShapeEntry::Builtin(shape) => {
+                    shape.validate()?;
+                    let (shape, flipped) = shape.convert(&convention);
+                    (shape.build(material.clone(), object.label.clone()), flipped)
+                }
+                ShapeEntry::Plugin(PluginDesc { kind, params }) =>
+                    (plugin::build_shape(kind, params, material.clone(), object.label.clone())?, false),
+            };
+            if let Some(lod) = &object.lod {
+                lod.proxies.iter().try_for_each(ShapeDesc::validate)?;
+                let mut proxies: Vec<_> = lod.proxies
+                    .iter()
+                    .map(|proxy| proxy.convert(&convention).0.build(material.clone(), None))
+                    .collect();
+                proxies.extend(lod.bounding_sphere.then(|| Lod::bounding_sphere(&obj)).flatten());
+                obj = Lod::new(obj, proxies, lod.size.unwrap_or(LOD_DEFAULT_SIZE));
+            }
+            // Turns about the up axis stay the same angle about the y axis
+            match (object.rotate_y, object.rotate_y_end) {
+                (Some(degree), None) => obj = RotationY::new(obj, degree),
+                (degree, Some(end)) => obj = RotationY::spinning(obj, degree.unwrap_or(0.), end),
+                (None, None) => {}
+            }
+            if let Some(offset) = object.translate {
+                obj = Translation::new(obj, convention.point(offset));
+            }
+            if object.visibility != VisibilityFlags::default() {
+                obj = Visibility::new(obj, object.visibility);
+            }
+            if object.flip_face != flipped {
+                obj = FlipFace::new(obj);
+            }
+            if object.light { lights.push(obj) } else { world.push(obj) }
+        }
+        lights.extend(self.windows.iter().map(|window| {
+            let [origin, u, v] = [window.origin, window.u, window.v].map(|p| convention.point(p));
+            Portal::window(Quad::new(origin, u, v)) as SharedHittable
+        }));
+        let mut scene = Scene::new(lights, world, Color::from(self.background), label);
+        if let Some([bottom, top]) = self.sky {
+            scene = scene.with_background(Background::Sky { bottom: Color::from(bottom), top: Color::from(top) });
+        }
+        if let Some(faces) = &self.skybox {
+            scene = scene.with_background(Background::cubemap(faces, 0.));
+        }
+        if let Some(fog) = &self.fog {
+            fog.validate()?;
+            let FogDesc { density, color } = *fog;
+            scene = scene.with_fog(Fog::new(density / convention.scale, color));
+        }
+        if !light_groups.is_empty() {
+            scene = scene.with_light_groups(light_groups);
+        }
+        if let Some(recommended) = self.recommended {
+            if recommended.max_depth == 0 {
+                return Err(Error::InvalidScene("a recommended max_depth of 0 leaves no bounce to trace".to_string()))
+            }
+            scene = scene.with_recommended(recommended);
+        }
+        let camera = self.camera.map(|camera| CameraSettings {
+            lookfrom: convention.point(camera.lookfrom),
+            lookat: convention.point(camera.lookat),
+            aperture: convention.length(camera.aperture),
+            focus_dist: convention.length(camera.focus_dist),
+            ..camera
+        });
+        Ok((scene, camera))
+    }
+}
+
+/// Texels of the image `file` once loaded, from its header and shrunk to at most `max_size` texels
+/// a side, 0 if it cannot be read. The file is kept for when the scene is built.
+fn texels(file: &str, max_size: Option<u32>) -> usize {
+    let size = resource::load_binary(file).ok().and_then(|bytes| {
+        image::io::Reader::new(std::io::Cursor::new(bytes)).with_guessed_format().ok()?.into_dimensions().ok()
+    });
+    let (width, height) = match size {
+        Some(size) => size,
+        None => return 0,
+    };
+    let scale = match max_size {
+        Some(max) if width.max(height) > max => max as f32 / width.max(height) as f32,
+        _ => 1.,
+    };
+    (width as f32 * scale).round().max(1.) as usize * (height as f32 * scale).round().max(1.) as usize
+}
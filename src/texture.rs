@@ -1,11 +1,30 @@
 use image::{Pixel, RgbaImage};
-use crate::types::{create_shared_mut, Color, Shared, SharedTexture, RGB};
+use crate::types::{blackbody, create_shared_mut, Color, Shared, SharedTexture, RGB};
+use crate::rand_gen;
 use na::Point3;
 use crate::perlin::Perlin;
 use crate::resource;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 pub trait Texture: Sync + Send {
     fn value(&self, uv: [f32; 2], p: Point3<f32>) -> Color;
+    /// The color of a texture that does not vary over the surface.
+    fn color(&self) -> Option<Color> {
+        None
+    }
+    /// Luminance of every texel row by row, and the number of texels per row,
+    /// for textures made of an image.
+    fn luminance(&self) -> Option<(usize, Vec<f32>)> {
+        None
+    }
+    /// Bytes held by the texture.
+    fn memory(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
 }
 
 pub struct SolidColor {
@@ -13,6 +32,7 @@ pub struct SolidColor {
 }
 
 impl SolidColor {
+    /// The same color everywhere.
     pub fn new(color: RGB) -> Shared<Self> {
         create_shared_mut(Self { color: Color::from(color) })
     }
@@ -22,6 +42,9 @@ impl Texture for SolidColor {
     fn value(&self, _uv: [f32; 2], _p: Point3<f32>) -> Color {
         self.color
     }
+    fn color(&self) -> Option<Color> {
+        Some(self.color)
+    }
 }
 
 pub struct CheckerTexture {
@@ -30,6 +53,7 @@ pub struct CheckerTexture {
 }
 
 impl CheckerTexture {
+    /// A 3D checker pattern of two colors.
     pub fn new(even: RGB, odd: RGB) -> Shared<Self> {
         create_shared_mut(Self {
             odd: SolidColor::new(odd),
@@ -44,6 +68,9 @@ impl Texture for CheckerTexture {
         let tex = if sines < 0. { &self.odd } else { &self.even };
         tex.read().unwrap().value(uv, p)
     }
+    fn memory(&self) -> usize {
+        std::mem::size_of_val(self) + self.odd.read().unwrap().memory() + self.even.read().unwrap().memory()
+    }
 }
 
 pub struct NoiseTexture {
@@ -52,6 +79,7 @@ pub struct NoiseTexture {
 }
 
 impl NoiseTexture {
+    /// Marble-like Perlin noise, with finer detail for a larger `scale`.
     pub fn new(scale: f32) -> Shared<Self> {
         create_shared_mut(Self{
             noise: Perlin::new(),
@@ -68,19 +96,75 @@ impl Texture for NoiseTexture {
     }
 }
 
+/// The lit windows of a building at night, glowing in the warm colors of lamps, dark between
+/// them. Floors are `floor` high from the ground and windows `spacing` apart along the facades.
+pub struct WindowTexture {
+    floor: f32,
+    spacing: f32,
+    /// Share of the windows that are lit.
+    lit: f32,
+    strength: f32,
+}
+
+/// Lamp color temperatures of the lit windows, in Kelvin.
+const WINDOW_KELVIN: [f32; 2] = [2700., 4500.];
+
+impl WindowTexture {
+    pub fn new(floor: f32, spacing: f32, lit: f32, strength: f32) -> Shared<Self> {
+        create_shared_mut(Self { floor, spacing, lit, strength })
+    }
+}
+
+impl Texture for WindowTexture {
+    fn value(&self, _uv: [f32; 2], p: Point3<f32>) -> Color {
+        // Along the facade, whichever of x and z it runs along
+        let (column, row) = ((p.x + p.z) / self.spacing, p.y / self.floor);
+        let (fx, fy) = (column - column.floor(), row - row.floor());
+        if !(0.2..0.8).contains(&fx) || !(0.3..0.8).contains(&fy) {
+            return Color::zeros()
+        }
+        let hash = rand_gen::hash_seed(&[column.floor() as i64 as u64, row.floor() as i64 as u64]);
+        let [lit, warmth] = [hash as u32, (hash >> 32) as u32].map(|bits| bits as f32 / u32::MAX as f32);
+        if lit >= self.lit {
+            return Color::zeros()
+        }
+        let kelvin = WINDOW_KELVIN[0] + warmth * (WINDOW_KELVIN[1] - WINDOW_KELVIN[0]);
+        Color::from(blackbody(kelvin)) * self.strength
+    }
+}
+
 pub struct ImageTexture {
     img: Option<RgbaImage>
 }
 
 impl ImageTexture {
     const COLOR_SCALE: f32 = 1. / 255.;
-    pub fn new(filename: &str) -> Shared<Self> {
-        let img = resource::load_binary(filename).and_then(|bytes| Self::from_bytes(&bytes)).ok();
+    /// A missing or broken image is shown in cyan, as a hint that loading failed.
+    /// See [`Self::from_bytes`] for `max_size`.
+    pub fn new(filename: &str, max_size: Option<u32>) -> Shared<Self> {
+        let img = Self::load(filename, max_size)
+            .map_err(|e| log::error!("Failed to load texture {}: {}", filename, e))
+            .ok();
         create_shared_mut(Self {img})
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<RgbaImage> {
+    pub fn load(filename: &str, max_size: Option<u32>) -> crate::Result<RgbaImage> {
+        resource::load_binary(filename).and_then(|bytes| Self::from_bytes(&bytes, max_size))
+    }
+
+    /// The image shrunk to at most `max_size` texels a side, e.g. 2048 to keep previews of scenes
+    /// with 8K textures quick, or kept as it is with `None`.
+    pub fn from_bytes(bytes: &[u8], max_size: Option<u32>) -> crate::Result<RgbaImage> {
         let mut img = image::load_from_memory(bytes)?.to_rgba8();
+        if let Some(max) = max_size {
+            let (w, h) = img.dimensions();
+            if w.max(h) > max {
+                let scale = max as f32 / w.max(h) as f32;
+                let size = [w, h].map(|d| ((d as f32 * scale).round() as u32).max(1));
+                log::info!("Downscaling a {}x{} texture to {}x{}", w, h, size[0], size[1]);
+                img = image::imageops::resize(&img, size[0], size[1], image::imageops::FilterType::Triangle);
+            }
+        }
         image::imageops::flip_vertical_in_place(&mut img);
         Ok(img)
     }
@@ -88,6 +172,10 @@ impl ImageTexture {
 }
 
 impl Texture for ImageTexture {
+    fn memory(&self) -> usize {
+        std::mem::size_of_val(self) + self.img.as_ref().map_or(0, |img| img.as_raw().capacity())
+    }
+
     fn value(&self, uv: [f32; 2], _p: Point3<f32>) -> Color {
         if let Some(img) = &self.img {
             let (w, h) = img.dimensions();
@@ -100,4 +188,165 @@ impl Texture for ImageTexture {
             Color::from([0., 1., 1.])
         }
     }
+
+    fn luminance(&self) -> Option<(usize, Vec<f32>)> {
+        let img = self.img.as_ref()?;
+        let luminance = img.pixels()
+            .map(|p| (0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32) * Self::COLOR_SCALE)
+            .collect();
+        Some((img.width() as usize, luminance))
+    }
+}
+
+/// Tiles a [`TiledTexture`] keeps in memory unless told otherwise, 48 MiB of 256x256 RGB tiles.
+pub const DEFAULT_TILE_CACHE: usize = 256;
+
+/// A tile of a [`TiledTexture`], rows of `width` RGB texels.
+struct Tile {
+    width: u32,
+    texels: Vec<[u8; 3]>,
+}
+
+/// The tiles read last, by index, with the time they were last used.
+struct TileCache {
+    tiles: HashMap<u32, (Arc<Tile>, u64)>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl TileCache {
+    fn get(&mut self, index: u32) -> Option<Arc<Tile>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.tiles.get_mut(&index).map(|(tile, used)| {
+            *used = clock;
+            tile.clone()
+        })
+    }
+
+    /// Keep `tile`, dropping the least recently used one when full.
+    fn insert(&mut self, index: u32, tile: Arc<Tile>) {
+        if self.tiles.len() >= self.capacity {
+            if let Some(&oldest) = self.tiles.iter().min_by_key(|(_, (_, used))| *used).map(|(index, _)| index) {
+                self.tiles.remove(&oldest);
+            }
+        }
+        self.tiles.insert(index, (tile, self.clock));
+    }
+}
+
+/// A huge image, e.g. the map of a planet, read from a tiled TIFF file a tile at a time as
+/// rays hit it, so only the last used tiles are held in memory instead of the whole image.
+pub struct TiledTexture {
+    decoder: Option<Mutex<tiff::decoder::Decoder<BufReader<File>>>>,
+    width: u32,
+    height: u32,
+    /// Size of the full tiles, the last ones of a row or column may be cut short.
+    tile_size: (u32, u32),
+    cache: Mutex<TileCache>,
+}
+
+impl TiledTexture {
+    /// Stream `path`, keeping `cache_tiles` tiles in memory. A missing or broken file is shown
+    /// in cyan, like a broken [`ImageTexture`].
+    pub fn new(path: &Path, cache_tiles: usize) -> Shared<Self> {
+        let cache = Mutex::new(TileCache { tiles: HashMap::new(), capacity: cache_tiles.max(1), clock: 0 });
+        let texture = match Self::open(path) {
+            Ok(mut decoder) => {
+                let ((width, height), tile_size) = (decoder.dimensions().unwrap_or_default(), decoder.chunk_dimensions());
+                log::info!("Streaming {:?}, {}x{} in tiles of {}x{}", path, width, height, tile_size.0, tile_size.1);
+                Self { decoder: Some(Mutex::new(decoder)), width, height, tile_size, cache }
+            }
+            Err(e) => {
+                log::error!("Failed to open texture {:?}: {}", path, e);
+                Self { decoder: None, width: 0, height: 0, tile_size: (1, 1), cache }
+            }
+        };
+        create_shared_mut(texture)
+    }
+
+    fn open(path: &Path) -> crate::Result<tiff::decoder::Decoder<BufReader<File>>> {
+        let file = File::open(path).map_err(|e| crate::Error::from(e).in_file(path))?;
+        let mut decoder = tiff::decoder::Decoder::new(BufReader::new(file))?;
+        Self::channels(decoder.colortype()?)?;
+        if let (0, _) | (_, 0) = decoder.dimensions()? {
+            return Err(crate::Error::Resource { name: path.display().to_string(), reason: "empty image".into() })
+        }
+        Ok(decoder)
+    }
+
+    /// Samples per texel of the color types that can be shown.
+    fn channels(color: tiff::ColorType) -> crate::Result<usize> {
+        use tiff::ColorType::*;
+        match color {
+            Gray(8 | 16) => Ok(1),
+            GrayA(8 | 16) => Ok(2),
+            RGB(8 | 16) => Ok(3),
+            RGBA(8 | 16) => Ok(4),
+            other => Err(crate::Error::Resource { name: "TIFF".into(), reason: format!("unsupported color type {:?}", other) }),
+        }
+    }
+
+    /// The tile `index`, read from the file unless cached.
+    fn tile(&self, index: u32) -> Option<Arc<Tile>> {
+        if let Some(tile) = self.cache.lock().unwrap().get(index) {
+            return Some(tile)
+        }
+        let mut decoder = self.decoder.as_ref()?.lock().unwrap();
+        // Another thread may have read it while this one waited for the file
+        if let Some(tile) = self.cache.lock().unwrap().get(index) {
+            return Some(tile)
+        }
+        let tile = Arc::new(Self::read_tile(&mut decoder, index, self.tile_size)
+            .map_err(|e| log::error!("Failed to read tile {}: {}", index, e))
+            .ok()?);
+        self.cache.lock().unwrap().insert(index, tile.clone());
+        Some(tile)
+    }
+
+    fn read_tile(decoder: &mut tiff::decoder::Decoder<BufReader<File>>, index: u32, tile_size: (u32, u32)) -> crate::Result<Tile> {
+        let channels = Self::channels(decoder.colortype()?)?;
+        let (width, height) = decoder.chunk_data_dimensions(index);
+        let samples: Vec<u8> = match decoder.read_chunk(index)? {
+            tiff::decoder::DecodingResult::U8(samples) => samples,
+            tiff::decoder::DecodingResult::U16(samples) => samples.into_iter().map(|s| (s >> 8) as u8).collect(),
+            _ => return Err(crate::Error::Resource { name: "TIFF".into(), reason: "unsupported sample format".into() }),
+        };
+        // Tiles at the edges may be stored padded to the full size
+        let stride = if samples.len() == (tile_size.0 * tile_size.1) as usize * channels { tile_size.0 } else { width };
+        let texels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (y * stride + x) as usize * channels))
+            .map(|i| match channels {
+                1 | 2 => [samples[i]; 3],
+                _ => [samples[i], samples[i + 1], samples[i + 2]],
+            })
+            .collect();
+        Ok(Tile { width, texels })
+    }
+}
+
+impl Texture for TiledTexture {
+    fn value(&self, uv: [f32; 2], _p: Point3<f32>) -> Color {
+        if self.decoder.is_none() {
+            return Color::from([0., 1., 1.])
+        }
+        // Rows are stored from the top, v grows upwards
+        let x = ((uv[0].clamp(0., 1.) * self.width as f32) as u32).min(self.width - 1);
+        let y = (((1. - uv[1].clamp(0., 1.)) * self.height as f32) as u32).min(self.height - 1);
+        let (tile_width, tile_height) = self.tile_size;
+        let tiles_across = (self.width + tile_width - 1) / tile_width;
+        match self.tile(y / tile_height * tiles_across + x / tile_width) {
+            Some(tile) => {
+                let texel = tile.texels[((y % tile_height) * tile.width + x % tile_width) as usize];
+                Color::from(texel.map(|c| c as f32 * ImageTexture::COLOR_SCALE))
+            }
+            None => Color::from([0., 1., 1.]),
+        }
+    }
+
+    fn memory(&self) -> usize {
+        let cache = self.cache.lock().unwrap();
+        std::mem::size_of_val(self)
+            + cache.tiles.values().map(|(tile, _)| tile.texels.len() * std::mem::size_of::<[u8; 3]>()).sum::<usize>()
+    }
 }
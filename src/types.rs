@@ -9,6 +9,9 @@ pub type Color = Vector3<f32>;
 pub type Shared<T> = Arc<RwLock<T>>;
 pub type SharedHittable = Shared<dyn Hittable>;
 pub type SharedSphere = Shared<Sphere>;
+// Materials and textures stay shared trait objects rather than arena handles with enum dispatch:
+// the GUI edits them in place through the objects using them, scene files and user code add
+// their own kinds, and hit records only bump a reference count instead of allocating.
 pub type SharedMaterial = Shared<dyn Material>;
 pub type SharedTexture = Shared<dyn Texture>;
 pub type RGB = [f32; 3];
@@ -16,3 +19,37 @@ pub type RGB = [f32; 3];
 pub fn create_shared_mut<T>(t: T) -> Shared<T> {
     Arc::new(RwLock::new(t))
 }
+
+/// Identity of a shared object, the same through every handle to it.
+pub fn shared_id<T: ?Sized>(shared: &Shared<T>) -> usize {
+    Arc::as_ptr(shared) as *const () as usize
+}
+
+/// Color of the light of a blackbody at `kelvin`, e.g. 2700 for a light bulb or 6500 for daylight,
+/// in linear sRGB scaled so the brightest channel is 1. Planck's law is integrated against the
+/// analytic fit of the CIE 1931 color matching functions by Wyman, Sloan and Shirley.
+pub fn blackbody(kelvin: f32) -> RGB {
+    let lobe = |lambda: f32, mu: f32, below: f32, above: f32| {
+        let t = (lambda - mu) / if lambda < mu { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    let kelvin = kelvin.max(100.);
+    let xyz = (380..=780).step_by(5).fold(Vector3::<f32>::zeros(), |xyz, nm| {
+        let lambda = nm as f32;
+        // Planck's law up to a constant factor, the wavelength in micrometers
+        let micro = lambda * 1e-3;
+        let radiance = 1. / (micro.powi(5) * ((1.4388e4 / (micro * kelvin)).exp() - 1.));
+        let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+        let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+        let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+        xyz + Vector3::new(x, y, z) * radiance
+    });
+    let rgb = Vector3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    ).map(|c| c.max(0.));
+    let max = rgb.max();
+    if max > 0. { (rgb / max).into() } else { [1.; 3] }
+}
@@ -0,0 +1,84 @@
+//! Renderer for embedding into web pages from JavaScript, without the GUI of [`crate::run`]:
+//!
+//! ```js
+//! const renderer = RtRenderer.new("canvas-id", "cornell");
+//! renderer.setSamples(64);
+//! const step = () => { if (renderer.renderFrame()) requestAnimationFrame(step); };
+//! step();
+//! ```
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+use crate::camera::{Camera, CameraSettings};
+use crate::renderer::{Precision, Renderer};
+use crate::scene::select_scene;
+use crate::scene_file::SceneFile;
+use crate::memory::memory_budget;
+
+#[wasm_bindgen]
+pub struct RtRenderer {
+    renderer: Renderer,
+    context: CanvasRenderingContext2d,
+    frame: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl RtRenderer {
+    /// Render into the canvas with id `canvas_id`, at its size. `scene` is either the JSON of a
+    /// scene file or the name of a built-in scene.
+    pub fn new(canvas_id: &str, scene: &str) -> Result<RtRenderer, JsValue> {
+        let canvas = web_sys::window()
+            .and_then(|win| win.document())
+            .and_then(|doc| doc.get_element_by_id(canvas_id))
+            .ok_or_else(|| JsValue::from_str(&format!("No element with id {}", canvas_id)))?
+            .dyn_into::<HtmlCanvasElement>()
+            .map_err(|_| JsValue::from_str(&format!("{} is not a canvas", canvas_id)))?;
+        let context = canvas
+            .get_context("2d")?
+            .ok_or_else(|| JsValue::from_str("No 2d context"))?
+            .dyn_into::<CanvasRenderingContext2d>()?;
+        let (width, height) = (canvas.width().max(1), canvas.height().max(1));
+        let (scene, camera) = if scene.trim_start().starts_with('{') {
+            let (scene, camera) = SceneFile::parse(scene.as_bytes(), "embedded", memory_budget(), None)
+                .map_err(|e| JsValue::from_str(&format!("{:?}", e)))?;
+            (scene, camera.unwrap_or_else(|| CameraSettings::select("")))
+        } else {
+            (select_scene(scene, None), CameraSettings::select(scene))
+        };
+        let camera = Camera::from_settings(&camera, width as f32 / height as f32);
+        Ok(Self {
+            renderer: Renderer::new(width, height, scene, camera),
+            context,
+            frame: vec![0; (width * height * 4) as usize],
+        })
+    }
+
+    /// Refine the image up to `samples` per pixel, starting over.
+    #[wasm_bindgen(js_name = setSamples)]
+    pub fn set_samples(&mut self, samples: usize) {
+        self.renderer.multisample = samples.max(1);
+        self.renderer.dirty = true;
+    }
+
+    /// Accumulate the image in half precision, for large canvases on phones, starting over.
+    #[wasm_bindgen(js_name = setHalfPrecision)]
+    pub fn set_half_precision(&mut self, half: bool) {
+        self.renderer.set_precision(if half { Precision::Half } else { Precision::Full });
+    }
+
+    /// Add a sample to the image and show it on the canvas.
+    /// Returns whether more samples are to come.
+    #[wasm_bindgen(js_name = renderFrame)]
+    pub fn render_frame(&mut self) -> Result<bool, JsValue> {
+        if self.renderer.draw_progressive(&mut self.frame) {
+            self.context.put_image_data(&self.image_data()?, 0., 0.)?;
+        }
+        Ok(self.renderer.samples_done < self.renderer.multisample)
+    }
+
+    /// The current image, as drawn on the canvas.
+    #[wasm_bindgen(js_name = getImageData)]
+    pub fn image_data(&self) -> Result<ImageData, JsValue> {
+        ImageData::new_with_u8_clamped_array_and_sh(Clamped(&self.frame), self.renderer.width, self.renderer.height)
+    }
+}
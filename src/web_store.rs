@@ -0,0 +1,115 @@
+//! The render in progress kept in IndexedDB with its settings, so reloading the page carries on
+//! with it instead of starting over.
+
+use js_sys::{Promise, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbOpenDbRequest, IdbRequest, IdbTransactionMode};
+use crate::error::{Error, Result};
+use crate::gui::Gui;
+
+const DATABASE: &str = "ray_tracing";
+const STORE: &str = "renders";
+/// Key of the render in progress, the only one kept.
+const CURRENT: &str = "current";
+
+fn js_err(e: JsValue) -> Error {
+    Error::Web(format!("{:?}", e))
+}
+
+/// The result of `request` once it succeeds.
+async fn done(request: &IdbRequest) -> Result<JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let target = request.clone();
+        let on_success = Closure::once_into_js(move |_: web_sys::Event| {
+            let _ = resolve.call1(&JsValue::NULL, &target.result().unwrap_or(JsValue::UNDEFINED));
+        });
+        let target = request.clone();
+        let on_error = Closure::once_into_js(move |_: web_sys::Event| {
+            let error = target.error().ok().flatten().map_or(JsValue::UNDEFINED, JsValue::from);
+            let _ = reject.call1(&JsValue::NULL, &error);
+        });
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        request.set_onerror(Some(on_error.unchecked_ref()));
+    });
+    JsFuture::from(promise).await.map_err(js_err)
+}
+
+async fn open() -> Result<IdbDatabase> {
+    let factory = web_sys::window()
+        .and_then(|win| win.indexed_db().ok().flatten())
+        .ok_or_else(|| Error::Web("No IndexedDB".into()))?;
+    let request = factory.open_with_u32(DATABASE, 1).map_err(js_err)?;
+    // Creating the store is only allowed while the database is set up
+    let on_upgrade = Closure::once_into_js(|e: web_sys::Event| {
+        let database = e.target()
+            .and_then(|target| target.dyn_into::<IdbOpenDbRequest>().ok())
+            .and_then(|request| request.result().ok())
+            .and_then(|result| result.dyn_into::<IdbDatabase>().ok());
+        if let Some(database) = database {
+            if let Err(e) = database.create_object_store(STORE) {
+                log::error!("Failed to create the render store: {:?}", e);
+            }
+        }
+    });
+    request.set_onupgradeneeded(Some(on_upgrade.unchecked_ref()));
+    Ok(done(&request).await?.unchecked_into())
+}
+
+/// Keep `render`, a [`crate::renderer::Renderer::checkpoint`], with the settings it was made with
+/// in place of the last one. Written in the background.
+pub fn save(gui: &Gui, render: Vec<u8>) {
+    let settings = match serde_json::to_vec(gui) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Failed to save the render: {}", e);
+            return
+        }
+    };
+    wasm_bindgen_futures::spawn_local(async move {
+        let saved = async {
+            let bytes = [&(settings.len() as u32).to_le_bytes()[..], &settings, &render].concat();
+            let store = open().await?
+                .transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)
+                .and_then(|transaction| transaction.object_store(STORE))
+                .map_err(js_err)?;
+            let request = store.put_with_key(&Uint8Array::from(&bytes[..]), &JsValue::from_str(CURRENT)).map_err(js_err)?;
+            done(&request).await
+        };
+        if let Err(e) = saved.await {
+            log::warn!("Failed to save the render: {}", e);
+        }
+    });
+}
+
+/// The render saved last and its settings, if there is one.
+pub async fn load() -> Option<(Gui, Vec<u8>)> {
+    let loaded = async {
+        let store = open().await?
+            .transaction_with_str(STORE)
+            .and_then(|transaction| transaction.object_store(STORE))
+            .map_err(js_err)?;
+        let request = store.get(&JsValue::from_str(CURRENT)).map_err(js_err)?;
+        done(&request).await
+    };
+    let value = match loaded.await {
+        Ok(value) if !value.is_undefined() => value,
+        Ok(_) => return None,
+        Err(e) => {
+            log::warn!("Failed to load the saved render: {}", e);
+            return None
+        }
+    };
+    let bytes = Uint8Array::new(&value).to_vec();
+    let length = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let settings = bytes.get(4..4 + length)?;
+    let render = &bytes[4 + length..];
+    match serde_json::from_slice(settings) {
+        Ok(gui) => Some((gui, render.to_vec())),
+        Err(e) => {
+            log::warn!("Ignoring the saved render: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Rendering on a pool of Web Workers sharing the wasm memory.
+//! The browser does not let the main thread block, so instead of joining the
+//! pool like rayon's parallel iterators do, it spins until every row is traced.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Start the workers, to be awaited from JavaScript before [`crate::run`].
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+/// Rows still being traced. Dropping it waits for all of them, also while unwinding, so no row
+/// outlives the pass and the closure it borrows.
+struct Pending(AtomicUsize);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        while self.0.load(Ordering::Acquire) > 0 {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// A row of a pass and what it is traced with, counted in `pending` from before its task is
+/// spawned until it was traced, or unwound out of.
+struct Row<'a, T, F> {
+    f: &'a F,
+    y: usize,
+    row: &'a mut [T],
+    pending: &'a Pending,
+}
+
+impl<T, F> Drop for Row<'_, T, F> {
+    fn drop(&mut self) {
+        self.pending.0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// A [`Row`] with its type and borrows erased, as rayon only takes `'static` tasks, the way
+/// rayon hands its own jobs around.
+struct Task {
+    row: *mut (),
+    trace: unsafe fn(*mut ()),
+}
+
+// SAFETY: it only stands for a `Row` of `Send` samples and a `Sync` closure.
+unsafe impl Send for Task {}
+
+impl Task {
+    fn new<T: Send, F: Fn(usize, &mut [T]) + Sync>(row: Row<'_, T, F>) -> Self {
+        /// SAFETY: `row` is the `Row<T, F>` the task was made with, its borrows still alive.
+        unsafe fn trace<T, F: Fn(usize, &mut [T])>(row: *mut ()) {
+            let mut row = Box::from_raw(row as *mut Row<'_, T, F>);
+            let f = row.f;
+            f(row.y, row.row);
+        }
+        Self { row: Box::into_raw(Box::new(row)) as *mut (), trace: trace::<T, F> }
+    }
+
+    fn run(self) {
+        // SAFETY: the `Pending` of the row waits for it before its borrows end
+        unsafe { (self.trace)(self.row) }
+    }
+}
+
+/// Call `f` with every row of `pass` and its index counted from the bottom, in parallel.
+pub fn for_each_row<T: Send, F>(pass: &mut [T], row_len: usize, f: F)
+    where F: Fn(usize, &mut [T]) + Sync
+{
+    let pending = Pending(AtomicUsize::new(0));
+    pass.chunks_exact_mut(row_len).rev().enumerate().for_each(|(y, row)| {
+        pending.0.fetch_add(1, Ordering::Relaxed);
+        let task = Task::new(Row { f: &f, y, row, pending: &pending });
+        rayon::spawn(move || task.run());
+    });
+    // `pending` is dropped before `f` and the borrow of `pass` end, waiting for every row
+}